@@ -187,8 +187,11 @@ async fn main() -> rsipstack::Result<()> {
                     content_type: None,
                     offer: None,
                     contact: contact.clone(),
-                    credential: Some(credential.clone()),
+                    credential: Some(credential.clone().into()),
                     headers: None,
+                    route_set: None,
+                    max_redirects: None,
+                    identity: None,
                 };
 
                 match make_call(dialog_layer, invite_option, opt, state_sender).await {
@@ -223,7 +226,7 @@ async fn process_registration(
         }
     }
 
-    let mut registration = Registration::new(endpoint, Some(credential));
+    let mut registration = Registration::new(endpoint, Some(credential.into()));
     loop {
         let resp = registration.register(&sip_server).await?;
         debug!("received response: {}", resp.to_string());
@@ -266,12 +269,15 @@ async fn process_incoming_request(
         // out dialog, new server dialog
         match tx.original.method {
             rsip::Method::Invite | rsip::Method::Ack => {
-                let mut dialog = match dialog_layer.get_or_create_server_invite(
-                    &tx,
-                    state_sender.clone(),
-                    None,
-                    Some(contact.clone()),
-                ) {
+                let mut dialog = match dialog_layer
+                    .get_or_create_server_invite(
+                        &tx,
+                        state_sender.clone(),
+                        None,
+                        Some(contact.clone()),
+                    )
+                    .await
+                {
                     Ok(d) => d,
                     Err(e) => {
                         // 481 Dialog/Transaction Does Not Exist
@@ -322,14 +328,17 @@ async fn process_dialog(
                     Dialog::ClientInvite(_) => {
                         info!("Client invite dialog {}", id);
                     }
+                    Dialog::ClientSubscription(_) | Dialog::ServerSubscription(_) => {
+                        info!("Subscription dialog {}", id);
+                    }
                 }
             }
             DialogState::Early(id, resp) => {
                 info!("Early dialog {} {}", id, resp);
             }
-            DialogState::Terminated(id, status_code) => {
-                info!("Dialog terminated {} {:?}", id, status_code);
-                dialog_layer.remove_dialog(&id);
+            DialogState::Terminated(id, status_code, reason) => {
+                info!("Dialog terminated {} {:?} {:?}", id, status_code, reason);
+                dialog_layer.remove_dialog(&id).await;
             }
             _ => {
                 info!("Received dialog state: {}", state);
@@ -391,7 +400,7 @@ async fn make_call(
     play_example_file(rtp_conn, rtp_token, ssrc, peer_addr)
         .await
         .expect("play example file");
-    dialog.bye().await.expect("send BYE");
+    dialog.bye(None).await.expect("send BYE");
     Ok(())
 }
 
@@ -433,7 +442,7 @@ async fn play_example_pcmu(opt: &MediaSessionOption, dialog: ServerInviteDialog)
         play_example_file(conn, rtp_token, ssrc, peer_addr)
             .await
             .expect("play example file");
-        dialog.bye().await.expect("send BYE");
+        dialog.bye(None).await.expect("send BYE");
     });
     Ok(())
 }
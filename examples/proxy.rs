@@ -272,7 +272,8 @@ async fn handle_invite(state: Arc<AppState>, mut tx: Transaction) -> Result<()>
         .unwrap_or_default();
     let target = state.users.lock().unwrap().get(&callee).cloned();
 
-    let record_route = tx.endpoint_inner.get_record_route()?;
+    // route in-dialog requests back over the caller's connection
+    let record_route = tx.endpoint_inner.get_flow_record_route(&tx)?;
 
     let target = match target {
         Some(u) => u,
@@ -384,10 +385,15 @@ async fn handle_bye(state: Arc<AppState>, mut tx: Transaction) -> Result<()> {
     let key = TransactionKey::from_request(&inv_req, TransactionRole::Client)
         .expect("client_transaction");
 
-    info!("Forwarding BYE to: {} -> {}", caller, peer.destination);
+    let flow = tx.endpoint_inner.transport_layer.route_flow(&tx.original);
+    let (connection, destination) = match flow {
+        Some(flow) => (Some(flow.connection), flow.remote),
+        None => (None, peer.destination),
+    };
+    info!("Forwarding BYE to: {} -> {}", caller, destination);
 
-    let mut bye_tx = Transaction::new_client(key, inv_req, tx.endpoint_inner.clone(), None);
-    bye_tx.destination = Some(peer.destination);
+    let mut bye_tx = Transaction::new_client(key, inv_req, tx.endpoint_inner.clone(), connection);
+    bye_tx.destination = Some(destination);
 
     bye_tx.send().await?;
 
@@ -6,7 +6,7 @@ use rsipstack::dialog::invitation::InviteOption;
 use rsipstack::dialog::DialogId;
 use rsipstack::Result;
 use rsipstack::{
-    dialog::authenticate::Credential,
+    dialog::authenticate::CredentialStoreRef,
     transaction::TransactionReceiver,
     transport::{udp::UdpConnection, TransportLayer},
     EndpointBuilder, Error,
@@ -102,6 +102,7 @@ async fn run_server(
                             None,
                             Some(contact.clone()),
                         )
+                        .await
                         .unwrap();
 
                     tokio::spawn(async move {
@@ -112,7 +113,7 @@ async fn run_server(
                     if let Ok(dialog_id) = DialogId::try_from(&tx.original) {
                         stats.active_calls.lock().unwrap().remove(&dialog_id);
                         tx.reply(rsip::StatusCode::OK).await.ok();
-                        dialog_layer.remove_dialog(&dialog_id);
+                        dialog_layer.remove_dialog(&dialog_id).await;
                     }
                 }
                 _ => {}
@@ -126,7 +127,7 @@ type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
 async fn run_client(
     dialog_layer: Arc<DialogLayer>,
     contact: rsip::Uri,
-    credential: Option<Credential>,
+    credential: Option<CredentialStoreRef>,
     concurrent_calls: u32,
     state_sender: DialogStateSender,
     stats: Stats,
@@ -176,6 +177,9 @@ async fn run_client(
                         contact,
                         credential,
                         headers: None,
+                        route_set: None,
+                        max_redirects: None,
+                        identity: None,
                     };
                     stats.total_calls.fetch_add(1, Ordering::Relaxed);
 
@@ -204,7 +208,7 @@ async fn run_client(
                     };
                     let duration = Duration::from_secs(rand::random_range(3..=10));
                     sleep(duration).await;
-                    dialog.bye().await.ok();
+                    dialog.bye(None).await.ok();
                 });
             }
         }
@@ -282,7 +286,7 @@ async fn process_dialog_state(
                     .unwrap()
                     .insert(id, Instant::now());
             }
-            DialogState::Terminated(id, status) => {
+            DialogState::Terminated(id, status, _) => {
                 match status {
                     Some(status) => {
                         if status == rsip::StatusCode::BusyHere {
@@ -293,7 +297,7 @@ async fn process_dialog_state(
                     }
                     None => {}
                 }
-                dialog_layer.remove_dialog(&id);
+                dialog_layer.remove_dialog(&id).await;
                 // Remove from active calls tracking
                 stats.active_calls.lock().unwrap().remove(&id);
             }
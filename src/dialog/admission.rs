@@ -0,0 +1,53 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+};
+use tokio::time::{Duration, Instant};
+
+/// Counters of the admission of new INVITE dialogs by a dialog layer, see
+/// `DialogLimits`
+#[derive(Default, Debug)]
+pub struct AdmissionStats {
+    pub admitted: AtomicU64,
+    /// Rejected over `max_dialogs`
+    pub rejected_dialogs: AtomicU64,
+    /// Rejected over `max_confirmed_dialogs`
+    pub rejected_confirmed: AtomicU64,
+    /// Rejected over `max_call_attempts_per_second`
+    pub rejected_rate: AtomicU64,
+}
+
+impl AdmissionStats {
+    pub fn rejected(&self) -> u64 {
+        self.rejected_dialogs.load(Ordering::Relaxed)
+            + self.rejected_confirmed.load(Ordering::Relaxed)
+            + self.rejected_rate.load(Ordering::Relaxed)
+    }
+}
+
+/// Call attempts of the current one-second window
+#[derive(Debug)]
+pub struct CallRateLimiter {
+    window: Mutex<(Instant, u32)>,
+}
+
+impl Default for CallRateLimiter {
+    fn default() -> Self {
+        Self {
+            window: Mutex::new((Instant::now(), 0)),
+        }
+    }
+}
+
+impl CallRateLimiter {
+    /// Counts an attempt made at `now`, false when it's beyond
+    /// `max_per_second` attempts in the current window
+    pub fn try_acquire(&self, max_per_second: u32, now: Instant) -> bool {
+        let mut window = self.window.lock().unwrap();
+        if now.duration_since(window.0) >= Duration::from_secs(1) {
+            *window = (now, 0);
+        }
+        window.1 += 1;
+        window.1 <= max_per_second
+    }
+}
@@ -1,13 +1,17 @@
+use super::digest::{DigestChallenge, DigestCredentials};
 use super::DialogId;
+use crate::rsip_ext::next_hop;
 use crate::transaction::key::{TransactionKey, TransactionRole};
 use crate::transaction::transaction::Transaction;
 use crate::transaction::{make_via_branch, random_text, CNONCE_LEN};
 use crate::Result;
-use rsip::headers::auth::AuthQop;
-use rsip::prelude::{HasHeaders, HeadersExt, ToTypedHeader};
-use rsip::services::DigestGenerator;
-use rsip::typed::{Authorization, ProxyAuthorization};
-use rsip::{Header, Param, Response};
+use rsip::prelude::{HasHeaders, HeadersExt, UntypedHeader};
+use rsip::{Header, Param, Request, Response};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tracing::{debug, info};
 
 #[derive(Clone)]
 pub struct Credential {
@@ -15,62 +19,363 @@ pub struct Credential {
     pub password: String,
 }
 
+/// Credentials of a client, answering the digest challenges of a realm
+/// sent for a destination: the host and port of the next hop of the
+/// challenged request. A UA talking to several providers or proxies, each
+/// its own realm, answers all of them on the same path.
+pub trait CredentialStore: Send + Sync {
+    fn credential(&self, realm: &str, destination: &str) -> Option<Credential>;
+    /// User the requests to `destination` are sent as before any challenge,
+    /// e.g. in the From and To of a REGISTER
+    fn username(&self, destination: &str) -> Option<String>;
+}
+pub type CredentialStoreRef = Arc<dyn CredentialStore>;
+
+/// A single credential answers every realm
+impl CredentialStore for Credential {
+    fn credential(&self, _realm: &str, _destination: &str) -> Option<Credential> {
+        Some(self.clone())
+    }
+
+    fn username(&self, _destination: &str) -> Option<String> {
+        Some(self.username.clone())
+    }
+}
+
+impl From<Credential> for CredentialStoreRef {
+    fn from(credential: Credential) -> Self {
+        Arc::new(credential)
+    }
+}
+
+/// Credentials per realm, optionally restricted to a destination, with a
+/// fallback for the other realms
+#[derive(Clone, Default)]
+pub struct RealmCredentials {
+    entries: Vec<(String, Option<String>, Credential)>,
+    fallback: Option<Credential>,
+}
+
+impl RealmCredentials {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answers the challenges of `realm`, whatever the destination
+    pub fn with_realm(mut self, realm: &str, credential: Credential) -> Self {
+        self.entries.push((realm.to_string(), None, credential));
+        self
+    }
+
+    /// Answers the challenges of `realm` sent for `destination` only, it
+    /// takes precedence over `with_realm`
+    pub fn with_destination(
+        mut self,
+        realm: &str,
+        destination: &str,
+        credential: Credential,
+    ) -> Self {
+        self.entries
+            .push((realm.to_string(), Some(destination.to_string()), credential));
+        self
+    }
+
+    /// Answers the challenges of the realms not listed
+    pub fn with_fallback(mut self, credential: Credential) -> Self {
+        self.fallback = Some(credential);
+        self
+    }
+}
+
+impl CredentialStore for RealmCredentials {
+    fn credential(&self, realm: &str, destination: &str) -> Option<Credential> {
+        let of_realm = |(r, _, _): &&(String, Option<String>, Credential)| r == realm;
+        self.entries
+            .iter()
+            .filter(of_realm)
+            .find(|(_, d, _)| {
+                d.as_deref()
+                    .is_some_and(|d| d.eq_ignore_ascii_case(destination))
+            })
+            .or_else(|| {
+                self.entries
+                    .iter()
+                    .filter(of_realm)
+                    .find(|(_, d, _)| d.is_none())
+            })
+            .map(|(_, _, credential)| credential.clone())
+            .or_else(|| self.fallback.clone())
+    }
+
+    fn username(&self, destination: &str) -> Option<String> {
+        self.entries
+            .iter()
+            .find(|(_, d, _)| {
+                d.as_deref()
+                    .is_some_and(|d| d.eq_ignore_ascii_case(destination))
+            })
+            .map(|(_, _, credential)| credential)
+            .or(self.fallback.as_ref())
+            .or_else(|| self.entries.first().map(|(_, _, credential)| credential))
+            .map(|credential| credential.username.clone())
+    }
+}
+
+/// A challenge answered, and the nonce count of its last use
+struct CachedChallenge {
+    challenge: DigestChallenge,
+    nc: u32,
+}
+
+/// Destination, realm, kind (WWW or Proxy) and username of a challenge
+/// answered
+type ChallengeKey = (String, String, bool, String);
+
+/// Challenges answered per destination, realm, kind and credential, so that
+/// the next requests are authorized ahead of time instead of taking a
+/// 401/407 round trip each (RFC 7616 3.4.5). A challenge answered with a
+/// credential is never replayed with another one.
+#[derive(Default)]
+pub struct AuthCache {
+    challenges: Mutex<HashMap<ChallengeKey, CachedChallenge>>,
+}
+
+/// Next hop of a request, whose challenges it gets
+pub fn auth_destination(request: &Request) -> String {
+    next_hop(request)
+        .unwrap_or_else(|| request.uri.clone())
+        .host_with_port
+        .to_string()
+}
+
+/// Authorization or Proxy-Authorization header carrying `auth`
+fn authorization_header(auth: &DigestCredentials, is_proxy: bool) -> Header {
+    if is_proxy {
+        Header::ProxyAuthorization(auth.to_string().into())
+    } else {
+        Header::Authorization(auth.to_string().into())
+    }
+}
+
+impl AuthCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remembers `challenge`, just answered as `username` with nonce count 1
+    pub fn remember(
+        &self,
+        request: &Request,
+        username: &str,
+        challenge: DigestChallenge,
+        is_proxy: bool,
+    ) {
+        let key = (
+            auth_destination(request),
+            challenge.realm.clone(),
+            is_proxy,
+            username.to_string(),
+        );
+        self.challenges
+            .lock()
+            .unwrap()
+            .insert(key, CachedChallenge { challenge, nc: 1 });
+    }
+
+    /// Authorization and Proxy-Authorization headers answering the cached
+    /// challenges of the destination of `request` answered with the same
+    /// credentials, with the next nonce count and a new cnonce. Empty for
+    /// ACK and CANCEL, which reuse those of their INVITE.
+    pub fn authorize(&self, request: &Request, credentials: &CredentialStoreRef) -> Vec<Header> {
+        if matches!(request.method, rsip::Method::Ack | rsip::Method::Cancel) {
+            return vec![];
+        }
+        let destination = auth_destination(request);
+        let mut challenges = self.challenges.lock().unwrap();
+        let mut headers = vec![];
+        for ((_, realm, is_proxy, username), cached) in challenges
+            .iter_mut()
+            .filter(|((d, _, _, _), _)| d == &destination)
+        {
+            let cred = match credentials.credential(realm, &destination) {
+                Some(cred) if cred.username == *username => cred,
+                _ => continue,
+            };
+            cached.nc += 1;
+            let mut auth = DigestCredentials::answer(
+                &cached.challenge,
+                &cred.username,
+                &cred.password,
+                &request.method,
+                &request.uri.to_string(),
+                &request.body,
+                &random_text(CNONCE_LEN),
+            );
+            if auth.qop.is_some() {
+                let ha1 = cached
+                    .challenge
+                    .algorithm
+                    .ha1(&cred.username, realm, &cred.password);
+                auth.nc = Some(cached.nc);
+                auth.response = auth.expected_response(&ha1, &request.method, &request.body);
+            }
+            debug!(
+                "preemptive authorization for {} realm {}, nc {}",
+                destination, realm, cached.nc
+            );
+            headers.push(authorization_header(&auth, *is_proxy));
+        }
+        headers
+    }
+
+    /// Adds the cached authorizations to `request` unless it carries some
+    pub fn authorize_request(&self, request: &mut Request, credentials: &CredentialStoreRef) {
+        let authorized = request
+            .headers
+            .iter()
+            .any(|h| matches!(h, Header::Authorization(_) | Header::ProxyAuthorization(_)));
+        if !authorized {
+            let headers = self.authorize(request, credentials);
+            request.headers.extend(headers);
+        }
+    }
+
+    /// Forgets the challenges answered by the authorizations of `request`,
+    /// once they got rejected
+    pub fn forget(&self, request: &Request) {
+        let destination = auth_destination(request);
+        let mut challenges = self.challenges.lock().unwrap();
+        for (value, is_proxy) in request.headers.iter().filter_map(|h| match h {
+            Header::Authorization(h) => Some((h.value(), false)),
+            Header::ProxyAuthorization(h) => Some((h.value(), true)),
+            _ => None,
+        }) {
+            if let Some(auth) = DigestCredentials::parse(value) {
+                debug!(
+                    "forgetting the challenge of {} realm {} for {}",
+                    destination, auth.realm, auth.username
+                );
+                challenges.remove(&(destination.clone(), auth.realm, is_proxy, auth.username));
+            }
+        }
+    }
+}
+
+/// Stale re-challenges answered in a row before giving up on a server that
+/// keeps expiring its nonces
+const MAX_STALE_RETRIES: u32 = 2;
+
+/// Whether a challenge of `resp` says the nonce answered expired, the
+/// credentials being right (RFC 7616 3.3)
+pub fn is_stale_challenge(resp: &Response) -> bool {
+    resp.headers()
+        .iter()
+        .filter_map(|h| match h {
+            Header::WwwAuthenticate(h) => Some(h.value()),
+            Header::ProxyAuthenticate(h) => Some(h.value()),
+            _ => None,
+        })
+        .filter_map(DigestChallenge::parse)
+        .any(|challenge| challenge.stale)
+}
+
+/// The digest challenges answered for a request: the first one, then only
+/// the re-challenges of a stale nonce, which aren't authentication failures
+#[derive(Default)]
+pub struct AuthAttempts {
+    answered: bool,
+    stale_retries: u32,
+}
+
+impl AuthAttempts {
+    /// Whether the challenge of `resp` may be answered, counting it
+    pub fn may_answer(&mut self, resp: &Response) -> bool {
+        if !self.answered {
+            self.answered = true;
+            return true;
+        }
+        if self.stale_retries < MAX_STALE_RETRIES && is_stale_challenge(resp) {
+            self.stale_retries += 1;
+            info!("nonce went stale, answering the new challenge");
+            return true;
+        }
+        false
+    }
+}
+
+/// Answers the digest challenges of `resp` with a copy of the request of
+/// `tx`, CSeq `new_seq`. Each realm challenged, e.g. a proxy and the UAS, is
+/// answered with the credential of `credentials` for it and the destination
+/// of the request; of several challenges of a realm, e.g. one per algorithm,
+/// the first one with an algorithm we support (RFC 8760 2.4). Authorizations
+/// of the realms not challenged again are kept.
 pub async fn handle_client_authenticate(
     new_seq: u32,
     tx: Transaction,
     resp: Response,
-    cred: &Credential,
+    credentials: &CredentialStoreRef,
 ) -> Result<Transaction> {
-    let header = match resp.www_authenticate_header() {
-        Some(h) => Header::WwwAuthenticate(h.clone()),
-        None => {
-            let proxy_header = rsip::header_opt!(resp.headers().iter(), Header::ProxyAuthenticate);
-            let proxy_header = proxy_header.ok_or(crate::Error::DialogError(
-                "missing proxy/www authenticate".to_string(),
-                DialogId::try_from(&tx.original)?,
-            ))?;
-            Header::ProxyAuthenticate(proxy_header.clone())
+    let mut challenges: Vec<(DigestChallenge, bool)> = vec![];
+    for (value, is_proxy) in resp.headers().iter().filter_map(|h| match h {
+        Header::WwwAuthenticate(h) => Some((h.value(), false)),
+        Header::ProxyAuthenticate(h) => Some((h.value(), true)),
+        _ => None,
+    }) {
+        let challenge = match DigestChallenge::parse(value) {
+            Some(challenge) => challenge,
+            None => continue,
+        };
+        if !challenges
+            .iter()
+            .any(|(c, p)| c.realm == challenge.realm && *p == is_proxy)
+        {
+            challenges.push((challenge, is_proxy));
         }
-    };
+    }
+    if challenges.is_empty() {
+        return Err(crate::Error::DialogError(
+            "missing proxy/www authenticate".to_string(),
+            DialogId::try_from(&tx.original)?,
+        ));
+    }
+
+    let destination = auth_destination(&tx.original);
+    let mut authorizations = vec![];
+    for (challenge, is_proxy) in challenges {
+        let cred = match credentials.credential(&challenge.realm, &destination) {
+            Some(cred) => cred,
+            None => {
+                info!(
+                    "no credential for realm {} at {}",
+                    challenge.realm, destination
+                );
+                continue;
+            }
+        };
+        let auth = DigestCredentials::answer(
+            &challenge,
+            &cred.username,
+            &cred.password,
+            &tx.original.method,
+            &tx.original.uri.to_string(),
+            &tx.original.body,
+            &random_text(CNONCE_LEN),
+        );
+        tx.endpoint_inner
+            .auth_cache
+            .remember(&tx.original, &cred.username, challenge, is_proxy);
+        authorizations.push((auth, is_proxy));
+    }
+    if authorizations.is_empty() {
+        return Err(crate::Error::DialogError(
+            format!("no credential for the realms challenged by {}", destination),
+            DialogId::try_from(&tx.original)?,
+        ));
+    }
 
     let mut new_req = tx.original.clone();
     new_req.cseq_header_mut()?.mut_seq(new_seq)?;
 
-    let auth_qop = AuthQop::Auth {
-        cnonce: random_text(CNONCE_LEN),
-        nc: 1,
-    };
-
-    let challenge = match &header {
-        Header::WwwAuthenticate(h) => h.typed()?,
-        Header::ProxyAuthenticate(h) => h.typed()?.0,
-        _ => unreachable!(),
-    };
-
-    let response = DigestGenerator {
-        username: cred.username.as_str(),
-        password: cred.password.as_str(),
-        algorithm: challenge.algorithm.unwrap_or_default(),
-        nonce: challenge.nonce.as_str(),
-        method: &tx.original.method,
-        qop: Some(&auth_qop),
-        uri: &tx.original.uri,
-        realm: challenge.realm.as_str(),
-    }
-    .compute();
-
-    let auth = Authorization {
-        scheme: challenge.scheme,
-        username: cred.username.clone(),
-        realm: challenge.realm,
-        nonce: challenge.nonce,
-        uri: tx.original.uri.clone(),
-        response,
-        algorithm: challenge.algorithm,
-        opaque: challenge.opaque,
-        qop: Some(auth_qop),
-    };
-
     let via_header = tx.original.via_header()?.clone();
 
     // update new branch
@@ -79,26 +384,23 @@ pub async fn handle_client_authenticate(
     params.push(Param::Other("rport".into(), None));
     new_req.headers_mut().unique_push(via_header.into());
 
-    new_req.headers_mut().retain(|h| {
-        !matches!(
-            h,
-            Header::ProxyAuthenticate(_)
-                | Header::Authorization(_)
-                | Header::WwwAuthenticate(_)
-                | Header::ProxyAuthorization(_)
-        )
+    let answered = |value: &str, is_proxy: bool| {
+        DigestCredentials::parse(value).map_or(true, |previous| {
+            authorizations
+                .iter()
+                .any(|(auth, p)| auth.realm == previous.realm && *p == is_proxy)
+        })
+    };
+    new_req.headers_mut().retain(|h| match h {
+        Header::ProxyAuthenticate(_) | Header::WwwAuthenticate(_) => false,
+        Header::Authorization(h) => !answered(h.value(), false),
+        Header::ProxyAuthorization(h) => !answered(h.value(), true),
+        _ => true,
     });
-
-    match header {
-        Header::WwwAuthenticate(_) => {
-            new_req.headers_mut().unique_push(auth.into());
-        }
-        Header::ProxyAuthenticate(_) => {
-            new_req
-                .headers_mut()
-                .unique_push(ProxyAuthorization(auth).into());
-        }
-        _ => unreachable!(),
+    for (auth, is_proxy) in authorizations.iter() {
+        new_req
+            .headers_mut()
+            .push(authorization_header(auth, *is_proxy));
     }
     let key = TransactionKey::from_request(&new_req, TransactionRole::Client)?;
     let new_tx = Transaction::new_client(
@@ -0,0 +1,405 @@
+use super::{
+    client_dialog::{ClientInviteDialog, InviteOutcome, ProgressCallback},
+    dialog::{DialogState, DialogStateReceiver, OfferAnswerHandler},
+    dialog_layer::DialogLayer,
+    dtmf::DtmfEvent,
+    invitation::InviteOption,
+    reason::Reason,
+    server_dialog::ServerInviteDialog,
+};
+use crate::{rsip_ext::header_value, Error, Result};
+use rsip::{Header, Response, StatusCode};
+use std::sync::Arc;
+use tokio::{select, sync::mpsc::unbounded_channel};
+use tracing::{info, warn};
+
+/// A leg of a bridged call: the caller is the UAS side, the callee the UAC
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BridgeLeg {
+    Caller,
+    Callee,
+}
+
+impl BridgeLeg {
+    pub fn other(self) -> Self {
+        match self {
+            BridgeLeg::Caller => BridgeLeg::Callee,
+            BridgeLeg::Callee => BridgeLeg::Caller,
+        }
+    }
+}
+
+/// Rewrites what a `B2bua` relays from one leg to the other, e.g. to hide
+/// topology, anchor media or add headers. `to` is the leg the message is
+/// sent on. Every method defaults to relaying unchanged.
+pub trait BridgeHooks: Send + Sync {
+    /// Headers of a request or response relayed toward `to`, seeded with
+    /// the extension headers (`Header::Other`) of the message received
+    fn rewrite_headers(&self, _to: BridgeLeg, _method: &rsip::Method, _headers: &mut Vec<Header>) {}
+    /// Session description relayed toward `to`
+    fn rewrite_sdp(&self, _to: BridgeLeg, sdp: Vec<u8>) -> Vec<u8> {
+        sdp
+    }
+}
+pub type BridgeHooksRef = Arc<dyn BridgeHooks>;
+
+struct NoHooks;
+impl BridgeHooks for NoHooks {}
+
+/// Headers relayed along a message received on the other leg
+pub(crate) fn relayed_headers(
+    hooks: &BridgeHooksRef,
+    to: BridgeLeg,
+    method: &rsip::Method,
+    headers: &rsip::Headers,
+) -> Vec<Header> {
+    let mut relayed = headers
+        .iter()
+        .filter(|h| matches!(h, Header::Other(..)))
+        .cloned()
+        .collect();
+    hooks.rewrite_headers(to, method, &mut relayed);
+    relayed
+}
+
+fn sdp_headers(headers: &mut Vec<Header>, body: &[u8]) {
+    if !body.is_empty() && !headers.iter().any(|h| matches!(h, Header::ContentType(_))) {
+        headers.push(Header::ContentType("application/sdp".into()));
+    }
+}
+
+#[derive(Clone)]
+enum LegDialog {
+    Caller(ServerInviteDialog),
+    Callee(ClientInviteDialog),
+}
+
+impl LegDialog {
+    /// Sends an offer in a re-INVITE, or in an UPDATE when `method` is one
+    async fn offer(
+        &self,
+        method: &rsip::Method,
+        headers: Vec<Header>,
+        body: Vec<u8>,
+    ) -> Result<Option<Response>> {
+        let (headers, body) = (Some(headers), Some(body));
+        match (self, method) {
+            (LegDialog::Caller(dialog), rsip::Method::Update) => dialog.update(headers, body).await,
+            (LegDialog::Callee(dialog), rsip::Method::Update) => dialog.update(headers, body).await,
+            (LegDialog::Caller(dialog), _) => dialog.reinvite(headers, body).await,
+            (LegDialog::Callee(dialog), _) => dialog.reinvite(headers, body).await,
+        }
+    }
+
+    /// Whether the leg sent `request` itself
+    fn is_local_request(&self, request: &rsip::Request) -> bool {
+        match self {
+            LegDialog::Caller(dialog) => dialog.inner.is_local_request(request),
+            LegDialog::Callee(dialog) => dialog.inner.is_local_request(request),
+        }
+    }
+
+    async fn info(
+        &self,
+        headers: Vec<Header>,
+        content_type: Option<String>,
+        body: Vec<u8>,
+    ) -> Result<Option<Response>> {
+        match self {
+            LegDialog::Caller(dialog) => dialog.info(Some(headers), content_type, Some(body)).await,
+            LegDialog::Callee(dialog) => dialog.info(Some(headers), content_type, Some(body)).await,
+        }
+    }
+
+    async fn send_dtmf(&self, event: &DtmfEvent) -> Result<Option<Response>> {
+        match self {
+            LegDialog::Caller(dialog) => dialog.send_dtmf(event.digit, event.duration).await,
+            LegDialog::Callee(dialog) => dialog.send_dtmf(event.digit, event.duration).await,
+        }
+    }
+
+    async fn bye(&self, headers: Vec<Header>) -> Result<()> {
+        match self {
+            LegDialog::Caller(dialog) => dialog.bye(Some(headers)).await,
+            LegDialog::Callee(dialog) => dialog.bye(Some(headers)).await,
+        }
+    }
+}
+
+/// Answers the offers of a leg, re-INVITEs and UPDATEs, with the answer the
+/// other leg gives to the same request carrying it
+struct RelayOffer {
+    /// Leg the offer is relayed to
+    to: BridgeLeg,
+    target: LegDialog,
+    hooks: BridgeHooksRef,
+}
+
+#[async_trait::async_trait]
+impl OfferAnswerHandler for RelayOffer {
+    async fn on_offer(&self, offer: Vec<u8>) -> Result<Vec<u8>> {
+        self.on_session_offer(&rsip::Method::Invite, offer).await
+    }
+
+    async fn on_answer(&self, _answer: Vec<u8>) -> Result<()> {
+        Ok(())
+    }
+
+    async fn on_session_offer(&self, method: &rsip::Method, offer: Vec<u8>) -> Result<Vec<u8>> {
+        let offer = self.hooks.rewrite_sdp(self.to, offer);
+        let mut headers = vec![];
+        self.hooks.rewrite_headers(self.to, method, &mut headers);
+        sdp_headers(&mut headers, &offer);
+        match self.target.offer(method, headers, offer).await? {
+            Some(resp) if resp.status_code.kind() == rsip::StatusCodeKind::Successful => {
+                Ok(self.hooks.rewrite_sdp(self.to.other(), resp.body))
+            }
+            resp => Err(Error::Error(format!(
+                "offer rejected by the {:?} leg: {:?}",
+                self.to,
+                resp.map(|r| r.status_code)
+            ))),
+        }
+    }
+}
+
+/// Back-to-back user agent bridging an incoming call, the caller leg, to an
+/// outgoing one, the callee leg. Provisional and final responses, offers
+/// (re-INVITE, UPDATE), INFO, DTMF and BYE of a leg are relayed to the
+/// other, through the `BridgeHooks` rewriting them.
+///
+/// The INVITE of the caller must carry the offer.
+pub struct B2bua {
+    pub caller: ServerInviteDialog,
+    pub callee: ClientInviteDialog,
+    hooks: BridgeHooksRef,
+    caller_states: DialogStateReceiver,
+    callee_states: DialogStateReceiver,
+}
+
+impl B2bua {
+    /// Bridges the established dialogs `caller` and `callee`
+    pub(super) fn new(
+        caller: (ServerInviteDialog, DialogStateReceiver),
+        callee: (ClientInviteDialog, DialogStateReceiver),
+        hooks: Option<BridgeHooksRef>,
+    ) -> Self {
+        Self {
+            caller: caller.0,
+            callee: callee.0,
+            hooks: hooks.unwrap_or_else(|| Arc::new(NoHooks)),
+            caller_states: caller.1,
+            callee_states: callee.1,
+        }
+    }
+
+    /// Calls the callee of `opt` with the offer of `caller`, relaying its
+    /// progress, and answers `caller` with the answer of the callee.
+    /// `caller_states` are the states of `caller`, a CANCEL of the caller
+    /// cancels the callee. Fails when the callee doesn't answer, the caller
+    /// being rejected with the status of the callee.
+    pub async fn connect(
+        layer: &DialogLayer,
+        caller: ServerInviteDialog,
+        mut caller_states: DialogStateReceiver,
+        mut opt: InviteOption,
+        hooks: Option<BridgeHooksRef>,
+    ) -> Result<Self> {
+        let hooks = hooks.unwrap_or_else(|| Arc::new(NoHooks));
+        let request = caller.initial_request().clone();
+        let offer = hooks.rewrite_sdp(BridgeLeg::Callee, request.body.clone());
+        let mut headers = relayed_headers(
+            &hooks,
+            BridgeLeg::Callee,
+            &rsip::Method::Invite,
+            &request.headers,
+        );
+        if let Some(content_type) = header_value(&request.headers, "Content-Type") {
+            opt.content_type.get_or_insert(content_type);
+        }
+        headers.extend(opt.headers.take().unwrap_or_default());
+        opt.headers = Some(headers);
+        opt.offer = Some(offer).filter(|o| !o.is_empty());
+
+        let (callee_sender, callee_states) = unbounded_channel();
+        let (callee, tx) = layer.create_client_invite(opt, callee_sender).await?;
+        let id = callee.id();
+
+        let progress_caller = caller.clone();
+        let progress_hooks = hooks.clone();
+        let on_progress: ProgressCallback = Box::new(move |resp: &Response| {
+            if resp.status_code == StatusCode::Trying {
+                return;
+            }
+            let mut headers = relayed_headers(
+                &progress_hooks,
+                BridgeLeg::Caller,
+                &rsip::Method::Invite,
+                &resp.headers,
+            );
+            let body = progress_hooks.rewrite_sdp(BridgeLeg::Caller, resp.body.clone());
+            sdp_headers(&mut headers, &body);
+            if let Err(e) = progress_caller.provisional(
+                resp.status_code.clone(),
+                Some(headers),
+                Some(body).filter(|b| !b.is_empty()),
+            ) {
+                warn!(
+                    "failed to relay {} to the caller: {:?}",
+                    resp.status_code, e
+                );
+            }
+        });
+
+        let answer = callee.wait_for_answer(tx, Some(on_progress));
+        tokio::pin!(answer);
+        let mut caller_gone = false;
+        let result = loop {
+            select! {
+                result = &mut answer => break result,
+                state = caller_states.recv(), if !caller_gone => {
+                    if matches!(state, Some(DialogState::Terminated(..)) | None) {
+                        info!("caller left, cancelling {}", id);
+                        caller_gone = true;
+                        if let Err(e) = callee.cancel(None).await {
+                            warn!("failed to cancel the callee: {:?}", e);
+                        }
+                    }
+                }
+            }
+        };
+        layer.inner.remove_dialog(&id).await;
+        // moved there by the remote tag of the final response
+        if callee.id() != id {
+            layer.inner.remove_dialog(&callee.id()).await;
+        }
+        let (new_id, outcome) = result?;
+
+        let (status, resp) = match outcome {
+            InviteOutcome::Answered { response, sdp } => {
+                layer
+                    .inner
+                    .insert_dialog(new_id, super::dialog::Dialog::ClientInvite(callee.clone()))
+                    .await;
+                let body = hooks.rewrite_sdp(BridgeLeg::Caller, sdp.unwrap_or_default());
+                let mut headers = relayed_headers(
+                    &hooks,
+                    BridgeLeg::Caller,
+                    &rsip::Method::Invite,
+                    &response.headers,
+                );
+                sdp_headers(&mut headers, &body);
+                if let Err(e) = caller.accept(Some(headers), Some(body)) {
+                    // the caller left while the callee answered
+                    callee.bye(None).await.ok();
+                    return Err(e);
+                }
+                return Ok(B2bua::new(
+                    (caller, caller_states),
+                    (callee, callee_states),
+                    Some(hooks),
+                ));
+            }
+            InviteOutcome::Busy(resp)
+            | InviteOutcome::Declined(resp)
+            | InviteOutcome::Rejected(resp) => (resp.status_code.clone(), Some(resp)),
+            InviteOutcome::Redirected(_) => (StatusCode::TemporarilyUnavailable, None),
+            InviteOutcome::Timeout => (StatusCode::RequestTimeout, None),
+            InviteOutcome::Cancelled => (StatusCode::RequestTerminated, None),
+        };
+        if !caller_gone {
+            let headers = resp.as_ref().map(|resp| {
+                relayed_headers(
+                    &hooks,
+                    BridgeLeg::Caller,
+                    &rsip::Method::Invite,
+                    &resp.headers,
+                )
+            });
+            caller.reject_with(status.clone(), headers)?;
+        }
+        Err(Error::DialogError(
+            format!("callee did not answer: {}", status),
+            caller.id(),
+        ))
+    }
+
+    fn leg(&self, leg: BridgeLeg) -> LegDialog {
+        match leg {
+            BridgeLeg::Caller => LegDialog::Caller(self.caller.clone()),
+            BridgeLeg::Callee => LegDialog::Callee(self.callee.clone()),
+        }
+    }
+
+    /// Relays the offers, INFO, DTMF and BYE between the legs until one of
+    /// them hangs up, the other one being hung up then. An offer is relayed
+    /// in the same method it came in, re-INVITE or UPDATE.
+    pub async fn run(mut self) -> Result<()> {
+        self.caller
+            .set_offer_answer_handler(Some(Arc::new(RelayOffer {
+                to: BridgeLeg::Callee,
+                target: self.leg(BridgeLeg::Callee),
+                hooks: self.hooks.clone(),
+            })));
+        self.callee
+            .set_offer_answer_handler(Some(Arc::new(RelayOffer {
+                to: BridgeLeg::Caller,
+                target: self.leg(BridgeLeg::Caller),
+                hooks: self.hooks.clone(),
+            })));
+
+        loop {
+            let (from, state) = select! {
+                state = self.caller_states.recv() => (BridgeLeg::Caller, state),
+                state = self.callee_states.recv() => (BridgeLeg::Callee, state),
+            };
+            let to = from.other();
+            let target = self.leg(to);
+            let result = match state {
+                // the INFO the bridge relayed to `from`, not one to relay back
+                Some(DialogState::Info(_, request))
+                    if self.leg(from).is_local_request(&request) =>
+                {
+                    Ok(())
+                }
+                Some(DialogState::Info(_, request)) => {
+                    let headers =
+                        relayed_headers(&self.hooks, to, &request.method, &request.headers);
+                    let content_type = header_value(&request.headers, "Content-Type");
+                    target
+                        .info(headers, content_type, request.body.clone())
+                        .await
+                        .map(|_| ())
+                }
+                Some(DialogState::Dtmf(_, event)) => target.send_dtmf(&event).await.map(|_| ()),
+                Some(DialogState::Terminated(_, _, reason)) => {
+                    info!("{:?} leg hung up", from);
+                    let mut headers = vec![];
+                    self.hooks
+                        .rewrite_headers(to, &rsip::Method::Bye, &mut headers);
+                    if let Some(reason) = reason {
+                        headers.push(reason.to_header());
+                    }
+                    if let Err(e) = target.bye(headers).await {
+                        warn!("failed to hang up the {:?} leg: {:?}", to, e);
+                    }
+                    break;
+                }
+                None => {
+                    if let Err(e) = target.bye(vec![Reason::sip(500).to_header()]).await {
+                        warn!("failed to hang up the {:?} leg: {:?}", to, e);
+                    }
+                    break;
+                }
+                _ => Ok(()),
+            };
+            if let Err(e) = result {
+                warn!("failed to relay to the {:?} leg: {:?}", to, e);
+            }
+        }
+
+        self.caller.set_offer_answer_handler(None);
+        self.callee.set_offer_answer_handler(None);
+        Ok(())
+    }
+}
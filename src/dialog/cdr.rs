@@ -0,0 +1,135 @@
+use super::{dialog::DialogInner, reason::Reason, DialogId};
+use crate::transaction::key::TransactionRole;
+use rsip::{
+    prelude::{HeadersExt, ToTypedHeader},
+    Request, StatusCode,
+};
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::info;
+
+/// Call detail record of an INVITE dialog, emitted once when it terminates
+#[derive(Clone, Debug)]
+pub struct CallDetailRecord {
+    pub id: DialogId,
+    pub call_id: String,
+    /// Whether the dialog was created by an INVITE we sent or received
+    pub role: TransactionRole,
+    /// From of the initial INVITE
+    pub caller: Option<rsip::Uri>,
+    /// To of the initial INVITE
+    pub callee: Option<rsip::Uri>,
+    /// When the initial INVITE was sent or received
+    pub setup_time: SystemTime,
+    /// When the dialog got confirmed, `None` for a call never answered
+    pub answer_time: Option<SystemTime>,
+    pub end_time: SystemTime,
+    /// Status of the termination: the final response of a failed call, or
+    /// the one set by BYE, CANCEL or a timeout
+    pub release_code: Option<StatusCode>,
+    pub release_reason: Option<Reason>,
+    /// Transport of the top Via of the initial INVITE
+    pub transport: Option<rsip::transport::Transport>,
+}
+
+impl CallDetailRecord {
+    /// Record of a call set up by `request`, still ongoing
+    pub fn new(
+        id: DialogId,
+        role: TransactionRole,
+        request: &Request,
+        setup_time: SystemTime,
+    ) -> Self {
+        Self {
+            call_id: id.call_id.clone(),
+            id,
+            role,
+            caller: request
+                .from_header()
+                .ok()
+                .and_then(|from| from.typed().ok())
+                .map(|from| from.uri),
+            callee: request
+                .to_header()
+                .ok()
+                .and_then(|to| to.typed().ok())
+                .map(|to| to.uri),
+            setup_time,
+            answer_time: None,
+            end_time: setup_time,
+            release_code: None,
+            release_reason: None,
+            transport: request
+                .via_header()
+                .ok()
+                .and_then(|via| via.typed().ok())
+                .map(|via| via.transport),
+        }
+    }
+
+    pub fn is_answered(&self) -> bool {
+        self.answer_time.is_some()
+    }
+
+    /// Billable duration, from the answer to the end of the call
+    pub fn duration(&self) -> Option<Duration> {
+        let answer_time = self.answer_time?;
+        Some(
+            self.end_time
+                .duration_since(answer_time)
+                .unwrap_or_default(),
+        )
+    }
+
+    /// Time from the INVITE to the answer or the failure of the call
+    pub fn setup_duration(&self) -> Duration {
+        self.answer_time
+            .unwrap_or(self.end_time)
+            .duration_since(self.setup_time)
+            .unwrap_or_default()
+    }
+}
+
+/// Receives the `CallDetailRecord` of every INVITE dialog of a dialog layer,
+/// see `DialogLayer::set_cdr_sink`. Called from the dialog state transition,
+/// so it must not block: hand the record over to a channel or a task.
+pub trait CdrSink: Send + Sync {
+    fn on_cdr(&self, cdr: CallDetailRecord);
+}
+pub type CdrSinkRef = Arc<dyn CdrSink>;
+
+impl CdrSink for UnboundedSender<CallDetailRecord> {
+    fn on_cdr(&self, cdr: CallDetailRecord) {
+        self.send(cdr).ok();
+    }
+}
+
+impl DialogInner {
+    /// Hands the record of the call to the CDR sink, once
+    pub(super) fn emit_cdr(
+        &self,
+        id: &DialogId,
+        code: &Option<StatusCode>,
+        reason: &Option<Reason>,
+    ) {
+        let sink = match self.cdr_sink.lock().unwrap().take() {
+            Some(sink) => sink,
+            None => return,
+        };
+        let mut cdr = CallDetailRecord::new(
+            id.clone(),
+            self.role.clone(),
+            &self.initial_request,
+            self.setup_time,
+        );
+        cdr.answer_time = *self.answer_time.lock().unwrap();
+        cdr.end_time = SystemTime::now();
+        cdr.release_code = code.clone();
+        cdr.release_reason = reason.clone();
+        info!("call detail record: {:?}", cdr);
+        sink.on_cdr(cdr);
+    }
+}
@@ -1,14 +1,82 @@
 use super::dialog::DialogInnerRef;
 use super::DialogId;
-use crate::dialog::{authenticate::handle_client_authenticate, dialog::DialogState};
-use crate::rsip_ext::RsipResponseExt;
-use crate::transaction::transaction::Transaction;
+use crate::dialog::{
+    authenticate::{handle_client_authenticate, AuthAttempts},
+    dialog::{DialogState, OfferAnswerHandlerRef, SessionRefreshMethod},
+    dialog_event::DialogEvent,
+    dtmf::{DtmfEvent, DTMF_RELAY_CONTENT_TYPE},
+    expiration::DialogExpiration,
+    keepalive::DialogKeepalive,
+    reason::Reason,
+    usage::DialogUsage,
+};
+use crate::rsip_ext::{extract_uri_from_contact, RsipResponseExt};
+use crate::transaction::{
+    key::{TransactionKey, TransactionRole},
+    transaction::Transaction,
+};
 use crate::Result;
-use rsip::prelude::HeadersExt;
-use rsip::{Response, SipMessage, StatusCode};
-use std::sync::atomic::Ordering;
+use rsip::prelude::{HeadersExt, UntypedHeader};
+use rsip::{Header, Response, SipMessage, StatusCode};
+use std::sync::{atomic::Ordering, Arc};
+use std::time::Duration;
+use tokio::{select, time::sleep};
 use tokio_util::sync::CancellationToken;
-use tracing::{info, trace};
+use tracing::{info, trace, warn};
+
+pub type ProgressCallback = Box<dyn FnMut(&Response) + Send>;
+
+/// Outcome of an outgoing INVITE, see `ClientInviteDialog::wait_for_answer`
+#[derive(Debug, Clone)]
+pub enum InviteOutcome {
+    Answered {
+        response: Response,
+        sdp: Option<Vec<u8>>,
+    },
+    Busy(Response),
+    Declined(Response),
+    Redirected(Vec<rsip::Uri>),
+    Timeout,
+    Cancelled,
+    Rejected(Response),
+}
+
+impl From<Option<Response>> for InviteOutcome {
+    fn from(resp: Option<Response>) -> Self {
+        let resp = match resp {
+            Some(resp) => resp,
+            None => return InviteOutcome::Timeout,
+        };
+        match resp.status_code.kind() {
+            rsip::StatusCodeKind::Successful => {
+                let sdp = Some(resp.body.clone()).filter(|b| !b.is_empty());
+                return InviteOutcome::Answered {
+                    response: resp,
+                    sdp,
+                };
+            }
+            rsip::StatusCodeKind::Redirection => {
+                let contacts = resp
+                    .headers
+                    .iter()
+                    .filter_map(|h| match h {
+                        Header::Contact(c) => extract_uri_from_contact(c.value()).ok(),
+                        _ => None,
+                    })
+                    .collect();
+                return InviteOutcome::Redirected(contacts);
+            }
+            _ => {}
+        }
+        match resp.status_code {
+            StatusCode::BusyHere | StatusCode::BusyEverywhere => InviteOutcome::Busy(resp),
+            StatusCode::Decline => InviteOutcome::Declined(resp),
+            StatusCode::RequestTimeout => InviteOutcome::Timeout,
+            StatusCode::RequestTerminated => InviteOutcome::Cancelled,
+            _ => InviteOutcome::Rejected(resp),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct ClientInviteDialog {
@@ -24,51 +92,215 @@ impl ClientInviteDialog {
         &self.inner.cancel_token
     }
 
-    pub async fn bye(&self) -> Result<()> {
+    /// Hangs up the confirmed dialog, `headers` are added to the BYE
+    pub async fn bye(&self, headers: Option<Vec<Header>>) -> Result<()> {
         if !self.inner.is_confirmed() {
             return Ok(());
         }
-        let request = self
-            .inner
-            .make_request(rsip::Method::Bye, None, None, None, None, None)?;
+        let request =
+            self.inner
+                .make_request(rsip::Method::Bye, None, None, None, headers, None)?;
+        let reason = Reason::from_headers(&request.headers);
         let resp = self.inner.do_request(request).await?;
         self.inner.transition(DialogState::Terminated(
             self.id(),
             resp.map(|r| r.status_code),
+            reason,
         ))?;
         Ok(())
     }
 
-    pub async fn cancel(&self) -> Result<()> {
-        let mut cancel_request = self.inner.initial_request.clone();
-        cancel_request.method = rsip::Method::Cancel;
-        cancel_request
-            .cseq_header_mut()?
-            .mut_seq(self.inner.get_local_seq())?;
-        cancel_request.body = vec![];
+    /// Cancels the pending INVITE, `headers` are added to the CANCEL along
+    /// with `Reason: SIP;cause=487` unless they carry a Reason already
+    pub async fn cancel(&self, headers: Option<Vec<Header>>) -> Result<()> {
+        let invite = self
+            .inner
+            .pending_invite
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or_else(|| self.inner.initial_request.clone());
+        let mut cancel_request = self.inner.endpoint_inner.make_cancel(&invite)?;
+        let mut headers = headers.unwrap_or_default();
+        if !headers
+            .iter()
+            .any(|h| matches!(h, Header::Other(name, _) if name.eq_ignore_ascii_case("Reason")))
+        {
+            headers.push(Reason::sip(487).to_header());
+        }
+        for header in headers {
+            cancel_request.headers.push(header);
+        }
         self.inner.do_request(cancel_request).await?;
         Ok(())
     }
 
-    pub async fn reinvite(&self) -> Result<()> {
-        if !self.inner.is_confirmed() {
-            return Ok(());
-        }
-        todo!()
+    pub async fn reinvite(
+        &self,
+        headers: Option<Vec<Header>>,
+        body: Option<Vec<u8>>,
+    ) -> Result<Option<Response>> {
+        self.inner.do_reinvite(headers, body).await
     }
 
-    pub async fn info(&self) -> Result<()> {
+    pub async fn update(
+        &self,
+        headers: Option<Vec<Header>>,
+        body: Option<Vec<u8>>,
+    ) -> Result<Option<Response>> {
+        self.inner.do_update(headers, body).await
+    }
+
+    /// Puts the call on hold with a `sendonly` re-INVITE of the local SDP
+    pub async fn hold(&self) -> Result<Option<Response>> {
+        self.inner.set_hold(true).await
+    }
+
+    /// Resumes a held call with a re-INVITE of the local SDP in its direction
+    /// before the hold, `sendrecv` by default
+    pub async fn unhold(&self) -> Result<Option<Response>> {
+        self.inner.set_hold(false).await
+    }
+
+    /// Terminates the dialog after a maximum lifetime or inactivity, so it
+    /// does not linger when the peer is gone
+    pub fn set_expiration(&self, expiration: DialogExpiration) {
+        self.inner.start_expiration(expiration);
+    }
+
+    /// Pings the peer periodically, terminating the dialog when it stops
+    /// answering
+    pub fn start_keepalive(&self, keepalive: DialogKeepalive) {
+        self.inner.start_keepalive(keepalive);
+    }
+
+    /// Typed events of the dialog from now on, see `DialogEvent`
+    pub fn events(&self) -> impl futures::Stream<Item = DialogEvent> {
+        self.inner.events()
+    }
+
+    /// Last session description sent to the peer, offer or answer
+    pub fn local_sdp(&self) -> Option<Vec<u8>> {
+        self.inner.local_sdp.lock().unwrap().clone()
+    }
+
+    /// Last session description received from the peer, offer or answer
+    pub fn remote_sdp(&self) -> Option<Vec<u8>> {
+        self.inner.remote_sdp.lock().unwrap().clone()
+    }
+
+    /// INVITE session and subscriptions currently sharing the dialog
+    pub fn usages(&self) -> Vec<DialogUsage> {
+        self.inner.usages()
+    }
+
+    /// Early dialogs created by the provisional responses carrying a to-tag,
+    /// one per UAS a forked INVITE reached, in order of arrival
+    pub fn early_dialogs(&self) -> Vec<DialogId> {
+        self.inner
+            .early_dialogs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|early| early.id.clone())
+            .collect()
+    }
+
+    /// Early dialog the final response was sent in, `None` when the UAS
+    /// answered without a provisional response carrying its to-tag
+    pub fn confirmed_early_dialog(&self) -> Option<DialogId> {
+        let to_tag = self.id().to_tag;
+        self.inner
+            .early_dialogs
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|early| !to_tag.is_empty() && early.id.to_tag == to_tag)
+            .map(|early| early.id.clone())
+    }
+
+    /// Sets the media layer hooks consulted when SDP is sent or received
+    pub fn set_offer_answer_handler(&self, handler: Option<OfferAnswerHandlerRef>) {
+        self.inner.set_offer_answer_handler(handler);
+    }
+
+    pub fn set_refresh_method(&self, method: SessionRefreshMethod) {
+        *self.inner.refresh_method.lock().unwrap() = method;
+    }
+
+    /// Gives up on an in-dialog request (BYE, INFO, re-INVITE...) without a
+    /// final response after `timeout`, `None` waits for the transaction
+    pub fn set_request_timeout(&self, timeout: Option<Duration>) {
+        *self.inner.request_timeout.lock().unwrap() = timeout;
+    }
+
+    /// Refreshes the session with UPDATE or re-INVITE, see `SessionRefreshMethod`
+    pub async fn refresh_session(&self) -> Result<Option<Response>> {
+        self.inner.refresh_session().await
+    }
+
+    /// Asks the peer to call `target` (blind transfer), the transfer progress
+    /// is reported as `DialogState::ReferProgress`
+    pub async fn refer(
+        &self,
+        target: rsip::Uri,
+        headers: Option<Vec<Header>>,
+    ) -> Result<Option<Response>> {
+        self.inner.do_refer(target, headers).await
+    }
+
+    /// Accepts the REFER reported as `DialogState::Refer` with 202
+    pub fn accept_refer(&self) -> Result<()> {
+        self.inner.respond_refer(StatusCode::Accepted)
+    }
+
+    pub fn reject_refer(&self, status: StatusCode) -> Result<()> {
+        self.inner.respond_refer(status)
+    }
+
+    /// Reports the progress of an accepted REFER to the referrer, a final
+    /// status ends the implicit subscription
+    pub async fn notify_refer(&self, status: StatusCode) -> Result<()> {
+        self.inner.notify_refer_progress(status).await
+    }
+
+    /// Sends an in-dialog MESSAGE with `body` of `content_type`
+    pub async fn message(&self, content_type: &str, body: Vec<u8>) -> Result<Option<Response>> {
+        self.inner.do_message(content_type, body).await
+    }
+
+    /// Sends an in-dialog INFO, e.g. DTMF or a video fast-update request
+    pub async fn info(
+        &self,
+        headers: Option<Vec<Header>>,
+        content_type: Option<String>,
+        body: Option<Vec<u8>>,
+    ) -> Result<Option<Response>> {
         if !self.inner.is_confirmed() {
-            return Ok(());
+            return Ok(None);
         }
-
-        let request = self
-            .inner
-            .make_request(rsip::Method::Info, None, None, None, None, None)?;
-        self.inner.do_request(request.clone()).await?;
+        let mut headers = headers.unwrap_or_default();
+        if let Some(content_type) = content_type {
+            headers.push(Header::ContentType(content_type.into()));
+        }
+        let request =
+            self.inner
+                .make_request(rsip::Method::Info, None, None, None, Some(headers), body)?;
+        let resp = self.inner.do_request(request.clone()).await?;
         self.inner
-            .transition(DialogState::Info(self.id(), request))?;
-        Ok(())
+            .transition(DialogState::Info(self.id(), Arc::new(request)))?;
+        Ok(resp)
+    }
+
+    /// Sends `digit` as an `application/dtmf-relay` INFO
+    pub async fn send_dtmf(&self, digit: char, duration: Duration) -> Result<Option<Response>> {
+        let event = DtmfEvent::new(digit, duration)?;
+        self.info(
+            None,
+            Some(DTMF_RELAY_CONTENT_TYPE.to_string()),
+            Some(event.to_body()),
+        )
+        .await
     }
 
     pub async fn handle(&mut self, mut tx: Transaction) -> Result<()> {
@@ -78,25 +310,26 @@ impl ClientInviteDialog {
             self.inner.state.lock().unwrap()
         );
 
-        let cseq = tx.original.cseq_header()?.seq()?;
-        if cseq < self.inner.remote_seq.load(Ordering::Relaxed) {
-            info!(
-                "received old request remote_seq: {} > {}",
-                self.inner.remote_seq.load(Ordering::Relaxed),
-                cseq
-            );
-            tx.reply(rsip::StatusCode::ServerInternalError).await?;
+        if !self.inner.screen_request(&mut tx).await? {
             return Ok(());
         }
 
-        self.inner.remote_seq.store(cseq, Ordering::Relaxed);
-
         if self.inner.is_confirmed() {
+            if self.inner.invite_usage_ended(&tx.original.method) {
+                tx.reply(rsip::StatusCode::CallTransactionDoesNotExist)
+                    .await?;
+                return Ok(());
+            }
             match tx.original.method {
-                rsip::Method::Invite => {}
+                rsip::Method::Invite | rsip::Method::Update => {
+                    return self.inner.handle_session_update(tx).await
+                }
                 rsip::Method::Bye => return self.handle_bye(tx).await,
                 rsip::Method::Info => return self.handle_info(tx).await,
                 rsip::Method::Options => return self.handle_options(tx).await,
+                rsip::Method::Message => return self.inner.handle_message(tx).await,
+                rsip::Method::Notify => return self.inner.handle_refer_notify(tx).await,
+                rsip::Method::Refer => return self.inner.handle_refer(tx).await,
                 _ => {
                     info!("invalid request method: {:?}", tx.original.method);
                     tx.reply(rsip::StatusCode::MethodNotAllowed).await?;
@@ -117,37 +350,222 @@ impl ClientInviteDialog {
 
     async fn handle_bye(&mut self, mut tx: Transaction) -> Result<()> {
         info!("received bye");
-        self.inner
-            .transition(DialogState::Terminated(self.id(), None))?;
+        if self.inner.end_usage(&DialogUsage::Invite)? {
+            self.inner.transition(DialogState::Terminated(
+                self.id(),
+                None,
+                Reason::from_headers(&tx.original.headers),
+            ))?;
+        }
         tx.reply(rsip::StatusCode::OK).await?;
         Ok(())
     }
 
     async fn handle_info(&mut self, mut tx: Transaction) -> Result<()> {
         info!("received info {}", tx.original.uri);
-        self.inner
-            .transition(DialogState::Info(self.id(), tx.original.clone()))?;
+        let state = match DtmfEvent::from_info(&tx.original) {
+            Some(event) => DialogState::Dtmf(self.id(), event),
+            None => DialogState::Info(self.id(), Arc::new(tx.original.clone())),
+        };
+        self.inner.transition(state)?;
         tx.reply(rsip::StatusCode::OK).await?;
         Ok(())
     }
 
     async fn handle_options(&mut self, mut tx: Transaction) -> Result<()> {
         info!("received options {}", tx.original.uri);
-        self.inner
-            .transition(DialogState::Options(self.id(), tx.original.clone()))?;
+        self.inner.transition(DialogState::Options(
+            self.id(),
+            Arc::new(tx.original.clone()),
+        ))?;
         tx.reply(rsip::StatusCode::OK).await?;
         Ok(())
     }
 
     pub(super) async fn process_invite(
+        &self,
+        tx: Transaction,
+    ) -> Result<(DialogId, Option<Response>)> {
+        let (dialog_id, final_response) = self.drive_invite(tx, None).await?;
+        match final_response {
+            Some(resp) if resp.status_code.kind() != rsip::StatusCodeKind::Successful => {
+                let mut reason = format!("{}", resp.status_code);
+                if let Some(reason_phrase) = resp.reason_phrase() {
+                    reason = format!("{};{}", reason, reason_phrase);
+                }
+                Err(crate::Error::DialogError(reason, self.id()))
+            }
+            final_response => Ok((dialog_id, final_response)),
+        }
+    }
+
+    /// Drives the INVITE transaction until its final response, and resolves
+    /// with the outcome of the call attempt. `on_progress` is called with each
+    /// ringing and early media response.
+    pub async fn wait_for_answer(
+        &self,
+        tx: Transaction,
+        on_progress: Option<ProgressCallback>,
+    ) -> Result<(DialogId, InviteOutcome)> {
+        let (dialog_id, final_response) = self.drive_invite(tx, on_progress).await?;
+        Ok((dialog_id, InviteOutcome::from(final_response)))
+    }
+
+    /// Answers the offer of `DialogState::LateOffer` in the ACK of the 2xx,
+    /// which confirms the dialog
+    pub async fn send_ack(&self, headers: Option<Vec<Header>>, body: Vec<u8>) -> Result<()> {
+        let pending = self.inner.pending_ack.lock().unwrap().take();
+        let (tx, resp) = match pending {
+            Some(pending) => pending,
+            None => {
+                return Err(crate::Error::DialogError(
+                    "no late offer to answer".to_string(),
+                    self.id(),
+                ))
+            }
+        };
+        self.inner.local_sdp.lock().unwrap().replace(body.clone());
+        self.ack_late_offer(tx, &resp, headers, Some(body)).await
+    }
+
+    /// A 2xx with an offer to an INVITE sent without SDP, that no
+    /// offer/answer handler answers
+    fn is_late_offer(&self, original: &rsip::Request, resp: &Response) -> bool {
+        resp.status_code.kind() == rsip::StatusCodeKind::Successful
+            && original.body.is_empty()
+            && !resp.body.is_empty()
+            && self.inner.offer_answer.lock().unwrap().is_none()
+    }
+
+    /// Hands the late offer of `resp` to the application, whose `send_ack`
+    /// acknowledges the 2xx. Without an answer within 64*T1 the 2xx is
+    /// acknowledged anyway, then hung up (RFC 3261 13.2.2.4).
+    fn wait_late_answer(&self, tx: Transaction, resp: Response) -> Result<()> {
+        self.inner
+            .pending_ack
+            .lock()
+            .unwrap()
+            .replace((tx, resp.clone()));
+        self.inner
+            .transition(DialogState::LateOffer(self.id(), Arc::new(resp)))?;
+        let dialog = self.clone();
+        tokio::spawn(async move {
+            let timed_out = select! {
+                _ = dialog.inner.cancel_token.cancelled() => false,
+                _ = sleep(dialog.inner.endpoint_inner.t1x64) => true,
+            };
+            let pending = dialog.inner.pending_ack.lock().unwrap().take();
+            let (tx, resp) = match pending {
+                Some(pending) => pending,
+                None => return,
+            };
+            warn!("no answer to the late offer of {}", dialog.id());
+            if let Err(e) = dialog.ack_late_offer(tx, &resp, None, None).await {
+                warn!("failed to acknowledge the late offer: {:?}", e);
+                return;
+            }
+            if timed_out {
+                dialog
+                    .inner
+                    .bye_on_error(Reason::sip(488).with_text("Not Acceptable Here"))
+                    .await
+                    .ok();
+            }
+        });
+        Ok(())
+    }
+
+    async fn ack_late_offer(
+        &self,
+        mut tx: Transaction,
+        resp: &Response,
+        headers: Option<Vec<Header>>,
+        answer: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let mut ack = self.inner.make_ack(&tx.original, resp, answer)?;
+        ack.headers.extend(headers.unwrap_or_default());
+        tx.send_ack(ack).await?;
+        self.inner.update_remote_allow(&resp.headers);
+        self.inner.transition(DialogState::Confirmed(self.id()))
+    }
+
+    /// Acknowledges a 3xx and retries the INVITE toward `target`, within the
+    /// same dialog attempt
+    async fn follow_redirect(
         &self,
         mut tx: Transaction,
+        resp: &Response,
+        target: rsip::Uri,
+    ) -> Result<Transaction> {
+        let mut ack = tx.original.clone();
+        ack.method = rsip::Method::Ack;
+        ack.body = vec![];
+        let seq = ack.cseq_header()?.seq()?;
+        ack.headers.retain(|h| {
+            !matches!(
+                h,
+                Header::To(_) | Header::CSeq(_) | Header::ContentType(_) | Header::ContentLength(_)
+            )
+        });
+        ack.headers.push(Header::To(resp.to_header()?.clone()));
+        ack.headers.push(Header::CSeq(
+            rsip::typed::CSeq {
+                seq,
+                method: rsip::Method::Ack,
+            }
+            .into(),
+        ));
+        ack.headers.push(Header::ContentLength(0.into()));
+        tx.send_ack(ack).await?;
+
+        let mut request = tx.original.clone();
+        request.uri = target;
+        request
+            .cseq_header_mut()?
+            .mut_seq(self.inner.increment_local_seq())?;
+        // the authorizations answered for the previous target don't cover
+        // the new Request-URI nor, maybe, its next hop
+        request
+            .headers
+            .retain(|h| !matches!(h, Header::Authorization(_) | Header::ProxyAuthorization(_)));
+        if let Some(cred) = &self.inner.credential {
+            self.inner
+                .endpoint_inner
+                .auth_cache
+                .authorize_request(&mut request, cred);
+        }
+        let via = self.inner.endpoint_inner.get_via(None, None)?;
+        request.headers.unique_push(Header::Via(via.into()));
+        let key = TransactionKey::from_request(&request, TransactionRole::Client)?;
+        Ok(Transaction::new_client(
+            key,
+            request,
+            self.inner.endpoint_inner.clone(),
+            None,
+        ))
+    }
+
+    /// Sends the INVITE of `tx`, the one `cancel` matches from now on
+    async fn send_invite(&self, tx: &mut Transaction) -> Result<()> {
+        self.inner
+            .pending_invite
+            .lock()
+            .unwrap()
+            .replace(tx.original.clone());
+        tx.send().await
+    }
+
+    async fn drive_invite(
+        &self,
+        mut tx: Transaction,
+        mut on_progress: Option<ProgressCallback>,
     ) -> Result<(DialogId, Option<Response>)> {
         self.inner.transition(DialogState::Calling(self.id()))?;
-        let mut auth_sent = false;
-        tx.send().await?;
+        let mut auth_attempts = AuthAttempts::default();
+        self.send_invite(&mut tx).await?;
         let mut dialog_id = self.id();
         let mut final_response = None;
+        let mut tried = vec![tx.original.uri.clone()];
         while let Some(msg) = tx.receive().await {
             match msg {
                 SipMessage::Request(_) => {}
@@ -158,20 +576,31 @@ impl ClientInviteDialog {
                             continue;
                         }
                         StatusCode::Ringing | StatusCode::SessionProgress => {
-                            self.inner.transition(DialogState::Early(self.id(), resp))?;
+                            if let Some(on_progress) = on_progress.as_mut() {
+                                on_progress(&resp);
+                            }
+                            // each to-tag is a distinct early dialog of a forked INVITE
+                            let early_id = self
+                                .inner
+                                .update_early_dialog(&resp)
+                                .unwrap_or_else(|| self.id());
+                            self.inner.send_prack(&resp)?;
+                            self.inner
+                                .transition(DialogState::Early(early_id, Arc::new(resp)))?;
                             continue;
                         }
                         StatusCode::ProxyAuthenticationRequired | StatusCode::Unauthorized => {
-                            if auth_sent {
+                            if !auth_attempts.may_answer(&resp) {
                                 final_response = Some(resp.clone());
                                 info!("received {} response after auth sent", resp.status_code);
+                                self.inner.endpoint_inner.auth_cache.forget(&tx.original);
                                 self.inner.transition(DialogState::Terminated(
                                     self.id(),
                                     Some(resp.status_code),
+                                    None,
                                 ))?;
                                 break;
                             }
-                            auth_sent = true;
                             if let Some(credential) = &self.inner.credential {
                                 tx = handle_client_authenticate(
                                     self.inner.increment_local_seq(),
@@ -180,50 +609,60 @@ impl ClientInviteDialog {
                                     credential,
                                 )
                                 .await?;
-                                tx.send().await?;
+                                self.send_invite(&mut tx).await?;
                                 continue;
                             } else {
                                 info!("received 407 response without auth option");
                                 self.inner.transition(DialogState::Terminated(
                                     self.id(),
                                     Some(resp.status_code),
+                                    None,
                                 ))?;
                             }
                             continue;
                         }
                         _ => {}
                     };
+                    if resp.status_code.kind() == rsip::StatusCodeKind::Redirection
+                        && self.inner.max_redirects.load(Ordering::Relaxed) > 0
+                    {
+                        if let Some(target) = redirect_target(&resp, &tried) {
+                            self.inner.max_redirects.fetch_sub(1, Ordering::Relaxed);
+                            info!("following {} to {}", resp.status_code, target);
+                            tried.push(target.clone());
+                            tx = self.follow_redirect(tx, &resp, target).await?;
+                            self.send_invite(&mut tx).await?;
+                            continue;
+                        }
+                    }
                     final_response = Some(resp.clone());
                     match resp.to_header()?.tag()? {
-                        Some(tag) => self.inner.update_remote_tag(tag.value())?,
+                        Some(tag) => self.inner.update_remote_tag(tag.value()).await?,
                         None => {}
                     }
+                    if resp.status_code.kind() == rsip::StatusCodeKind::Successful {
+                        self.inner.establish_route(&resp);
+                    }
 
-                    let branch = match tx
-                        .original
-                        .via_header()?
-                        .params()?
-                        .iter()
-                        .find(|p| matches!(p, rsip::Param::Branch(_)))
-                    {
-                        Some(p) => p.clone(),
-                        None => {
-                            info!("no branch found in via header");
-                            return Err(crate::Error::DialogError(
-                                "no branch found in via header".to_string(),
-                                self.id(),
-                            ));
+                    // the 2xx is acknowledged anyway, then hung up (RFC 3261 13.2.2.4)
+                    let mut sdp_error = None;
+                    let answer = match self.inner.negotiate_sdp(&tx.original.body, &resp).await {
+                        Ok(answer) => answer,
+                        Err(e) => {
+                            warn!("failed to negotiate sdp: {:?}", e);
+                            sdp_error = Some(e);
+                            None
                         }
                     };
-
-                    let ack = self.inner.make_request(
-                        rsip::Method::Ack,
-                        resp.cseq_header()?.seq().ok(),
-                        None,
-                        Some(branch),
-                        None,
-                        None,
-                    )?;
+                    if answer.is_none()
+                        && sdp_error.is_none()
+                        && self.is_late_offer(&tx.original, &resp)
+                    {
+                        // acknowledged once the application answers
+                        self.wait_late_answer(tx, resp)?;
+                        return Ok((self.id(), final_response));
+                    }
+                    let ack = self.inner.make_ack(&tx.original, &resp, answer)?;
 
                     if let Ok(id) = DialogId::try_from(&ack) {
                         dialog_id = id;
@@ -231,19 +670,22 @@ impl ClientInviteDialog {
                     tx.send_ack(ack).await?;
                     match resp.status_code {
                         StatusCode::OK => {
+                            self.inner.update_remote_allow(&resp.headers);
                             self.inner
                                 .transition(DialogState::Confirmed(dialog_id.clone()))?;
+                            if sdp_error.is_some() {
+                                self.inner
+                                    .bye_on_error(Reason::sip(488).with_text("Not Acceptable Here"))
+                                    .await?;
+                            }
                         }
                         _ => {
-                            let mut reason = format!("{}", resp.status_code);
-                            if let Some(reason_phrase) = resp.reason_phrase() {
-                                reason = format!("{};{}", reason, reason_phrase);
-                            }
                             self.inner.transition(DialogState::Terminated(
                                 self.id(),
                                 Some(resp.status_code.clone()),
+                                Reason::from_headers(&resp.headers),
                             ))?;
-                            return Err(crate::Error::DialogError(reason, self.id()));
+                            break;
                         }
                     }
                 }
@@ -252,3 +694,31 @@ impl ClientInviteDialog {
         Ok((dialog_id, final_response))
     }
 }
+
+fn contact_q(line: &str) -> f32 {
+    line.rsplit('>')
+        .next()
+        .unwrap_or_default()
+        .split(';')
+        .find_map(|p| p.trim().strip_prefix("q="))
+        .and_then(|q| q.parse().ok())
+        .unwrap_or(1.0)
+}
+
+/// Preferred Contact of a 3xx not tried yet: the highest q, the first listed
+/// among equals (RFC 3261 8.1.3.4)
+pub fn redirect_target(resp: &Response, tried: &[rsip::Uri]) -> Option<rsip::Uri> {
+    let mut targets = resp
+        .headers
+        .iter()
+        .filter_map(|h| match h {
+            Header::Contact(c) => extract_uri_from_contact(c.value())
+                .ok()
+                .map(|uri| (contact_q(c.value()), uri)),
+            _ => None,
+        })
+        .filter(|(_, uri)| !tried.contains(uri))
+        .collect::<Vec<_>>();
+    targets.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+    targets.into_iter().next().map(|(_, uri)| uri)
+}
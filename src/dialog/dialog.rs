@@ -1,51 +1,134 @@
 use super::{
-    authenticate::{handle_client_authenticate, Credential},
+    authenticate::{handle_client_authenticate, AuthAttempts, CredentialStoreRef},
+    cdr::CdrSinkRef,
     client_dialog::ClientInviteDialog,
+    dialog_event::{event_stream, DialogEvent, DIALOG_EVENT_CAPACITY},
+    dialog_layer::DialogLayerInner,
+    dtmf::DtmfEvent,
+    hold::{HoldState, MediaDirection},
+    identity::IdentityVerification,
+    kpml::KpmlResponse,
+    reason::Reason,
+    refer::ReferTo,
     server_dialog::ServerInviteDialog,
+    subscription::{ClientSubscriptionDialog, ServerSubscriptionDialog},
+    usage::DialogUsage,
     DialogId,
 };
 use crate::{
-    header_pop,
-    rsip_ext::extract_uri_from_contact,
+    rsip_ext::{
+        extract_uri_from_contact, header_value, header_values, is_loose_route, next_hop, route_uri,
+    },
     transaction::{
         endpoint::EndpointInnerRef,
         key::{TransactionKey, TransactionRole},
+        random_between,
         transaction::{Transaction, TransactionEventSender},
     },
     Result,
 };
+use futures::Stream;
 use rsip::{
     headers::Route,
     prelude::{HeadersExt, ToTypedHeader, UntypedHeader},
     typed::{CSeq, Contact},
     Header, Param, Request, Response, SipMessage, StatusCode,
 };
-use std::sync::{
-    atomic::{AtomicU32, Ordering},
-    Arc, Mutex,
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    select,
+    sync::{
+        broadcast,
+        mpsc::{UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    time::sleep,
 };
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
+
+/// Re-INVITEs and UPDATEs rejected with 491 are retried this many times
+const MAX_GLARE_RETRIES: u32 = 3;
+
+/// Wait before retrying an offer rejected with 491 (RFC 3261 14.1): 2.1-4s
+/// for the owner of the Call-ID, the UAC of the dialog, 0-2s otherwise
+pub fn glare_timeout(owner: bool) -> Duration {
+    let (min, max) = if owner { (210, 400) } else { (0, 200) };
+    Duration::from_millis(random_between(min, max) * 10)
+}
 
-/// DialogState is the state of the dialog
+/// DialogState is the state of the dialog, the requests and responses it
+/// carries are shared so cloning a state stays cheap
 #[derive(Clone)]
 pub enum DialogState {
     Calling(DialogId),
     Trying(DialogId),
-    Early(DialogId, rsip::Response),
-    WaitAck(DialogId, rsip::Response),
+    Early(DialogId, Arc<rsip::Response>),
+    WaitAck(DialogId, Arc<rsip::Response>),
     Confirmed(DialogId),
-    Updated(DialogId, rsip::Request),
-    Notify(DialogId, rsip::Request),
-    Info(DialogId, rsip::Request),
-    Options(DialogId, rsip::Request),
-    Terminated(DialogId, Option<rsip::StatusCode>),
+    Updated(DialogId, Arc<rsip::Request>),
+    Notify(DialogId, Arc<rsip::Request>),
+    Info(DialogId, Arc<rsip::Request>),
+    /// A dtmf-relay INFO
+    Dtmf(DialogId, DtmfEvent),
+    Options(DialogId, Arc<rsip::Request>),
+    Message(DialogId, Arc<rsip::Request>),
+    /// Progress of a transfer requested with REFER, from the NOTIFY sipfrag
+    ReferProgress(DialogId, rsip::StatusCode),
+    /// Incoming REFER, answered with `accept_refer` or `reject_refer`
+    Refer(DialogId, ReferTo, Arc<rsip::Request>),
+    /// Digits reported by a KPML subscription of the dialog
+    Kpml(DialogId, KpmlResponse),
+    /// 2xx carrying the offer of the peer to an INVITE sent without SDP,
+    /// answered in the ACK with `ClientInviteDialog::send_ack`
+    LateOffer(DialogId, Arc<rsip::Response>),
+    /// The call was put on hold or resumed with `hold` and `unhold`
+    HoldStateChanged(DialogId, HoldState),
+    /// One usage of the dialog ended while others keep it alive, e.g. a BYE
+    /// received before the final NOTIFY of a REFER. Once the INVITE usage
+    /// ended it stays the state of the dialog, still confirmed for the
+    /// remaining subscriptions.
+    UsageTerminated(DialogId, DialogUsage),
+    /// Final status of the dialog and the Reason given by the peer or sent by the stack
+    Terminated(DialogId, Option<rsip::StatusCode>, Option<Reason>),
 }
+/// Method used to refresh a session (RFC 4028 10)
+#[derive(Clone, Debug, PartialEq)]
+pub enum SessionRefreshMethod {
+    /// UPDATE when the peer allows it, otherwise re-INVITE with the last local SDP
+    Auto,
+    Update,
+    Invite,
+}
+
+/// Media layer hooks, consulted whenever the dialog must produce or consume
+/// SDP: initial INVITE, re-INVITE, UPDATE and late offers
+#[async_trait::async_trait]
+pub trait OfferAnswerHandler: Send + Sync {
+    /// Returns the answer to an offer received from the peer
+    async fn on_offer(&self, offer: Vec<u8>) -> Result<Vec<u8>>;
+    /// Called with the peer's answer to a local offer
+    async fn on_answer(&self, answer: Vec<u8>) -> Result<()>;
+    /// Returns the answer to an offer of an in-dialog re-INVITE or UPDATE,
+    /// `method` telling which, defaults to `on_offer`
+    async fn on_session_offer(&self, _method: &rsip::Method, offer: Vec<u8>) -> Result<Vec<u8>> {
+        self.on_offer(offer).await
+    }
+}
+pub type OfferAnswerHandlerRef = Arc<dyn OfferAnswerHandler>;
+
 #[derive(Clone)]
 pub enum Dialog {
     ServerInvite(ServerInviteDialog),
     ClientInvite(ClientInviteDialog),
+    ClientSubscription(ClientSubscriptionDialog),
+    ServerSubscription(ServerSubscriptionDialog),
 }
 
 pub struct DialogInner {
@@ -58,28 +141,212 @@ pub struct DialogInner {
     pub local_contact: Option<rsip::Uri>,
 
     pub remote_seq: AtomicU32,
-    pub remote_uri: rsip::Uri,
+    /// Method of the last request received, tells a retransmission apart
+    /// from an ACK or CANCEL sharing its CSeq
+    pub(super) remote_method: Mutex<Option<rsip::Method>>,
+    /// Transaction of the last request received, whose final response is
+    /// resent to retransmissions coming on another transaction
+    pub(super) remote_key: Mutex<Option<TransactionKey>>,
+    /// Remote target, the Contact of the peer
+    pub remote_uri: Mutex<rsip::Uri>,
 
     pub from: String,
     pub to: Mutex<String>,
 
-    pub credential: Option<Credential>,
-    pub route_set: Vec<Route>,
+    pub credential: Option<CredentialStoreRef>,
+    pub route_set: Mutex<Vec<Route>>,
+    pub refresh_method: Mutex<SessionRefreshMethod>,
+    pub remote_allow: Mutex<Vec<String>>,
+    pub local_sdp: Mutex<Option<Vec<u8>>>,
+    /// Media direction of the local SDP before `hold`, restored by `unhold`
+    pub(super) held_direction: Mutex<Option<MediaDirection>>,
+    /// Last session description received from the peer
+    pub remote_sdp: Mutex<Option<Vec<u8>>>,
+    pub offer_answer: Mutex<Option<OfferAnswerHandlerRef>>,
+    /// RSeq of the last reliable provisional response sent
+    pub(super) local_rseq: AtomicU32,
+    /// RSeq awaiting its PRACK, with the waiter of `provisional_reliable`
+    pub(super) pending_prack: Mutex<Option<(u32, oneshot::Sender<Request>)>>,
+    /// Held while an offer of a re-INVITE or UPDATE is outstanding in either
+    /// direction, see `send_offer`
+    pub(super) offer_lock: tokio::sync::Mutex<()>,
+    /// Last time a message of the dialog was received, see `DialogExpiration`
+    pub(super) last_activity: Mutex<tokio::time::Instant>,
+    /// Final status answering the initial INVITE right away, set when the
+    /// dialog layer is over its `DialogLimits`
+    pub(super) overload_status: Mutex<Option<StatusCode>>,
+    /// Early dialogs of the initial INVITE, one per to-tag of its provisional
+    /// responses
+    pub(super) early_dialogs: Mutex<Vec<EarlyDialog>>,
+    /// 3xx responses to the initial INVITE still to be followed
+    pub(super) max_redirects: AtomicU32,
+    /// INVITE of the client transaction in progress, the initial one retried
+    /// after a challenge or a redirect, which a CANCEL must match
+    pub(super) pending_invite: Mutex<Option<Request>>,
+    /// Transaction of a 2xx with a late offer and the 2xx, acknowledged by
+    /// `send_ack` with the answer
+    pub(super) pending_ack: Mutex<Option<(Transaction, Response)>>,
+    /// INVITE session and subscriptions sharing the dialog, see `DialogUsage`
+    pub(super) usages: Mutex<Vec<DialogUsage>>,
+    /// Overall timeout of the requests sent in the dialog, `None` waits as
+    /// long as the transaction does
+    pub(super) request_timeout: Mutex<Option<Duration>>,
+    /// Decision of the application on the incoming REFER being handled
+    pub(super) pending_refer: Mutex<Option<oneshot::Sender<StatusCode>>>,
+    /// When the initial request was sent or received, and when the dialog
+    /// got confirmed, for its `CallDetailRecord`
+    pub(super) setup_time: SystemTime,
+    pub(super) answer_time: Mutex<Option<SystemTime>>,
+    /// Layer the dialog is registered in, where it's moved to its new id
+    /// when it learns the remote tag
+    pub(super) layer: Mutex<Option<Weak<DialogLayerInner>>>,
+    /// Set by the dialog layer on INVITE dialogs, taken on termination
+    pub(super) cdr_sink: Mutex<Option<CdrSinkRef>>,
+    /// Verification of the Identity of the initial INVITE received
+    pub(super) identity: Mutex<Option<IdentityVerification>>,
     pub(super) endpoint_inner: EndpointInnerRef,
     pub(super) state_sender: DialogStateSender,
+    pub(super) event_sender: broadcast::Sender<DialogEvent>,
     pub(super) tu_sender: TuSenderRef,
     pub(super) initial_request: Request,
 }
 
+/// Early dialog a provisional response to the initial INVITE created, one
+/// per UAS a forked INVITE reached (RFC 3261 12.1.2). The dialog itself
+/// takes the to-tag of the final response.
+pub(super) struct EarlyDialog {
+    pub id: DialogId,
+    /// Contact of the UAS
+    pub remote_target: Option<rsip::Uri>,
+    pub route_set: Vec<Route>,
+    /// RSeq of the last reliable provisional response of the UAS
+    pub remote_rseq: u32,
+}
+
 pub type DialogStateReceiver = UnboundedReceiver<DialogState>;
 pub type DialogStateSender = UnboundedSender<DialogState>;
 
+/// Wraps a `DialogStateReceiver` into a `Stream` of dialog states
+pub fn dialog_state_stream(mut receiver: DialogStateReceiver) -> impl Stream<Item = DialogState> {
+    futures::stream::poll_fn(move |cx| receiver.poll_recv(cx))
+}
+
 pub(super) type DialogInnerRef = Arc<DialogInner>;
 pub(super) type TuSenderRef = Mutex<Option<TransactionEventSender>>;
 
+/// CSeq sequence numbers MUST be less than 2**31 (RFC 3261 8.1.1.5)
+pub const MAX_CSEQ: u32 = 1 << 31;
+
+/// Returns the CSeq following `seq`, restarting from 1 when the 2**31 limit is reached
+pub fn next_cseq(seq: u32) -> u32 {
+    if seq >= MAX_CSEQ - 1 {
+        1
+    } else {
+        seq + 1
+    }
+}
+
+/// Returns true if `seq` comes before `last` in the 31-bit CSeq space.
+///
+/// Distances larger than half of the space are treated as a wrap-around,
+/// so a peer restarting its CSeq near 2**31 is not seen as going backwards.
+pub fn cseq_before(seq: u32, last: u32) -> bool {
+    let distance = last.wrapping_sub(seq) & (MAX_CSEQ - 1);
+    distance != 0 && distance < MAX_CSEQ / 2
+}
+
+/// Route set from the Record-Route headers of a request or response, in the
+/// order the UAS receives them, reversed for the UAC
+pub fn record_route_set(headers: &rsip::Headers, role: TransactionRole) -> Vec<Route> {
+    let mut routes = headers
+        .iter()
+        .filter_map(|h| match h {
+            Header::RecordRoute(rr) => Some(rr.value().to_string()),
+            _ => None,
+        })
+        .flat_map(|v| {
+            v.split(',')
+                .map(|r| r.trim().to_string())
+                .filter(|r| !r.is_empty())
+                .collect::<Vec<_>>()
+        })
+        .map(Route::from)
+        .collect::<Vec<_>>();
+    if role == TransactionRole::Client {
+        routes.reverse();
+    }
+    routes
+}
+
+/// Request-URI and Route headers of an in-dialog request (RFC 3261 12.2.1.1).
+///
+/// With a strict first route the Request-URI is that route and the remote
+/// target is appended as the last Route.
+pub fn request_target(
+    route_set: &[Route],
+    remote_target: &rsip::Uri,
+) -> Result<(rsip::Uri, Vec<Route>)> {
+    match route_set.first() {
+        Some(first) if !is_loose_route(first) => {
+            let uri = route_uri(first).ok_or(crate::Error::Error(format!(
+                "invalid route: {}",
+                first.value()
+            )))?;
+            let mut routes = route_set[1..].to_vec();
+            routes.push(Route::from(format!("<{}>", remote_target)));
+            Ok((uri, routes))
+        }
+        _ => Ok((remote_target.clone(), route_set.to_vec())),
+    }
+}
+
+/// Returns the RSeq of a reliable provisional response (RFC 3262), `None`
+/// when the response does not require a PRACK
+pub fn reliable_rseq(resp: &Response) -> Option<u32> {
+    if resp.status_code.kind() != rsip::StatusCodeKind::Provisional {
+        return None;
+    }
+    let required = header_values(&resp.headers, "Require")
+        .iter()
+        .flat_map(|v| {
+            v.split(',')
+                .map(|o| o.trim().to_string())
+                .collect::<Vec<_>>()
+        })
+        .any(|o| o.eq_ignore_ascii_case("100rel"));
+    if !required {
+        return None;
+    }
+    header_value(&resp.headers, "RSeq")?.parse().ok()
+}
+
+/// Verdict on the CSeq of an incoming in-dialog request
+#[derive(Clone, Debug, PartialEq)]
+pub enum RemoteSeq {
+    Accepted,
+    /// Same CSeq and method as the last request
+    Retransmission,
+    /// Lower CSeq than the last request
+    OutOfOrder,
+}
+
+/// Returns true if the CSeq of `resp` names `request`, a response with
+/// another number or method is not an answer to it
+pub fn response_matches(request: &Request, resp: &Response) -> bool {
+    let expected = request.cseq_header().and_then(|c| c.typed());
+    let cseq = resp.cseq_header().and_then(|c| c.typed());
+    match (expected, cseq) {
+        (Ok(expected), Ok(cseq)) => expected.seq == cseq.seq && expected.method == cseq.method,
+        _ => false,
+    }
+}
+
 impl DialogState {
     pub fn is_confirmed(&self) -> bool {
-        matches!(self, DialogState::Confirmed(_))
+        matches!(
+            self,
+            DialogState::Confirmed(_) | DialogState::UsageTerminated(_, DialogUsage::Invite)
+        )
     }
 }
 
@@ -90,11 +357,16 @@ impl DialogInner {
         initial_request: Request,
         endpoint_inner: EndpointInnerRef,
         state_sender: DialogStateSender,
-        credential: Option<Credential>,
+        credential: Option<CredentialStoreRef>,
         local_contact: Option<rsip::Uri>,
     ) -> Result<Self> {
         let mut initial_request = initial_request;
         let cseq = initial_request.cseq_header()?.seq()?;
+        // the remote CSeq is unknown for the UAC until the callee sends a request
+        let remote_seq = match role {
+            TransactionRole::Client => 0,
+            TransactionRole::Server => cseq,
+        };
 
         let remote_uri = match role {
             TransactionRole::Client => initial_request.uri.clone(),
@@ -114,15 +386,37 @@ impl DialogInner {
             TransactionRole::Server => (to.to_string(), from.to_string()),
         };
 
-        let mut route_set = vec![];
-        initial_request.headers.retain(|h| {
-            if let Header::RecordRoute(rr) = h {
-                route_set.push(Route::from(rr.value()));
-                false
-            } else {
-                true
+        // the UAC learns its route set from the responses
+        let route_set = match role {
+            TransactionRole::Server => record_route_set(&initial_request.headers, role.clone()),
+            TransactionRole::Client => vec![],
+        };
+        initial_request
+            .headers
+            .retain(|h| !matches!(h, Header::RecordRoute(_)));
+        let local_sdp = match role {
+            TransactionRole::Client if !initial_request.body.is_empty() => {
+                Some(initial_request.body.clone())
             }
-        });
+            _ => None,
+        };
+        let remote_sdp = match role {
+            TransactionRole::Server if !initial_request.body.is_empty() => {
+                Some(initial_request.body.clone())
+            }
+            _ => None,
+        };
+        let remote_allow = match role {
+            TransactionRole::Server => parse_allow(&initial_request.headers),
+            TransactionRole::Client => vec![],
+        };
+        let usages = match initial_request.method {
+            rsip::Method::Invite => vec![DialogUsage::Invite],
+            rsip::Method::Subscribe => header_value(&initial_request.headers, "Event")
+                .map(|event| vec![DialogUsage::from_event(&event)])
+                .unwrap_or_default(),
+            _ => vec![],
+        };
         Ok(Self {
             role,
             cancel_token: CancellationToken::new(),
@@ -130,12 +424,38 @@ impl DialogInner {
             from,
             to: Mutex::new(to),
             local_seq: AtomicU32::new(cseq),
-            remote_uri,
-            remote_seq: AtomicU32::new(cseq),
+            remote_uri: Mutex::new(remote_uri),
+            remote_seq: AtomicU32::new(remote_seq),
+            remote_method: Mutex::new(None),
+            remote_key: Mutex::new(None),
             credential,
-            route_set,
+            route_set: Mutex::new(route_set),
+            refresh_method: Mutex::new(SessionRefreshMethod::Auto),
+            remote_allow: Mutex::new(remote_allow),
+            local_sdp: Mutex::new(local_sdp),
+            held_direction: Mutex::new(None),
+            remote_sdp: Mutex::new(remote_sdp),
+            offer_answer: Mutex::new(None),
+            local_rseq: AtomicU32::new(0),
+            pending_prack: Mutex::new(None),
+            offer_lock: tokio::sync::Mutex::new(()),
+            pending_ack: Mutex::new(None),
+            last_activity: Mutex::new(tokio::time::Instant::now()),
+            overload_status: Mutex::new(None),
+            early_dialogs: Mutex::new(vec![]),
+            max_redirects: AtomicU32::new(0),
+            pending_invite: Mutex::new(None),
+            usages: Mutex::new(usages),
+            request_timeout: Mutex::new(None),
+            pending_refer: Mutex::new(None),
+            setup_time: SystemTime::now(),
+            answer_time: Mutex::new(None),
+            layer: Mutex::new(None),
+            cdr_sink: Mutex::new(None),
+            identity: Mutex::new(None),
             endpoint_inner,
             state_sender,
+            event_sender: broadcast::channel(DIALOG_EVENT_CAPACITY).0,
             tu_sender: Mutex::new(None),
             state: Mutex::new(DialogState::Calling(id)),
             initial_request,
@@ -146,27 +466,215 @@ impl DialogInner {
     pub fn is_confirmed(&self) -> bool {
         self.state.lock().unwrap().is_confirmed()
     }
+
+    /// Typed events of the dialog from now on, see `DialogEvent`
+    pub fn events(&self) -> impl Stream<Item = DialogEvent> {
+        event_stream(self.event_sender.subscribe())
+    }
     pub fn get_local_seq(&self) -> u32 {
         self.local_seq.load(Ordering::Relaxed)
     }
     pub fn increment_local_seq(&self) -> u32 {
-        self.local_seq.fetch_add(1, Ordering::Relaxed);
-        self.local_seq.load(Ordering::Relaxed)
+        let last = self
+            .local_seq
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |seq| {
+                Some(next_cseq(seq))
+            })
+            .unwrap_or_default();
+        next_cseq(last)
     }
 
     pub fn increment_remove_seq(&self) -> u32 {
-        self.remote_seq.fetch_add(1, Ordering::Relaxed);
-        self.remote_seq.load(Ordering::Relaxed)
+        let last = self
+            .remote_seq
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |seq| {
+                Some(next_cseq(seq))
+            })
+            .unwrap_or_default();
+        next_cseq(last)
+    }
+
+    /// Address of record of the peer, `to` always names the remote side
+    pub fn remote_aor(&self) -> Option<rsip::Uri> {
+        let to: rsip::headers::untyped::To = self.to.lock().unwrap().clone().into();
+        to.typed().ok().map(|to| to.uri)
+    }
+
+    /// Checks the CSeq of an incoming in-dialog request against the last one
+    /// received (RFC 3261 12.2.2)
+    pub fn check_remote_seq(&self, cseq: u32, method: &rsip::Method) -> RemoteSeq {
+        self.touch();
+        let last = self.remote_seq.load(Ordering::Relaxed);
+        let mut last_method = self.remote_method.lock().unwrap();
+        if last != 0 && cseq_before(cseq, last) {
+            return RemoteSeq::OutOfOrder;
+        }
+        if cseq == last && last_method.as_ref() == Some(method) {
+            return RemoteSeq::Retransmission;
+        }
+        self.remote_seq.store(cseq, Ordering::Relaxed);
+        last_method.replace(method.clone());
+        RemoteSeq::Accepted
+    }
+
+    /// Screens an incoming in-dialog request by its CSeq, returns false when
+    /// it must not be processed: retransmissions get the final response of
+    /// the request again,
+    /// out-of-order requests get 500 with Retry-After and those requiring an
+    /// unsupported extension 420, which ends a dialog not established yet
+    pub(super) async fn screen_request(&self, tx: &mut Transaction) -> Result<bool> {
+        let cseq = tx.original.cseq_header()?.seq()?;
+        match self.check_remote_seq(cseq, &tx.original.method) {
+            RemoteSeq::Accepted => {
+                self.remote_key.lock().unwrap().replace(tx.key.clone());
+                if !tx.reply_unsupported("Require").await? {
+                    return Ok(true);
+                }
+                if tx.original.method == rsip::Method::Invite && !self.is_confirmed() {
+                    self.transition(DialogState::Terminated(
+                        self.id.lock().unwrap().clone(),
+                        Some(StatusCode::from(420)),
+                        None,
+                    ))?;
+                }
+                Ok(false)
+            }
+            RemoteSeq::Retransmission => {
+                let last_response = self
+                    .remote_key
+                    .lock()
+                    .unwrap()
+                    .as_ref()
+                    .and_then(|key| self.endpoint_inner.finished_transactions.get(key))
+                    .flatten();
+                match last_response {
+                    // an ACK gets no response
+                    Some(SipMessage::Response(mut resp))
+                        if tx.original.method != rsip::Method::Ack =>
+                    {
+                        info!(
+                            "resending {} to retransmitted {} cseq: {}",
+                            resp.status_code, tx.original.method, cseq
+                        );
+                        // the Vias of the retransmission route the response
+                        let vias = tx
+                            .original
+                            .headers
+                            .iter()
+                            .filter(|h| matches!(h, Header::Via(_)))
+                            .cloned()
+                            .collect::<Vec<_>>();
+                        resp.headers.retain(|h| !matches!(h, Header::Via(_)));
+                        for via in vias.into_iter().rev() {
+                            resp.headers.push_front(via);
+                        }
+                        tx.respond(resp).await?;
+                    }
+                    _ => info!(
+                        "absorbing retransmitted {} cseq: {}",
+                        tx.original.method, cseq
+                    ),
+                }
+                Ok(false)
+            }
+            RemoteSeq::OutOfOrder => {
+                info!(
+                    "received out of order {} cseq: {} < {}",
+                    tx.original.method,
+                    cseq,
+                    self.remote_seq.load(Ordering::Relaxed)
+                );
+                // an ACK gets no response
+                if tx.original.method != rsip::Method::Ack {
+                    let headers = vec![Header::Other(
+                        "Retry-After".into(),
+                        random_between(0, 10).to_string(),
+                    )];
+                    let resp = self.make_response(
+                        &tx.original,
+                        StatusCode::ServerInternalError,
+                        Some(headers),
+                        None,
+                    );
+                    tx.respond(resp).await?;
+                }
+                Ok(false)
+            }
+        }
     }
 
-    pub fn update_remote_tag(&self, tag: &str) -> Result<()> {
-        self.id.lock().unwrap().to_tag = tag.to_string();
+    pub async fn update_remote_tag(&self, tag: &str) -> Result<()> {
+        let (old_id, new_id) = {
+            let mut id = self.id.lock().unwrap();
+            let old_id = id.clone();
+            id.to_tag = tag.to_string();
+            (old_id, id.clone())
+        };
         let to: rsip::headers::untyped::To = self.to.lock().unwrap().clone().into();
         *self.to.lock().unwrap() = to.typed()?.with_tag(tag.to_string().into()).to_string();
         info!("updating remote tag to: {}", self.to.lock().unwrap());
+        let layer = self.layer.lock().unwrap().as_ref().and_then(Weak::upgrade);
+        if let Some(layer) = layer.filter(|_| old_id != new_id) {
+            layer.rekey_dialog(&old_id, new_id).await;
+        }
         Ok(())
     }
 
+    /// Removes the dialog from the layer it was added to, e.g. once its
+    /// subscription terminated
+    pub(super) async fn remove_from_layer(&self) {
+        let layer = self.layer.lock().unwrap().as_ref().and_then(Weak::upgrade);
+        if let Some(layer) = layer {
+            let id = self.id.lock().unwrap().clone();
+            layer.remove_dialog(&id).await;
+        }
+    }
+
+    pub fn remote_allows(&self, method: &rsip::Method) -> bool {
+        let method = method.to_string();
+        self.remote_allow
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|m| *m == method)
+    }
+
+    pub(super) fn update_remote_allow(&self, headers: &rsip::Headers) {
+        let allow = parse_allow(headers);
+        if !allow.is_empty() {
+            *self.remote_allow.lock().unwrap() = allow;
+        }
+    }
+
+    /// Sets the route set and remote target of the UAC from a response
+    /// establishing the dialog (RFC 3261 12.1.2)
+    pub(super) fn establish_route(&self, resp: &Response) {
+        if self.role != TransactionRole::Client {
+            return;
+        }
+        *self.route_set.lock().unwrap() = record_route_set(&resp.headers, self.role.clone());
+        self.refresh_remote_target(&resp.headers);
+    }
+
+    /// Updates the remote target from the Contact of a target refresh request
+    /// or of its 2xx (RFC 3261 12.2), the route set is left as is
+    pub(super) fn refresh_remote_target(&self, headers: &rsip::Headers) {
+        let uri = headers
+            .iter()
+            .find_map(|h| match h {
+                Header::Contact(contact) => Some(contact.value().to_string()),
+                _ => None,
+            })
+            .and_then(|contact| extract_uri_from_contact(&contact).ok());
+        if let Some(uri) = uri {
+            let mut remote_uri = self.remote_uri.lock().unwrap();
+            if *remote_uri != uri {
+                info!("remote target refreshed: {} -> {}", remote_uri, uri);
+                *remote_uri = uri;
+            }
+        }
+    }
+
     pub(super) fn make_request(
         &self,
         method: rsip::Method,
@@ -178,7 +686,7 @@ impl DialogInner {
     ) -> Result<rsip::Request> {
         let mut headers = headers.unwrap_or_default();
         let cseq_header = CSeq {
-            seq: cseq.unwrap_or_else(|| self.increment_remove_seq()),
+            seq: cseq.unwrap_or_else(|| self.increment_local_seq()),
             method,
         };
 
@@ -190,27 +698,28 @@ impl DialogInner {
         headers.push(Header::From(self.from.clone().into()));
         headers.push(Header::To(self.to.lock().unwrap().clone().into()));
         headers.push(Header::CSeq(cseq_header.into()));
-        headers.push(Header::UserAgent(
-            self.endpoint_inner.user_agent.clone().into(),
-        ));
 
         self.local_contact
             .as_ref()
             .map(|c| headers.push(Contact::from(c.clone()).into()));
 
-        for route in &self.route_set {
-            headers.push(Header::Route(route.clone()));
+        let route_set = self.route_set.lock().unwrap().clone();
+        let remote_target = self.remote_uri.lock().unwrap().clone();
+        let (uri, routes) = request_target(&route_set, &remote_target)?;
+        for route in routes {
+            headers.push(Header::Route(route));
         }
-        headers.push(Header::MaxForwards(70.into()));
 
         body.as_ref().map(|b| {
             headers.push(Header::ContentLength((b.len() as u32).into()));
         });
 
+        let mut headers: rsip::Headers = headers.into();
+        self.endpoint_inner.apply_identity(&mut headers, false);
         let req = rsip::Request {
             method,
-            uri: self.remote_uri.clone(),
-            headers: headers.into(),
+            uri,
+            headers,
             body: body.unwrap_or_default(),
             version: rsip::Version::V2,
         };
@@ -275,9 +784,7 @@ impl DialogInner {
             resp_headers.push(Header::ContentLength((b.len() as u32).into()));
         });
 
-        resp_headers.unique_push(Header::UserAgent(
-            self.endpoint_inner.user_agent.clone().into(),
-        ));
+        self.endpoint_inner.apply_identity(&mut resp_headers, true);
 
         Response {
             status_code: status,
@@ -287,45 +794,427 @@ impl DialogInner {
         }
     }
 
-    pub(super) async fn do_request(&self, mut request: Request) -> Result<Option<rsip::Response>> {
+    pub(super) fn make_ack(
+        &self,
+        original: &Request,
+        resp: &Response,
+        body: Option<Vec<u8>>,
+    ) -> Result<Request> {
+        let branch = match original
+            .via_header()?
+            .params()?
+            .iter()
+            .find(|p| matches!(p, Param::Branch(_)))
+        {
+            Some(p) => p.clone(),
+            None => {
+                info!("no branch found in via header");
+                return Err(crate::Error::DialogError(
+                    "no branch found in via header".to_string(),
+                    self.id.lock().unwrap().clone(),
+                ));
+            }
+        };
+        let headers = body
+            .as_ref()
+            .map(|_| vec![Header::ContentType("application/sdp".into())]);
+        self.make_request(
+            rsip::Method::Ack,
+            resp.cseq_header()?.seq().ok(),
+            None,
+            Some(branch),
+            headers,
+            body,
+        )
+    }
+
+    /// Acknowledges a reliable provisional response with PRACK, retransmissions
+    /// of an already acknowledged RSeq are ignored
+    /// Records the early dialog of a provisional response carrying a
+    /// to-tag, refreshing its remote target and route set, and returns its id
+    pub(super) fn update_early_dialog(&self, resp: &Response) -> Option<DialogId> {
+        let id = DialogId::try_from(resp).ok()?;
+        let remote_target = resp
+            .contact_header()
+            .ok()
+            .and_then(|contact| extract_uri_from_contact(contact.value()).ok());
+        let route_set = record_route_set(&resp.headers, TransactionRole::Client);
+        let mut early_dialogs = self.early_dialogs.lock().unwrap();
+        match early_dialogs.iter_mut().find(|early| early.id == id) {
+            Some(early) => {
+                if remote_target.is_some() {
+                    early.remote_target = remote_target;
+                }
+            }
+            None => early_dialogs.push(EarlyDialog {
+                id: id.clone(),
+                remote_target,
+                route_set,
+                remote_rseq: 0,
+            }),
+        }
+        Some(id)
+    }
+
+    /// Request within the early dialog `early`: its to-tag, remote target and
+    /// route set instead of those of the dialog
+    fn make_early_request(
+        &self,
+        early: &EarlyDialog,
+        method: rsip::Method,
+        headers: Option<Vec<Header>>,
+    ) -> Result<Request> {
+        let mut request = self.make_request(method, None, None, None, headers, None)?;
+        let to: rsip::headers::untyped::To = self.to.lock().unwrap().clone().into();
+        let to = to.typed()?.with_tag(early.id.to_tag.clone().into());
+        let remote_target = early
+            .remote_target
+            .clone()
+            .unwrap_or_else(|| self.remote_uri.lock().unwrap().clone());
+        let (uri, routes) = request_target(&early.route_set, &remote_target)?;
+        request
+            .headers
+            .retain(|h| !matches!(h, Header::To(_) | Header::Route(_)));
+        request.headers.push(Header::To(to.into()));
+        for route in routes {
+            request.headers.push(Header::Route(route));
+        }
+        request.uri = uri;
+        Ok(request)
+    }
+
+    /// PRACKs a reliable provisional response within its early dialog
+    /// (RFC 3262 4), once `update_early_dialog` recorded it
+    pub(super) fn send_prack(self: &Arc<Self>, resp: &Response) -> Result<()> {
+        let rseq = match reliable_rseq(resp) {
+            Some(rseq) => rseq,
+            None => return Ok(()),
+        };
+        let id = DialogId::try_from(resp).ok();
+        let request = {
+            let mut early_dialogs = self.early_dialogs.lock().unwrap();
+            let early = match early_dialogs
+                .iter_mut()
+                .find(|early| Some(&early.id) == id.as_ref())
+            {
+                Some(early) => early,
+                None => {
+                    info!("reliable provisional outside an early dialog: {:?}", id);
+                    return Ok(());
+                }
+            };
+            // RSeq spaces are per early dialog
+            if early.remote_rseq != 0 && rseq <= early.remote_rseq {
+                debug!("ignoring retransmitted reliable provisional rseq: {}", rseq);
+                return Ok(());
+            }
+            early.remote_rseq = rseq;
+            let cseq = resp.cseq_header()?;
+            let rack = format!("{} {} {}", rseq, cseq.seq()?, cseq.method()?);
+            self.make_early_request(
+                early,
+                rsip::Method::PRack,
+                Some(vec![Header::Other("RAck".into(), rack)]),
+            )?
+        };
+        // the INVITE transaction keeps running while the PRACK is sent
+        let inner = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = inner.do_request(request).await {
+                info!("failed to send prack: {:?}", e);
+            }
+        });
+        Ok(())
+    }
+
+    pub fn set_offer_answer_handler(&self, handler: Option<OfferAnswerHandlerRef>) {
+        *self.offer_answer.lock().unwrap() = handler;
+    }
+
+    /// Whether `request` was sent by this side of the dialog, e.g. the INFO
+    /// of a `DialogState::Info` reported for a local `info`
+    pub(super) fn is_local_request(&self, request: &Request) -> bool {
+        request
+            .from_header()
+            .is_ok_and(|from| from.value() == self.from)
+    }
+
+    /// Keeps `body` as the last session description of the peer, unless empty
+    pub(super) fn update_remote_sdp(&self, body: &[u8]) {
+        if !body.is_empty() {
+            self.remote_sdp.lock().unwrap().replace(body.to_vec());
+        }
+    }
+
+    /// Hands the SDP of a 2xx to the offer/answer handler: as the answer when
+    /// `offer` was sent, or as a late offer whose answer is returned
+    pub(super) async fn negotiate_sdp(
+        &self,
+        offer: &[u8],
+        resp: &Response,
+    ) -> Result<Option<Vec<u8>>> {
+        if resp.body.is_empty() || resp.status_code.kind() != rsip::StatusCodeKind::Successful {
+            return Ok(None);
+        }
+        self.update_remote_sdp(&resp.body);
+        let handler = match self.offer_answer.lock().unwrap().clone() {
+            Some(handler) => handler,
+            None => return Ok(None),
+        };
+        if !offer.is_empty() {
+            handler.on_answer(resp.body.clone()).await?;
+            return Ok(None);
+        }
+        let answer = handler.on_offer(resp.body.clone()).await?;
+        self.local_sdp.lock().unwrap().replace(answer.clone());
+        Ok(Some(answer))
+    }
+
+    /// Answers an in-dialog re-INVITE or UPDATE from the peer, with the
+    /// answer of the offer/answer handler or the last local SDP
+    pub(super) async fn handle_session_update(&self, mut tx: Transaction) -> Result<()> {
+        info!("received {} {}", tx.original.method, tx.original.uri);
+        // our own offer is outstanding, the peer retries after its glare timer
+        let _offer = match self.offer_lock.try_lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                info!("{} glare, rejecting with 491", tx.original.method);
+                tx.reply(StatusCode::from(491)).await?;
+                return Ok(());
+            }
+        };
+        self.refresh_remote_target(&tx.original.headers);
+        let handler = self.offer_answer.lock().unwrap().clone();
+        let offer = tx.original.body.clone();
+        self.update_remote_sdp(&offer);
+        let answer = match handler.as_ref() {
+            Some(handler) if !offer.is_empty() => {
+                let answer = handler
+                    .on_session_offer(&tx.original.method, offer.clone())
+                    .await?;
+                self.local_sdp.lock().unwrap().replace(answer.clone());
+                Some(answer)
+            }
+            // an UPDATE without offer doesn't change the session, its 2xx
+            // carries none either (RFC 3311 5.2)
+            _ if offer.is_empty() && tx.original.method == rsip::Method::Update => None,
+            // a re-INVITE without offer gets ours in the 2xx
+            _ => self.local_sdp.lock().unwrap().clone(),
+        };
+        self.transition(DialogState::Updated(
+            self.id.lock().unwrap().clone(),
+            Arc::new(tx.original.clone()),
+        ))?;
+        let headers = answer
+            .as_ref()
+            .map(|_| vec![Header::ContentType("application/sdp".into())]);
+        let resp = self.make_response(&tx.original, StatusCode::OK, headers, answer);
+        tx.respond(resp).await?;
+
+        if tx.original.method != rsip::Method::Invite {
+            return Ok(());
+        }
+        while let Some(msg) = tx.receive().await {
+            if let SipMessage::Request(ack) = msg {
+                if ack.method != rsip::Method::Ack {
+                    continue;
+                }
+                // a re-INVITE without offer gets the answer in the ACK
+                self.update_remote_sdp(&ack.body);
+                match handler.as_ref() {
+                    Some(handler) if offer.is_empty() && !ack.body.is_empty() => {
+                        handler.on_answer(ack.body).await?;
+                    }
+                    _ => {}
+                }
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a re-INVITE within the confirmed dialog, `body` becomes the new local SDP
+    /// once the peer accepts it
+    pub(super) async fn do_reinvite(
+        &self,
+        headers: Option<Vec<Header>>,
+        body: Option<Vec<u8>>,
+    ) -> Result<Option<Response>> {
+        if !self.is_confirmed() {
+            return Ok(None);
+        }
+        self.send_offer(rsip::Method::Invite, headers, body).await
+    }
+
+    pub(super) async fn do_update(
+        &self,
+        headers: Option<Vec<Header>>,
+        body: Option<Vec<u8>>,
+    ) -> Result<Option<Response>> {
+        if !self.is_confirmed() {
+            return Ok(None);
+        }
+        self.send_offer(rsip::Method::Update, headers, body).await
+    }
+
+    /// Sends a re-INVITE or UPDATE once no other offer is outstanding, a
+    /// local one waits for an incoming one to complete. On 491 it is retried
+    /// after the glare timer (RFC 3261 14.1).
+    async fn send_offer(
+        &self,
+        method: rsip::Method,
+        headers: Option<Vec<Header>>,
+        body: Option<Vec<u8>>,
+    ) -> Result<Option<Response>> {
+        let mut retries = 0;
+        loop {
+            let resp = {
+                let _offer = self.offer_lock.lock().await;
+                let request = self.make_request(
+                    method.clone(),
+                    None,
+                    None,
+                    None,
+                    headers.clone(),
+                    body.clone(),
+                )?;
+                self.do_request(request).await?
+            };
+            match resp {
+                Some(ref r)
+                    if r.status_code == StatusCode::from(491)
+                        && retries < MAX_GLARE_RETRIES
+                        && self.is_confirmed() =>
+                {
+                    retries += 1;
+                    let timeout = glare_timeout(self.role == TransactionRole::Client);
+                    info!("{} glare, retrying in {:?}", method, timeout);
+                    sleep(timeout).await;
+                }
+                _ => {
+                    self.on_offer_accepted(resp.as_ref(), body);
+                    return Ok(resp);
+                }
+            }
+        }
+    }
+
+    fn on_offer_accepted(&self, resp: Option<&Response>, body: Option<Vec<u8>>) {
+        if let Some(resp) =
+            resp.filter(|r| r.status_code.kind() == rsip::StatusCodeKind::Successful)
+        {
+            self.update_remote_sdp(&resp.body);
+        }
+        match (resp, body) {
+            (Some(resp), Some(body))
+                if resp.status_code.kind() == rsip::StatusCodeKind::Successful
+                    && !body.is_empty() =>
+            {
+                self.local_sdp.lock().unwrap().replace(body);
+            }
+            _ => {}
+        }
+    }
+
+    /// Refreshes the session with the configured `SessionRefreshMethod`.
+    /// A re-INVITE carries the last negotiated local SDP.
+    pub(super) async fn refresh_session(&self) -> Result<Option<Response>> {
+        let method = self.refresh_method.lock().unwrap().clone();
+        let use_update = match method {
+            SessionRefreshMethod::Update => true,
+            SessionRefreshMethod::Invite => false,
+            SessionRefreshMethod::Auto => self.remote_allows(&rsip::Method::Update),
+        };
+        if use_update {
+            return self.do_update(None, None).await;
+        }
+        let body = self.local_sdp.lock().unwrap().clone();
+        let headers = body
+            .as_ref()
+            .map(|_| vec![Header::ContentType("application/sdp".into())]);
+        self.do_reinvite(headers, body).await
+    }
+
+    /// Sends an in-dialog request and waits for its final response, gives up
+    /// after the `request_timeout` of the dialog or once it's cancelled
+    pub(super) async fn do_request(&self, request: Request) -> Result<Option<rsip::Response>> {
         let method = request.method().to_owned();
-        let destination = request
-            .route_header()
-            .map(|r| {
-                r.typed()
-                    .ok()
-                    .map(|r| r.uris().first().map(|u| u.uri.clone()))
-                    .flatten()
-            })
-            .flatten();
+        let request_timeout = *self.request_timeout.lock().unwrap();
+        let timeout = async {
+            match request_timeout {
+                Some(duration) => sleep(duration).await,
+                None => std::future::pending().await,
+            }
+        };
+        select! {
+            result = self.exchange_request(request) => result,
+            _ = self.cancel_token.cancelled() => Err(crate::Error::DialogError(
+                format!("{} cancelled", method),
+                self.id.lock().unwrap().clone(),
+            )),
+            _ = timeout => {
+                warn!("no final response to {} in {:?}", method, request_timeout);
+                Err(crate::Error::DialogError(
+                    format!("{} timed out", method),
+                    self.id.lock().unwrap().clone(),
+                ))
+            }
+        }
+    }
 
-        header_pop!(request.headers, Header::Route);
+    async fn exchange_request(&self, mut request: Request) -> Result<Option<rsip::Response>> {
+        let method = request.method().to_owned();
+        if let Some(cred) = &self.credential {
+            self.endpoint_inner
+                .auth_cache
+                .authorize_request(&mut request, cred);
+        }
+        // with a loose route the request goes to the first Route, otherwise
+        // the Request-URI already is the next hop
+        let destination = next_hop(&request);
 
         let key = TransactionKey::from_request(&request, TransactionRole::Client)?;
         let mut tx = Transaction::new_client(key, request, self.endpoint_inner.clone(), None);
         tx.destination = destination.as_ref().map(|d| d.try_into().ok()).flatten();
 
         tx.send().await?;
-        let mut auth_sent = false;
+        let mut auth_attempts = AuthAttempts::default();
 
         while let Some(msg) = tx.receive().await {
             match msg {
+                SipMessage::Response(resp) if !response_matches(&tx.original, &resp) => {
+                    warn!(
+                        "discarding response with mismatched cseq: {:?} {}",
+                        resp.cseq_header().map(|c| c.value().to_string()),
+                        resp.status_code
+                    );
+                    continue;
+                }
                 SipMessage::Response(resp) => match resp.status_code {
                     StatusCode::Trying => {
                         continue;
                     }
                     StatusCode::Ringing | StatusCode::SessionProgress => {
-                        self.transition(DialogState::Early(self.id.lock().unwrap().clone(), resp))?;
+                        if !self.is_confirmed() {
+                            self.transition(DialogState::Early(
+                                self.id.lock().unwrap().clone(),
+                                Arc::new(resp),
+                            ))?;
+                        }
                         continue;
                     }
                     StatusCode::ProxyAuthenticationRequired | StatusCode::Unauthorized => {
                         let id = self.id.lock().unwrap().clone();
-                        if auth_sent {
+                        if !auth_attempts.may_answer(&resp) {
                             info!("received {} response after auth sent", resp.status_code);
-                            self.transition(DialogState::Terminated(id, Some(resp.status_code)))?;
+                            self.endpoint_inner.auth_cache.forget(&tx.original);
+                            self.transition(DialogState::Terminated(
+                                id,
+                                Some(resp.status_code),
+                                None,
+                            ))?;
                             break;
                         }
-                        auth_sent = true;
                         if let Some(cred) = &self.credential {
                             let new_seq = match method {
                                 rsip::Method::Cancel => self.get_local_seq(),
@@ -336,11 +1225,31 @@ impl DialogInner {
                             continue;
                         } else {
                             info!("received 407 response without auth option");
-                            self.transition(DialogState::Terminated(id, Some(resp.status_code)))?;
+                            self.transition(DialogState::Terminated(
+                                id,
+                                Some(resp.status_code),
+                                None,
+                            ))?;
                         }
                     }
                     _ => {
                         debug!("dialog do_request done: {:?}", resp.status_code);
+                        self.touch();
+                        let answer = match method {
+                            rsip::Method::Invite | rsip::Method::Update => {
+                                if resp.status_code.kind() == rsip::StatusCodeKind::Successful
+                                    && self.is_confirmed()
+                                {
+                                    self.refresh_remote_target(&resp.headers);
+                                }
+                                self.negotiate_sdp(&tx.original.body, &resp).await?
+                            }
+                            _ => None,
+                        };
+                        if method == rsip::Method::Invite {
+                            let ack = self.make_ack(&tx.original, &resp, answer)?;
+                            tx.send_ack(ack).await?;
+                        }
                         return Ok(Some(resp));
                     }
                 },
@@ -352,22 +1261,69 @@ impl DialogInner {
 
     pub(super) fn transition(&self, state: DialogState) -> Result<()> {
         self.state_sender.send(state.clone())?;
+        if let Some(event) = DialogEvent::from_state(&state) {
+            // no subscriber is not an error
+            self.event_sender.send(event).ok();
+        }
         match state {
             DialogState::Updated(_, _)
             | DialogState::Notify(_, _)
             | DialogState::Info(_, _)
-            | DialogState::Options(_, _) => {
+            | DialogState::Dtmf(_, _)
+            | DialogState::Options(_, _)
+            | DialogState::Message(_, _)
+            | DialogState::ReferProgress(_, _)
+            | DialogState::Refer(_, _, _)
+            | DialogState::Kpml(_, _)
+            | DialogState::LateOffer(_, _)
+            | DialogState::HoldStateChanged(_, _)
+            | DialogState::UsageTerminated(_, DialogUsage::Subscription { .. }) => {
                 return Ok(());
             }
+            DialogState::Confirmed(_) => {
+                self.answer_time
+                    .lock()
+                    .unwrap()
+                    .get_or_insert_with(SystemTime::now);
+            }
+            DialogState::Terminated(ref id, ref code, ref reason) => {
+                self.emit_cdr(id, code, reason);
+            }
             _ => {}
         }
-        let mut old_state = self.state.lock().unwrap();
-        info!("transitioning state: {} -> {}", old_state, state);
-        *old_state = state;
+        {
+            let mut old_state = self.state.lock().unwrap();
+            info!("transitioning state: {} -> {}", old_state, state);
+            *old_state = state;
+        }
+        if self.initial_request.method == rsip::Method::Invite {
+            let layer = self.layer.lock().unwrap().as_ref().and_then(Weak::upgrade);
+            if let Some(layer) = layer {
+                // no watcher is not an error
+                let id = self.id.lock().unwrap().clone();
+                layer.state_changes.send(id).ok();
+            }
+        }
         Ok(())
     }
 }
 
+fn parse_allow(headers: &rsip::Headers) -> Vec<String> {
+    headers
+        .iter()
+        .filter_map(|h| match h {
+            Header::Allow(allow) => Some(allow.value().to_string()),
+            _ => None,
+        })
+        .flat_map(|v| {
+            v.split(',')
+                .map(|m| m.trim().to_uppercase())
+                .filter(|m| !m.is_empty())
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
 impl std::fmt::Display for DialogState {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -379,8 +1335,20 @@ impl std::fmt::Display for DialogState {
             DialogState::Updated(id, _) => write!(f, "{}(Updated)", id),
             DialogState::Notify(id, _) => write!(f, "{}(Notify)", id),
             DialogState::Info(id, _) => write!(f, "{}(Info)", id),
+            DialogState::Dtmf(id, event) => write!(f, "{}(Dtmf {})", id, event.digit),
             DialogState::Options(id, _) => write!(f, "{}(Options)", id),
-            DialogState::Terminated(id, code) => write!(f, "{}(Terminated {:?})", id, code),
+            DialogState::Message(id, _) => write!(f, "{}(Message)", id),
+            DialogState::ReferProgress(id, code) => write!(f, "{}(ReferProgress {})", id, code),
+            DialogState::Refer(id, refer_to, _) => write!(f, "{}(Refer {})", id, refer_to.uri),
+            DialogState::Kpml(id, report) => write!(f, "{}(Kpml {})", id, report.code),
+            DialogState::LateOffer(id, _) => write!(f, "{}(LateOffer)", id),
+            DialogState::HoldStateChanged(id, state) => {
+                write!(f, "{}(HoldStateChanged {:?})", id, state)
+            }
+            DialogState::UsageTerminated(id, usage) => {
+                write!(f, "{}(UsageTerminated {})", id, usage)
+            }
+            DialogState::Terminated(id, code, _) => write!(f, "{}(Terminated {:?})", id, code),
         }
     }
 }
@@ -390,12 +1358,24 @@ impl Dialog {
         match self {
             Dialog::ServerInvite(d) => d.inner.id.lock().unwrap().clone(),
             Dialog::ClientInvite(d) => d.inner.id.lock().unwrap().clone(),
+            Dialog::ClientSubscription(d) => d.inner.id.lock().unwrap().clone(),
+            Dialog::ServerSubscription(d) => d.inner.id.lock().unwrap().clone(),
+        }
+    }
+    pub(super) fn inner(&self) -> &DialogInnerRef {
+        match self {
+            Dialog::ServerInvite(d) => &d.inner,
+            Dialog::ClientInvite(d) => &d.inner,
+            Dialog::ClientSubscription(d) => &d.inner,
+            Dialog::ServerSubscription(d) => &d.inner,
         }
     }
     pub async fn handle(&mut self, tx: Transaction) -> Result<()> {
         match self {
             Dialog::ServerInvite(d) => d.handle(tx).await,
             Dialog::ClientInvite(d) => d.handle(tx).await,
+            Dialog::ClientSubscription(d) => d.handle(tx).await,
+            Dialog::ServerSubscription(d) => d.handle(tx).await,
         }
     }
     pub fn on_remove(&self) {
@@ -406,19 +1386,53 @@ impl Dialog {
             Dialog::ClientInvite(d) => {
                 d.inner.cancel_token.cancel();
             }
+            Dialog::ClientSubscription(d) => {
+                d.subscription.stop_token.cancel();
+                d.inner.cancel_token.cancel();
+            }
+            Dialog::ServerSubscription(d) => {
+                d.inner.cancel_token.cancel();
+            }
+        }
+    }
+
+    /// Sends an in-dialog request of any method, with the CSeq, route set
+    /// and credentials of the dialog. Content-Type goes in `headers`.
+    ///
+    /// INVITE, ACK and CANCEL are refused, their transactions are handled by
+    /// `reinvite`, the INVITE transaction and `cancel`.
+    pub async fn send_request(
+        &self,
+        method: rsip::Method,
+        headers: Option<Vec<Header>>,
+        body: Option<Vec<u8>>,
+    ) -> Result<Option<Response>> {
+        let inner = self.inner();
+        if matches!(
+            method,
+            rsip::Method::Invite | rsip::Method::Ack | rsip::Method::Cancel
+        ) {
+            return Err(crate::Error::DialogError(
+                format!("{} can't be sent as a generic request", method),
+                self.id(),
+            ));
         }
+        let request = inner.make_request(method, None, None, None, headers, body)?;
+        inner.do_request(request).await
     }
 
     pub async fn hangup(&self) -> Result<()> {
         match self {
-            Dialog::ServerInvite(d) => d.bye().await,
+            Dialog::ServerInvite(d) => d.bye(None).await,
             Dialog::ClientInvite(d) => {
                 if d.inner.is_confirmed() {
-                    d.bye().await
+                    d.bye(None).await
                 } else {
-                    d.cancel().await
+                    d.cancel(None).await
                 }
             }
+            Dialog::ClientSubscription(d) => d.unsubscribe().await,
+            Dialog::ServerSubscription(d) => d.terminate(None).await,
         }
     }
 }
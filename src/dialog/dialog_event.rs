@@ -0,0 +1,86 @@
+use super::{dialog::DialogState, hold::HoldState, reason::Reason, refer::ReferTo, DialogId};
+use futures::Stream;
+use rsip::{Response, StatusCode};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+/// Events kept for a slow subscriber of `events()`, older ones are dropped
+pub(super) const DIALOG_EVENT_CAPACITY: usize = 64;
+
+/// Typed dialog events broadcast to every subscriber of `events()`
+#[derive(Clone, Debug)]
+pub enum DialogEvent {
+    Early {
+        id: DialogId,
+        response: Arc<Response>,
+    },
+    Confirmed {
+        id: DialogId,
+    },
+    Hold {
+        id: DialogId,
+        state: HoldState,
+    },
+    Refer {
+        id: DialogId,
+        refer_to: ReferTo,
+    },
+    Terminated {
+        id: DialogId,
+        code: Option<StatusCode>,
+        reason: Option<Reason>,
+    },
+}
+
+impl DialogEvent {
+    /// The event of a dialog state, `None` for the states without one
+    pub fn from_state(state: &DialogState) -> Option<Self> {
+        match state {
+            DialogState::Early(id, response) => Some(DialogEvent::Early {
+                id: id.clone(),
+                response: response.clone(),
+            }),
+            DialogState::Confirmed(id) => Some(DialogEvent::Confirmed { id: id.clone() }),
+            DialogState::HoldStateChanged(id, state) => Some(DialogEvent::Hold {
+                id: id.clone(),
+                state: *state,
+            }),
+            DialogState::Refer(id, refer_to, _) => Some(DialogEvent::Refer {
+                id: id.clone(),
+                refer_to: refer_to.clone(),
+            }),
+            DialogState::Terminated(id, code, reason) => Some(DialogEvent::Terminated {
+                id: id.clone(),
+                code: code.clone(),
+                reason: reason.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn id(&self) -> &DialogId {
+        match self {
+            DialogEvent::Early { id, .. }
+            | DialogEvent::Confirmed { id }
+            | DialogEvent::Hold { id, .. }
+            | DialogEvent::Refer { id, .. }
+            | DialogEvent::Terminated { id, .. } => id,
+        }
+    }
+}
+
+/// Stream of the events sent after the subscription, it ends with the dialog.
+/// A subscriber lagging behind skips the events it missed.
+pub(super) fn event_stream(
+    receiver: broadcast::Receiver<DialogEvent>,
+) -> impl Stream<Item = DialogEvent> {
+    futures::stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => return Some((event, receiver)),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    })
+}
@@ -0,0 +1,403 @@
+use super::{
+    dialog::{Dialog, DialogState, DialogStateSender},
+    dialog_layer::{DialogLayer, DialogLayerInner},
+    event_package::{event_name, SimpleEventPackage},
+    presence::{presentity, xml_escape},
+    subscription::{
+        ClientSubscriptionDialog, ServerSubscriptionDialog, SubscribeOption, SubscriptionState,
+    },
+    usage::DialogUsage,
+};
+use crate::{
+    rsip_ext::header_value, transaction::key::TransactionRole,
+    transaction::transaction::Transaction, Error, Result,
+};
+use rsip::{
+    prelude::{HeadersExt, ToTypedHeader},
+    Response, StatusCode,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+pub const DIALOG_EVENT: &str = "dialog";
+pub const DIALOG_INFO_CONTENT_TYPE: &str = "application/dialog-info+xml";
+
+/// State of a dialog in a dialog-info document (RFC 4235 4.1.6)
+#[derive(Clone, Debug, PartialEq)]
+pub enum DialogInfoState {
+    Trying,
+    Proceeding,
+    Early,
+    Confirmed,
+    Terminated,
+}
+
+impl DialogInfoState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DialogInfoState::Trying => "trying",
+            DialogInfoState::Proceeding => "proceeding",
+            DialogInfoState::Early => "early",
+            DialogInfoState::Confirmed => "confirmed",
+            DialogInfoState::Terminated => "terminated",
+        }
+    }
+}
+
+impl TryFrom<&str> for DialogInfoState {
+    type Error = crate::Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "trying" => Ok(DialogInfoState::Trying),
+            "proceeding" => Ok(DialogInfoState::Proceeding),
+            "early" => Ok(DialogInfoState::Early),
+            "confirmed" => Ok(DialogInfoState::Confirmed),
+            "terminated" => Ok(DialogInfoState::Terminated),
+            _ => Err(Error::Error(format!("invalid dialog state: {}", value))),
+        }
+    }
+}
+
+impl From<&DialogState> for DialogInfoState {
+    fn from(state: &DialogState) -> Self {
+        match state {
+            DialogState::Calling(_) => DialogInfoState::Trying,
+            DialogState::Trying(_) => DialogInfoState::Proceeding,
+            // a provisional response without To tag establishes no early dialog
+            DialogState::Early(_, resp) => match resp.to_header().and_then(|to| to.tag()) {
+                Ok(Some(_)) => DialogInfoState::Early,
+                _ => DialogInfoState::Proceeding,
+            },
+            // the session is over even if subscriptions still share the dialog
+            DialogState::Terminated(..) | DialogState::UsageTerminated(_, DialogUsage::Invite) => {
+                DialogInfoState::Terminated
+            }
+            _ => DialogInfoState::Confirmed,
+        }
+    }
+}
+
+/// A `<dialog>` element of a dialog-info document
+#[derive(Clone, Debug, PartialEq)]
+pub struct DialogInfoEntry {
+    pub id: String,
+    pub call_id: Option<String>,
+    pub local_tag: Option<String>,
+    pub remote_tag: Option<String>,
+    /// `initiator` or `recipient`
+    pub direction: Option<String>,
+    pub state: DialogInfoState,
+}
+
+/// A dialog-info document (RFC 4235 4.1)
+#[derive(Clone, Debug, PartialEq)]
+pub struct DialogInfo {
+    pub version: u32,
+    /// `full` or `partial`
+    pub state: String,
+    pub entity: String,
+    pub dialogs: Vec<DialogInfoEntry>,
+}
+
+pub(crate) fn xml_attr(element: &str, name: &str) -> Option<String> {
+    let pattern = format!(" {}=", name);
+    let start = element.find(&pattern)? + pattern.len();
+    let quote = element[start..].chars().next()?;
+    let rest = &element[start + 1..];
+    let end = rest.find(quote)?;
+    Some(
+        rest[..end]
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&amp;", "&"),
+    )
+}
+
+pub(crate) fn xml_text<'a>(element: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{}", name);
+    let start = element.find(&open)?;
+    let start = start + element[start..].find('>')? + 1;
+    let end = start + element[start..].find(&format!("</{}>", name))?;
+    Some(&element[start..end])
+}
+
+impl DialogInfo {
+    pub fn to_xml(&self) -> String {
+        let mut xml = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\r\n\
+             <dialog-info xmlns=\"urn:ietf:params:xml:ns:dialog-info\" version=\"{}\" state=\"{}\" entity=\"{}\">\r\n",
+            self.version,
+            xml_escape(&self.state),
+            xml_escape(&self.entity)
+        );
+        for dialog in &self.dialogs {
+            xml.push_str(&format!("<dialog id=\"{}\"", xml_escape(&dialog.id)));
+            let attrs = [
+                ("call-id", &dialog.call_id),
+                ("local-tag", &dialog.local_tag),
+                ("remote-tag", &dialog.remote_tag),
+                ("direction", &dialog.direction),
+            ];
+            for (name, value) in attrs {
+                if let Some(value) = value {
+                    xml.push_str(&format!(" {}=\"{}\"", name, xml_escape(value)));
+                }
+            }
+            xml.push_str(&format!(
+                "><state>{}</state></dialog>\r\n",
+                dialog.state.as_str()
+            ));
+        }
+        xml.push_str("</dialog-info>\r\n");
+        xml
+    }
+}
+
+impl TryFrom<&str> for DialogInfo {
+    type Error = crate::Error;
+
+    fn try_from(xml: &str) -> Result<Self> {
+        let start = xml
+            .find("<dialog-info")
+            .ok_or(Error::Error("missing dialog-info element".to_string()))?;
+        let root = &xml[start..start + xml[start..].find('>').unwrap_or_default()];
+        let mut info = DialogInfo {
+            version: xml_attr(root, "version")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or_default(),
+            state: xml_attr(root, "state").unwrap_or_else(|| "full".to_string()),
+            entity: xml_attr(root, "entity").unwrap_or_default(),
+            dialogs: vec![],
+        };
+        let mut rest = &xml[start + root.len()..];
+        while let Some(pos) = rest.find("<dialog ") {
+            let element = &rest[pos..];
+            let end = element
+                .find("</dialog>")
+                .map(|e| e + "</dialog>".len())
+                .unwrap_or(element.len());
+            let element = &element[..end];
+            let head = &element[..element.find('>').unwrap_or(element.len())];
+            let state = xml_text(element, "state")
+                .ok_or(Error::Error("missing dialog state".to_string()))?;
+            info.dialogs.push(DialogInfoEntry {
+                id: xml_attr(head, "id").unwrap_or_default(),
+                call_id: xml_attr(head, "call-id"),
+                local_tag: xml_attr(head, "local-tag"),
+                remote_tag: xml_attr(head, "remote-tag"),
+                direction: xml_attr(head, "direction"),
+                state: DialogInfoState::try_from(state)?,
+            });
+            rest = &rest[pos + end..];
+        }
+        Ok(info)
+    }
+}
+
+impl DialogLayer {
+    /// Invite dialogs of the local party `entity` (`user@host`), as a full
+    /// dialog-info document
+    pub fn dialog_info(&self, entity: &str, version: u32) -> DialogInfo {
+        self.inner.dialog_info(entity, version)
+    }
+
+    fn register_dialog_package(&self) {
+        if self.get_event_package(DIALOG_EVENT).is_none() {
+            self.register_event_package(Arc::new(
+                SimpleEventPackage::new(DIALOG_EVENT).with_content_type(DIALOG_INFO_CONTENT_TYPE),
+            ));
+        }
+    }
+
+    /// Watches the dialogs of another UA (e.g. for BLF), the NOTIFYs reported
+    /// as `DialogState::Notify` are parsed with `DialogInfo::try_from`
+    pub async fn subscribe_dialog_info(
+        &self,
+        mut opt: SubscribeOption,
+        state_sender: DialogStateSender,
+    ) -> Result<(ClientSubscriptionDialog, Option<Response>)> {
+        self.register_dialog_package();
+        opt.event = DIALOG_EVENT.to_string();
+        opt.accept = Some(DIALOG_INFO_CONTENT_TYPE.to_string());
+        self.do_subscribe(opt, state_sender).await
+    }
+}
+
+impl DialogLayerInner {
+    pub(super) fn dialog_info(&self, entity: &str, version: u32) -> DialogInfo {
+        let dialogs = self
+            .dialogs
+            .read()
+            .unwrap()
+            .values()
+            .filter(|d| matches!(d, Dialog::ServerInvite(_) | Dialog::ClientInvite(_)))
+            .filter_map(|d| {
+                let inner = d.inner();
+                let local = rsip::headers::From::from(inner.from.clone()).typed().ok()?;
+                if presentity(&local.uri) != entity {
+                    return None;
+                }
+                let id = d.id();
+                let (local_tag, remote_tag, direction) = match inner.role {
+                    TransactionRole::Client => {
+                        (id.from_tag.clone(), id.to_tag.clone(), "initiator")
+                    }
+                    TransactionRole::Server => {
+                        (id.to_tag.clone(), id.from_tag.clone(), "recipient")
+                    }
+                };
+                Some(DialogInfoEntry {
+                    id: id.to_string(),
+                    call_id: Some(id.call_id.clone()),
+                    local_tag: Some(local_tag).filter(|t| !t.is_empty()),
+                    remote_tag: Some(remote_tag).filter(|t| !t.is_empty()),
+                    direction: Some(direction.to_string()),
+                    state: DialogInfoState::from(&*inner.state.lock().unwrap()),
+                })
+            })
+            .collect();
+        DialogInfo {
+            version,
+            state: "full".to_string(),
+            entity: format!("sip:{}", entity),
+            dialogs,
+        }
+    }
+}
+
+struct Watcher {
+    dialog: ServerSubscriptionDialog,
+    version: u32,
+}
+
+/// Notifier of the `dialog` event package, generating the dialog-info
+/// documents from the invite dialogs of the `DialogLayer`.
+///
+/// Watchers get the current document when they subscribe, and the updated
+/// documents whenever an invite dialog of the layer changes state.
+pub struct DialogInfoNotifier {
+    watchers: RwLock<HashMap<String, Vec<Watcher>>>,
+}
+
+impl DialogInfoNotifier {
+    pub fn new(layer: &DialogLayer) -> Arc<Self> {
+        layer.register_dialog_package();
+        let notifier = Arc::new(Self {
+            watchers: RwLock::new(HashMap::new()),
+        });
+        let mut changes = layer.state_changes();
+        let weak_layer = Arc::downgrade(&layer.inner);
+        let weak_notifier = Arc::downgrade(&notifier);
+        tokio::spawn(async move {
+            loop {
+                match changes.recv().await {
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return,
+                }
+                let (layer, notifier) = match (weak_layer.upgrade(), weak_notifier.upgrade()) {
+                    (Some(layer), Some(notifier)) => (layer, notifier),
+                    _ => return,
+                };
+                notifier.notify_watchers(&layer).await;
+            }
+        });
+        notifier
+    }
+
+    pub async fn handle_subscribe(
+        &self,
+        layer: &DialogLayer,
+        mut tx: Transaction,
+        state_sender: DialogStateSender,
+        contact: Option<rsip::Uri>,
+    ) -> Result<()> {
+        let event = header_value(&tx.original.headers, "Event").unwrap_or_default();
+        if event_name(&event) != DIALOG_EVENT {
+            info!("rejecting subscribe for event: {}", event);
+            tx.reply(StatusCode::from(489)).await?;
+            return Ok(());
+        }
+        let mut dialog = layer
+            .get_or_create_server_subscription(&tx, state_sender, None, contact)
+            .await?;
+        let entity = presentity(&tx.original.uri);
+        dialog.handle(tx).await?;
+
+        let version = {
+            let mut watchers = self.watchers.write().unwrap();
+            let list = watchers.entry(entity.clone()).or_default();
+            if dialog.expires() == 0 {
+                list.retain(|w| w.dialog.id() != dialog.id());
+                return Ok(());
+            }
+            match list.iter_mut().find(|w| w.dialog.id() == dialog.id()) {
+                Some(watcher) => {
+                    watcher.version += 1;
+                    watcher.version
+                }
+                None => {
+                    info!("new dialog watcher of {}: {}", entity, dialog.id());
+                    list.push(Watcher {
+                        dialog: dialog.clone(),
+                        version: 0,
+                    });
+                    0
+                }
+            }
+        };
+        notify_dialog_info(&dialog, &layer.dialog_info(&entity, version)).await
+    }
+
+    /// Sends the current dialogs of every watched entity to its watchers,
+    /// besides the automatic updates on dialog state changes
+    pub async fn refresh(&self, layer: &DialogLayer) -> Result<()> {
+        self.notify_watchers(&layer.inner).await;
+        Ok(())
+    }
+
+    async fn notify_watchers(&self, layer: &DialogLayerInner) {
+        let pending = {
+            let mut watchers = self.watchers.write().unwrap();
+            let mut pending = vec![];
+            for (entity, list) in watchers.iter_mut() {
+                list.retain(|w| {
+                    !w.dialog.cancel_token().is_cancelled()
+                        && !matches!(
+                            *w.dialog.inner.state.lock().unwrap(),
+                            DialogState::Terminated(..)
+                        )
+                });
+                for watcher in list.iter_mut() {
+                    watcher.version += 1;
+                    pending.push((entity.clone(), watcher.dialog.clone(), watcher.version));
+                }
+            }
+            pending
+        };
+        for (entity, dialog, version) in pending {
+            let info = layer.dialog_info(&entity, version);
+            if let Err(e) = notify_dialog_info(&dialog, &info).await {
+                warn!("failed to notify dialog watcher {}: {:?}", dialog.id(), e);
+            }
+        }
+    }
+}
+
+async fn notify_dialog_info(dialog: &ServerSubscriptionDialog, info: &DialogInfo) -> Result<()> {
+    dialog
+        .notify(
+            SubscriptionState::Active {
+                expires: Some(dialog.expires()),
+            },
+            None,
+            Some(info.to_xml().into_bytes()),
+        )
+        .await
+}
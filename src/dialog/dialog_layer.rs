@@ -1,25 +1,144 @@
-use super::authenticate::Credential;
-use super::dialog::DialogStateSender;
-use super::{dialog::Dialog, server_dialog::ServerInviteDialog, DialogId};
-use crate::dialog::dialog::DialogInner;
+use super::admission::{AdmissionStats, CallRateLimiter};
+use super::authenticate::CredentialStoreRef;
+use super::cdr::CdrSinkRef;
+use super::dialog::{DialogState, DialogStateSender};
+use super::dialog_store::{DialogKind, DialogRecord, DialogStoreRef, MemoryDialogStore};
+use super::event_package::{event_name, EventPackageRef};
+use super::usage::DialogUsage;
+use super::{dialog::Dialog, reason::Reason, server_dialog::ServerInviteDialog, DialogId};
+use crate::dialog::dialog::{next_cseq, DialogInner};
 use crate::transaction::key::TransactionRole;
 use crate::transaction::make_tag;
 use crate::transaction::{endpoint::EndpointInnerRef, transaction::Transaction};
 use crate::Result;
-use rsip::Request;
+use rsip::{Request, StatusCode};
 use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
 use std::{
     collections::HashMap,
     sync::{Arc, RwLock},
 };
-use tracing::info;
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+/// State changes kept for a slow subscriber of `state_changes()`
+const STATE_CHANGE_CAPACITY: usize = 64;
+
+/// Bounds on the concurrent dialogs of a `DialogLayer`, and on the rate of
+/// the new INVITEs
+#[derive(Clone, Debug)]
+pub struct DialogLimits {
+    pub max_dialogs: Option<usize>,
+    /// Calls in progress, the INVITE dialogs confirmed
+    pub max_confirmed_dialogs: Option<usize>,
+    pub max_call_attempts_per_second: Option<u32>,
+    /// Answer to new INVITEs over a limit, 503 or 486
+    pub reject_status: StatusCode,
+    /// Makes room by dropping the least recently active dialog that never
+    /// got confirmed, instead of rejecting the new INVITE
+    pub evict_unconfirmed: bool,
+}
+
+impl Default for DialogLimits {
+    fn default() -> Self {
+        Self {
+            max_dialogs: None,
+            max_confirmed_dialogs: None,
+            max_call_attempts_per_second: None,
+            reject_status: StatusCode::ServiceUnavailable,
+            evict_unconfirmed: false,
+        }
+    }
+}
 
 pub struct DialogLayerInner {
     pub(super) last_seq: AtomicU32,
     pub(super) dialogs: RwLock<HashMap<DialogId, Dialog>>,
+    pub(super) event_packages: RwLock<HashMap<String, EventPackageRef>>,
+    pub(super) store: DialogStoreRef,
+    pub(super) limits: RwLock<DialogLimits>,
+    pub(super) admission: AdmissionStats,
+    pub(super) call_rate: CallRateLimiter,
+    /// Receives the call detail records of the INVITE dialogs
+    pub(super) cdr_sink: RwLock<Option<CdrSinkRef>>,
+    /// Owner of the dialogs registered in `store`
+    pub(super) node_id: String,
+    /// Ids of the INVITE dialogs changing state, see `state_changes`
+    pub(super) state_changes: broadcast::Sender<DialogId>,
+    pub(super) endpoint: EndpointInnerRef,
 }
 pub type DialogLayerInnerRef = Arc<DialogLayerInner>;
 
+impl DialogLayerInner {
+    /// Adds a dialog to the layer, and registers it in the dialog store
+    pub(super) async fn insert_dialog(self: &Arc<Self>, id: DialogId, dialog: Dialog) {
+        let record = DialogRecord {
+            id: id.clone(),
+            kind: DialogKind::from(&dialog),
+            owner: self.node_id.clone(),
+        };
+        if let (Dialog::ServerInvite(_) | Dialog::ClientInvite(_), Some(sink)) =
+            (&dialog, self.cdr_sink.read().unwrap().as_ref())
+        {
+            dialog
+                .inner()
+                .cdr_sink
+                .lock()
+                .unwrap()
+                .replace(sink.clone());
+        }
+        dialog
+            .inner()
+            .layer
+            .lock()
+            .unwrap()
+            .replace(Arc::downgrade(self));
+        if self.dialogs.write().unwrap().insert(id, dialog).is_none() {
+            self.endpoint.on_dialog_count(true);
+        }
+        if let Err(e) = self.store.register(record).await {
+            warn!("failed to register dialog: {:?}", e);
+        }
+    }
+
+    /// Moves a dialog to the id it got with the remote tag, in the layer and
+    /// the dialog store
+    pub(super) async fn rekey_dialog(&self, old_id: &DialogId, new_id: DialogId) {
+        let dialog = {
+            let mut dialogs = self.dialogs.write().unwrap();
+            let dialog = match dialogs.remove(old_id) {
+                Some(dialog) => dialog,
+                None => return,
+            };
+            dialogs.insert(new_id.clone(), dialog.clone());
+            dialog
+        };
+        let record = DialogRecord {
+            id: new_id,
+            kind: DialogKind::from(&dialog),
+            owner: self.node_id.clone(),
+        };
+        if let Err(e) = self.store.unregister(old_id).await {
+            warn!("failed to unregister dialog: {:?}", e);
+        }
+        if let Err(e) = self.store.register(record).await {
+            warn!("failed to register dialog: {:?}", e);
+        }
+    }
+
+    /// Removes a dialog from the layer and the dialog store
+    pub(super) async fn remove_dialog(&self, id: &DialogId) -> Option<Dialog> {
+        let dialog = self.dialogs.write().unwrap().remove(id);
+        if dialog.is_some() {
+            self.endpoint.on_dialog_count(false);
+        }
+        if let Err(e) = self.store.unregister(id).await {
+            warn!("failed to unregister dialog: {:?}", e);
+        }
+        dialog
+    }
+}
+
 pub struct DialogLayer {
     pub endpoint: EndpointInnerRef,
     pub inner: DialogLayerInnerRef,
@@ -27,20 +146,45 @@ pub struct DialogLayer {
 
 impl DialogLayer {
     pub fn new(endpoint: EndpointInnerRef) -> Self {
+        Self::with_store(endpoint, Arc::new(MemoryDialogStore::new()), "local")
+    }
+
+    /// A layer registering its dialogs in `store` as owned by `node_id`, so
+    /// that the nodes of a cluster sharing the store can locate them
+    pub fn with_store(endpoint: EndpointInnerRef, store: DialogStoreRef, node_id: &str) -> Self {
         Self {
-            endpoint,
+            endpoint: endpoint.clone(),
             inner: Arc::new(DialogLayerInner {
                 last_seq: AtomicU32::new(0),
                 dialogs: RwLock::new(HashMap::new()),
+                event_packages: RwLock::new(HashMap::new()),
+                store,
+                limits: RwLock::new(DialogLimits::default()),
+                admission: AdmissionStats::default(),
+                call_rate: CallRateLimiter::default(),
+                cdr_sink: RwLock::new(None),
+                node_id: node_id.to_string(),
+                state_changes: broadcast::channel(STATE_CHANGE_CAPACITY).0,
+                endpoint,
             }),
         }
     }
 
-    pub fn get_or_create_server_invite(
+    pub fn node_id(&self) -> &str {
+        &self.inner.node_id
+    }
+
+    /// Looks up the owner of a dialog in the dialog store, which may be
+    /// another node of the cluster
+    pub async fn lookup_dialog_owner(&self, id: &DialogId) -> Result<Option<DialogRecord>> {
+        self.inner.store.lookup(id).await
+    }
+
+    pub async fn get_or_create_server_invite(
         &self,
         tx: &Transaction,
         state_sender: DialogStateSender,
-        credential: Option<Credential>,
+        credential: Option<CredentialStoreRef>,
         contact: Option<rsip::Uri>,
     ) -> Result<ServerInviteDialog> {
         let mut id = DialogId::try_from(&tx.original)?;
@@ -57,6 +201,7 @@ impl DialogLayer {
             }
         }
         id.to_tag = make_tag().to_string(); // generate to tag
+        let overload_status = self.admit_dialog().await;
 
         let dlg_inner = DialogInner::new(
             TransactionRole::Server,
@@ -71,24 +216,157 @@ impl DialogLayer {
         let dialog = ServerInviteDialog {
             inner: Arc::new(dlg_inner),
         };
+        if let Some(status) = overload_status {
+            // not registered, `handle` answers the INVITE with `status`
+            info!("too many dialogs, rejecting {id} with {status}");
+            dialog.inner.overload_status.lock().unwrap().replace(status);
+            return Ok(dialog);
+        }
         self.inner
-            .dialogs
-            .write()
-            .unwrap()
-            .insert(id.clone(), Dialog::ServerInvite(dialog.clone()));
+            .insert_dialog(id.clone(), Dialog::ServerInvite(dialog.clone()))
+            .await;
         info!("server invite dialog created: {id}");
         Ok(dialog)
     }
 
+    pub fn set_limits(&self, limits: DialogLimits) {
+        *self.inner.limits.write().unwrap() = limits;
+    }
+
+    /// Emits a `CallDetailRecord` to `sink` when an INVITE dialog added
+    /// from now on terminates, `None` stops it
+    pub fn set_cdr_sink(&self, sink: Option<CdrSinkRef>) {
+        *self.inner.cdr_sink.write().unwrap() = sink;
+    }
+
+    pub fn admission_stats(&self) -> &AdmissionStats {
+        &self.inner.admission
+    }
+
+    /// Checks a new INVITE against the `DialogLimits`: the call attempt
+    /// rate, the calls in progress, then the room for a new dialog. Returns
+    /// the status rejecting it, before the application ever handles it.
+    async fn admit_dialog(&self) -> Option<StatusCode> {
+        let limits = self.inner.limits.read().unwrap().clone();
+        let stats = &self.inner.admission;
+        let rejected = if !self.admit_call_attempt(&limits) {
+            Some(&stats.rejected_rate)
+        } else if limits
+            .max_confirmed_dialogs
+            .is_some_and(|max| self.confirmed_len() >= max)
+        {
+            Some(&stats.rejected_confirmed)
+        } else if !self.admit_dialog_count(&limits).await {
+            Some(&stats.rejected_dialogs)
+        } else {
+            None
+        };
+        match rejected {
+            Some(counter) => {
+                counter.fetch_add(1, Ordering::Relaxed);
+                Some(limits.reject_status)
+            }
+            None => {
+                stats.admitted.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    fn admit_call_attempt(&self, limits: &DialogLimits) -> bool {
+        match limits.max_call_attempts_per_second {
+            Some(max) => self
+                .inner
+                .call_rate
+                .try_acquire(max, tokio::time::Instant::now()),
+            None => true,
+        }
+    }
+
+    /// Checks the room for a new dialog against `max_dialogs`, evicting an
+    /// unconfirmed dialog when allowed
+    async fn admit_dialog_count(&self, limits: &DialogLimits) -> bool {
+        let max_dialogs = match limits.max_dialogs {
+            Some(max_dialogs) => max_dialogs,
+            None => return true,
+        };
+        if self.len() < max_dialogs {
+            return true;
+        }
+        if limits.evict_unconfirmed {
+            let oldest = self
+                .inner
+                .dialogs
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|(_, d)| {
+                    !d.inner().is_confirmed()
+                        && matches!(d, Dialog::ServerInvite(_) | Dialog::ClientInvite(_))
+                })
+                .min_by_key(|(_, d)| *d.inner().last_activity.lock().unwrap())
+                .map(|(id, d)| (id.clone(), d.clone()));
+            if let Some((id, dialog)) = oldest {
+                info!("evicting unconfirmed dialog: {id}");
+                if let Dialog::ServerInvite(d) = &dialog {
+                    d.reject_with(StatusCode::ServiceUnavailable, None).ok();
+                }
+                self.remove_dialog(&id).await;
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn register_event_package(&self, package: EventPackageRef) {
+        info!("register event package: {}", package.name());
+        self.inner
+            .event_packages
+            .write()
+            .unwrap()
+            .insert(event_name(package.name()), package);
+    }
+
+    /// Looks up the package of an Event header value
+    pub fn get_event_package(&self, event: &str) -> Option<EventPackageRef> {
+        self.inner
+            .event_packages
+            .read()
+            .unwrap()
+            .get(&event_name(event))
+            .cloned()
+    }
+
     pub fn increment_last_seq(&self) -> u32 {
-        self.inner.last_seq.fetch_add(1, Ordering::Relaxed);
-        self.inner.last_seq.load(Ordering::Relaxed)
+        let last = self
+            .inner
+            .last_seq
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |seq| {
+                Some(next_cseq(seq))
+            })
+            .unwrap_or_default();
+        next_cseq(last)
     }
 
     pub fn len(&self) -> usize {
         self.inner.dialogs.read().unwrap().len()
     }
 
+    /// INVITE dialogs confirmed, the calls in progress
+    pub fn confirmed_len(&self) -> usize {
+        self.inner
+            .dialogs
+            .read()
+            .unwrap()
+            .values()
+            .filter(|d| {
+                matches!(d, Dialog::ServerInvite(_) | Dialog::ClientInvite(_))
+                    && d.inner().is_confirmed()
+                    && d.inner().has_usage(&DialogUsage::Invite)
+            })
+            .count()
+    }
+
     pub fn get_dialog(&self, id: &DialogId) -> Option<Dialog> {
         let dialogs = self.inner.dialogs.read().unwrap();
         match dialogs.get(id) {
@@ -106,18 +384,122 @@ impl DialogLayer {
         }
     }
 
-    pub fn remove_dialog(&self, id: &DialogId) {
+    pub async fn remove_dialog(&self, id: &DialogId) {
         info!("remove dialog: {id}");
+        self.inner.remove_dialog(id).await.map(|d| d.on_remove());
+    }
+
+    /// Hangs up every dialog for a clean shutdown, concurrently and each
+    /// within `timeout`: BYE for confirmed ones, CANCEL for outgoing INVITEs
+    /// still pending and 487 for incoming ones. Resolves once all of them are
+    /// removed.
+    pub async fn shutdown(&self, timeout: Duration) {
+        let dialogs = self
+            .inner
+            .dialogs
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        info!("shutting down {} dialogs", dialogs.len());
+        let hangups = dialogs.into_iter().map(|dialog| async move {
+            let hangup = async {
+                match &dialog {
+                    Dialog::ServerInvite(d) if !d.inner.is_confirmed() => d.reject_with(
+                        rsip::StatusCode::RequestTerminated,
+                        Some(vec![Reason::sip(487).to_header()]),
+                    ),
+                    _ => dialog.hangup().await,
+                }
+            };
+            match tokio::time::timeout(timeout, hangup).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => warn!("failed to hang up {}: {:?}", dialog.id(), e),
+                Err(_) => warn!("timeout hanging up {}", dialog.id()),
+            }
+            dialog.id()
+        });
+        for id in futures::future::join_all(hangups).await {
+            self.remove_dialog(&id).await;
+        }
+    }
+
+    /// Dialogs of a call, the forks of an INVITE share its Call-ID
+    pub fn find_by_call_id(&self, call_id: &str) -> Vec<Dialog> {
         self.inner
             .dialogs
-            .write()
+            .read()
             .unwrap()
-            .remove(id)
-            .map(|d| d.on_remove());
+            .iter()
+            .filter(|(id, _)| id.call_id == call_id)
+            .map(|(_, d)| d.clone())
+            .collect()
+    }
+
+    /// Dialogs whose peer is `aor`, compared on user and host only
+    pub fn find_by_remote_aor(&self, aor: &rsip::Uri) -> Vec<Dialog> {
+        self.inner
+            .dialogs
+            .read()
+            .unwrap()
+            .values()
+            .filter(|d| {
+                d.inner()
+                    .remote_aor()
+                    .map(|remote| same_aor(&remote, aor))
+                    .unwrap_or(false)
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Ids of the INVITE dialogs of the layer changing state from now on, a
+    /// subscriber lagging behind skips the changes it missed
+    pub fn state_changes(&self) -> broadcast::Receiver<DialogId> {
+        self.inner.state_changes.subscribe()
+    }
+
+    /// Snapshot of the id and state of every dialog
+    pub fn states(&self) -> impl Iterator<Item = (DialogId, DialogState)> {
+        self.inner
+            .dialogs
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(id, d)| (id.clone(), d.inner().state.lock().unwrap().clone()))
+            .collect::<Vec<_>>()
+            .into_iter()
     }
 
     pub fn match_dialog(&self, req: &Request) -> Option<Dialog> {
         let id = DialogId::try_from(req).ok()?;
         self.get_dialog(&id)
+            .or_else(|| self.match_pending_subscription(req, &id))
+    }
+
+    /// A NOTIFY may come before the 2xx of its SUBSCRIBE, it then matches the
+    /// subscription still without remote tag (RFC 6665 4.1.2.4)
+    fn match_pending_subscription(&self, req: &Request, id: &DialogId) -> Option<Dialog> {
+        if req.method != rsip::Method::Notify {
+            return None;
+        }
+        let pending = DialogId {
+            call_id: id.call_id.clone(),
+            from_tag: id.to_tag.clone(),
+            to_tag: String::new(),
+        };
+        match self.inner.dialogs.read().unwrap().get(&pending) {
+            Some(dialog @ Dialog::ClientSubscription(_)) => Some(dialog.clone()),
+            _ => None,
+        }
     }
 }
+
+fn same_aor(a: &rsip::Uri, b: &rsip::Uri) -> bool {
+    a.user() == b.user()
+        && a.host_with_port
+            .host
+            .to_string()
+            .eq_ignore_ascii_case(&b.host_with_port.host.to_string())
+}
@@ -0,0 +1,94 @@
+use super::{dialog::Dialog, DialogId};
+use crate::Result;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+/// Kind of a registered dialog
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DialogKind {
+    ServerInvite,
+    ClientInvite,
+    ClientSubscription,
+    ServerSubscription,
+}
+
+impl DialogKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DialogKind::ServerInvite => "server-invite",
+            DialogKind::ClientInvite => "client-invite",
+            DialogKind::ClientSubscription => "client-subscription",
+            DialogKind::ServerSubscription => "server-subscription",
+        }
+    }
+}
+
+impl From<&Dialog> for DialogKind {
+    fn from(dialog: &Dialog) -> Self {
+        match dialog {
+            Dialog::ServerInvite(_) => DialogKind::ServerInvite,
+            Dialog::ClientInvite(_) => DialogKind::ClientInvite,
+            Dialog::ClientSubscription(_) => DialogKind::ClientSubscription,
+            Dialog::ServerSubscription(_) => DialogKind::ServerSubscription,
+        }
+    }
+}
+
+/// Ownership of a dialog: which SIP front-end of a cluster holds its state
+#[derive(Clone, Debug, PartialEq)]
+pub struct DialogRecord {
+    pub id: DialogId,
+    pub kind: DialogKind,
+    /// Node id given to `DialogLayer::with_store`
+    pub owner: String,
+}
+
+/// Registry of the dialogs of a `DialogLayer`, shared by the nodes of a
+/// cluster when backed by an external database. The live dialog state stays
+/// in the layer of the owner node.
+#[async_trait::async_trait]
+pub trait DialogStore: Send + Sync {
+    async fn register(&self, record: DialogRecord) -> Result<()>;
+    async fn unregister(&self, id: &DialogId) -> Result<()>;
+    async fn lookup(&self, id: &DialogId) -> Result<Option<DialogRecord>>;
+    async fn list(&self) -> Result<Vec<DialogRecord>>;
+}
+pub type DialogStoreRef = Arc<dyn DialogStore>;
+
+/// Default store, local to the process
+#[derive(Default)]
+pub struct MemoryDialogStore {
+    records: RwLock<HashMap<DialogId, DialogRecord>>,
+}
+
+impl MemoryDialogStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait::async_trait]
+impl DialogStore for MemoryDialogStore {
+    async fn register(&self, record: DialogRecord) -> Result<()> {
+        self.records
+            .write()
+            .unwrap()
+            .insert(record.id.clone(), record);
+        Ok(())
+    }
+
+    async fn unregister(&self, id: &DialogId) -> Result<()> {
+        self.records.write().unwrap().remove(id);
+        Ok(())
+    }
+
+    async fn lookup(&self, id: &DialogId) -> Result<Option<DialogRecord>> {
+        Ok(self.records.read().unwrap().get(id).cloned())
+    }
+
+    async fn list(&self) -> Result<Vec<DialogRecord>> {
+        Ok(self.records.read().unwrap().values().cloned().collect())
+    }
+}
@@ -0,0 +1,653 @@
+use crate::{transaction::random_text, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use rsip::{prelude::UntypedHeader, Header, Request};
+use sha2::{Digest, Sha256, Sha512_256};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::info;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const NONCE_SALT_LEN: usize = 8;
+/// Most nonces whose nonce count is tracked at once; a live nonce is never
+/// forgotten, new ones being challenged again until some expire
+const MAX_NONCE_COUNTS: usize = 4096;
+
+/// Digest algorithms (RFC 7616, RFC 8760 for SIP), MD5 when a challenge
+/// doesn't name one
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum DigestAlgorithm {
+    #[default]
+    Md5,
+    Md5Sess,
+    Sha256,
+    Sha256Sess,
+    Sha512_256,
+    Sha512_256Sess,
+}
+
+impl DigestAlgorithm {
+    pub fn parse(value: &str) -> Option<Self> {
+        let algorithm = match value.trim().to_uppercase().as_str() {
+            "MD5" => Self::Md5,
+            "MD5-SESS" => Self::Md5Sess,
+            "SHA-256" => Self::Sha256,
+            "SHA-256-SESS" => Self::Sha256Sess,
+            "SHA-512-256" => Self::Sha512_256,
+            "SHA-512-256-SESS" => Self::Sha512_256Sess,
+            _ => return None,
+        };
+        Some(algorithm)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Md5 => "MD5",
+            Self::Md5Sess => "MD5-sess",
+            Self::Sha256 => "SHA-256",
+            Self::Sha256Sess => "SHA-256-sess",
+            Self::Sha512_256 => "SHA-512-256",
+            Self::Sha512_256Sess => "SHA-512-256-sess",
+        }
+    }
+
+    /// Whether HA1 covers the nonce and cnonce too
+    pub fn is_sess(&self) -> bool {
+        matches!(
+            self,
+            Self::Md5Sess | Self::Sha256Sess | Self::Sha512_256Sess
+        )
+    }
+
+    /// Lowercase hex hash of `data`
+    pub fn hash(&self, data: &[u8]) -> String {
+        match self {
+            Self::Md5 | Self::Md5Sess => format!("{:x}", md5::compute(data)),
+            Self::Sha256 | Self::Sha256Sess => format!("{:x}", Sha256::digest(data)),
+            Self::Sha512_256 | Self::Sha512_256Sess => format!("{:x}", Sha512_256::digest(data)),
+        }
+    }
+
+    /// H(username:realm:password), what a server may store instead of the
+    /// password
+    pub fn ha1(&self, username: &str, realm: &str, password: &str) -> String {
+        self.hash(format!("{}:{}:{}", username, realm, password).as_bytes())
+    }
+}
+
+impl fmt::Display for DigestAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Scheme and parameters of an authentication header value, the quotes of
+/// the values removed
+fn parse_params(value: &str) -> Option<(String, Vec<(String, String)>)> {
+    let value = value.trim();
+    let (scheme, rest) = value.split_once(char::is_whitespace)?;
+    let mut params = vec![];
+    let mut chars = rest.chars().peekable();
+    loop {
+        while matches!(chars.peek(), Some(c) if c.is_whitespace() || *c == ',') {
+            chars.next();
+        }
+        let mut name = String::new();
+        while let Some(c) = chars.peek() {
+            if *c == '=' || *c == ',' {
+                break;
+            }
+            name.push(*c);
+            chars.next();
+        }
+        if name.trim().is_empty() {
+            break;
+        }
+        let mut value = String::new();
+        if chars.peek() == Some(&'=') {
+            chars.next();
+            while matches!(chars.peek(), Some(c) if c.is_whitespace()) {
+                chars.next();
+            }
+            if chars.peek() == Some(&'"') {
+                chars.next();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => value.extend(chars.next()),
+                        c => value.push(c),
+                    }
+                }
+            } else {
+                while let Some(c) = chars.peek() {
+                    if *c == ',' {
+                        break;
+                    }
+                    value.push(*c);
+                    chars.next();
+                }
+            }
+        }
+        params.push((name.trim().to_lowercase(), value.trim().to_string()));
+    }
+    Some((scheme.to_string(), params))
+}
+
+fn param<'a>(params: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    params
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, v)| v.as_str())
+}
+
+/// A Digest challenge of a WWW-Authenticate or Proxy-Authenticate header
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DigestChallenge {
+    pub realm: String,
+    pub nonce: String,
+    pub opaque: Option<String>,
+    pub algorithm: DigestAlgorithm,
+    /// Quality of protection options offered, empty for none
+    pub qop: Vec<String>,
+    pub stale: bool,
+}
+
+impl DigestChallenge {
+    /// `None` for another scheme, or an algorithm we don't support
+    pub fn parse(value: &str) -> Option<Self> {
+        let (scheme, params) = parse_params(value)?;
+        if !scheme.eq_ignore_ascii_case("Digest") {
+            return None;
+        }
+        let algorithm = match param(&params, "algorithm") {
+            Some(algorithm) => DigestAlgorithm::parse(algorithm)?,
+            None => DigestAlgorithm::Md5,
+        };
+        Some(Self {
+            realm: param(&params, "realm")?.to_string(),
+            nonce: param(&params, "nonce")?.to_string(),
+            opaque: param(&params, "opaque").map(|o| o.to_string()),
+            algorithm,
+            qop: param(&params, "qop")
+                .map(|qop| {
+                    qop.split(',')
+                        .map(|q| q.trim().to_lowercase())
+                        .filter(|q| !q.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            stale: param(&params, "stale").is_some_and(|s| s.eq_ignore_ascii_case("true")),
+        })
+    }
+}
+
+impl fmt::Display for DigestChallenge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Digest realm=\"{}\", nonce=\"{}\"",
+            self.realm, self.nonce
+        )?;
+        if let Some(opaque) = self.opaque.as_ref() {
+            write!(f, ", opaque=\"{}\"", opaque)?;
+        }
+        write!(f, ", algorithm={}", self.algorithm)?;
+        if !self.qop.is_empty() {
+            write!(f, ", qop=\"{}\"", self.qop.join(","))?;
+        }
+        if self.stale {
+            write!(f, ", stale=true")?;
+        }
+        Ok(())
+    }
+}
+
+/// The Digest credentials of an Authorization or Proxy-Authorization header
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DigestCredentials {
+    pub username: String,
+    pub realm: String,
+    pub nonce: String,
+    pub uri: String,
+    pub response: String,
+    pub algorithm: DigestAlgorithm,
+    pub opaque: Option<String>,
+    /// `auth` or `auth-int` when the challenge offered it, with `cnonce`
+    /// and `nc`
+    pub qop: Option<String>,
+    pub cnonce: Option<String>,
+    pub nc: Option<u32>,
+}
+
+impl DigestCredentials {
+    pub fn parse(value: &str) -> Option<Self> {
+        let (scheme, params) = parse_params(value)?;
+        if !scheme.eq_ignore_ascii_case("Digest") {
+            return None;
+        }
+        let algorithm = match param(&params, "algorithm") {
+            Some(algorithm) => DigestAlgorithm::parse(algorithm)?,
+            None => DigestAlgorithm::Md5,
+        };
+        Some(Self {
+            username: param(&params, "username")?.to_string(),
+            realm: param(&params, "realm")?.to_string(),
+            nonce: param(&params, "nonce")?.to_string(),
+            uri: param(&params, "uri")?.to_string(),
+            response: param(&params, "response")?.to_lowercase(),
+            algorithm,
+            opaque: param(&params, "opaque").map(|o| o.to_string()),
+            qop: param(&params, "qop").map(|q| q.to_lowercase()),
+            cnonce: param(&params, "cnonce").map(|c| c.to_string()),
+            nc: param(&params, "nc").and_then(|nc| u32::from_str_radix(nc, 16).ok()),
+        })
+    }
+
+    /// Answers `challenge` for a request of `method` to `uri`. A request
+    /// with a body gets `auth-int` protection when the challenge offers it,
+    /// else `auth` when offered, else `auth-int`.
+    pub fn answer(
+        challenge: &DigestChallenge,
+        username: &str,
+        password: &str,
+        method: &rsip::Method,
+        uri: &str,
+        body: &[u8],
+        cnonce: &str,
+    ) -> Self {
+        let offers = |qop: &str| challenge.qop.iter().any(|q| q == qop);
+        let qop = if !body.is_empty() && offers("auth-int") {
+            Some("auth-int")
+        } else if offers("auth") {
+            Some("auth")
+        } else if offers("auth-int") {
+            Some("auth-int")
+        } else {
+            None
+        }
+        .map(|qop| qop.to_string());
+        let mut credentials = Self {
+            username: username.to_string(),
+            realm: challenge.realm.clone(),
+            nonce: challenge.nonce.clone(),
+            uri: uri.to_string(),
+            response: String::new(),
+            algorithm: challenge.algorithm,
+            opaque: challenge.opaque.clone(),
+            cnonce: qop.as_ref().map(|_| cnonce.to_string()),
+            nc: qop.as_ref().map(|_| 1),
+            qop,
+        };
+        let ha1 = challenge
+            .algorithm
+            .ha1(username, &challenge.realm, password);
+        credentials.response = credentials.expected_response(&ha1, method, body);
+        credentials
+    }
+
+    /// Response the credentials must carry for a request of `method` with
+    /// `body`, given the HA1 of the user (RFC 7616 3.4.1). The body only
+    /// counts with `auth-int`.
+    pub fn expected_response(&self, ha1: &str, method: &rsip::Method, body: &[u8]) -> String {
+        let algorithm = self.algorithm;
+        let cnonce = self.cnonce.as_deref().unwrap_or_default();
+        let ha1 = if algorithm.is_sess() {
+            algorithm.hash(format!("{}:{}:{}", ha1, self.nonce, cnonce).as_bytes())
+        } else {
+            ha1.to_string()
+        };
+        let ha2 = match self.qop.as_deref() {
+            Some("auth-int") => algorithm
+                .hash(format!("{}:{}:{}", method, self.uri, algorithm.hash(body)).as_bytes()),
+            _ => algorithm.hash(format!("{}:{}", method, self.uri).as_bytes()),
+        };
+        let value = match self.qop.as_ref() {
+            Some(qop) => format!(
+                "{}:{}:{:08x}:{}:{}:{}",
+                ha1,
+                self.nonce,
+                self.nc.unwrap_or(1),
+                cnonce,
+                qop,
+                ha2
+            ),
+            None => format!("{}:{}:{}", ha1, self.nonce, ha2),
+        };
+        algorithm.hash(value.as_bytes())
+    }
+
+    /// Whether the response matches the HA1 of the user, for a request of
+    /// `method` with `body`
+    pub fn verify(&self, ha1: &str, method: &rsip::Method, body: &[u8]) -> bool {
+        constant_time_eq(
+            self.expected_response(ha1, method, body).as_bytes(),
+            self.response.as_bytes(),
+        )
+    }
+
+    /// Whether the digest-uri is the Request-URI of `request`, compared as
+    /// URIs when it parses (RFC 3261 22.4)
+    pub fn matches_uri(&self, request: &Request) -> bool {
+        match rsip::Uri::try_from(self.uri.as_str()) {
+            Ok(uri) => uri == request.uri,
+            Err(_) => self.uri == request.uri.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for DigestCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", response=\"{}\", algorithm={}",
+            self.username, self.realm, self.nonce, self.uri, self.response, self.algorithm
+        )?;
+        if let Some(opaque) = self.opaque.as_ref() {
+            write!(f, ", opaque=\"{}\"", opaque)?;
+        }
+        if let Some(qop) = self.qop.as_ref() {
+            write!(
+                f,
+                ", qop={}, cnonce=\"{}\", nc={:08x}",
+                qop,
+                self.cnonce.as_deref().unwrap_or_default(),
+                self.nc.unwrap_or(1)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The first challenge of `values` with an algorithm we support: the server
+/// lists them by preference (RFC 8760 2.4)
+pub fn select_challenge<'a>(values: impl IntoIterator<Item = &'a str>) -> Option<DigestChallenge> {
+    values.into_iter().find_map(DigestChallenge::parse)
+}
+
+/// What a server knows of a user to check its digest responses
+#[derive(Clone, Debug)]
+pub enum DigestSecret {
+    Password(String),
+    /// H(username:realm:password) of the algorithm asked for
+    Ha1(String),
+}
+
+impl DigestSecret {
+    pub fn ha1(&self, algorithm: DigestAlgorithm, username: &str, realm: &str) -> String {
+        match self {
+            Self::Password(password) => algorithm.ha1(username, realm, password),
+            Self::Ha1(ha1) => ha1.clone(),
+        }
+    }
+}
+
+/// Looks up the secrets of the users a server authenticates
+#[async_trait::async_trait]
+pub trait DigestSecretStore: Send + Sync {
+    /// Secret of `username` in `realm`, `None` for an unknown user. A store
+    /// of HA1s returns the one of `algorithm`.
+    async fn secret(
+        &self,
+        username: &str,
+        realm: &str,
+        algorithm: DigestAlgorithm,
+    ) -> Result<Option<DigestSecret>>;
+}
+pub type DigestSecretStoreRef = Arc<dyn DigestSecretStore>;
+
+/// Compares in a time independent of where the values differ
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// A nonce answered, and the highest nonce count accepted with it
+struct NonceState {
+    issued: Instant,
+    nc: u32,
+}
+
+/// Outcome of checking the credentials of a request
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DigestVerdict {
+    /// The credentials answer one of our challenges, for this username
+    Authorized(String),
+    /// Right credentials of an expired or unknown nonce: the client is
+    /// challenged again with `stale=true` and retries without asking the
+    /// user (RFC 7616 3.3)
+    Stale,
+    /// Missing or wrong credentials, or a nonce count replayed
+    Unauthorized,
+    /// The digest-uri isn't the Request-URI: answered with 400 Bad Request
+    UriMismatch,
+}
+
+/// Server side of digest authentication (RFC 3261 22, RFC 7616): issues the
+/// challenges of a 401 or a 407 and checks the Authorization or
+/// Proxy-Authorization answering them, for a registrar, a proxy or a UAS.
+pub struct DigestAuthenticator {
+    pub realm: String,
+    /// Algorithms challenged with, by preference, one challenge each
+    pub algorithms: Vec<DigestAlgorithm>,
+    /// Quality of protection offered, empty for none
+    pub qop: Vec<String>,
+    /// Sent in every challenge, and then required back unchanged
+    pub opaque: Option<String>,
+    /// How long a nonce may be answered after it was issued
+    pub nonce_lifetime: Duration,
+    secrets: DigestSecretStoreRef,
+    /// Signs the nonces, which carry the time they were issued at so that
+    /// nothing is stored until one is answered
+    key: [u8; 32],
+    epoch: Instant,
+    nonces: Mutex<HashMap<String, NonceState>>,
+}
+
+impl DigestAuthenticator {
+    pub fn new(realm: &str, secrets: DigestSecretStoreRef) -> Self {
+        Self {
+            realm: realm.to_string(),
+            algorithms: vec![DigestAlgorithm::Md5],
+            qop: vec!["auth".to_string()],
+            opaque: None,
+            nonce_lifetime: Duration::from_secs(300),
+            secrets,
+            key: rand::random(),
+            epoch: Instant::now(),
+            nonces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Challenges with one header per algorithm of `algorithms`, e.g.
+    /// SHA-256 then MD5 for the older clients (RFC 8760)
+    pub fn with_algorithms(mut self, algorithms: Vec<DigestAlgorithm>) -> Self {
+        self.algorithms = algorithms;
+        self
+    }
+
+    pub fn with_qop(mut self, qop: Vec<String>) -> Self {
+        self.qop = qop;
+        self
+    }
+
+    pub fn with_opaque(mut self, opaque: &str) -> Self {
+        self.opaque = Some(opaque.to_string());
+        self
+    }
+
+    pub fn with_nonce_lifetime(mut self, nonce_lifetime: Duration) -> Self {
+        self.nonce_lifetime = nonce_lifetime;
+        self
+    }
+
+    /// A new nonce, valid for `nonce_lifetime`: the time it is issued at
+    /// and a salt, signed with the key of the authenticator
+    pub fn make_nonce(&self) -> String {
+        let payload = format!(
+            "{:x}.{}",
+            self.epoch.elapsed().as_millis(),
+            random_text(NONCE_SALT_LEN)
+        );
+        format!(
+            "{}.{}",
+            payload,
+            URL_SAFE_NO_PAD.encode(self.sign(&payload))
+        )
+    }
+
+    fn sign(&self, payload: &str) -> Vec<u8> {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC takes any key size");
+        mac.update(payload.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    /// Time since `nonce` was issued, `None` when we didn't issue it
+    fn nonce_age(&self, nonce: &str) -> Option<Duration> {
+        let (payload, signature) = nonce.rsplit_once('.')?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        if !constant_time_eq(&self.sign(payload), &signature) {
+            return None;
+        }
+        let issued = u64::from_str_radix(payload.split('.').next()?, 16).ok()?;
+        self.epoch
+            .elapsed()
+            .checked_sub(Duration::from_millis(issued))
+    }
+
+    /// Challenges of a new nonce, one per algorithm, `stale` when the
+    /// previous nonce answered expired
+    pub fn challenges(&self, stale: bool) -> Vec<DigestChallenge> {
+        let nonce = self.make_nonce();
+        self.algorithms
+            .iter()
+            .map(|algorithm| DigestChallenge {
+                realm: self.realm.clone(),
+                nonce: nonce.clone(),
+                opaque: self.opaque.clone(),
+                algorithm: *algorithm,
+                qop: self.qop.clone(),
+                stale,
+            })
+            .collect()
+    }
+
+    /// Proxy-Authenticate headers of a 407 when `is_proxy`, else
+    /// WWW-Authenticate headers of a 401
+    pub fn challenge_headers(&self, is_proxy: bool, stale: bool) -> Vec<Header> {
+        self.challenges(stale)
+            .into_iter()
+            .map(|challenge| {
+                if is_proxy {
+                    Header::ProxyAuthenticate(challenge.to_string().into())
+                } else {
+                    Header::WwwAuthenticate(challenge.to_string().into())
+                }
+            })
+            .collect()
+    }
+
+    /// The credentials of `request` for our realm, from its
+    /// Proxy-Authorization when `is_proxy`, else its Authorization
+    pub fn credentials(&self, request: &Request, is_proxy: bool) -> Option<DigestCredentials> {
+        request
+            .headers
+            .iter()
+            .filter_map(|h| match h {
+                Header::Authorization(auth) if !is_proxy => DigestCredentials::parse(auth.value()),
+                Header::ProxyAuthorization(auth) if is_proxy => {
+                    DigestCredentials::parse(auth.value())
+                }
+                _ => None,
+            })
+            .find(|auth| auth.realm == self.realm)
+    }
+
+    /// Whether `nonce` was issued by us and may still be answered
+    pub fn is_valid_nonce(&self, nonce: &str) -> bool {
+        self.nonce_age(nonce)
+            .is_some_and(|age| age < self.nonce_lifetime)
+    }
+
+    /// Username of the credentials of `request` when they answer one of our
+    /// challenges with the secret of the user, `None` when the request must
+    /// be challenged (again)
+    pub async fn verify(&self, request: &Request, is_proxy: bool) -> Result<Option<String>> {
+        match self.check(request, is_proxy).await? {
+            DigestVerdict::Authorized(username) => Ok(Some(username)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Checks the credentials of `request` for our realm. With a qop, each
+    /// nonce count is accepted once and in increasing order, a replayed
+    /// request being unauthorized; the nonce count is only recorded once
+    /// the response proved right. A nonce new to a full table is stale.
+    pub async fn check(&self, request: &Request, is_proxy: bool) -> Result<DigestVerdict> {
+        let auth = match self.credentials(request, is_proxy) {
+            Some(auth) => auth,
+            None => return Ok(DigestVerdict::Unauthorized),
+        };
+        if !auth.matches_uri(request) {
+            info!(
+                "digest-uri {} of {} isn't the Request-URI {}",
+                auth.uri, auth.username, request.uri
+            );
+            return Ok(DigestVerdict::UriMismatch);
+        }
+        if !self.algorithms.contains(&auth.algorithm)
+            || (self.opaque.is_some() && auth.opaque != self.opaque)
+            || auth.qop.as_ref().is_some_and(|qop| !self.qop.contains(qop))
+            || (auth.qop.is_none() && !self.qop.is_empty())
+        {
+            return Ok(DigestVerdict::Unauthorized);
+        }
+        let secret = match self
+            .secrets
+            .secret(&auth.username, &self.realm, auth.algorithm)
+            .await?
+        {
+            Some(secret) => secret,
+            None => return Ok(DigestVerdict::Unauthorized),
+        };
+        let ha1 = secret.ha1(auth.algorithm, &auth.username, &self.realm);
+        if !auth.verify(&ha1, &request.method, &request.body) {
+            return Ok(DigestVerdict::Unauthorized);
+        }
+
+        let age = match self.nonce_age(&auth.nonce) {
+            Some(age) if age < self.nonce_lifetime => age,
+            _ => {
+                info!("stale nonce from {}", auth.username);
+                return Ok(DigestVerdict::Stale);
+            }
+        };
+        if auth.qop.is_some() {
+            let mut nonces = self.nonces.lock().unwrap();
+            nonces.retain(|_, state| state.issued.elapsed() < self.nonce_lifetime);
+            if !nonces.contains_key(&auth.nonce) && nonces.len() >= MAX_NONCE_COUNTS {
+                info!("nonce table full, {} challenged again", auth.username);
+                return Ok(DigestVerdict::Stale);
+            }
+            let state = nonces
+                .entry(auth.nonce.clone())
+                .or_insert_with(|| NonceState {
+                    issued: Instant::now().checked_sub(age).unwrap_or_else(Instant::now),
+                    nc: 0,
+                });
+            let nc = auth.nc.unwrap_or_default();
+            if nc <= state.nc {
+                info!(
+                    "nonce count {} replayed by {}, last {}",
+                    nc, auth.username, state.nc
+                );
+                return Ok(DigestVerdict::Unauthorized);
+            }
+            state.nc = nc;
+        }
+        Ok(DigestVerdict::Authorized(auth.username))
+    }
+}
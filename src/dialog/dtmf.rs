@@ -0,0 +1,75 @@
+use crate::{rsip_ext::header_value, Error, Result};
+use rsip::Request;
+use std::time::Duration;
+
+pub const DTMF_RELAY_CONTENT_TYPE: &str = "application/dtmf-relay";
+
+/// A digit carried by an `application/dtmf-relay` INFO
+#[derive(Clone, Debug, PartialEq)]
+pub struct DtmfEvent {
+    /// `0`-`9`, `*`, `#` or `A`-`D`
+    pub digit: char,
+    pub duration: Duration,
+}
+
+fn is_dtmf_digit(digit: char) -> bool {
+    matches!(digit, '0'..='9' | '*' | '#' | 'A'..='D')
+}
+
+impl DtmfEvent {
+    pub fn new(digit: char, duration: Duration) -> Result<Self> {
+        let digit = digit.to_ascii_uppercase();
+        if !is_dtmf_digit(digit) {
+            return Err(Error::Error(format!("invalid dtmf digit: {}", digit)));
+        }
+        Ok(Self { digit, duration })
+    }
+
+    pub fn to_body(&self) -> Vec<u8> {
+        format!(
+            "Signal={}\r\nDuration={}\r\n",
+            self.digit,
+            self.duration.as_millis()
+        )
+        .into_bytes()
+    }
+
+    /// Parses an incoming INFO, `None` when it isn't a valid dtmf-relay one
+    pub fn from_info(request: &Request) -> Option<Self> {
+        let content_type = header_value(&request.headers, "Content-Type")
+            .or_else(|| header_value(&request.headers, "c"))?;
+        let mime = content_type.split(';').next().unwrap_or_default().trim();
+        if !mime.eq_ignore_ascii_case(DTMF_RELAY_CONTENT_TYPE) {
+            return None;
+        }
+        let body = std::str::from_utf8(&request.body).ok()?;
+        DtmfEvent::try_from(body).ok()
+    }
+}
+
+impl TryFrom<&str> for DtmfEvent {
+    type Error = crate::Error;
+
+    fn try_from(body: &str) -> Result<Self> {
+        let mut digit = None;
+        let mut duration = Duration::ZERO;
+        for line in body.lines() {
+            let (name, value) = match line.split_once('=') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => continue,
+            };
+            if name.eq_ignore_ascii_case("Signal") {
+                // some UAs send 10 and 11 for * and #
+                digit = match value {
+                    "10" => Some('*'),
+                    "11" => Some('#'),
+                    _ => value.chars().next(),
+                };
+            } else if name.eq_ignore_ascii_case("Duration") {
+                duration = Duration::from_millis(value.parse().unwrap_or_default());
+            }
+        }
+        let digit = digit.ok_or(Error::Error("missing dtmf signal".to_string()))?;
+        DtmfEvent::new(digit, duration)
+    }
+}
@@ -0,0 +1,81 @@
+use std::sync::Arc;
+
+/// A SIP event package (RFC 6665 7) served over SUBSCRIBE/NOTIFY.
+///
+/// Registering a package with the `DialogLayer` provides the defaults used by
+/// subscriptions of that event, and how NOTIFY bodies are merged when they
+/// are coalesced by the throttling of the notifier.
+pub trait EventPackage: Send + Sync {
+    /// Event header value, e.g. `presence` or `dialog`
+    fn name(&self) -> &str;
+
+    /// Expiry used when the SUBSCRIBE doesn't carry an Expires header
+    fn default_expires(&self) -> u32 {
+        3600
+    }
+
+    /// Content-Type of the NOTIFY bodies
+    fn content_type(&self) -> Option<&str> {
+        None
+    }
+
+    /// Merges `update` into a NOTIFY body still waiting to be sent,
+    /// the latest state wins by default
+    fn merge(&self, _pending: &[u8], update: Vec<u8>) -> Vec<u8> {
+        update
+    }
+}
+
+pub type EventPackageRef = Arc<dyn EventPackage>;
+
+/// Event package with a fixed name, expiry and content type
+#[derive(Clone, Debug)]
+pub struct SimpleEventPackage {
+    pub name: String,
+    pub default_expires: u32,
+    pub content_type: Option<String>,
+}
+
+impl SimpleEventPackage {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            default_expires: 3600,
+            content_type: None,
+        }
+    }
+
+    pub fn with_expires(mut self, expires: u32) -> Self {
+        self.default_expires = expires;
+        self
+    }
+
+    pub fn with_content_type(mut self, content_type: &str) -> Self {
+        self.content_type = Some(content_type.to_string());
+        self
+    }
+}
+
+impl EventPackage for SimpleEventPackage {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn default_expires(&self) -> u32 {
+        self.default_expires
+    }
+
+    fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+}
+
+/// Returns the package name of an Event header value, without its parameters
+pub fn event_name(event: &str) -> String {
+    event
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase()
+}
@@ -0,0 +1,92 @@
+use super::{
+    dialog::{DialogInner, DialogState},
+    reason::Reason,
+};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    select,
+    time::{sleep_until, Instant},
+};
+use tracing::{info, warn};
+
+/// Limits on how long a dialog may live, see `start_expiration`
+#[derive(Clone, Debug, Default)]
+pub struct DialogExpiration {
+    /// Terminates the dialog this long after the timer is started
+    pub max_lifetime: Option<Duration>,
+    /// Terminates the dialog when no request or response of it was
+    /// received for this long
+    pub inactivity: Option<Duration>,
+}
+
+impl DialogInner {
+    /// Records that a message of the dialog was received
+    pub(super) fn touch(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+
+    /// Starts a task terminating the dialog once `expiration` is reached: a
+    /// confirmed dialog is hung up with BYE, others just end
+    pub(super) fn start_expiration(self: &Arc<Self>, expiration: DialogExpiration) {
+        if expiration.max_lifetime.is_none() && expiration.inactivity.is_none() {
+            return;
+        }
+        let inner = self.clone();
+        let started = Instant::now();
+        tokio::spawn(async move {
+            loop {
+                let lifetime_deadline = expiration.max_lifetime.map(|d| started + d);
+                let inactivity_deadline = expiration
+                    .inactivity
+                    .map(|d| *inner.last_activity.lock().unwrap() + d);
+                let deadline = match (lifetime_deadline, inactivity_deadline) {
+                    (Some(a), Some(b)) => a.min(b),
+                    (Some(a), None) | (None, Some(a)) => a,
+                    (None, None) => return,
+                };
+                select! {
+                    _ = inner.cancel_token.cancelled() => return,
+                    _ = sleep_until(deadline) => {}
+                }
+                if matches!(
+                    *inner.state.lock().unwrap(),
+                    DialogState::Terminated(_, _, _)
+                ) {
+                    return;
+                }
+                // activity moved the inactivity deadline meanwhile
+                let now = Instant::now();
+                let expired = lifetime_deadline.map(|d| d <= now).unwrap_or(false)
+                    || expiration
+                        .inactivity
+                        .map(|d| *inner.last_activity.lock().unwrap() + d <= now)
+                        .unwrap_or(false);
+                if expired {
+                    inner.expire().await;
+                    return;
+                }
+            }
+        });
+    }
+
+    async fn expire(&self) {
+        let id = self.id.lock().unwrap().clone();
+        info!("dialog expired: {}", id);
+        if self.is_confirmed() {
+            if let Err(e) = self
+                .bye_on_error(Reason::sip(408).with_text("Dialog Expired"))
+                .await
+            {
+                warn!("failed to hang up expired dialog: {} {:?}", id, e);
+            }
+        } else {
+            self.transition(DialogState::Terminated(
+                id,
+                Some(rsip::StatusCode::RequestTimeout),
+                None,
+            ))
+            .ok();
+        }
+        self.cancel_token.cancel();
+    }
+}
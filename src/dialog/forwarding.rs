@@ -0,0 +1,107 @@
+use crate::{rsip_ext::header_values, transaction::transaction::Transaction, Result};
+use rsip::{Header, Request, StatusCode};
+use std::sync::Arc;
+use tracing::info;
+
+pub type ForwardFilter = Arc<dyn Fn(&Request) -> bool + Send + Sync>;
+
+/// Answers incoming INVITEs with 302 Moved Temporarily to a forwarding target.
+#[derive(Clone)]
+pub struct CallForwarder {
+    pub target: rsip::Uri,
+    /// Reason of the Diversion header (RFC 5806), no Diversion header if `None`
+    pub diversion: Option<String>,
+    /// Appends History-Info entries (RFC 7044)
+    pub history_info: bool,
+    pub filter: Option<ForwardFilter>,
+}
+
+impl CallForwarder {
+    pub fn new(target: rsip::Uri) -> Self {
+        Self {
+            target,
+            diversion: None,
+            history_info: false,
+            filter: None,
+        }
+    }
+
+    pub fn with_diversion(mut self, reason: &str) -> Self {
+        self.diversion = Some(reason.to_string());
+        self
+    }
+
+    pub fn with_history_info(mut self) -> Self {
+        self.history_info = true;
+        self
+    }
+
+    /// Only forwards the INVITEs accepted by `filter`
+    pub fn with_filter(mut self, filter: ForwardFilter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    pub fn matches(&self, request: &Request) -> bool {
+        request.method == rsip::Method::Invite
+            && self.filter.as_ref().map(|f| f(request)).unwrap_or(true)
+    }
+
+    /// Headers of the 302 response for `request`
+    pub fn make_headers(&self, request: &Request) -> Vec<Header> {
+        let mut headers = vec![Header::Contact(
+            rsip::typed::Contact {
+                display_name: None,
+                uri: self.target.clone(),
+                params: vec![],
+            }
+            .into(),
+        )];
+        if let Some(reason) = self.diversion.as_ref() {
+            let counter = header_values(&request.headers, "Diversion").len() + 1;
+            headers.push(Header::Other(
+                "Diversion".into(),
+                format!("<{}>;reason={};counter={}", request.uri, reason, counter),
+            ));
+        }
+        if self.history_info {
+            let entries = header_values(&request.headers, "History-Info")
+                .iter()
+                .flat_map(|v| v.split(',').map(|e| e.trim().to_string()))
+                .collect::<Vec<_>>();
+            let last_index = entries.last().and_then(|e| {
+                e.split(';')
+                    .filter_map(|p| p.trim().strip_prefix("index="))
+                    .next()
+                    .map(|i| i.to_string())
+            });
+            let mut history = vec![];
+            let index = match last_index {
+                Some(index) => index,
+                None => {
+                    history.push(format!("<{}>;index=1", request.uri));
+                    "1".to_string()
+                }
+            };
+            history.push(format!(
+                "<{}?Reason=SIP%3Bcause%3D302>;index={}.1",
+                self.target, index
+            ));
+            headers.push(Header::Other("History-Info".into(), history.join(", ")));
+        }
+        headers
+    }
+
+    /// Replies 302 if the transaction is selected for forwarding,
+    /// returns whether it was forwarded
+    pub async fn forward(&self, tx: &mut Transaction) -> Result<bool> {
+        if !self.matches(&tx.original) {
+            return Ok(false);
+        }
+        info!("forwarding {} to {}", tx.original.uri, self.target);
+        let headers = self.make_headers(&tx.original);
+        tx.reply_with(StatusCode::MovedTemporarily, headers, None)
+            .await?;
+        Ok(true)
+    }
+}
@@ -0,0 +1,148 @@
+use super::dialog::{DialogInner, DialogState};
+use crate::Result;
+use rsip::{Header, Response};
+
+/// Media direction attribute of an SDP media section (RFC 3264 5.1)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MediaDirection {
+    SendRecv,
+    SendOnly,
+    RecvOnly,
+    Inactive,
+}
+
+impl MediaDirection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MediaDirection::SendRecv => "sendrecv",
+            MediaDirection::SendOnly => "sendonly",
+            MediaDirection::RecvOnly => "recvonly",
+            MediaDirection::Inactive => "inactive",
+        }
+    }
+}
+
+impl TryFrom<&str> for MediaDirection {
+    type Error = crate::Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        match value.trim() {
+            "sendrecv" => Ok(MediaDirection::SendRecv),
+            "sendonly" => Ok(MediaDirection::SendOnly),
+            "recvonly" => Ok(MediaDirection::RecvOnly),
+            "inactive" => Ok(MediaDirection::Inactive),
+            _ => Err(crate::Error::Error(format!(
+                "invalid media direction: {}",
+                value
+            ))),
+        }
+    }
+}
+
+/// Whether the local side put the call on hold, see `DialogState::HoldStateChanged`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HoldState {
+    Active,
+    Held,
+}
+
+/// Rewrites `sdp` with `direction` on every media section, replacing the
+/// direction attributes it had, and bumps the version of the `o=` line as a
+/// modified offer must (RFC 3264 8)
+pub fn set_media_direction(sdp: &[u8], direction: MediaDirection) -> Vec<u8> {
+    let sdp = String::from_utf8_lossy(sdp);
+    let attr = format!("a={}", direction.as_str());
+    let mut lines = vec![];
+    let mut in_media = false;
+    for line in sdp.lines().filter(|l| !l.is_empty()) {
+        if line.starts_with("a=") && MediaDirection::try_from(&line[2..]).is_ok() {
+            continue;
+        }
+        if line.starts_with("m=") {
+            if in_media {
+                lines.push(attr.clone());
+            }
+            in_media = true;
+        }
+        match line.strip_prefix("o=") {
+            Some(origin) => lines.push(format!("o={}", bump_version(origin))),
+            None => lines.push(line.to_string()),
+        }
+    }
+    if in_media {
+        lines.push(attr);
+    }
+    let mut sdp = lines.join("\r\n");
+    sdp.push_str("\r\n");
+    sdp.into_bytes()
+}
+
+/// Direction of the first media section of `sdp`, `sendrecv` when absent
+pub fn media_direction(sdp: &[u8]) -> MediaDirection {
+    String::from_utf8_lossy(sdp)
+        .lines()
+        .skip_while(|l| !l.starts_with("m="))
+        .find_map(|l| {
+            l.strip_prefix("a=")
+                .and_then(|a| MediaDirection::try_from(a).ok())
+        })
+        .unwrap_or(MediaDirection::SendRecv)
+}
+
+fn bump_version(origin: &str) -> String {
+    let mut fields = origin.split(' ').map(|f| f.to_string()).collect::<Vec<_>>();
+    if let Some(version) = fields.get_mut(2) {
+        if let Ok(v) = version.parse::<u64>() {
+            *version = (v + 1).to_string();
+        }
+    }
+    fields.join(" ")
+}
+
+impl DialogInner {
+    /// Re-INVITEs the peer with the last local SDP made `sendonly`, or
+    /// back to its direction before the hold to resume, `sendrecv` when
+    /// there was none
+    pub(super) async fn set_hold(&self, hold: bool) -> Result<Option<Response>> {
+        let sdp = match self.local_sdp.lock().unwrap().clone() {
+            Some(sdp) => sdp,
+            None => {
+                return Err(crate::Error::DialogError(
+                    "no local sdp to put on hold".to_string(),
+                    self.id.lock().unwrap().clone(),
+                ))
+            }
+        };
+        let (direction, state) = match hold {
+            true => (MediaDirection::SendOnly, HoldState::Held),
+            false => (
+                self.held_direction
+                    .lock()
+                    .unwrap()
+                    .unwrap_or(MediaDirection::SendRecv),
+                HoldState::Active,
+            ),
+        };
+        let body = set_media_direction(&sdp, direction);
+        let headers = vec![Header::ContentType("application/sdp".into())];
+        let resp = self.do_reinvite(Some(headers), Some(body)).await?;
+        if let Some(resp) = resp.as_ref() {
+            if resp.status_code.kind() == rsip::StatusCodeKind::Successful {
+                let mut held_direction = self.held_direction.lock().unwrap();
+                match hold {
+                    // held twice, the SDP is already `sendonly`
+                    true => {
+                        held_direction.get_or_insert(media_direction(&sdp));
+                    }
+                    false => *held_direction = None,
+                }
+                drop(held_direction);
+                self.transition(DialogState::HoldStateChanged(
+                    self.id.lock().unwrap().clone(),
+                    state,
+                ))?;
+            }
+        }
+        Ok(resp)
+    }
+}
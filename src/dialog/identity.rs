@@ -0,0 +1,395 @@
+use crate::{
+    rsip_ext::{extract_uri_from_contact, header_value},
+    Error, Result,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rsip::{
+    prelude::{HeadersExt, ToTypedHeader},
+    Header, Request, StatusCode,
+};
+use serde_json::{json, Value};
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::info;
+
+/// Oldest `iat` of a PASSporT still accepted, and how far in the future
+/// (RFC 8224 6.2.1)
+pub const IDENTITY_MAX_AGE: Duration = Duration::from_secs(60);
+
+/// Attestation level of a SHAKEN PASSporT (ATIS-1000074)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Attestation {
+    /// Full: the caller is a customer of the signer, entitled to the number
+    A,
+    /// Partial: the caller is a customer, not known to own the number
+    B,
+    /// Gateway: the call entered the network from elsewhere
+    C,
+}
+
+impl Attestation {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "A" => Some(Self::A),
+            "B" => Some(Self::B),
+            "C" => Some(Self::C),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::A => "A",
+            Self::B => "B",
+            Self::C => "C",
+        }
+    }
+}
+
+/// Claims of a SHAKEN PASSporT (RFC 8225, RFC 8588)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Passport {
+    pub attest: Attestation,
+    /// Calling number, normalized
+    pub orig: String,
+    /// Called numbers, normalized
+    pub dest: Vec<String>,
+    /// Seconds since the epoch of the signing
+    pub iat: u64,
+    /// Opaque identifier of the origination point of the call
+    pub origid: String,
+    /// URL of the certificate of the signing key
+    pub x5u: String,
+}
+
+impl Passport {
+    fn header_json(&self) -> Value {
+        json!({
+            "alg": "ES256",
+            "ppt": "shaken",
+            "typ": "passport",
+            "x5u": self.x5u,
+        })
+    }
+
+    fn claims_json(&self) -> Value {
+        json!({
+            "attest": self.attest.as_str(),
+            "dest": { "tn": self.dest },
+            "iat": self.iat,
+            "orig": { "tn": self.orig },
+            "origid": self.origid,
+        })
+    }
+
+    /// Header and claims of a PASSporT in full form, `None` when it isn't
+    /// one of SHAKEN signed with ES256
+    fn from_json(header: &Value, claims: &Value) -> Option<Self> {
+        if header["alg"] != "ES256" || header["ppt"] != "shaken" {
+            return None;
+        }
+        Some(Self {
+            attest: Attestation::parse(claims["attest"].as_str()?)?,
+            orig: claims["orig"]["tn"].as_str()?.to_string(),
+            dest: claims["dest"]["tn"]
+                .as_array()?
+                .iter()
+                .filter_map(|tn| tn.as_str().map(|tn| tn.to_string()))
+                .collect(),
+            iat: claims["iat"].as_u64()?,
+            origid: claims["origid"].as_str().unwrap_or_default().to_string(),
+            x5u: header["x5u"].as_str()?.to_string(),
+        })
+    }
+
+    /// The `header.claims` part of the JWS the signature covers
+    pub fn signing_input(&self) -> String {
+        format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(self.header_json().to_string()),
+            URL_SAFE_NO_PAD.encode(self.claims_json().to_string())
+        )
+    }
+}
+
+/// Certificate and private key of an authentication service (RFC 8224 5)
+pub trait IdentitySigner: Send + Sync {
+    /// URL where the certificate of the key is published
+    fn x5u(&self) -> String;
+    /// ES256 signature of `input`: the raw `r || s`, 64 bytes
+    fn sign(&self, input: &[u8]) -> Result<Vec<u8>>;
+}
+pub type IdentitySignerRef = Arc<dyn IdentitySigner>;
+
+/// Certificates of a verification service (RFC 8224 6)
+#[async_trait::async_trait]
+pub trait IdentityVerifier: Send + Sync {
+    /// Whether `signature`, the raw `r || s` of ES256, of `input` was made
+    /// with the key of the certificate at `x5u`. An error when the
+    /// certificate can't be fetched or doesn't chain to a trusted
+    /// certification authority.
+    async fn verify(&self, x5u: &str, input: &[u8], signature: &[u8]) -> Result<bool>;
+}
+pub type IdentityVerifierRef = Arc<dyn IdentityVerifier>;
+
+/// How the INVITEs sent with `InviteOption::identity` are signed
+#[derive(Clone)]
+pub struct IdentitySigning {
+    pub signer: IdentitySignerRef,
+    pub attest: Attestation,
+    /// Identifier of the origination point, the same for all its calls
+    pub origid: String,
+}
+
+/// Telephone number of a URI (RFC 8224 8.3): its user without visual
+/// separators nor leading `+`, `None` when it isn't a number
+pub fn telephone_number(uri: &rsip::Uri) -> Option<String> {
+    let user = uri.auth.as_ref()?.user.clone();
+    let tn = user
+        .trim_start_matches('+')
+        .chars()
+        .filter(|c| !matches!(c, '-' | '.' | '(' | ')' | ' '))
+        .collect::<String>();
+    (!tn.is_empty() && tn.chars().all(|c| c.is_ascii_digit())).then_some(tn)
+}
+
+/// Calling number of a request: its P-Asserted-Identity, else its From
+fn orig_number(request: &Request) -> Option<String> {
+    let uri = match header_value(&request.headers, "P-Asserted-Identity") {
+        Some(identity) => extract_uri_from_contact(&identity).ok()?,
+        None => request.from_header().ok()?.typed().ok()?.uri,
+    };
+    telephone_number(&uri)
+}
+
+/// Called number of a request: its To
+fn dest_number(request: &Request) -> Option<String> {
+    telephone_number(&request.to_header().ok()?.typed().ok()?.uri)
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Signs `request` with a PASSporT of the numbers of its P-Asserted-Identity
+/// or From and its To, adding the Identity header (RFC 8224 4)
+pub fn sign_request(request: &mut Request, signing: &IdentitySigning) -> Result<()> {
+    let (orig, dest) = match (orig_number(request), dest_number(request)) {
+        (Some(orig), Some(dest)) => (orig, dest),
+        _ => {
+            return Err(Error::Error(
+                "identity needs telephone numbers in From and To".to_string(),
+            ))
+        }
+    };
+    let passport = Passport {
+        attest: signing.attest,
+        orig,
+        dest: vec![dest],
+        iat: unix_time(SystemTime::now()),
+        origid: signing.origid.clone(),
+        x5u: signing.signer.x5u(),
+    };
+    let input = passport.signing_input();
+    let signature = signing.signer.sign(input.as_bytes())?;
+    request.headers.push(Header::Other(
+        "Identity".into(),
+        format!(
+            "{}.{};info=<{}>;alg=ES256;ppt=shaken",
+            input,
+            URL_SAFE_NO_PAD.encode(signature),
+            passport.x5u
+        ),
+    ));
+    Ok(())
+}
+
+/// Outcome of the verification of the Identity of a request
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IdentityStatus {
+    Valid,
+    /// No Identity header
+    Missing,
+    /// Not a PASSporT in full form, or without the `info` of its
+    /// certificate
+    Malformed,
+    /// Signed with another algorithm than ES256
+    UnsupportedAlgorithm,
+    /// A PASSporT of another type than SHAKEN
+    UnsupportedType,
+    /// `iat` too far from now, see `IDENTITY_MAX_AGE`
+    Stale,
+    /// The numbers signed aren't those of the request
+    NumberMismatch,
+    /// The signature isn't the one of the certificate
+    BadSignature,
+    /// The certificate couldn't be fetched, isn't trusted or isn't the one
+    /// of `info`
+    BadCertificate,
+}
+
+impl IdentityStatus {
+    pub fn is_valid(&self) -> bool {
+        *self == Self::Valid
+    }
+
+    /// Rejection of the request when the identity must be valid (RFC 8224
+    /// 6.2.2), `None` when it is
+    pub fn status_code(&self) -> Option<StatusCode> {
+        let code = match self {
+            Self::Valid => return None,
+            Self::Missing => 428,
+            Self::Malformed | Self::UnsupportedType | Self::NumberMismatch | Self::BadSignature => {
+                438
+            }
+            Self::UnsupportedAlgorithm => 437,
+            Self::Stale => 403,
+            Self::BadCertificate => 436,
+        };
+        Some(StatusCode::from(code))
+    }
+}
+
+/// What the verification service learnt of the identity of a request
+#[derive(Clone, Debug)]
+pub struct IdentityVerification {
+    pub status: IdentityStatus,
+    /// The PASSporT, whenever it could be parsed
+    pub passport: Option<Passport>,
+}
+
+impl IdentityVerification {
+    fn new(status: IdentityStatus, passport: Option<Passport>) -> Self {
+        Self { status, passport }
+    }
+
+    /// Attestation of a valid identity
+    pub fn attestation(&self) -> Option<Attestation> {
+        if !self.status.is_valid() {
+            return None;
+        }
+        self.passport.as_ref().map(|passport| passport.attest)
+    }
+}
+
+fn decode_json(value: &str) -> Option<Value> {
+    serde_json::from_slice(&URL_SAFE_NO_PAD.decode(value).ok()?).ok()
+}
+
+/// The JWS of an Identity header value and its parameters, the `info` URI
+/// kept whole when it has parameters of its own
+fn split_identity(value: &str) -> (&str, Vec<(&str, &str)>) {
+    let mut parts = vec![];
+    let (mut start, mut in_uri) = (0, false);
+    for (i, c) in value.char_indices() {
+        match c {
+            '<' => in_uri = true,
+            '>' => in_uri = false,
+            ';' if !in_uri => {
+                parts.push(value[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(value[start..].trim());
+    let params = parts[1..]
+        .iter()
+        .filter_map(|p| p.split_once('='))
+        .map(|(name, value)| (name.trim(), value.trim()))
+        .collect();
+    (parts[0], params)
+}
+
+/// Verifies the Identity of `request` at `now` (RFC 8224 6.2): the
+/// PASSporT must be a SHAKEN one, fresh, sign the numbers of the request
+/// and carry the signature of the certificate its `info` points at
+pub async fn verify_request_at(
+    request: &Request,
+    verifier: &IdentityVerifierRef,
+    now: SystemTime,
+) -> IdentityVerification {
+    let identity = match header_value(&request.headers, "Identity") {
+        Some(identity) => identity,
+        None => return IdentityVerification::new(IdentityStatus::Missing, None),
+    };
+    let (jws, params) = split_identity(&identity);
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| *value)
+    };
+    if param("alg").is_some_and(|alg| alg != "ES256") {
+        return IdentityVerification::new(IdentityStatus::UnsupportedAlgorithm, None);
+    }
+    if param("ppt").is_some_and(|ppt| ppt.trim_matches('"') != "shaken") {
+        return IdentityVerification::new(IdentityStatus::UnsupportedType, None);
+    }
+    let info = match param("info")
+        .and_then(|info| info.strip_prefix('<'))
+        .and_then(|info| info.strip_suffix('>'))
+    {
+        Some(info) => info,
+        None => return IdentityVerification::new(IdentityStatus::Malformed, None),
+    };
+    let parts = jws.split('.').collect::<Vec<_>>();
+    let (header, claims, signature) = match (
+        parts.first().and_then(|p| decode_json(p)),
+        parts.get(1).and_then(|p| decode_json(p)),
+        parts.get(2).and_then(|p| URL_SAFE_NO_PAD.decode(p).ok()),
+    ) {
+        (Some(header), Some(claims), Some(signature)) if parts.len() == 3 => {
+            (header, claims, signature)
+        }
+        _ => return IdentityVerification::new(IdentityStatus::Malformed, None),
+    };
+    if header["alg"] != "ES256" {
+        return IdentityVerification::new(IdentityStatus::UnsupportedAlgorithm, None);
+    }
+    if header["ppt"] != "shaken" {
+        return IdentityVerification::new(IdentityStatus::UnsupportedType, None);
+    }
+    let passport = match Passport::from_json(&header, &claims) {
+        Some(passport) => passport,
+        None => return IdentityVerification::new(IdentityStatus::Malformed, None),
+    };
+
+    let age = unix_time(now).abs_diff(passport.iat);
+    let status = if passport.x5u != info {
+        info!("info {} isn't the x5u {} signed", info, passport.x5u);
+        IdentityStatus::BadCertificate
+    } else if age > IDENTITY_MAX_AGE.as_secs() {
+        IdentityStatus::Stale
+    } else if orig_number(request).as_ref() != Some(&passport.orig)
+        || !dest_number(request).is_some_and(|dest| passport.dest.contains(&dest))
+    {
+        IdentityStatus::NumberMismatch
+    } else {
+        let input = format!("{}.{}", parts[0], parts[1]);
+        match verifier
+            .verify(&passport.x5u, input.as_bytes(), &signature)
+            .await
+        {
+            Ok(true) => IdentityStatus::Valid,
+            Ok(false) => IdentityStatus::BadSignature,
+            Err(e) => {
+                info!("certificate {} rejected: {:?}", passport.x5u, e);
+                IdentityStatus::BadCertificate
+            }
+        }
+    };
+    IdentityVerification::new(status, Some(passport))
+}
+
+/// Verifies the Identity of `request` now
+pub async fn verify_request(
+    request: &Request,
+    verifier: &IdentityVerifierRef,
+) -> IdentityVerification {
+    verify_request_at(request, verifier, SystemTime::now()).await
+}
@@ -1,11 +1,13 @@
 use super::{
-    authenticate::Credential,
-    client_dialog::ClientInviteDialog,
+    authenticate::CredentialStoreRef,
+    client_dialog::{ClientInviteDialog, InviteOutcome, ProgressCallback},
     dialog::{DialogInner, DialogStateSender},
     dialog_layer::DialogLayer,
+    identity::{sign_request, IdentitySigning},
 };
 use crate::{
     dialog::{dialog::Dialog, DialogId},
+    rsip_ext::set_route_set,
     transaction::{
         key::{TransactionKey, TransactionRole},
         make_tag,
@@ -14,7 +16,7 @@ use crate::{
     Result,
 };
 use rsip::{Request, Response};
-use std::sync::Arc;
+use std::sync::{atomic::Ordering, Arc};
 use tracing::{debug, info};
 pub struct InviteOption {
     pub caller: rsip::Uri,
@@ -22,8 +24,17 @@ pub struct InviteOption {
     pub content_type: Option<String>,
     pub offer: Option<Vec<u8>>,
     pub contact: rsip::Uri,
-    pub credential: Option<Credential>,
+    pub credential: Option<CredentialStoreRef>,
     pub headers: Option<Vec<rsip::Header>>,
+    /// Overrides the preloaded route set of the endpoint, `Some(vec![])`
+    /// sends the INVITE straight to the callee
+    pub route_set: Option<Vec<rsip::Uri>>,
+    /// Follows up to this many 3xx responses to their preferred Contact,
+    /// `None` ends the call attempt on a redirect
+    pub max_redirects: Option<u32>,
+    /// Signs the INVITE with a PASSporT of its calling and called numbers
+    /// (RFC 8224)
+    pub identity: Option<IdentitySigning>,
 }
 
 impl DialogLayer {
@@ -64,6 +75,9 @@ impl DialogLayer {
                 .unwrap_or("application/sdp".to_string())
                 .into(),
         ));
+        if let Some(route_set) = opt.route_set.as_ref() {
+            set_route_set(&mut request, route_set);
+        }
         // can override default headers
         if let Some(headers) = opt.headers.as_ref() {
             for header in headers {
@@ -73,16 +87,25 @@ impl DialogLayer {
         Ok(request)
     }
 
-    pub async fn do_invite(
+    pub(super) async fn create_client_invite(
         &self,
         opt: InviteOption,
         state_sender: DialogStateSender,
-    ) -> Result<(ClientInviteDialog, Option<Response>)> {
+    ) -> Result<(ClientInviteDialog, Transaction)> {
         let mut request = self.make_invite_request(&opt)?;
+        let max_redirects = opt.max_redirects.unwrap_or(0);
         request.body = opt.offer.unwrap_or_default();
         request.headers.unique_push(rsip::Header::ContentLength(
             (request.body.len() as u32).into(),
         ));
+        if let Some(identity) = opt.identity.as_ref() {
+            sign_request(&mut request, identity)?;
+        }
+        if let Some(cred) = &opt.credential {
+            self.endpoint
+                .auth_cache
+                .authorize_request(&mut request, cred);
+        }
 
         let id = DialogId::try_from(&request)?;
         let dlg_inner = DialogInner::new(
@@ -94,6 +117,9 @@ impl DialogLayer {
             opt.credential,
             Some(opt.contact),
         )?;
+        dlg_inner
+            .max_redirects
+            .store(max_redirects, Ordering::Relaxed);
 
         let key =
             TransactionKey::from_request(&dlg_inner.initial_request, TransactionRole::Client)?;
@@ -103,12 +129,20 @@ impl DialogLayer {
             inner: Arc::new(dlg_inner),
         };
         self.inner
-            .dialogs
-            .write()
-            .unwrap()
-            .insert(id.clone(), Dialog::ClientInvite(dialog.clone()));
+            .insert_dialog(id.clone(), Dialog::ClientInvite(dialog.clone()))
+            .await;
 
         info!("client invite dialog created: {:?}", id);
+        Ok((dialog, tx))
+    }
+
+    pub async fn do_invite(
+        &self,
+        opt: InviteOption,
+        state_sender: DialogStateSender,
+    ) -> Result<(ClientInviteDialog, Option<Response>)> {
+        let (dialog, tx) = self.create_client_invite(opt, state_sender).await?;
+        let id = dialog.id();
 
         match dialog.process_invite(tx).await {
             Ok((new_dialog_id, resp)) => {
@@ -116,19 +150,47 @@ impl DialogLayer {
                     "client invite dialog confirmed: {} => {}",
                     id, new_dialog_id
                 );
-                self.inner.dialogs.write().unwrap().remove(&id);
+                self.inner.remove_dialog(&id).await;
                 // update with new dialog id
                 self.inner
-                    .dialogs
-                    .write()
-                    .unwrap()
-                    .insert(new_dialog_id, Dialog::ClientInvite(dialog.clone()));
+                    .insert_dialog(new_dialog_id, Dialog::ClientInvite(dialog.clone()))
+                    .await;
                 return Ok((dialog, resp));
             }
             Err(e) => {
-                self.inner.dialogs.write().unwrap().remove(&id);
+                self.inner.remove_dialog(&id).await;
+                // moved there by the remote tag of the final response
+                if dialog.id() != id {
+                    self.inner.remove_dialog(&dialog.id()).await;
+                }
                 return Err(e);
             }
         }
     }
+
+    /// Like `do_invite`, but resolves with the typed outcome of the call
+    /// attempt instead of failing on a non-2xx final response
+    pub async fn do_invite_outcome(
+        &self,
+        opt: InviteOption,
+        state_sender: DialogStateSender,
+        on_progress: Option<ProgressCallback>,
+    ) -> Result<(ClientInviteDialog, InviteOutcome)> {
+        let (dialog, tx) = self.create_client_invite(opt, state_sender).await?;
+        let id = dialog.id();
+
+        let result = dialog.wait_for_answer(tx, on_progress).await;
+        self.inner.remove_dialog(&id).await;
+        // moved there by the remote tag of the final response
+        if dialog.id() != id {
+            self.inner.remove_dialog(&dialog.id()).await;
+        }
+        let (new_dialog_id, outcome) = result?;
+        if let InviteOutcome::Answered { .. } = outcome {
+            self.inner
+                .insert_dialog(new_dialog_id, Dialog::ClientInvite(dialog.clone()))
+                .await;
+        }
+        Ok((dialog, outcome))
+    }
 }
@@ -0,0 +1,101 @@
+use super::{
+    dialog::{DialogInner, DialogState},
+    reason::Reason,
+};
+use std::{sync::Arc, time::Duration};
+use tokio::{select, time::sleep};
+use tracing::{info, warn};
+
+/// Request pinging the peer of a confirmed dialog
+#[derive(Clone, Debug, PartialEq)]
+pub enum KeepaliveMethod {
+    Options,
+    Update,
+}
+
+/// Periodic in-dialog ping, see `start_keepalive`
+#[derive(Clone, Debug)]
+pub struct DialogKeepalive {
+    pub interval: Duration,
+    pub method: KeepaliveMethod,
+    /// Consecutive failed pings after which the dialog is terminated
+    pub max_failures: u32,
+}
+
+impl Default for DialogKeepalive {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            method: KeepaliveMethod::Options,
+            max_failures: 3,
+        }
+    }
+}
+
+/// A ping fails without response, on timeout, or when the peer no longer
+/// knows the dialog (RFC 5057 5.1); any other response proves it alive
+pub fn is_keepalive_failure(resp: Option<&rsip::Response>) -> bool {
+    match resp {
+        None => true,
+        Some(resp) => matches!(
+            resp.status_code,
+            rsip::StatusCode::RequestTimeout | rsip::StatusCode::CallTransactionDoesNotExist
+        ),
+    }
+}
+
+impl DialogInner {
+    /// Pings the peer every `keepalive.interval` while the dialog is confirmed,
+    /// the dialog is terminated once `max_failures` pings in a row fail
+    pub(super) fn start_keepalive(self: &Arc<Self>, keepalive: DialogKeepalive) {
+        let inner = self.clone();
+        tokio::spawn(async move {
+            let mut failures = 0;
+            loop {
+                select! {
+                    _ = inner.cancel_token.cancelled() => return,
+                    _ = sleep(keepalive.interval) => {}
+                }
+                match *inner.state.lock().unwrap() {
+                    DialogState::Terminated(_, _, _) => return,
+                    DialogState::Confirmed(_) => {}
+                    _ => continue,
+                }
+                let method = match keepalive.method {
+                    KeepaliveMethod::Options => rsip::Method::Options,
+                    KeepaliveMethod::Update => rsip::Method::Update,
+                };
+                let resp = match inner.make_request(method, None, None, None, None, None) {
+                    Ok(request) => inner.do_request(request).await,
+                    Err(e) => Err(e),
+                };
+                let failed = match resp {
+                    Ok(resp) => is_keepalive_failure(resp.as_ref()),
+                    Err(e) => {
+                        warn!("keepalive failed: {:?}", e);
+                        true
+                    }
+                };
+                if !failed {
+                    failures = 0;
+                    continue;
+                }
+                failures += 1;
+                if failures < keepalive.max_failures {
+                    continue;
+                }
+                let id = inner.id.lock().unwrap().clone();
+                info!("dialog {} unreachable after {} keepalives", id, failures);
+                inner
+                    .transition(DialogState::Terminated(
+                        id,
+                        Some(rsip::StatusCode::RequestTimeout),
+                        Some(Reason::sip(408).with_text("Keepalive Failed")),
+                    ))
+                    .ok();
+                inner.cancel_token.cancel();
+                return;
+            }
+        });
+    }
+}
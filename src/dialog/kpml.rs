@@ -0,0 +1,183 @@
+use super::{
+    dialog::DialogState,
+    dialog_info::xml_attr,
+    dialog_layer::DialogLayer,
+    presence::xml_escape,
+    server_dialog::ServerInviteDialog,
+    subscription::{ClientSubscriptionDialog, SubscribeOption},
+};
+use crate::{Error, Result};
+use rsip::{prelude::ToTypedHeader, Request, Response};
+use tokio::sync::mpsc::unbounded_channel;
+use tracing::{debug, info};
+
+pub const KPML_EVENT: &str = "kpml";
+pub const KPML_REQUEST_CONTENT_TYPE: &str = "application/kpml-request+xml";
+pub const KPML_RESPONSE_CONTENT_TYPE: &str = "application/kpml-response+xml";
+
+/// How long a KPML subscription keeps reporting (RFC 4730 5.2)
+#[derive(Clone, Debug, PartialEq)]
+pub enum KpmlPersist {
+    /// The subscription terminates after the first report
+    OneShot,
+    /// Every matching digit string is reported
+    Persist,
+    /// Every match is reported, but NOTIFYs are only sent once
+    SingleNotify,
+}
+
+impl KpmlPersist {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KpmlPersist::OneShot => "one-shot",
+            KpmlPersist::Persist => "persist",
+            KpmlPersist::SingleNotify => "single-notify",
+        }
+    }
+}
+
+/// Body of a KPML SUBSCRIBE, a single pattern to collect
+#[derive(Clone, Debug)]
+pub struct KpmlRequest {
+    /// DRegex of the digits to collect, e.g. `x{4}#`
+    pub regex: String,
+    pub tag: Option<String>,
+    pub persist: KpmlPersist,
+    /// Inter-digit timeout in milliseconds
+    pub interdigit_timer: Option<u32>,
+}
+
+impl KpmlRequest {
+    pub fn new(regex: &str) -> Self {
+        Self {
+            regex: regex.to_string(),
+            tag: None,
+            persist: KpmlPersist::OneShot,
+            interdigit_timer: None,
+        }
+    }
+
+    pub fn to_xml(&self) -> String {
+        let mut pattern = format!("<pattern persist=\"{}\"", self.persist.as_str());
+        if let Some(timer) = self.interdigit_timer {
+            pattern.push_str(&format!(" interdigittimer=\"{}\"", timer));
+        }
+        let regex = match self.tag.as_ref() {
+            Some(tag) => format!(
+                "<regex tag=\"{}\">{}</regex>",
+                xml_escape(tag),
+                xml_escape(&self.regex)
+            ),
+            None => format!("<regex>{}</regex>", xml_escape(&self.regex)),
+        };
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\r\n\
+             <kpml-request xmlns=\"urn:ietf:params:xml:ns:kpml-request\" version=\"1.0\">\r\n\
+             {}>{}</pattern>\r\n\
+             </kpml-request>\r\n",
+            pattern, regex
+        )
+    }
+}
+
+/// Digit report of a KPML NOTIFY (RFC 4730 6.2)
+#[derive(Clone, Debug, PartialEq)]
+pub struct KpmlResponse {
+    /// 200 on a match, 423 on timeout, 487 when the subscription is gone
+    pub code: u16,
+    pub text: String,
+    pub digits: Option<String>,
+    pub tag: Option<String>,
+}
+
+impl KpmlResponse {
+    pub fn is_success(&self) -> bool {
+        self.code == 200
+    }
+}
+
+impl TryFrom<&str> for KpmlResponse {
+    type Error = crate::Error;
+
+    fn try_from(xml: &str) -> Result<Self> {
+        let start = xml
+            .find("<kpml-response")
+            .ok_or(Error::Error("missing kpml-response element".to_string()))?;
+        let element = &xml[start..];
+        let element = &element[..element.find('>').unwrap_or(element.len())];
+        let code = xml_attr(element, "code")
+            .and_then(|c| c.parse().ok())
+            .ok_or(Error::Error("invalid kpml-response code".to_string()))?;
+        Ok(KpmlResponse {
+            code,
+            text: xml_attr(element, "text").unwrap_or_default(),
+            digits: xml_attr(element, "digits"),
+            tag: xml_attr(element, "tag"),
+        })
+    }
+}
+
+impl TryFrom<&Request> for KpmlResponse {
+    type Error = crate::Error;
+
+    fn try_from(request: &Request) -> Result<Self> {
+        let body = std::str::from_utf8(&request.body)
+            .map_err(|e| Error::Error(format!("invalid kpml-response body: {}", e)))?;
+        KpmlResponse::try_from(body)
+    }
+}
+
+impl DialogLayer {
+    /// Subscribes to the digits the remote party of `dialog` enters.
+    ///
+    /// The SUBSCRIBE identifies the invite dialog in its Event parameters and
+    /// each digit report is sent to the state channel of `dialog` as
+    /// `DialogState::Kpml`.
+    pub async fn subscribe_kpml(
+        &self,
+        dialog: &ServerInviteDialog,
+        request: &KpmlRequest,
+        expires: u32,
+    ) -> Result<(ClientSubscriptionDialog, Option<Response>)> {
+        let inner = dialog.inner.clone();
+        let id = dialog.id();
+        let subscriber = rsip::headers::From::from(inner.from.clone()).typed()?.uri;
+        let opt = SubscribeOption {
+            contact: inner
+                .local_contact
+                .clone()
+                .unwrap_or_else(|| subscriber.clone()),
+            subscriber,
+            target: inner.remote_uri.lock().unwrap().clone(),
+            event: format!(
+                "{};call-id={};from-tag={};to-tag={}",
+                KPML_EVENT, id.call_id, id.from_tag, id.to_tag
+            ),
+            accept: Some(KPML_RESPONSE_CONTENT_TYPE.to_string()),
+            expires,
+            credential: inner.credential.clone(),
+            headers: None,
+            content_type: Some(KPML_REQUEST_CONTENT_TYPE.to_string()),
+            body: Some(request.to_xml().into_bytes()),
+            route_set: None,
+        };
+
+        let (state_sender, mut state_receiver) = unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(state) = state_receiver.recv().await {
+                match state {
+                    DialogState::Notify(_, notify) => match KpmlResponse::try_from(notify.as_ref())
+                    {
+                        Ok(report) => {
+                            info!("kpml report {}: {:?}", id, report.digits);
+                            inner.transition(DialogState::Kpml(id.clone(), report)).ok();
+                        }
+                        Err(e) => info!("invalid kpml notify: {:?}", e),
+                    },
+                    state => debug!("kpml subscription state: {}", state),
+                }
+            }
+        });
+        self.do_subscribe(opt, state_sender).await
+    }
+}
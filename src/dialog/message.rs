@@ -0,0 +1,171 @@
+use super::{
+    authenticate::{handle_client_authenticate, AuthAttempts, CredentialStoreRef},
+    dialog::{next_cseq, DialogInner, DialogState},
+};
+use crate::{
+    transaction::{
+        endpoint::Endpoint,
+        key::{TransactionKey, TransactionRole},
+        make_tag,
+        transaction::Transaction,
+    },
+    Error, Result,
+};
+use rsip::{prelude::HeadersExt, Header, Request, Response, SipMessage, StatusCode};
+use std::sync::{atomic::Ordering, Arc};
+use tracing::info;
+
+impl Endpoint {
+    /// Sends an out-of-dialog MESSAGE (RFC 3428) to `uri` and resolves with
+    /// the final response, digest challenges are answered with `credential`
+    pub async fn send_message(
+        &self,
+        uri: rsip::Uri,
+        content_type: &str,
+        body: Vec<u8>,
+        credential: Option<CredentialStoreRef>,
+    ) -> Result<Response> {
+        let mut request =
+            self.make_out_of_dialog_request(rsip::Method::Message, uri, credential.as_ref())?;
+        request
+            .headers
+            .unique_push(Header::ContentType(content_type.into()));
+        request
+            .headers
+            .unique_push(Header::ContentLength((body.len() as u32).into()));
+        request.body = body;
+        self.send_out_of_dialog(request, credential.as_ref()).await
+    }
+
+    /// Standalone request to `uri`, From the user `credential` has for its
+    /// host, or else the first address of the endpoint. Each one gets the
+    /// next CSeq of the endpoint.
+    pub(super) fn make_out_of_dialog_request(
+        &self,
+        method: rsip::Method,
+        uri: rsip::Uri,
+        credential: Option<&CredentialStoreRef>,
+    ) -> Result<Request> {
+        let destination = uri.host_with_port.to_string();
+        let from_uri = match credential.and_then(|cred| cred.username(&destination)) {
+            Some(user) => rsip::Uri {
+                scheme: Some(rsip::Scheme::Sip),
+                auth: Some(rsip::Auth {
+                    user,
+                    password: None,
+                }),
+                host_with_port: uri.host_with_port.clone(),
+                ..Default::default()
+            },
+            None => self
+                .get_addrs()
+                .first()
+                .cloned()
+                .ok_or(Error::EndpointError("not sipaddrs".to_string()))?
+                .into(),
+        };
+        let from = rsip::typed::From {
+            display_name: None,
+            uri: from_uri,
+            params: vec![],
+        }
+        .with_tag(make_tag());
+        let to = rsip::typed::To {
+            display_name: None,
+            uri: uri.clone(),
+            params: vec![],
+        };
+        let via = self.inner.get_via(None, None)?;
+        let seq = self
+            .inner
+            .last_seq
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |seq| {
+                Some(next_cseq(seq))
+            })
+            .map(next_cseq)
+            .unwrap_or(1);
+        Ok(self.inner.make_request(method, uri, via, from, to, seq))
+    }
+
+    /// Runs the client transaction of `request` to its final response,
+    /// answering one digest challenge with `credential`
+    pub(super) async fn send_out_of_dialog(
+        &self,
+        mut request: Request,
+        credential: Option<&CredentialStoreRef>,
+    ) -> Result<Response> {
+        if let Some(cred) = credential {
+            self.inner.auth_cache.authorize_request(&mut request, cred);
+        }
+        let method = request.method.clone();
+        let mut seq = request.cseq_header()?.seq()?;
+        let key = TransactionKey::from_request(&request, TransactionRole::Client)?;
+        let mut tx = Transaction::new_client(key, request, self.inner.clone(), None);
+        tx.send().await?;
+        let mut auth_attempts = AuthAttempts::default();
+
+        while let Some(msg) = tx.receive().await {
+            let resp = match msg {
+                SipMessage::Response(resp) => resp,
+                _ => break,
+            };
+            match resp.status_code {
+                StatusCode::ProxyAuthenticationRequired | StatusCode::Unauthorized => {
+                    let cred = match credential {
+                        Some(cred) if auth_attempts.may_answer(&resp) => cred,
+                        _ => {
+                            info!("received {} response for {}", resp.status_code, method);
+                            self.inner.auth_cache.forget(&tx.original);
+                            return Ok(resp);
+                        }
+                    };
+                    seq = next_cseq(seq);
+                    tx = handle_client_authenticate(seq, tx, resp, cred).await?;
+                    tx.send().await?;
+                }
+                _ if resp.status_code.kind() == rsip::StatusCodeKind::Provisional => {}
+                _ => {
+                    info!("{} done: {:?}", method, resp.status_code);
+                    return Ok(resp);
+                }
+            }
+        }
+        Err(Error::EndpointError(format!(
+            "{} transaction is already terminated",
+            method
+        )))
+    }
+}
+
+impl DialogInner {
+    /// Sends a MESSAGE within the confirmed dialog (paging-mode IM during a call)
+    pub(super) async fn do_message(
+        &self,
+        content_type: &str,
+        body: Vec<u8>,
+    ) -> Result<Option<Response>> {
+        if !self.is_confirmed() {
+            return Ok(None);
+        }
+        let headers = vec![Header::ContentType(content_type.into())];
+        let request = self.make_request(
+            rsip::Method::Message,
+            None,
+            None,
+            None,
+            Some(headers),
+            Some(body),
+        )?;
+        self.do_request(request).await
+    }
+
+    pub(super) async fn handle_message(&self, mut tx: Transaction) -> Result<()> {
+        info!("received message {}", tx.original.uri);
+        self.transition(DialogState::Message(
+            self.id.lock().unwrap().clone(),
+            Arc::new(tx.original.clone()),
+        ))?;
+        tx.reply(StatusCode::OK).await?;
+        Ok(())
+    }
+}
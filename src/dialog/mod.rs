@@ -4,13 +4,45 @@ use rsip::{
     Request, Response,
 };
 
+pub mod admission;
 pub mod authenticate;
+pub mod b2bua;
+pub mod cdr;
 pub mod client_dialog;
 pub mod dialog;
+pub mod dialog_event;
+pub mod dialog_info;
 pub mod dialog_layer;
+pub mod dialog_store;
+pub mod digest;
+pub mod dtmf;
+pub mod event_package;
+pub mod expiration;
+pub mod forwarding;
+pub mod hold;
+pub mod identity;
 pub mod invitation;
+pub mod keepalive;
+pub mod kpml;
+pub mod message;
+pub mod monitor;
+pub mod mwi;
+pub mod options;
+pub mod outbound;
+pub mod presence;
+pub mod publication;
+pub mod reason;
+pub mod refer;
+pub mod reginfo;
+pub mod registrar;
 pub mod registration;
 pub mod server_dialog;
+pub mod subscription;
+pub mod usage;
+
+#[cfg(test)]
+mod tests;
+
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub struct DialogId {
     pub call_id: String,
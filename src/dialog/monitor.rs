@@ -0,0 +1,236 @@
+use super::{authenticate::CredentialStoreRef, options::Capabilities};
+use crate::transaction::endpoint::{Endpoint, EndpointInnerRef};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{select, sync::broadcast, time::sleep};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// Events kept for a slow subscriber of `events()`, older ones are dropped
+const PEER_EVENT_CAPACITY: usize = 64;
+
+/// Settings of a `PeerMonitor`
+#[derive(Clone)]
+pub struct PeerMonitorOption {
+    /// Time between two pings of a peer
+    pub interval: Duration,
+    /// Consecutive failed pings after which a peer is down
+    pub max_failures: u32,
+    /// Answers the digest challenges of the pinged peers
+    pub credential: Option<CredentialStoreRef>,
+}
+
+impl Default for PeerMonitorOption {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            max_failures: 2,
+            credential: None,
+        }
+    }
+}
+
+/// Reachability change of a monitored peer
+#[derive(Clone, Debug)]
+pub enum PeerEvent {
+    Up { uri: rsip::Uri, rtt: Duration },
+    Down { uri: rsip::Uri, failures: u32 },
+}
+
+/// What the pings of a monitored peer told so far
+#[derive(Clone, Debug)]
+pub struct PeerStatus {
+    pub uri: rsip::Uri,
+    /// `None` until the first ping decided it
+    pub up: Option<bool>,
+    /// Round-trip time of the last answered ping
+    pub rtt: Option<Duration>,
+    /// Pings failed in a row
+    pub failures: u32,
+    pub last_checked: Option<Instant>,
+    /// Answer to the last successful ping
+    pub capabilities: Option<Capabilities>,
+}
+
+impl PeerStatus {
+    pub fn new(uri: rsip::Uri) -> Self {
+        Self {
+            uri,
+            up: None,
+            rtt: None,
+            failures: 0,
+            last_checked: None,
+            capabilities: None,
+        }
+    }
+
+    /// Whether requests may be routed to the peer: it isn't known down
+    pub fn is_available(&self) -> bool {
+        self.up != Some(false)
+    }
+
+    /// Records the outcome of a ping, its answer and round-trip time when
+    /// the peer is available, returns the event of a reachability change
+    pub fn on_ping(
+        &mut self,
+        answer: Option<(Capabilities, Duration)>,
+        max_failures: u32,
+    ) -> Option<PeerEvent> {
+        self.last_checked = Some(Instant::now());
+        match answer {
+            Some((capabilities, rtt)) => {
+                self.failures = 0;
+                self.rtt = Some(rtt);
+                self.capabilities = Some(capabilities);
+                if self.up == Some(true) {
+                    return None;
+                }
+                self.up = Some(true);
+                Some(PeerEvent::Up {
+                    uri: self.uri.clone(),
+                    rtt,
+                })
+            }
+            None => {
+                self.failures += 1;
+                if self.up == Some(false) || self.failures < max_failures.max(1) {
+                    return None;
+                }
+                self.up = Some(false);
+                Some(PeerEvent::Down {
+                    uri: self.uri.clone(),
+                    failures: self.failures,
+                })
+            }
+        }
+    }
+}
+
+/// Periodically pings a set of peers, e.g. trunks, with out-of-dialog
+/// OPTIONS, tracking their reachability and round-trip time for the routing
+/// decisions. A peer goes down after `max_failures` pings in a row got no
+/// answer, a timeout or a 503, and up again on the first answered one; each
+/// change is broadcast to the subscribers of `events()`.
+pub struct PeerMonitor {
+    endpoint: EndpointInnerRef,
+    option: PeerMonitorOption,
+    peers: Mutex<Vec<PeerStatus>>,
+    event_sender: broadcast::Sender<PeerEvent>,
+    cancel_token: CancellationToken,
+}
+
+impl PeerMonitor {
+    pub fn new(endpoint: EndpointInnerRef, option: PeerMonitorOption) -> Self {
+        Self {
+            endpoint,
+            option,
+            peers: Mutex::new(vec![]),
+            event_sender: broadcast::channel(PEER_EVENT_CAPACITY).0,
+            cancel_token: CancellationToken::new(),
+        }
+    }
+
+    pub fn add_peer(&self, uri: rsip::Uri) {
+        let mut peers = self.peers.lock().unwrap();
+        if !peers.iter().any(|peer| peer.uri == uri) {
+            peers.push(PeerStatus::new(uri));
+        }
+    }
+
+    pub fn remove_peer(&self, uri: &rsip::Uri) {
+        self.peers.lock().unwrap().retain(|peer| &peer.uri != uri);
+    }
+
+    pub fn status(&self, uri: &rsip::Uri) -> Option<PeerStatus> {
+        self.peers
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|peer| &peer.uri == uri)
+            .cloned()
+    }
+
+    pub fn statuses(&self) -> Vec<PeerStatus> {
+        self.peers.lock().unwrap().clone()
+    }
+
+    /// Peers not known down, in the order they were added
+    pub fn available_peers(&self) -> Vec<rsip::Uri> {
+        self.peers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|peer| peer.is_available())
+            .map(|peer| peer.uri.clone())
+            .collect()
+    }
+
+    /// Reachability changes sent after the subscription
+    pub fn events(&self) -> broadcast::Receiver<PeerEvent> {
+        self.event_sender.subscribe()
+    }
+
+    /// Pings `uri` once and records the outcome, returns its status
+    pub async fn ping(&self, uri: &rsip::Uri) -> Option<PeerStatus> {
+        let endpoint = Endpoint {
+            inner: self.endpoint.clone(),
+        };
+        let started = Instant::now();
+        let answer = match endpoint
+            .options(uri.clone(), self.option.credential.clone())
+            .await
+        {
+            Ok(capabilities) if capabilities.is_available() => {
+                Some((capabilities, started.elapsed()))
+            }
+            Ok(capabilities) => {
+                info!("peer {} unavailable: {}", uri, capabilities.status_code);
+                None
+            }
+            Err(e) => {
+                warn!("failed to ping peer {}: {:?}", uri, e);
+                None
+            }
+        };
+        let (status, event) = {
+            let mut peers = self.peers.lock().unwrap();
+            let peer = peers.iter_mut().find(|peer| &peer.uri == uri)?;
+            let event = peer.on_ping(answer, self.option.max_failures);
+            (peer.clone(), event)
+        };
+        if let Some(event) = event {
+            info!("peer status changed: {:?}", event);
+            self.event_sender.send(event).ok();
+        }
+        Some(status)
+    }
+
+    /// Pings every peer each `interval` until `stop()`
+    pub fn start(self: &Arc<Self>) {
+        let monitor = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let uris = monitor
+                    .statuses()
+                    .into_iter()
+                    .map(|peer| peer.uri)
+                    .collect::<Vec<_>>();
+                let pings = futures::future::join_all(uris.iter().map(|uri| monitor.ping(uri)));
+                select! {
+                    _ = monitor.cancel_token.cancelled() => return,
+                    _ = pings => {}
+                }
+                select! {
+                    _ = monitor.cancel_token.cancelled() => return,
+                    _ = sleep(monitor.option.interval) => {}
+                }
+            }
+        });
+    }
+
+    pub fn stop(&self) {
+        self.cancel_token.cancel();
+    }
+}
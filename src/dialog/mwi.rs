@@ -0,0 +1,175 @@
+use super::{
+    dialog::DialogStateSender,
+    dialog_layer::DialogLayer,
+    event_package::SimpleEventPackage,
+    subscription::{
+        ClientSubscriptionDialog, ServerSubscriptionDialog, SubscribeOption, SubscriptionState,
+    },
+};
+use crate::{transaction::transaction::Transaction, Error, Result};
+use rsip::{Request, Response};
+use std::sync::Arc;
+
+pub const MESSAGE_SUMMARY_EVENT: &str = "message-summary";
+pub const MESSAGE_SUMMARY_CONTENT_TYPE: &str = "application/simple-message-summary";
+
+/// Message counts of a message context class, `new/old (urgent_new/urgent_old)`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MessageCounts {
+    pub new: u32,
+    pub old: u32,
+    pub urgent_new: u32,
+    pub urgent_old: u32,
+}
+
+/// Body of a message-summary NOTIFY (RFC 3842 5.2)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MessageSummary {
+    pub messages_waiting: bool,
+    pub account: Option<String>,
+    /// Counts per message context class, e.g. `Voice-Message`
+    pub messages: Vec<(String, MessageCounts)>,
+}
+
+impl MessageCounts {
+    fn parse(value: &str) -> Option<Self> {
+        let (counts, urgent) = match value.split_once('(') {
+            Some((counts, urgent)) => (counts, Some(urgent.trim_end_matches(')'))),
+            None => (value, None),
+        };
+        let pair = |v: &str| -> Option<(u32, u32)> {
+            let (a, b) = v.trim().split_once('/')?;
+            Some((a.trim().parse().ok()?, b.trim().parse().ok()?))
+        };
+        let (new, old) = pair(counts)?;
+        let (urgent_new, urgent_old) = match urgent {
+            Some(urgent) => pair(urgent)?,
+            None => (0, 0),
+        };
+        Some(MessageCounts {
+            new,
+            old,
+            urgent_new,
+            urgent_old,
+        })
+    }
+}
+
+impl MessageSummary {
+    pub fn voice(&self) -> Option<&MessageCounts> {
+        self.messages
+            .iter()
+            .find(|(class, _)| class.eq_ignore_ascii_case("Voice-Message"))
+            .map(|(_, counts)| counts)
+    }
+
+    pub fn to_body(&self) -> String {
+        let mut body = format!(
+            "Messages-Waiting: {}\r\n",
+            if self.messages_waiting { "yes" } else { "no" }
+        );
+        if let Some(account) = self.account.as_ref() {
+            body.push_str(&format!("Message-Account: {}\r\n", account));
+        }
+        for (class, counts) in &self.messages {
+            body.push_str(&format!(
+                "{}: {}/{} ({}/{})\r\n",
+                class, counts.new, counts.old, counts.urgent_new, counts.urgent_old
+            ));
+        }
+        body
+    }
+}
+
+impl TryFrom<&str> for MessageSummary {
+    type Error = crate::Error;
+
+    fn try_from(body: &str) -> Result<Self> {
+        let mut summary = MessageSummary::default();
+        let mut waiting = None;
+        for line in body.lines() {
+            let (name, value) = match line.split_once(':') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => continue,
+            };
+            if name.eq_ignore_ascii_case("Messages-Waiting") {
+                waiting = Some(value.eq_ignore_ascii_case("yes"));
+            } else if name.eq_ignore_ascii_case("Message-Account") {
+                summary.account = Some(value.to_string());
+            } else if let Some(counts) = MessageCounts::parse(value) {
+                summary.messages.push((name.to_string(), counts));
+            }
+        }
+        summary.messages_waiting = waiting.ok_or(Error::Error(
+            "missing Messages-Waiting in message summary".to_string(),
+        ))?;
+        Ok(summary)
+    }
+}
+
+impl TryFrom<&Request> for MessageSummary {
+    type Error = crate::Error;
+
+    /// Parses the body of a message-summary NOTIFY
+    fn try_from(request: &Request) -> Result<Self> {
+        MessageSummary::try_from(String::from_utf8_lossy(&request.body).as_ref())
+    }
+}
+
+impl DialogLayer {
+    fn register_message_summary(&self) {
+        if self.get_event_package(MESSAGE_SUMMARY_EVENT).is_none() {
+            self.register_event_package(Arc::new(
+                SimpleEventPackage::new(MESSAGE_SUMMARY_EVENT)
+                    .with_content_type(MESSAGE_SUMMARY_CONTENT_TYPE),
+            ));
+        }
+    }
+
+    /// Subscribes to the message-summary of a voicemail server, the
+    /// NOTIFYs reported as `DialogState::Notify` are parsed with
+    /// `MessageSummary::try_from(&request)`
+    pub async fn subscribe_mwi(
+        &self,
+        mut opt: SubscribeOption,
+        state_sender: DialogStateSender,
+    ) -> Result<(ClientSubscriptionDialog, Option<Response>)> {
+        self.register_message_summary();
+        opt.event = MESSAGE_SUMMARY_EVENT.to_string();
+        opt.accept = Some(MESSAGE_SUMMARY_CONTENT_TYPE.to_string());
+        self.do_subscribe(opt, state_sender).await
+    }
+
+    /// Accepts a message-summary SUBSCRIBE and notifies `summary` right away,
+    /// later changes are sent with `notify_mwi`
+    pub async fn accept_mwi(
+        &self,
+        tx: Transaction,
+        state_sender: DialogStateSender,
+        contact: Option<rsip::Uri>,
+        summary: &MessageSummary,
+    ) -> Result<ServerSubscriptionDialog> {
+        self.register_message_summary();
+        let mut dialog = self
+            .get_or_create_server_subscription(&tx, state_sender, None, contact)
+            .await?;
+        dialog.handle(tx).await?;
+        if dialog.expires() > 0 {
+            notify_mwi(&dialog, summary).await?;
+        }
+        Ok(dialog)
+    }
+}
+
+/// Sends the message summary to a subscriber
+pub async fn notify_mwi(dialog: &ServerSubscriptionDialog, summary: &MessageSummary) -> Result<()> {
+    dialog
+        .notify(
+            SubscriptionState::Active {
+                expires: Some(dialog.expires()),
+            },
+            None,
+            Some(summary.to_body().into_bytes()),
+        )
+        .await
+}
@@ -0,0 +1,90 @@
+use super::authenticate::CredentialStoreRef;
+use crate::{
+    rsip_ext::{header_value, header_values},
+    transaction::endpoint::Endpoint,
+    Result,
+};
+use rsip::{Header, Response, StatusCode};
+
+/// What a peer answered to an OPTIONS ping (RFC 3261 11.2)
+#[derive(Clone, Debug)]
+pub struct Capabilities {
+    pub status_code: StatusCode,
+    /// Methods of the Allow header, uppercased
+    pub allow: Vec<String>,
+    /// Media types of the Accept header
+    pub accept: Vec<String>,
+    /// Option tags of the Supported header
+    pub supported: Vec<String>,
+    pub server: Option<String>,
+    pub response: Response,
+}
+
+fn list_values(headers: &rsip::Headers, name: &str) -> Vec<String> {
+    header_values(headers, name)
+        .iter()
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+impl From<Response> for Capabilities {
+    fn from(response: Response) -> Self {
+        let headers = &response.headers;
+        Capabilities {
+            status_code: response.status_code.clone(),
+            allow: list_values(headers, "Allow")
+                .into_iter()
+                .map(|m| m.to_uppercase())
+                .collect(),
+            accept: list_values(headers, "Accept"),
+            supported: list_values(headers, "Supported"),
+            server: header_value(headers, "Server"),
+            response,
+        }
+    }
+}
+
+impl Capabilities {
+    /// Whether the peer is up: any response proves it reachable, except a
+    /// timeout or a 503, which is also what a transport failure yields
+    pub fn is_available(&self) -> bool {
+        !matches!(
+            self.status_code,
+            StatusCode::RequestTimeout | StatusCode::ServiceUnavailable
+        )
+    }
+
+    pub fn allows(&self, method: &rsip::Method) -> bool {
+        let method = method.to_string();
+        self.allow.iter().any(|m| m.eq_ignore_ascii_case(&method))
+    }
+}
+
+impl Endpoint {
+    /// Pings `target` with an out-of-dialog OPTIONS, e.g. to check a trunk
+    /// is available, digest challenges are answered with `credential`
+    pub async fn options(
+        &self,
+        target: rsip::Uri,
+        credential: Option<CredentialStoreRef>,
+    ) -> Result<Capabilities> {
+        let mut request =
+            self.make_out_of_dialog_request(rsip::Method::Options, target, credential.as_ref())?;
+        if !request
+            .headers
+            .iter()
+            .any(|h| matches!(h, Header::Accept(_)))
+        {
+            request
+                .headers
+                .push(Header::Accept("application/sdp".into()));
+        }
+        request.headers.unique_push(Header::ContentLength(0.into()));
+        let resp = self
+            .send_out_of_dialog(request, credential.as_ref())
+            .await?;
+        Ok(resp.into())
+    }
+}
@@ -0,0 +1,112 @@
+use super::{authenticate::CredentialStoreRef, registration::Registration};
+use crate::{transaction::endpoint::EndpointInnerRef, Result};
+use rsip::Param;
+use std::time::{Duration, Instant};
+use tokio::{select, time::sleep};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// Client side of SIP Outbound (RFC 5626): the device keeps one registered
+/// flow per outbound proxy, each with its own reg-id, all sharing its
+/// instance id. The flows are pinged with CRLF keepalives, a failed flow is
+/// registered again while the others keep the device reachable.
+pub struct OutboundRegistration {
+    pub server: String,
+    pub flows: Vec<Registration>,
+    pub keepalive_interval: Duration,
+}
+
+impl OutboundRegistration {
+    /// One flow through each of `proxies`, in order of preference, their
+    /// URIs getting the `ob` parameter asking for a flow token
+    pub fn new(
+        endpoint: EndpointInnerRef,
+        credential: Option<CredentialStoreRef>,
+        server: &str,
+        instance: &str,
+        proxies: Vec<rsip::Uri>,
+    ) -> Self {
+        let flows = proxies
+            .into_iter()
+            .enumerate()
+            .map(|(i, mut proxy)| {
+                if !proxy
+                    .params
+                    .iter()
+                    .any(|p| matches!(p, Param::Other(name, _) if name.value() == "ob"))
+                {
+                    proxy.params.push(Param::Other("ob".into(), None));
+                }
+                let mut registration = Registration::new(endpoint.clone(), credential.clone())
+                    .with_outbound(instance, i as u32 + 1);
+                registration.route_set = Some(vec![proxy]);
+                registration
+            })
+            .collect();
+        Self {
+            server: server.to_string(),
+            flows,
+            keepalive_interval: Duration::from_secs(30),
+        }
+    }
+
+    /// Flows registered and not known to have failed
+    pub fn active_flows(&self) -> usize {
+        self.flows.iter().filter(|r| r.has_flow()).count()
+    }
+
+    /// Registers every flow, or only those without a working flow, returns
+    /// the number of active flows
+    pub async fn register(&mut self, failed_only: bool) -> usize {
+        for registration in self.flows.iter_mut() {
+            if failed_only && registration.has_flow() {
+                continue;
+            }
+            match registration.register(&self.server).await {
+                Ok(resp) if resp.status_code.kind() == rsip::StatusCodeKind::Successful => {}
+                Ok(resp) => warn!(
+                    "outbound flow {:?} rejected: {}",
+                    registration.reg_id, resp.status_code
+                ),
+                Err(e) => warn!("outbound flow {:?} failed: {:?}", registration.reg_id, e),
+            }
+        }
+        self.active_flows()
+    }
+
+    /// Pings every flow, those failing are registered again over a new
+    /// connection. Returns the number of active flows.
+    pub async fn check_flows(&mut self) -> usize {
+        let mut failed = false;
+        for registration in self.flows.iter_mut().filter(|r| r.has_flow()) {
+            if registration.ping_flow().await.is_err() {
+                info!("outbound flow {:?} failed", registration.reg_id);
+                failed = true;
+            }
+        }
+        if failed || self.active_flows() < self.flows.len() {
+            return self.register(true).await;
+        }
+        self.active_flows()
+    }
+
+    /// Keeps the flows registered and alive until `cancel_token` is
+    /// cancelled, refreshing the registrations at half their expiry
+    pub async fn run(&mut self, cancel_token: CancellationToken) -> Result<()> {
+        self.register(false).await;
+        let mut refreshed_at = Instant::now();
+        loop {
+            select! {
+                _ = cancel_token.cancelled() => return Ok(()),
+                _ = sleep(self.keepalive_interval) => {}
+            }
+            let refresh = self.flows.iter().map(|r| r.expires()).min().unwrap_or(0);
+            if refreshed_at.elapsed() >= Duration::from_secs(refresh as u64 / 2) {
+                self.register(false).await;
+                refreshed_at = Instant::now();
+            } else if self.check_flows().await == 0 {
+                warn!("no outbound flow is active");
+            }
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use super::{
+    dialog::{DialogState, DialogStateSender},
+    dialog_layer::DialogLayer,
+    event_package::{event_name, SimpleEventPackage},
+    subscription::{ServerSubscriptionDialog, SubscriptionState},
+};
+use crate::{rsip_ext::header_value, transaction::transaction::Transaction, Result};
+use rsip::StatusCode;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+use tracing::{info, warn};
+
+pub const PIDF_CONTENT_TYPE: &str = "application/pidf+xml";
+
+/// Basic status of a presentity (RFC 3863 4.1.4)
+#[derive(Clone, Debug, PartialEq)]
+pub enum BasicStatus {
+    Open,
+    Closed,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PresenceState {
+    pub status: BasicStatus,
+    pub note: Option<String>,
+}
+
+impl PresenceState {
+    pub fn open() -> Self {
+        Self {
+            status: BasicStatus::Open,
+            note: None,
+        }
+    }
+
+    pub fn closed() -> Self {
+        Self {
+            status: BasicStatus::Closed,
+            note: None,
+        }
+    }
+
+    pub fn with_note(mut self, note: &str) -> Self {
+        self.note = Some(note.to_string());
+        self
+    }
+
+    /// PIDF document of `entity`, e.g. `sip:alice@example.com`
+    pub fn to_pidf(&self, entity: &str) -> String {
+        let status = match self.status {
+            BasicStatus::Open => "open",
+            BasicStatus::Closed => "closed",
+        };
+        let note = self
+            .note
+            .as_ref()
+            .map(|n| format!("<note>{}</note>", xml_escape(n)))
+            .unwrap_or_default();
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\r\n\
+             <presence xmlns=\"urn:ietf:params:xml:ns:pidf\" entity=\"{}\">\r\n\
+             <tuple id=\"t1\"><status><basic>{}</basic></status>{}</tuple>\r\n\
+             </presence>\r\n",
+            xml_escape(entity),
+            status,
+            note
+        )
+    }
+}
+
+pub(crate) fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Presentity key of a Request-URI, `user@host` without scheme and params
+pub fn presentity(uri: &rsip::Uri) -> String {
+    match uri.auth.as_ref() {
+        Some(auth) => format!("{}@{}", auth.user, uri.host_with_port.host),
+        None => uri.host_with_port.host.to_string(),
+    }
+}
+
+/// A presence agent (RFC 3856) serving the `presence` event package.
+///
+/// Watchers subscribing to a presentity get its current state right away,
+/// and a NOTIFY with the new PIDF document whenever the application calls
+/// `update`. Terminated subscriptions are dropped on the next update.
+pub struct PresenceAgent {
+    watchers: RwLock<HashMap<String, Vec<ServerSubscriptionDialog>>>,
+    states: RwLock<HashMap<String, PresenceState>>,
+}
+pub type PresenceAgentRef = Arc<PresenceAgent>;
+
+impl PresenceAgent {
+    /// Creates the agent and registers the `presence` package with `layer`
+    pub fn new(layer: &DialogLayer) -> Self {
+        layer.register_event_package(Arc::new(
+            SimpleEventPackage::new("presence").with_content_type(PIDF_CONTENT_TYPE),
+        ));
+        Self {
+            watchers: RwLock::new(HashMap::new()),
+            states: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn get_state(&self, presentity: &str) -> Option<PresenceState> {
+        self.states.read().unwrap().get(presentity).cloned()
+    }
+
+    pub fn watchers(&self, presentity: &str) -> usize {
+        self.watchers
+            .read()
+            .unwrap()
+            .get(presentity)
+            .map(|w| w.len())
+            .unwrap_or(0)
+    }
+
+    /// Accepts an initial or refreshing presence SUBSCRIBE and notifies the
+    /// watcher of the current state of the presentity
+    pub async fn handle_subscribe(
+        &self,
+        layer: &DialogLayer,
+        mut tx: Transaction,
+        state_sender: DialogStateSender,
+        contact: Option<rsip::Uri>,
+    ) -> Result<()> {
+        let event = header_value(&tx.original.headers, "Event").unwrap_or_default();
+        if event_name(&event) != "presence" {
+            info!("rejecting subscribe for event: {}", event);
+            tx.reply(StatusCode::from(489)).await?;
+            return Ok(());
+        }
+        let mut dialog = layer
+            .get_or_create_server_subscription(&tx, state_sender, None, contact)
+            .await?;
+        let is_new = !dialog.inner.is_confirmed();
+        let key = presentity(&tx.original.uri);
+        dialog.handle(tx).await?;
+        if dialog.expires() == 0 {
+            if let Some(list) = self.watchers.write().unwrap().get_mut(&key) {
+                list.retain(|w| w.id() != dialog.id());
+            }
+            return Ok(());
+        }
+        if is_new {
+            info!("new watcher of {}: {}", key, dialog.id());
+            self.watchers
+                .write()
+                .unwrap()
+                .entry(key.clone())
+                .or_default()
+                .push(dialog.clone());
+        }
+        let state = self.get_state(&key).unwrap_or_else(PresenceState::closed);
+        self.notify(&dialog, &key, &state).await
+    }
+
+    /// Sets the state of `presentity` and notifies all its watchers
+    pub async fn update(&self, presentity: &str, state: PresenceState) -> Result<()> {
+        self.states
+            .write()
+            .unwrap()
+            .insert(presentity.to_string(), state.clone());
+        let watchers = {
+            let mut watchers = self.watchers.write().unwrap();
+            let list = watchers.entry(presentity.to_string()).or_default();
+            list.retain(|w| {
+                !w.cancel_token().is_cancelled()
+                    && !matches!(*w.inner.state.lock().unwrap(), DialogState::Terminated(..))
+            });
+            list.clone()
+        };
+        for watcher in watchers {
+            if let Err(e) = self.notify(&watcher, presentity, &state).await {
+                warn!("failed to notify watcher {}: {:?}", watcher.id(), e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn notify(
+        &self,
+        dialog: &ServerSubscriptionDialog,
+        presentity: &str,
+        state: &PresenceState,
+    ) -> Result<()> {
+        let entity = format!("sip:{}", presentity);
+        let body = state.to_pidf(&entity).into_bytes();
+        dialog
+            .notify(
+                SubscriptionState::Active {
+                    expires: Some(dialog.expires()),
+                },
+                None,
+                Some(body),
+            )
+            .await
+    }
+}
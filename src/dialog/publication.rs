@@ -0,0 +1,216 @@
+use super::{
+    authenticate::{handle_client_authenticate, AuthAttempts, CredentialStoreRef},
+    dialog::next_cseq,
+};
+use crate::{
+    rsip_ext::header_value,
+    transaction::{
+        endpoint::EndpointInnerRef,
+        key::{TransactionKey, TransactionRole},
+        make_call_id, make_tag,
+        transaction::Transaction,
+    },
+    Error, Result,
+};
+use rsip::{Header, Response, SipMessage, StatusCode};
+use tracing::info;
+
+/// Event state publication (RFC 3903), e.g. presence to a presence agent.
+///
+/// The SIP-ETag of the last accepted PUBLISH is sent as SIP-If-Match by
+/// refreshes, modifications and the removal. When the ETag has expired on
+/// the server (412), the full state is published again. Every PUBLISH of a
+/// publication shares its Call-ID and From tag.
+pub struct Publication {
+    pub last_seq: u32,
+    pub endpoint: EndpointInnerRef,
+    pub credential: Option<CredentialStoreRef>,
+    pub target: rsip::Uri,
+    pub event: String,
+    pub expires: u32,
+    pub etag: Option<String>,
+    state: Option<(String, Vec<u8>)>,
+    call_id: rsip::headers::CallId,
+    from_tag: rsip::param::Tag,
+}
+
+/// 412 Conditional Request Failed, the SIP-If-Match ETag is unknown
+fn is_etag_expired(resp: &Response) -> bool {
+    resp.status_code == StatusCode::from(412)
+}
+
+impl Publication {
+    pub fn new(
+        endpoint: EndpointInnerRef,
+        credential: Option<CredentialStoreRef>,
+        target: rsip::Uri,
+        event: &str,
+    ) -> Self {
+        Self {
+            last_seq: 0,
+            endpoint,
+            credential,
+            target,
+            event: event.to_string(),
+            expires: 3600,
+            etag: None,
+            state: None,
+            call_id: make_call_id(None),
+            from_tag: make_tag(),
+        }
+    }
+
+    /// Publishes new event state, replacing the state published before
+    pub async fn publish(&mut self, content_type: &str, body: Vec<u8>) -> Result<Response> {
+        self.state = Some((content_type.to_string(), body));
+        let resp = self.send(self.expires, true).await?;
+        if is_etag_expired(&resp) {
+            info!("publication etag expired, publishing full state");
+            self.etag = None;
+            return self.send(self.expires, true).await;
+        }
+        Ok(resp)
+    }
+
+    /// Refreshes the published state before it expires, without a body
+    pub async fn refresh(&mut self) -> Result<Response> {
+        if self.etag.is_none() {
+            return Err(Error::Error("nothing published".to_string()));
+        }
+        let resp = self.send(self.expires, false).await?;
+        if is_etag_expired(&resp) && self.state.is_some() {
+            info!("publication etag expired, publishing full state");
+            self.etag = None;
+            return self.send(self.expires, true).await;
+        }
+        Ok(resp)
+    }
+
+    /// Removes the published state, which is kept when the removal fails
+    /// so that it may be retried
+    pub async fn remove(&mut self) -> Result<Response> {
+        if self.etag.is_none() {
+            return Err(Error::Error("nothing published".to_string()));
+        }
+        let resp = self.send(0, false).await?;
+        if resp.status_code.kind() == rsip::StatusCodeKind::Successful || is_etag_expired(&resp) {
+            self.etag = None;
+            self.state = None;
+        }
+        Ok(resp)
+    }
+
+    async fn send(&mut self, expires: u32, with_body: bool) -> Result<Response> {
+        self.last_seq = next_cseq(self.last_seq);
+
+        let mut uri = self.target.clone();
+        let destination = uri.host_with_port.to_string();
+        if let Some(user) = self
+            .credential
+            .as_ref()
+            .and_then(|cred| cred.username(&destination))
+        {
+            uri.auth = Some(rsip::Auth {
+                user,
+                password: None,
+            });
+        }
+        let from = rsip::typed::From {
+            display_name: None,
+            uri: uri.clone(),
+            params: vec![],
+        }
+        .with_tag(self.from_tag.clone());
+        let to = rsip::typed::To {
+            display_name: None,
+            uri,
+            params: vec![],
+        };
+        let via = self.endpoint.get_via(None, None)?;
+        let mut request = self.endpoint.make_request(
+            rsip::Method::Publish,
+            self.target.clone(),
+            via,
+            from,
+            to,
+            self.last_seq,
+        );
+        request
+            .headers
+            .unique_push(Header::CallId(self.call_id.clone()));
+        request
+            .headers
+            .unique_push(Header::Other("Event".into(), self.event.clone()));
+        request
+            .headers
+            .unique_push(Header::Expires(expires.to_string().into()));
+        if let Some(etag) = &self.etag {
+            request
+                .headers
+                .unique_push(Header::Other("SIP-If-Match".into(), etag.clone()));
+        }
+        match self.state.as_ref() {
+            Some((content_type, body)) if with_body => {
+                request
+                    .headers
+                    .unique_push(Header::ContentType(content_type.clone().into()));
+                request.body = body.clone();
+            }
+            _ => {}
+        }
+        request
+            .headers
+            .unique_push(Header::ContentLength((request.body.len() as u32).into()));
+        if let Some(cred) = &self.credential {
+            self.endpoint
+                .auth_cache
+                .authorize_request(&mut request, cred);
+        }
+
+        let key = TransactionKey::from_request(&request, TransactionRole::Client)?;
+        let mut tx = Transaction::new_client(key, request, self.endpoint.clone(), None);
+        tx.send().await?;
+        let mut auth_attempts = AuthAttempts::default();
+
+        while let Some(msg) = tx.receive().await {
+            let resp = match msg {
+                SipMessage::Response(resp) => resp,
+                _ => break,
+            };
+            match resp.status_code {
+                StatusCode::ProxyAuthenticationRequired | StatusCode::Unauthorized => {
+                    let cred = match self.credential.as_ref() {
+                        Some(cred) if auth_attempts.may_answer(&resp) => cred,
+                        _ => {
+                            info!("received {} response for publish", resp.status_code);
+                            self.endpoint.auth_cache.forget(&tx.original);
+                            return Ok(resp);
+                        }
+                    };
+                    self.last_seq = next_cseq(self.last_seq);
+                    tx = handle_client_authenticate(self.last_seq, tx, resp, cred).await?;
+                    tx.send().await?;
+                }
+                _ if resp.status_code.kind() == rsip::StatusCodeKind::Provisional => {}
+                _ => {
+                    if resp.status_code.kind() == rsip::StatusCodeKind::Successful {
+                        if let Some(etag) = header_value(&resp.headers, "SIP-ETag") {
+                            self.etag = Some(etag);
+                        }
+                        if let Some(expires) = header_value(&resp.headers, "Expires")
+                            .and_then(|e| e.parse::<u32>().ok())
+                            .filter(|e| *e > 0)
+                        {
+                            self.expires = expires;
+                        }
+                    }
+                    info!("publish done: {:?}", resp.status_code);
+                    return Ok(resp);
+                }
+            }
+        }
+        Err(Error::Error(
+            "publish transaction is already terminated".to_string(),
+        ))
+    }
+}
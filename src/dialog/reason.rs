@@ -0,0 +1,109 @@
+use super::dialog::{DialogInner, DialogState};
+use crate::{rsip_ext::header_values, Error, Result};
+use rsip::Header;
+use tracing::info;
+
+/// Reason header value (RFC 3326), e.g. `SIP;cause=487;text="Request Terminated"`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reason {
+    /// `SIP` or `Q.850`
+    pub protocol: String,
+    pub cause: u16,
+    pub text: Option<String>,
+}
+
+impl Reason {
+    pub fn sip(cause: u16) -> Self {
+        Self {
+            protocol: "SIP".to_string(),
+            cause,
+            text: None,
+        }
+    }
+
+    pub fn q850(cause: u16) -> Self {
+        Self {
+            protocol: "Q.850".to_string(),
+            cause,
+            text: None,
+        }
+    }
+
+    pub fn with_text(mut self, text: &str) -> Self {
+        self.text = Some(text.to_string());
+        self
+    }
+
+    pub fn to_header(&self) -> Header {
+        Header::Other("Reason".into(), self.to_string())
+    }
+
+    /// The first valid Reason of `headers`
+    pub fn from_headers(headers: &rsip::Headers) -> Option<Self> {
+        header_values(headers, "Reason")
+            .iter()
+            .flat_map(|v| v.split(','))
+            .find_map(|v| Reason::try_from(v).ok())
+    }
+}
+
+impl std::fmt::Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{};cause={}", self.protocol, self.cause)?;
+        if let Some(text) = self.text.as_ref() {
+            write!(f, ";text=\"{}\"", text.replace('"', "'"))?;
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<&str> for Reason {
+    type Error = crate::Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        let mut parts = value.split(';');
+        let protocol = parts.next().unwrap_or_default().trim();
+        if protocol.is_empty() {
+            return Err(Error::Error(format!("invalid reason: {}", value)));
+        }
+        let mut cause = None;
+        let mut text = None;
+        for param in parts {
+            match param.split_once('=') {
+                Some((name, value)) if name.trim().eq_ignore_ascii_case("cause") => {
+                    cause = value.trim().parse::<u16>().ok();
+                }
+                Some((name, value)) if name.trim().eq_ignore_ascii_case("text") => {
+                    text = Some(value.trim().trim_matches('"').to_string());
+                }
+                _ => {}
+            }
+        }
+        Ok(Reason {
+            protocol: protocol.to_string(),
+            cause: cause.ok_or(Error::Error(format!("reason without cause: {}", value)))?,
+            text,
+        })
+    }
+}
+
+impl DialogInner {
+    /// Hangs up a dialog the stack can't keep up, with `reason` in the BYE
+    pub(super) async fn bye_on_error(&self, reason: Reason) -> Result<()> {
+        info!("hanging up on error: {}", reason);
+        let request = self.make_request(
+            rsip::Method::Bye,
+            None,
+            None,
+            None,
+            Some(vec![reason.to_header()]),
+            None,
+        )?;
+        let resp = self.do_request(request).await?;
+        self.transition(DialogState::Terminated(
+            self.id.lock().unwrap().clone(),
+            resp.map(|r| r.status_code),
+            Some(reason),
+        ))
+    }
+}
@@ -0,0 +1,347 @@
+use super::{
+    authenticate::CredentialStoreRef,
+    dialog::{Dialog, DialogInner, DialogState, DialogStateSender},
+    dialog_layer::DialogLayer,
+    invitation::InviteOption,
+    usage::DialogUsage,
+};
+use crate::{
+    rsip_ext::{header_value, percent_decode},
+    transaction::transaction::Transaction,
+    Error, Result,
+};
+use rsip::{prelude::HeadersExt, Header, Request, Response, StatusCode};
+use std::sync::Arc;
+use tokio::{select, sync::oneshot, time::sleep};
+use tracing::{info, warn};
+
+pub type InviteHook = Arc<dyn Fn(&mut InviteOption) + Send + Sync>;
+
+/// Options of the INVITE sent by the transferee to the Refer-To target
+#[derive(Clone)]
+pub struct TransfereeOption {
+    pub contact: rsip::Uri,
+    pub credential: Option<CredentialStoreRef>,
+    pub content_type: Option<String>,
+    pub offer: Option<Vec<u8>>,
+    /// Called with the new INVITE before it's sent
+    pub on_invite: Option<InviteHook>,
+}
+
+/// Refer-To header value (RFC 3515 2.1), with the headers embedded in the URI
+#[derive(Clone, Debug)]
+pub struct ReferTo {
+    pub uri: rsip::Uri,
+    pub headers: Vec<(String, String)>,
+}
+
+impl TryFrom<&str> for ReferTo {
+    type Error = crate::Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        let value = value.trim();
+        let addr = match (value.find('<'), value.find('>')) {
+            (Some(start), Some(end)) if start < end => &value[start + 1..end],
+            _ => value.split(';').next().unwrap_or_default(),
+        };
+        let (uri, headers) = addr.split_once('?').unwrap_or((addr, ""));
+        let headers = headers
+            .split('&')
+            .filter_map(|h| h.split_once('='))
+            .map(|(name, value)| (percent_decode(name), percent_decode(value)))
+            .collect();
+        Ok(ReferTo {
+            uri: rsip::Uri::try_from(uri)?,
+            headers,
+        })
+    }
+}
+
+/// Parses the Refer-To header of a REFER, `None` when it's missing
+pub fn refer_to_header(request: &Request) -> Option<Result<ReferTo>> {
+    header_value(&request.headers, "Refer-To")
+        .or_else(|| header_value(&request.headers, "r"))
+        .map(|v| ReferTo::try_from(v.as_str()))
+}
+
+/// Status code of a message/sipfrag body, e.g. `SIP/2.0 180 Ringing`
+pub fn sipfrag_status(body: &[u8]) -> Option<StatusCode> {
+    let line = std::str::from_utf8(body).ok()?.lines().next()?;
+    let code = line
+        .strip_prefix("SIP/2.0")?
+        .split_whitespace()
+        .next()?
+        .parse::<u16>()
+        .ok()?;
+    Some(StatusCode::from(code))
+}
+
+impl DialogInner {
+    /// Sends an in-dialog REFER to `target` and starts tracking its implicit
+    /// subscription, the NOTIFYs are reported as `DialogState::ReferProgress`
+    pub(super) async fn do_refer(
+        &self,
+        target: rsip::Uri,
+        headers: Option<Vec<Header>>,
+    ) -> Result<Option<Response>> {
+        if !self.is_confirmed() {
+            return Ok(None);
+        }
+        let referred_by = rsip::headers::From::from(self.from.clone()).typed()?.uri;
+        let mut headers = headers.unwrap_or_default();
+        headers.push(Header::Other("Refer-To".into(), format!("<{}>", target)));
+        headers.push(Header::Other(
+            "Referred-By".into(),
+            format!("<{}>", referred_by),
+        ));
+        let request =
+            self.make_request(rsip::Method::Refer, None, None, None, Some(headers), None)?;
+        let usage = refer_usage(&request)?;
+        // a NOTIFY may arrive before the 202
+        self.add_usage(usage.clone());
+        let resp = self.do_request(request).await;
+        match resp.as_ref() {
+            Ok(Some(resp)) if resp.status_code.kind() == rsip::StatusCodeKind::Successful => {}
+            _ => self.usages.lock().unwrap().retain(|u| u != &usage),
+        }
+        resp
+    }
+
+    /// Handles a NOTIFY of the implicit REFER subscription
+    pub(super) async fn handle_refer_notify(&self, mut tx: Transaction) -> Result<()> {
+        let event = header_value(&tx.original.headers, "Event")
+            .or_else(|| header_value(&tx.original.headers, "o"))
+            .unwrap_or_default();
+        let usage = DialogUsage::from_event(&event);
+        let is_refer =
+            matches!(&usage, DialogUsage::Subscription { event, .. } if event == "refer");
+        if !is_refer || !self.has_usage(&usage) {
+            info!("notify without refer subscription: {}", event);
+            tx.reply(StatusCode::CallTransactionDoesNotExist).await?;
+            return Ok(());
+        }
+        let terminated = header_value(&tx.original.headers, "Subscription-State")
+            .map(|s| s.trim().to_lowercase().starts_with("terminated"))
+            .unwrap_or(false);
+        let last_usage = terminated && self.end_usage(&usage)?;
+        tx.reply(StatusCode::OK).await?;
+
+        let id = self.id.lock().unwrap().clone();
+        match sipfrag_status(&tx.original.body) {
+            Some(status) => self.transition(DialogState::ReferProgress(id.clone(), status))?,
+            None => info!("refer notify without sipfrag status"),
+        }
+        if last_usage {
+            // the INVITE usage ended before the transfer did
+            self.transition(DialogState::Terminated(id, None, None))?;
+        }
+        Ok(())
+    }
+
+    /// Reports an incoming REFER as `DialogState::Refer` and answers it with
+    /// the decision of the application, declined when none is made in time.
+    /// The decision is awaited in a task of its own, with the first NOTIFY of
+    /// an accepted REFER, so that the dialog keeps handling its requests.
+    pub(super) async fn handle_refer(self: &Arc<Self>, mut tx: Transaction) -> Result<()> {
+        let id = self.id.lock().unwrap().clone();
+        let refer_to = match refer_to_header(&tx.original) {
+            Some(Ok(refer_to)) => refer_to,
+            _ => {
+                tx.reply(StatusCode::BadRequest).await?;
+                return Err(Error::DialogError(
+                    "invalid Refer-To header".to_string(),
+                    id,
+                ));
+            }
+        };
+        info!("received refer {} to: {}", id, refer_to.uri);
+        let (sender, receiver) = oneshot::channel();
+        self.pending_refer.lock().unwrap().replace(sender);
+        self.transition(DialogState::Refer(
+            id,
+            refer_to,
+            Arc::new(tx.original.clone()),
+        ))?;
+
+        let inner = self.clone();
+        tokio::spawn(async move {
+            // answer well before the referrer's transaction times out
+            let status = select! {
+                status = receiver => status.unwrap_or(StatusCode::Decline),
+                _ = sleep(inner.endpoint_inner.t1x64 / 2) => {
+                    info!("no decision on refer, declining");
+                    StatusCode::Decline
+                }
+            };
+            inner.pending_refer.lock().unwrap().take();
+            let result = async {
+                tx.reply(status.clone()).await?;
+                if status.kind() == rsip::StatusCodeKind::Successful {
+                    inner.add_usage(refer_usage(&tx.original)?);
+                    inner.notify_refer_progress(StatusCode::Trying).await?;
+                }
+                Ok::<_, Error>(())
+            };
+            if let Err(e) = result.await {
+                warn!("failed to answer refer: {:?}", e);
+            }
+        });
+        Ok(())
+    }
+
+    pub(super) fn respond_refer(&self, status: StatusCode) -> Result<()> {
+        match self.pending_refer.lock().unwrap().take() {
+            Some(sender) => {
+                sender.send(status).ok();
+                Ok(())
+            }
+            None => Err(Error::DialogError(
+                "no pending refer".to_string(),
+                self.id.lock().unwrap().clone(),
+            )),
+        }
+    }
+
+    /// Sends the progress of the last accepted REFER as NOTIFY sipfrag, a
+    /// final status terminates its implicit subscription
+    pub(super) async fn notify_refer_progress(&self, status: StatusCode) -> Result<()> {
+        let usage = self
+            .usages()
+            .into_iter()
+            .rev()
+            .find(|u| matches!(u, DialogUsage::Subscription { event, .. } if event == "refer"))
+            .ok_or(Error::DialogError(
+                "no refer subscription".to_string(),
+                self.id.lock().unwrap().clone(),
+            ))?;
+        notify_refer(self, &usage, status).await
+    }
+}
+
+/// Usage of the implicit subscription of a REFER, identified by its CSeq
+/// (RFC 3515 2.4.6)
+fn refer_usage(refer: &Request) -> Result<DialogUsage> {
+    Ok(DialogUsage::Subscription {
+        event: "refer".to_string(),
+        id: Some(refer.cseq_header()?.seq()?.to_string()),
+    })
+}
+
+/// Sends a NOTIFY of the `usage` subscription, a final status terminates it
+async fn notify_refer(inner: &DialogInner, usage: &DialogUsage, status: StatusCode) -> Result<()> {
+    let state = match status.kind() {
+        rsip::StatusCodeKind::Provisional => "active",
+        _ => "terminated;reason=noresource",
+    };
+    let headers = vec![
+        Header::Other("Event".into(), usage.to_string()),
+        Header::Other("Subscription-State".into(), state.into()),
+        Header::ContentType("message/sipfrag;version=2.0".into()),
+    ];
+    let body = format!("SIP/2.0 {}", status).into_bytes();
+    let request = inner.make_request(
+        rsip::Method::Notify,
+        None,
+        None,
+        None,
+        Some(headers),
+        Some(body),
+    )?;
+    inner.do_request(request).await?;
+    if state != "active" && inner.end_usage(usage)? {
+        inner.transition(DialogState::Terminated(
+            inner.id.lock().unwrap().clone(),
+            None,
+            None,
+        ))?;
+    }
+    Ok(())
+}
+
+impl DialogLayer {
+    /// Accepts an in-dialog REFER and performs the transfer on behalf of the
+    /// application.
+    ///
+    /// The INVITE to the Refer-To target carries its embedded headers (e.g.
+    /// Replaces) and Referred-By, progress is reported to the transferor with
+    /// NOTIFY and the referred dialog is hung up once the new one is answered.
+    pub async fn accept_refer(
+        &self,
+        dialog: &Dialog,
+        mut tx: Transaction,
+        opt: TransfereeOption,
+        state_sender: DialogStateSender,
+    ) -> Result<()> {
+        let inner = dialog.inner().clone();
+        let refer_to = match refer_to_header(&tx.original) {
+            Some(Ok(refer_to)) => refer_to,
+            _ => {
+                tx.reply(StatusCode::BadRequest).await?;
+                return Err(Error::DialogError(
+                    "invalid Refer-To header".to_string(),
+                    dialog.id(),
+                ));
+            }
+        };
+        info!("accept refer {} to: {}", dialog.id(), refer_to.uri);
+        let usage = refer_usage(&tx.original)?;
+        tx.reply(StatusCode::Accepted).await?;
+        inner.add_usage(usage.clone());
+
+        let mut headers = refer_to
+            .headers
+            .iter()
+            .map(|(name, value)| Header::Other(name.clone(), value.clone()))
+            .collect::<Vec<_>>();
+        if let Some(referred_by) = header_value(&tx.original.headers, "Referred-By")
+            .or_else(|| header_value(&tx.original.headers, "b"))
+        {
+            headers.push(Header::Other("Referred-By".into(), referred_by));
+        }
+        let caller = rsip::headers::From::from(inner.from.clone()).typed()?.uri;
+        let mut invite = InviteOption {
+            caller,
+            callee: refer_to.uri,
+            content_type: opt.content_type,
+            offer: opt.offer,
+            contact: opt.contact,
+            credential: opt.credential,
+            headers: Some(headers),
+            route_set: None,
+            max_redirects: None,
+            identity: None,
+        };
+        if let Some(hook) = opt.on_invite.as_ref() {
+            hook(&mut invite);
+        }
+
+        let layer = DialogLayer {
+            endpoint: self.endpoint.clone(),
+            inner: self.inner.clone(),
+        };
+        let dialog = dialog.clone();
+        tokio::spawn(async move {
+            if let Err(e) = notify_refer(&inner, &usage, StatusCode::Trying).await {
+                warn!("failed to notify transferor: {:?}", e);
+            }
+            let status = match layer.do_invite(invite, state_sender).await {
+                Ok((_, Some(resp))) => resp.status_code,
+                Ok((_, None)) => StatusCode::RequestTimeout,
+                Err(e) => {
+                    warn!("transfer failed: {} {:?}", dialog.id(), e);
+                    StatusCode::ServiceUnavailable
+                }
+            };
+            let success = status.kind() == rsip::StatusCodeKind::Successful;
+            notify_refer(&inner, &usage, status)
+                .await
+                .map_err(|e| warn!("failed to notify transferor: {:?}", e))
+                .ok();
+            if success {
+                info!("transfer succeeded, hanging up {}", dialog.id());
+                dialog.hangup().await.ok();
+            }
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,406 @@
+use super::{
+    dialog::{DialogState, DialogStateSender},
+    dialog_info::{xml_attr, xml_text},
+    dialog_layer::DialogLayer,
+    event_package::{event_name, SimpleEventPackage},
+    presence::xml_escape,
+    registrar::{aor_of, Binding, LocationServiceRef},
+    subscription::{
+        ClientSubscriptionDialog, ServerSubscriptionDialog, SubscribeOption, SubscriptionState,
+    },
+};
+use crate::{rsip_ext::header_value, transaction::transaction::Transaction, Error, Result};
+use rsip::{Response, StatusCode};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{Arc, RwLock},
+};
+use tracing::{info, warn};
+
+pub const REG_EVENT: &str = "reg";
+pub const REGINFO_CONTENT_TYPE: &str = "application/reginfo+xml";
+
+/// Why a contact changed state (RFC 3680 5.3)
+#[derive(Clone, Debug, PartialEq)]
+pub enum ContactEvent {
+    Registered,
+    Created,
+    Refreshed,
+    Shortened,
+    Expired,
+    Deactivated,
+    Probation,
+    Unregistered,
+    Rejected,
+}
+
+impl ContactEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContactEvent::Registered => "registered",
+            ContactEvent::Created => "created",
+            ContactEvent::Refreshed => "refreshed",
+            ContactEvent::Shortened => "shortened",
+            ContactEvent::Expired => "expired",
+            ContactEvent::Deactivated => "deactivated",
+            ContactEvent::Probation => "probation",
+            ContactEvent::Unregistered => "unregistered",
+            ContactEvent::Rejected => "rejected",
+        }
+    }
+
+    /// Whether the contact is left terminated by the event
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            ContactEvent::Expired
+                | ContactEvent::Deactivated
+                | ContactEvent::Probation
+                | ContactEvent::Unregistered
+                | ContactEvent::Rejected
+        )
+    }
+}
+
+impl TryFrom<&str> for ContactEvent {
+    type Error = crate::Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "registered" => Ok(ContactEvent::Registered),
+            "created" => Ok(ContactEvent::Created),
+            "refreshed" => Ok(ContactEvent::Refreshed),
+            "shortened" => Ok(ContactEvent::Shortened),
+            "expired" => Ok(ContactEvent::Expired),
+            "deactivated" => Ok(ContactEvent::Deactivated),
+            "probation" => Ok(ContactEvent::Probation),
+            "unregistered" => Ok(ContactEvent::Unregistered),
+            "rejected" => Ok(ContactEvent::Rejected),
+            _ => Err(Error::Error(format!("invalid contact event: {}", value))),
+        }
+    }
+}
+
+/// A `<contact>` element of a reginfo document
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContactInfo {
+    pub id: String,
+    /// `active` or `terminated`
+    pub state: String,
+    pub event: ContactEvent,
+    pub expires: Option<u32>,
+    pub uri: String,
+}
+
+/// A `<registration>` element, the contacts of one address-of-record
+#[derive(Clone, Debug, PartialEq)]
+pub struct RegistrationInfo {
+    pub aor: String,
+    pub id: String,
+    /// `init`, `active` or `terminated`
+    pub state: String,
+    pub contacts: Vec<ContactInfo>,
+}
+
+/// A reginfo document (RFC 3680 5.3)
+#[derive(Clone, Debug, PartialEq)]
+pub struct RegInfo {
+    pub version: u32,
+    /// `full` or `partial`
+    pub state: String,
+    pub registrations: Vec<RegistrationInfo>,
+}
+
+fn make_id(value: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    format!("{:x}", hasher.finish())
+}
+
+impl RegistrationInfo {
+    /// Registration state of `aor` from its current `bindings`, contacts in
+    /// `changes` are reported with their event, removed ones as terminated
+    pub fn new(aor: &str, bindings: &[Binding], changes: &[(rsip::Uri, ContactEvent)]) -> Self {
+        let mut contacts = bindings
+            .iter()
+            .filter(|b| b.remaining() > 0)
+            .map(|b| {
+                let event = changes
+                    .iter()
+                    .find(|(uri, _)| uri == &b.contact.uri)
+                    .map(|(_, event)| event.clone())
+                    .unwrap_or(ContactEvent::Registered);
+                let uri = b.contact.uri.to_string();
+                ContactInfo {
+                    id: make_id(&uri),
+                    state: "active".to_string(),
+                    event,
+                    expires: Some(b.remaining()),
+                    uri,
+                }
+            })
+            .collect::<Vec<_>>();
+        for (uri, event) in changes.iter().filter(|(_, e)| e.is_terminal()) {
+            let uri = uri.to_string();
+            contacts.push(ContactInfo {
+                id: make_id(&uri),
+                state: "terminated".to_string(),
+                event: event.clone(),
+                expires: None,
+                uri,
+            });
+        }
+        let state = if contacts.iter().any(|c| c.state == "active") {
+            "active"
+        } else {
+            "terminated"
+        };
+        RegistrationInfo {
+            aor: aor.to_string(),
+            id: make_id(aor),
+            state: state.to_string(),
+            contacts,
+        }
+    }
+}
+
+impl RegInfo {
+    pub fn to_xml(&self) -> String {
+        let mut xml = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\r\n\
+             <reginfo xmlns=\"urn:ietf:params:xml:ns:reginfo\" version=\"{}\" state=\"{}\">\r\n",
+            self.version,
+            xml_escape(&self.state)
+        );
+        for registration in &self.registrations {
+            xml.push_str(&format!(
+                "<registration aor=\"{}\" id=\"{}\" state=\"{}\">\r\n",
+                xml_escape(&registration.aor),
+                xml_escape(&registration.id),
+                xml_escape(&registration.state)
+            ));
+            for contact in &registration.contacts {
+                xml.push_str(&format!(
+                    "<contact id=\"{}\" state=\"{}\" event=\"{}\"",
+                    xml_escape(&contact.id),
+                    xml_escape(&contact.state),
+                    contact.event.as_str()
+                ));
+                if let Some(expires) = contact.expires {
+                    xml.push_str(&format!(" expires=\"{}\"", expires));
+                }
+                xml.push_str(&format!(
+                    "><uri>{}</uri></contact>\r\n",
+                    xml_escape(&contact.uri)
+                ));
+            }
+            xml.push_str("</registration>\r\n");
+        }
+        xml.push_str("</reginfo>\r\n");
+        xml
+    }
+}
+
+impl TryFrom<&str> for RegInfo {
+    type Error = crate::Error;
+
+    fn try_from(xml: &str) -> Result<Self> {
+        let start = xml
+            .find("<reginfo")
+            .ok_or(Error::Error("missing reginfo element".to_string()))?;
+        let root = &xml[start..start + xml[start..].find('>').unwrap_or_default()];
+        let mut info = RegInfo {
+            version: xml_attr(root, "version")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or_default(),
+            state: xml_attr(root, "state").unwrap_or_else(|| "full".to_string()),
+            registrations: vec![],
+        };
+        let mut rest = &xml[start + root.len()..];
+        while let Some(pos) = rest.find("<registration ") {
+            let element = &rest[pos..];
+            let end = element
+                .find("</registration>")
+                .map(|e| e + "</registration>".len())
+                .unwrap_or(element.len());
+            let element = &element[..end];
+            let head = &element[..element.find('>').unwrap_or(element.len())];
+            let mut registration = RegistrationInfo {
+                aor: xml_attr(head, "aor").unwrap_or_default(),
+                id: xml_attr(head, "id").unwrap_or_default(),
+                state: xml_attr(head, "state").unwrap_or_default(),
+                contacts: vec![],
+            };
+            let mut contacts = &element[head.len()..];
+            while let Some(pos) = contacts.find("<contact ") {
+                let contact = &contacts[pos..];
+                let end = contact
+                    .find("</contact>")
+                    .map(|e| e + "</contact>".len())
+                    .unwrap_or(contact.len());
+                let contact = &contact[..end];
+                let head = &contact[..contact.find('>').unwrap_or(contact.len())];
+                registration.contacts.push(ContactInfo {
+                    id: xml_attr(head, "id").unwrap_or_default(),
+                    state: xml_attr(head, "state").unwrap_or_default(),
+                    event: ContactEvent::try_from(
+                        xml_attr(head, "event").unwrap_or_default().as_str(),
+                    )?,
+                    expires: xml_attr(head, "expires").and_then(|e| e.parse().ok()),
+                    uri: xml_text(contact, "uri")
+                        .map(|u| u.trim().to_string())
+                        .ok_or(Error::Error("missing contact uri".to_string()))?,
+                });
+                contacts = &contacts[pos + end..];
+            }
+            info.registrations.push(registration);
+            rest = &rest[pos + end..];
+        }
+        Ok(info)
+    }
+}
+
+impl DialogLayer {
+    fn register_reg_package(&self) {
+        if self.get_event_package(REG_EVENT).is_none() {
+            self.register_event_package(Arc::new(
+                SimpleEventPackage::new(REG_EVENT).with_content_type(REGINFO_CONTENT_TYPE),
+            ));
+        }
+    }
+
+    /// Watches the registration state of an address-of-record, the NOTIFYs
+    /// reported as `DialogState::Notify` are parsed with `RegInfo::try_from`
+    pub async fn subscribe_reg(
+        &self,
+        mut opt: SubscribeOption,
+        state_sender: DialogStateSender,
+    ) -> Result<(ClientSubscriptionDialog, Option<Response>)> {
+        self.register_reg_package();
+        opt.event = REG_EVENT.to_string();
+        opt.accept = Some(REGINFO_CONTENT_TYPE.to_string());
+        self.do_subscribe(opt, state_sender).await
+    }
+}
+
+struct Watcher {
+    dialog: ServerSubscriptionDialog,
+    version: u32,
+}
+
+/// Notifier of the `reg` event package, set on a `Registrar` with
+/// `with_reg_event` so that watchers of an address-of-record, usually its
+/// own devices, learn about bindings created, shortened or removed.
+pub struct RegEventNotifier {
+    watchers: RwLock<HashMap<String, Vec<Watcher>>>,
+}
+
+impl RegEventNotifier {
+    pub fn new(layer: &DialogLayer) -> Self {
+        layer.register_reg_package();
+        Self {
+            watchers: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Accepts a SUBSCRIBE to the registrations of its Request-URI and
+    /// notifies the current bindings of `location` right away
+    pub async fn handle_subscribe(
+        &self,
+        layer: &DialogLayer,
+        location: &LocationServiceRef,
+        mut tx: Transaction,
+        state_sender: DialogStateSender,
+        contact: Option<rsip::Uri>,
+    ) -> Result<()> {
+        let event = header_value(&tx.original.headers, "Event").unwrap_or_default();
+        if event_name(&event) != REG_EVENT {
+            info!("rejecting subscribe for event: {}", event);
+            tx.reply(StatusCode::from(489)).await?;
+            return Ok(());
+        }
+        let mut dialog = layer
+            .get_or_create_server_subscription(&tx, state_sender, None, contact)
+            .await?;
+        let aor = aor_of(&tx.original.uri);
+        dialog.handle(tx).await?;
+
+        let version = {
+            let mut watchers = self.watchers.write().unwrap();
+            let list = watchers.entry(aor.clone()).or_default();
+            if dialog.expires() == 0 {
+                list.retain(|w| w.dialog.id() != dialog.id());
+                return Ok(());
+            }
+            match list.iter_mut().find(|w| w.dialog.id() == dialog.id()) {
+                Some(watcher) => {
+                    watcher.version += 1;
+                    watcher.version
+                }
+                None => {
+                    info!("new reg watcher of {}: {}", aor, dialog.id());
+                    list.push(Watcher {
+                        dialog: dialog.clone(),
+                        version: 0,
+                    });
+                    0
+                }
+            }
+        };
+        let bindings = location.lookup(&aor).await?;
+        let registration = RegistrationInfo::new(&aor, &bindings, &[]);
+        notify_reginfo(&dialog, &registration, version).await
+    }
+
+    /// Sends the new registration state of an address-of-record to its
+    /// watchers, failures are only logged
+    pub async fn notify(&self, registration: &RegistrationInfo) {
+        let pending = {
+            let mut watchers = self.watchers.write().unwrap();
+            let list = match watchers.get_mut(&registration.aor) {
+                Some(list) => list,
+                None => return,
+            };
+            list.retain(|w| {
+                !w.dialog.cancel_token().is_cancelled()
+                    && !matches!(
+                        *w.dialog.inner.state.lock().unwrap(),
+                        DialogState::Terminated(..)
+                    )
+            });
+            list.iter_mut()
+                .map(|w| {
+                    w.version += 1;
+                    (w.dialog.clone(), w.version)
+                })
+                .collect::<Vec<_>>()
+        };
+        for (dialog, version) in pending {
+            if let Err(e) = notify_reginfo(&dialog, registration, version).await {
+                warn!("failed to notify reg watcher {}: {:?}", dialog.id(), e);
+            }
+        }
+    }
+}
+
+async fn notify_reginfo(
+    dialog: &ServerSubscriptionDialog,
+    registration: &RegistrationInfo,
+    version: u32,
+) -> Result<()> {
+    let info = RegInfo {
+        version,
+        state: "full".to_string(),
+        registrations: vec![registration.clone()],
+    };
+    dialog
+        .notify(
+            SubscriptionState::Active {
+                expires: Some(dialog.expires()),
+            },
+            None,
+            Some(info.to_xml().into_bytes()),
+        )
+        .await
+}
@@ -0,0 +1,530 @@
+use super::{
+    digest::{
+        DigestAlgorithm, DigestAuthenticator, DigestSecret, DigestSecretStore, DigestVerdict,
+    },
+    reginfo::{ContactEvent, RegEventNotifier, RegistrationInfo},
+    registration::ContactBinding,
+};
+use crate::{
+    rsip_ext::{contact_values, has_supported, header_value, path_values, route_uri},
+    transaction::{transaction::Transaction, IncomingRequest, RequestHandler},
+    transport::{
+        flow::{flow_remote, Flow},
+        TransportLayer,
+    },
+    Result,
+};
+use rsip::{
+    prelude::{HeadersExt, ToTypedHeader, UntypedHeader},
+    Header, Param, Request, StatusCode,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tracing::info;
+
+/// A Contact registered for an address-of-record
+#[derive(Clone, Debug)]
+pub struct Binding {
+    /// The Contact as registered, without its expires parameter
+    pub contact: rsip::typed::Contact,
+    pub call_id: String,
+    pub cseq: u32,
+    pub expires_at: SystemTime,
+    /// The `+sip.instance` of the device, a new binding of the same instance
+    /// replaces the previous one
+    pub instance: Option<String>,
+    /// The `reg-id` of an outbound flow (RFC 5626), each flow of an instance
+    /// being a binding of its own
+    pub reg_id: Option<u32>,
+    /// Token of the flow the binding was registered over, requests to an
+    /// outbound binding must be sent over it
+    pub flow: Option<String>,
+    /// The Path of the REGISTER (RFC 3327), the proxies to route requests
+    /// toward the UA through
+    pub path: Vec<rsip::Uri>,
+}
+
+impl Binding {
+    /// Whether a Contact registered with `instance`, `reg_id` and `uri`
+    /// replaces this binding
+    pub fn matches(&self, instance: Option<&String>, reg_id: Option<u32>, uri: &rsip::Uri) -> bool {
+        match (self.instance.as_ref(), instance) {
+            (Some(a), Some(b)) => a == b && self.reg_id == reg_id,
+            _ => &self.contact.uri == uri,
+        }
+    }
+
+    /// Route set of the requests to the contact, e.g. for
+    /// `InviteOption::route_set`
+    pub fn route_set(&self) -> Vec<rsip::Uri> {
+        self.path.clone()
+    }
+
+    /// The connection to reach an outbound binding over, if still open
+    pub fn flow(&self, transport_layer: &TransportLayer) -> Option<Flow> {
+        self.flow
+            .as_ref()
+            .and_then(|token| transport_layer.get_flow(token))
+    }
+
+    /// Seconds left before the binding expires, 0 once expired
+    pub fn remaining(&self) -> u32 {
+        self.expires_at
+            .duration_since(SystemTime::now())
+            .map(|d| d.as_secs() as u32)
+            .unwrap_or(0)
+    }
+}
+
+/// Storage of the bindings of a `Registrar`, keyed by address-of-record,
+/// backed by an external database to share it between nodes
+#[async_trait::async_trait]
+pub trait LocationService: Send + Sync {
+    /// Bindings of `aor`, expired ones may be included
+    async fn lookup(&self, aor: &str) -> Result<Vec<Binding>>;
+    /// Adds `binding`, or replaces the one it matches: the binding of the
+    /// same contact URI, or of the same instance and reg-id
+    async fn update(&self, aor: &str, binding: Binding) -> Result<()>;
+    async fn remove(&self, aor: &str, contact: &rsip::Uri) -> Result<()>;
+    async fn remove_all(&self, aor: &str) -> Result<()>;
+}
+pub type LocationServiceRef = Arc<dyn LocationService>;
+
+/// Default location service, local to the process
+#[derive(Default)]
+pub struct MemoryLocationService {
+    bindings: RwLock<HashMap<String, Vec<Binding>>>,
+}
+
+impl MemoryLocationService {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait::async_trait]
+impl LocationService for MemoryLocationService {
+    async fn lookup(&self, aor: &str) -> Result<Vec<Binding>> {
+        Ok(self
+            .bindings
+            .read()
+            .unwrap()
+            .get(aor)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn update(&self, aor: &str, binding: Binding) -> Result<()> {
+        let mut bindings = self.bindings.write().unwrap();
+        let entry = bindings.entry(aor.to_string()).or_default();
+        entry.retain(|b| {
+            !b.matches(
+                binding.instance.as_ref(),
+                binding.reg_id,
+                &binding.contact.uri,
+            ) && b.remaining() > 0
+        });
+        entry.push(binding);
+        Ok(())
+    }
+
+    async fn remove(&self, aor: &str, contact: &rsip::Uri) -> Result<()> {
+        let mut bindings = self.bindings.write().unwrap();
+        if let Some(entry) = bindings.get_mut(aor) {
+            entry.retain(|b| &b.contact.uri != contact);
+            if entry.is_empty() {
+                bindings.remove(aor);
+            }
+        }
+        Ok(())
+    }
+
+    async fn remove_all(&self, aor: &str) -> Result<()> {
+        self.bindings.write().unwrap().remove(aor);
+        Ok(())
+    }
+}
+
+/// Passwords of the users allowed to register
+#[async_trait::async_trait]
+pub trait PasswordStore: Send + Sync {
+    async fn password(&self, username: &str, realm: &str) -> Result<Option<String>>;
+}
+pub type PasswordStoreRef = Arc<dyn PasswordStore>;
+
+/// Whether the authenticated user, the first argument, may modify the
+/// bindings of the address-of-record
+pub type AorAuthorizer = Arc<dyn Fn(&str, &rsip::Uri) -> bool + Send + Sync>;
+
+/// The passwords of a `PasswordStore` as digest secrets
+struct PasswordSecrets(PasswordStoreRef);
+
+#[async_trait::async_trait]
+impl DigestSecretStore for PasswordSecrets {
+    async fn secret(
+        &self,
+        username: &str,
+        realm: &str,
+        _algorithm: DigestAlgorithm,
+    ) -> Result<Option<DigestSecret>> {
+        Ok(self
+            .0
+            .password(username, realm)
+            .await?
+            .map(DigestSecret::Password))
+    }
+}
+
+/// Registrar (RFC 3261 10.3) answering REGISTER requests: it challenges the
+/// client when credentials are set, enforces the expires bounds, and
+/// replies with every binding of the address-of-record.
+pub struct Registrar {
+    pub realm: String,
+    /// Shorter registrations are rejected with 423 Interval Too Brief
+    pub min_expires: u32,
+    /// Longer registrations are shortened to it
+    pub max_expires: u32,
+    /// Expiration of a contact without expires parameter nor Expires header
+    pub default_expires: u32,
+    location: LocationServiceRef,
+    algorithms: Vec<DigestAlgorithm>,
+    authenticator: Option<DigestAuthenticator>,
+    aor_authorizer: Option<AorAuthorizer>,
+    reg_event: Option<Arc<RegEventNotifier>>,
+}
+
+impl Registrar {
+    pub fn new(realm: &str, location: LocationServiceRef) -> Self {
+        Self {
+            realm: realm.to_string(),
+            min_expires: 60,
+            max_expires: 7200,
+            default_expires: 3600,
+            location,
+            algorithms: vec![DigestAlgorithm::Md5],
+            authenticator: None,
+            aor_authorizer: None,
+            reg_event: None,
+        }
+    }
+
+    /// Digest-challenges every REGISTER, checking answers against `credentials`
+    pub fn with_credentials(self, credentials: PasswordStoreRef) -> Self {
+        let authenticator =
+            DigestAuthenticator::new(&self.realm, Arc::new(PasswordSecrets(credentials)))
+                .with_algorithms(self.algorithms.clone());
+        self.with_authenticator(authenticator)
+    }
+
+    /// Digest-challenges every REGISTER with `authenticator`, e.g. one
+    /// checking the HA1s of the users instead of their passwords
+    pub fn with_authenticator(mut self, authenticator: DigestAuthenticator) -> Self {
+        self.algorithms = authenticator.algorithms.clone();
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Challenges with one WWW-Authenticate per algorithm of `algorithms`,
+    /// e.g. SHA-256 then MD5 for the older clients (RFC 8760)
+    pub fn with_algorithms(mut self, algorithms: Vec<DigestAlgorithm>) -> Self {
+        if let Some(authenticator) = self.authenticator.as_mut() {
+            authenticator.algorithms = algorithms.clone();
+        }
+        self.algorithms = algorithms;
+        self
+    }
+
+    /// Decides which addresses-of-record an authenticated user may register,
+    /// by default only the one of its own username
+    pub fn with_aor_authorizer(mut self, authorizer: AorAuthorizer) -> Self {
+        self.aor_authorizer = Some(authorizer);
+        self
+    }
+
+    /// Whether `username` may modify the bindings of `aor`
+    pub fn authorize(&self, username: &str, aor: &rsip::Uri) -> bool {
+        match self.aor_authorizer.as_ref() {
+            Some(authorizer) => authorizer(username, aor),
+            None => aor.auth.as_ref().is_some_and(|auth| auth.user == username),
+        }
+    }
+
+    /// Notifies the watchers of `notifier` of every binding change
+    pub fn with_reg_event(mut self, notifier: Arc<RegEventNotifier>) -> Self {
+        self.reg_event = Some(notifier);
+        self
+    }
+
+    pub fn location(&self) -> &LocationServiceRef {
+        &self.location
+    }
+
+    /// Answers the REGISTER of the server transaction `tx`, remembering the
+    /// flow it came over once it registered an outbound flow
+    pub async fn handle(&self, tx: &mut Transaction) -> Result<()> {
+        let endpoint = tx.endpoint_inner.clone();
+        let flow = match tx.connection.as_ref() {
+            Some(connection) if uses_outbound(&tx.original) => {
+                let remote = flow_remote(connection, &tx.original)?;
+                Some((connection.clone(), remote))
+            }
+            _ => None,
+        };
+        let token = flow
+            .as_ref()
+            .map(|(_, remote)| endpoint.transport_layer.flow_token(remote));
+        let (status, headers) = self
+            .process_with_flow(&tx.original, token.as_deref())
+            .await?;
+        if status == StatusCode::OK {
+            if let Some((connection, remote)) = flow {
+                endpoint.transport_layer.register_flow(connection, remote);
+            }
+        }
+        tx.reply_with(status, headers, None).await
+    }
+
+    /// Status and headers of the answer to `request`, the bindings being
+    /// updated when it's a 200 OK
+    pub async fn process(&self, request: &Request) -> Result<(StatusCode, Vec<Header>)> {
+        self.process_with_flow(request, None).await
+    }
+
+    /// Same as `process`, outbound bindings (RFC 5626 6) being bound to the
+    /// flow of token `flow` the request was received over
+    pub async fn process_with_flow(
+        &self,
+        request: &Request,
+        flow: Option<&str>,
+    ) -> Result<(StatusCode, Vec<Header>)> {
+        let to_uri = request.to_header()?.typed()?.uri;
+        if let Some(authenticator) = self.authenticator.as_ref() {
+            match authenticator.check(request, false).await? {
+                DigestVerdict::Authorized(username) => {
+                    if !self.authorize(&username, &to_uri) {
+                        info!("{} may not register {}", username, to_uri);
+                        return Ok((StatusCode::Forbidden, vec![]));
+                    }
+                }
+                DigestVerdict::UriMismatch => return Ok((StatusCode::BadRequest, vec![])),
+                verdict => {
+                    let stale = verdict == DigestVerdict::Stale;
+                    return Ok((
+                        StatusCode::Unauthorized,
+                        authenticator.challenge_headers(false, stale),
+                    ));
+                }
+            }
+        }
+
+        let aor = aor_of(&to_uri);
+        let call_id = request.call_id_header()?.value().to_string();
+        let cseq = request.cseq_header()?.seq()?;
+        let header_expires =
+            header_value(&request.headers, "Expires").and_then(|e| e.trim().parse::<u32>().ok());
+        let contacts = contact_values(&request.headers);
+        let outbound = uses_outbound(request);
+        let path = match path_values(&request.headers)
+            .into_iter()
+            .map(|v| route_uri(&rsip::headers::Route::new(v)))
+            .collect::<Option<Vec<_>>>()
+        {
+            Some(path) => path,
+            None => return Ok((StatusCode::BadRequest, vec![])),
+        };
+
+        if contacts.iter().any(|c| c == "*") {
+            // wildcard removal of all the bindings (RFC 3261 10.2.2)
+            if contacts.len() > 1 || header_expires != Some(0) {
+                return Ok((StatusCode::BadRequest, vec![]));
+            }
+            info!("removing all bindings of {}", aor);
+            let changes = self
+                .location
+                .lookup(&aor)
+                .await?
+                .into_iter()
+                .map(|b| (b.contact.uri, ContactEvent::Unregistered))
+                .collect::<Vec<_>>();
+            self.location.remove_all(&aor).await?;
+            self.notify_changes(&aor, &changes).await?;
+            return Ok((StatusCode::OK, vec![]));
+        }
+
+        let existing = self.location.lookup(&aor).await?;
+        let mut updates = vec![];
+        for value in contacts.iter() {
+            let mut binding = match ContactBinding::parse(
+                value,
+                header_expires.unwrap_or(self.default_expires),
+            ) {
+                Some(binding) => binding,
+                None => return Ok((StatusCode::BadRequest, vec![])),
+            };
+            if !outbound || binding.instance.is_none() {
+                // reg-id is meaningless without outbound support
+                binding.reg_id = None;
+            }
+            if binding.expires != 0 && binding.expires < self.min_expires {
+                return Ok((
+                    StatusCode::from(423),
+                    vec![Header::Other(
+                        "Min-Expires".into(),
+                        self.min_expires.to_string(),
+                    )],
+                ));
+            }
+            let previous = existing.iter().find(|b| {
+                b.matches(
+                    binding.instance.as_ref(),
+                    binding.reg_id,
+                    &binding.contact.uri,
+                )
+            });
+            if let Some(previous) = previous {
+                if previous.call_id == call_id && previous.cseq >= cseq {
+                    // an older REGISTER arriving late must not undo a newer one
+                    return Ok((StatusCode::ServerInternalError, vec![]));
+                }
+            }
+            updates.push((binding, previous.cloned()));
+        }
+        let flows = updates.iter().filter(|(b, _)| b.reg_id.is_some()).count();
+        if flows > 1 {
+            // a REGISTER binds a single outbound flow (RFC 5626 6)
+            return Ok((StatusCode::BadRequest, vec![]));
+        }
+
+        let mut changes = vec![];
+        for (binding, previous) in updates {
+            if let Some(previous) = previous.as_ref() {
+                let uri = &previous.contact.uri;
+                if binding.expires == 0 || uri != &binding.contact.uri {
+                    self.location.remove(&aor, uri).await?;
+                    // other flows of the instance may share the contact URI
+                    for other in existing.iter().filter(|b| {
+                        &b.contact.uri == uri
+                            && !b.matches(previous.instance.as_ref(), previous.reg_id, uri)
+                    }) {
+                        self.location.update(&aor, other.clone()).await?;
+                    }
+                    changes.push((uri.clone(), ContactEvent::Unregistered));
+                }
+            }
+            if binding.expires == 0 {
+                continue;
+            }
+            let previous = previous.map(|b| b.contact.uri);
+            let mut contact = binding.contact;
+            contact.params.retain(|p| !matches!(p, Param::Expires(_)));
+            let expires = binding.expires.min(self.max_expires);
+            let event = if expires < binding.expires {
+                ContactEvent::Shortened
+            } else if previous.as_ref() == Some(&contact.uri) {
+                ContactEvent::Refreshed
+            } else {
+                ContactEvent::Created
+            };
+            changes.push((contact.uri.clone(), event));
+            self.location
+                .update(
+                    &aor,
+                    Binding {
+                        contact,
+                        call_id: call_id.clone(),
+                        cseq,
+                        expires_at: SystemTime::now() + Duration::from_secs(expires as u64),
+                        instance: binding.instance,
+                        reg_id: binding.reg_id,
+                        flow: binding.reg_id.and(flow.map(|token| token.to_string())),
+                        path: path.clone(),
+                    },
+                )
+                .await?;
+        }
+
+        self.notify_changes(&aor, &changes).await?;
+
+        let mut headers: Vec<Header> = self
+            .location
+            .lookup(&aor)
+            .await?
+            .into_iter()
+            .filter(|b| b.remaining() > 0)
+            .map(|b| {
+                let remaining = b.remaining();
+                let mut contact = b.contact;
+                contact
+                    .params
+                    .push(Param::Expires(remaining.to_string().into()));
+                Header::Contact(contact.into())
+            })
+            .collect();
+        if flows > 0 {
+            headers.push(Header::Require("outbound".into()));
+        }
+        if !path.is_empty() && has_supported(&request.headers, "path") {
+            // the UA learns the proxies that will route its requests
+            headers.extend(
+                path.iter()
+                    .map(|uri| Header::Other("Path".into(), format!("<{}>", uri))),
+            );
+        }
+        Ok((StatusCode::OK, headers))
+    }
+
+    /// Removes a binding on the server side, e.g. by an administrator, its
+    /// watchers learn it was deactivated and should register again
+    pub async fn remove_binding(&self, aor: &str, contact: &rsip::Uri) -> Result<()> {
+        self.location.remove(aor, contact).await?;
+        self.notify_changes(aor, &[(contact.clone(), ContactEvent::Deactivated)])
+            .await
+    }
+
+    async fn notify_changes(&self, aor: &str, changes: &[(rsip::Uri, ContactEvent)]) -> Result<()> {
+        let notifier = match self.reg_event.as_ref() {
+            Some(notifier) if !changes.is_empty() => notifier,
+            _ => return Ok(()),
+        };
+        let bindings = self.location.lookup(aor).await?;
+        notifier
+            .notify(&RegistrationInfo::new(aor, &bindings, changes))
+            .await;
+        Ok(())
+    }
+}
+
+/// Whether `request` registers an outbound flow: the UA supports outbound
+/// and a Contact carries a reg-id (RFC 5626 4.2)
+fn uses_outbound(request: &Request) -> bool {
+    has_supported(&request.headers, "outbound")
+        && contact_values(&request.headers)
+            .iter()
+            .filter_map(|c| ContactBinding::parse(c, 0))
+            .any(|b| b.reg_id.is_some())
+}
+
+/// Address-of-record of a URI, i.e. without parameters nor headers: the To
+/// of a REGISTER, or the Request-URI of a reg SUBSCRIBE
+pub fn aor_of(uri: &rsip::Uri) -> String {
+    let mut uri = uri.clone();
+    uri.params.clear();
+    uri.headers.clear();
+    uri.to_string()
+}
+
+/// Answers REGISTER requests, any other request with 405 Method Not
+/// Allowed, when the endpoint is only a registrar
+#[async_trait::async_trait]
+impl RequestHandler for Registrar {
+    async fn on_request(&self, request: IncomingRequest) -> Result<()> {
+        let mut tx = request.into_transaction();
+        match tx.original.method {
+            rsip::Method::Register => self.handle(&mut tx).await,
+            _ => tx.reply(StatusCode::MethodNotAllowed).await,
+        }
+    }
+}
@@ -1,44 +1,182 @@
 use super::{
-    authenticate::{handle_client_authenticate, Credential},
+    authenticate::{handle_client_authenticate, AuthAttempts, CredentialStoreRef},
+    dialog::next_cseq,
     DialogId,
 };
 use crate::{
+    rsip_ext::{
+        add_supported, address_values, contact_values, header_value, route_uri, set_route_set,
+    },
     transaction::{
         endpoint::EndpointInnerRef,
         key::{TransactionKey, TransactionRole},
         make_tag,
         transaction::Transaction,
     },
-    transport::SipAddr,
+    transport::{SipAddr, SipConnection},
     Error, Result,
 };
 use get_if_addrs::get_if_addrs;
-use rsip::{HostWithPort, Response, SipMessage, StatusCode};
+use rsip::{prelude::ToTypedHeader, HostWithPort, Param, Response, SipMessage, StatusCode};
 use rsip_dns::trust_dns_resolver::TokioAsyncResolver;
 use rsip_dns::ResolvableExt;
 use std::net::IpAddr;
 use tracing::info;
 
+/// A Contact bound to the AOR, as listed by the 2xx of a REGISTER
+#[derive(Clone, Debug)]
+pub struct ContactBinding {
+    pub contact: rsip::typed::Contact,
+    /// Seconds until the binding expires, granted by the registrar
+    pub expires: u32,
+    pub q: Option<f32>,
+    /// The `+sip.instance` of the device, without quotes nor brackets
+    pub instance: Option<String>,
+    /// The `reg-id` of an outbound flow (RFC 5626 4.2)
+    pub reg_id: Option<u32>,
+}
+
+impl ContactBinding {
+    /// Parses one contact of a Contact header, see `contact_values`, which
+    /// expires after `default_expires` unless it has an expires parameter
+    pub fn parse(value: &str, default_expires: u32) -> Option<Self> {
+        let contact = rsip::headers::Contact::new(value).typed().ok()?;
+        let expires = contact
+            .expires()
+            .and_then(|e| e.seconds().ok())
+            .unwrap_or(default_expires);
+        let q = contact.params.iter().find_map(|p| match p {
+            Param::Q(q) => q.value().parse().ok(),
+            _ => None,
+        });
+        let instance = contact.params.iter().find_map(|p| match p {
+            Param::Other(name, Some(value)) if name.value() == "+sip.instance" => Some(
+                value
+                    .value()
+                    .trim_matches(|c| c == '"' || c == '<' || c == '>')
+                    .to_string(),
+            ),
+            _ => None,
+        });
+        let reg_id = contact.params.iter().find_map(|p| match p {
+            Param::Other(name, Some(value)) if name.value() == "reg-id" => {
+                value.value().parse().ok()
+            }
+            _ => None,
+        });
+        Some(ContactBinding {
+            contact,
+            expires,
+            q,
+            instance,
+            reg_id,
+        })
+    }
+}
+
+/// Sets the preference of `contact` among the bindings of the AOR, from 0 to 1
+pub fn set_contact_q(contact: &mut rsip::typed::Contact, q: f32) {
+    contact.params.retain(|p| !matches!(p, Param::Q(_)));
+    contact
+        .params
+        .push(Param::Q(format!("{}", q.clamp(0.0, 1.0)).into()));
+}
+
+/// Sets the `+sip.instance` of `contact` (RFC 5626 4.1), e.g. `urn:uuid:...`,
+/// telling the registrar the bindings of one device apart
+pub fn set_contact_instance(contact: &mut rsip::typed::Contact, instance: &str) {
+    contact
+        .params
+        .retain(|p| !matches!(p, Param::Other(name, _) if name.value() == "+sip.instance"));
+    contact.params.push(Param::Other(
+        "+sip.instance".into(),
+        Some(format!("\"<{}>\"", instance).into()),
+    ));
+}
+
 pub struct Registration {
     pub last_seq: u32,
     pub endpoint: EndpointInnerRef,
-    pub credential: Option<Credential>,
+    pub credential: Option<CredentialStoreRef>,
     pub contact: Option<rsip::typed::Contact>,
+    /// Further bindings registered along with `contact`, e.g. other
+    /// transports or instances of the device
+    pub contacts: Vec<rsip::typed::Contact>,
     pub allow: rsip::headers::Allow,
+    /// Overrides the preloaded route set of the endpoint
+    pub route_set: Option<Vec<rsip::Uri>>,
+    /// Every binding of the AOR listed by the last 2xx, those of other
+    /// devices included
+    pub bindings: Vec<ContactBinding>,
+    /// `+sip.instance` of the device, registering an outbound flow (RFC 5626)
+    /// together with `reg_id`
+    pub instance: Option<String>,
+    pub reg_id: Option<u32>,
+    /// Service-Route (RFC 3608) of the last 2xx, also set as the service
+    /// route of the endpoint
+    pub service_route: Vec<rsip::Uri>,
+    granted_expires: Option<u32>,
+    flow: Option<(SipConnection, Option<SipAddr>)>,
 }
 
 impl Registration {
-    pub fn new(endpoint: EndpointInnerRef, credential: Option<Credential>) -> Self {
+    pub fn new(endpoint: EndpointInnerRef, credential: Option<CredentialStoreRef>) -> Self {
         Self {
             last_seq: 0,
             endpoint,
             credential,
             contact: None,
+            contacts: vec![],
             allow: Default::default(),
+            route_set: None,
+            bindings: vec![],
+            instance: None,
+            reg_id: None,
+            service_route: vec![],
+            granted_expires: None,
+            flow: None,
+        }
+    }
+
+    /// Registers an outbound flow (RFC 5626 4.2) of the device `instance`,
+    /// e.g. `urn:uuid:...`, each flow of the device with its own `reg_id`
+    pub fn with_outbound(mut self, instance: &str, reg_id: u32) -> Self {
+        self.instance = Some(instance.to_string());
+        self.reg_id = Some(reg_id);
+        self
+    }
+
+    /// Whether the last REGISTER succeeded over a flow still usable
+    pub fn has_flow(&self) -> bool {
+        self.flow.is_some()
+    }
+
+    /// Sends a CRLF keepalive over the flow of the last REGISTER, an error
+    /// means the flow failed and must be registered again
+    pub async fn ping_flow(&mut self) -> Result<()> {
+        let (connection, destination) = self
+            .flow
+            .as_ref()
+            .ok_or(Error::Error("no registered flow".to_string()))?;
+        if let Err(e) = connection.send_keepalive(destination.as_ref()).await {
+            info!("registration flow failed: {:?}", e);
+            self.flow = None;
+            return Err(e);
         }
+        Ok(())
+    }
+
+    /// Registers `contact` as a further binding of the AOR
+    pub fn add_contact(&mut self, contact: rsip::typed::Contact) {
+        self.contacts.push(contact);
     }
 
+    /// Seconds the registrar granted to our bindings by the last 2xx, or else
+    /// the expires asked for by `contact`
     pub fn expires(&self) -> u32 {
+        if let Some(expires) = self.granted_expires {
+            return expires;
+        }
         self.contact
             .as_ref()
             .and_then(|c| c.expires())
@@ -58,7 +196,7 @@ impl Registration {
     }
 
     pub async fn register(&mut self, server: &String) -> Result<Response> {
-        self.last_seq += 1;
+        self.last_seq = next_cseq(self.last_seq);
 
         let recipient = rsip::Uri::try_from(format!("sip:{}", server))?;
 
@@ -68,9 +206,14 @@ impl Registration {
             params: vec![],
         };
 
-        if let Some(cred) = &self.credential {
+        let destination = recipient.host_with_port.to_string();
+        if let Some(user) = self
+            .credential
+            .as_ref()
+            .and_then(|cred| cred.username(&destination))
+        {
             to.uri.auth = Some(rsip::auth::Auth {
-                user: cred.username.clone(),
+                user,
                 password: None,
             });
         }
@@ -107,7 +250,7 @@ impl Registration {
                 }?,
             }
         };
-        let contact = self
+        let mut contact = self
             .contact
             .clone()
             .unwrap_or_else(|| rsip::typed::Contact {
@@ -131,14 +274,43 @@ impl Registration {
             self.last_seq,
         );
 
+        if let Some(instance) = self.instance.as_ref() {
+            set_contact_instance(&mut contact, instance);
+            if let Some(reg_id) = self.reg_id {
+                contact
+                    .params
+                    .retain(|p| !matches!(p, Param::Other(name, _) if name.value() == "reg-id"));
+                contact.params.push(Param::Other(
+                    "reg-id".into(),
+                    Some(reg_id.to_string().into()),
+                ));
+            }
+            add_supported(&mut request.headers, "outbound");
+        }
+        // edge proxies may record themselves with Path (RFC 3327)
+        add_supported(&mut request.headers, "path");
+
+        let mut own_contacts = vec![contact.uri.clone()];
         request.headers.unique_push(contact.into());
+        for contact in self.contacts.iter() {
+            own_contacts.push(contact.uri.clone());
+            request.headers.push(contact.clone().into());
+        }
         request.headers.unique_push(self.allow.clone().into());
+        if let Some(route_set) = self.route_set.as_ref() {
+            set_route_set(&mut request, route_set);
+        }
+        if let Some(cred) = &self.credential {
+            self.endpoint
+                .auth_cache
+                .authorize_request(&mut request, cred);
+        }
 
         let key = TransactionKey::from_request(&request, TransactionRole::Client)?;
         let mut tx = Transaction::new_client(key, request, self.endpoint.clone(), None);
 
         tx.send().await?;
-        let mut auth_sent = false;
+        let mut auth_attempts = AuthAttempts::default();
 
         while let Some(msg) = tx.receive().await {
             match msg {
@@ -147,16 +319,16 @@ impl Registration {
                         continue;
                     }
                     StatusCode::ProxyAuthenticationRequired | StatusCode::Unauthorized => {
-                        if auth_sent {
+                        if !auth_attempts.may_answer(&resp) {
                             info!("received {} response after auth sent", resp.status_code);
+                            self.endpoint.auth_cache.forget(&tx.original);
                             return Ok(resp);
                         }
 
                         if let Some(cred) = &self.credential {
-                            self.last_seq += 1;
+                            self.last_seq = next_cseq(self.last_seq);
                             tx = handle_client_authenticate(self.last_seq, tx, resp, cred).await?;
                             tx.send().await?;
-                            auth_sent = true;
                             continue;
                         } else {
                             info!("received {} response without credential", resp.status_code);
@@ -165,6 +337,16 @@ impl Registration {
                     }
                     _ => {
                         info!("registration do_request done: {:?}", resp.status_code);
+                        if resp.status_code.kind() == rsip::StatusCodeKind::Successful {
+                            self.update_bindings(&resp, &own_contacts);
+                            let destination = tx
+                                .destination
+                                .clone()
+                                .or_else(|| SipAddr::try_from(&tx.original.uri).ok());
+                            self.flow = tx.connection.clone().map(|c| (c, destination));
+                        } else if tx.transport_failure().is_some() {
+                            self.flow = None;
+                        }
                         return Ok(resp);
                     }
                 },
@@ -176,4 +358,27 @@ impl Registration {
             DialogId::try_from(&tx.original)?,
         ));
     }
+
+    /// Records the bindings listed by a 2xx, and the shortest expires granted
+    /// to ours
+    fn update_bindings(&mut self, resp: &Response, own_contacts: &[rsip::Uri]) {
+        let default_expires = header_value(&resp.headers, "Expires")
+            .and_then(|e| e.trim().parse().ok())
+            .unwrap_or_else(|| self.expires());
+        self.bindings = contact_values(&resp.headers)
+            .iter()
+            .filter_map(|value| ContactBinding::parse(value, default_expires))
+            .collect();
+        self.granted_expires = self
+            .bindings
+            .iter()
+            .filter(|b| own_contacts.contains(&b.contact.uri))
+            .map(|b| b.expires)
+            .min();
+        self.service_route = address_values(&resp.headers, "Service-Route")
+            .into_iter()
+            .filter_map(|v| route_uri(&rsip::headers::Route::new(v)))
+            .collect();
+        self.endpoint.set_service_route(self.service_route.clone());
+    }
 }
@@ -1,11 +1,21 @@
 use super::dialog::{Dialog, DialogInnerRef};
 use super::DialogId;
-use crate::dialog::dialog::DialogState;
+use crate::dialog::dialog::{DialogState, OfferAnswerHandlerRef, SessionRefreshMethod};
+use crate::dialog::dialog_event::DialogEvent;
+use crate::dialog::dtmf::{DtmfEvent, DTMF_RELAY_CONTENT_TYPE};
+use crate::dialog::expiration::DialogExpiration;
+use crate::dialog::identity::{verify_request, IdentityVerification, IdentityVerifierRef};
+use crate::dialog::keepalive::DialogKeepalive;
+use crate::dialog::reason::Reason;
+use crate::dialog::usage::DialogUsage;
+use crate::rsip_ext::{has_required, has_supported, header_value};
 use crate::transaction::transaction::{Transaction, TransactionEvent};
 use crate::Result;
 use rsip::prelude::HeadersExt;
-use rsip::{Header, Request, SipMessage, StatusCode};
-use std::sync::atomic::Ordering;
+use rsip::{Header, Request, Response, SipMessage, StatusCode};
+use std::sync::{atomic::Ordering, Arc};
+use std::time::Duration;
+use tokio::{select, sync::oneshot, time::sleep};
 use tokio_util::sync::CancellationToken;
 use tracing::{info, trace, warn};
 
@@ -14,6 +24,36 @@ pub struct ServerInviteDialog {
     pub(super) inner: DialogInnerRef,
 }
 
+/// Contact of a 3xx sent by `ServerInviteDialog::redirect`
+#[derive(Clone, Debug)]
+pub struct RedirectTarget {
+    pub uri: rsip::Uri,
+    /// Preference among the targets, from 0 to 1
+    pub q: Option<f32>,
+    pub expires: Option<u32>,
+}
+
+impl RedirectTarget {
+    pub fn new(uri: rsip::Uri) -> Self {
+        Self {
+            uri,
+            q: None,
+            expires: None,
+        }
+    }
+
+    pub fn to_header(&self) -> Header {
+        let mut value = format!("<{}>", self.uri);
+        if let Some(q) = self.q {
+            value.push_str(&format!(";q={:.3}", q.clamp(0.0, 1.0)));
+        }
+        if let Some(expires) = self.expires {
+            value.push_str(&format!(";expires={}", expires));
+        }
+        Header::Contact(value.into())
+    }
+}
+
 impl ServerInviteDialog {
     pub fn id(&self) -> DialogId {
         self.inner.id.lock().unwrap().clone()
@@ -25,8 +65,17 @@ impl ServerInviteDialog {
         &self.inner.initial_request
     }
 
+    /// The INVITE came without SDP: the offer goes in the 2xx given to
+    /// `accept`, and the answer comes back in the ACK
+    pub fn is_delayed_offer(&self) -> bool {
+        self.inner.initial_request.body.is_empty()
+    }
+
     pub fn accept(&self, headers: Option<Vec<Header>>, body: Option<Vec<u8>>) -> Result<()> {
         if let Some(sender) = self.inner.tu_sender.lock().unwrap().as_ref() {
+            if let Some(body) = body.as_ref().filter(|b| !b.is_empty()) {
+                self.inner.local_sdp.lock().unwrap().replace(body.clone());
+            }
             let resp = self.inner.make_response(
                 &self.inner.initial_request,
                 rsip::StatusCode::OK,
@@ -37,7 +86,7 @@ impl ServerInviteDialog {
             sender.send(TransactionEvent::Respond(resp.clone()))?;
 
             self.inner
-                .transition(DialogState::WaitAck(self.id(), resp))?;
+                .transition(DialogState::WaitAck(self.id(), Arc::new(resp)))?;
             Ok(())
         } else {
             Err(crate::Error::DialogError(
@@ -47,14 +96,170 @@ impl ServerInviteDialog {
         }
     }
 
-    pub fn reject(&self) -> Result<()> {
+    /// Sends a provisional response to the INVITE, e.g. 180 Ringing, without
+    /// waiting for any acknowledgement
+    pub fn provisional(
+        &self,
+        status: StatusCode,
+        headers: Option<Vec<Header>>,
+        body: Option<Vec<u8>>,
+    ) -> Result<()> {
+        if status.kind() != rsip::StatusCodeKind::Provisional || status == StatusCode::Trying {
+            return Err(crate::Error::DialogError(
+                format!("not a provisional status: {}", status),
+                self.id(),
+            ));
+        }
+        let sender = match self.inner.tu_sender.lock().unwrap().as_ref() {
+            Some(sender) => sender.clone(),
+            None => {
+                return Err(crate::Error::DialogError(
+                    "transaction is already terminated".to_string(),
+                    self.id(),
+                ))
+            }
+        };
+        if let Some(body) = body.as_ref().filter(|b| !b.is_empty()) {
+            self.inner.local_sdp.lock().unwrap().replace(body.clone());
+        }
+        let resp = self
+            .inner
+            .make_response(&self.inner.initial_request, status, headers, body);
+        sender.send(TransactionEvent::Respond(resp.clone()))?;
+        self.inner
+            .transition(DialogState::Early(self.id(), Arc::new(resp)))
+    }
+
+    pub fn ringing(&self, headers: Option<Vec<Header>>) -> Result<()> {
+        self.provisional(StatusCode::Ringing, headers, None)
+    }
+
+    /// Sends 183 Session Progress with the early media SDP in `body`
+    pub fn session_progress(
+        &self,
+        headers: Option<Vec<Header>>,
+        body: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let mut headers = headers.unwrap_or_default();
+        if body.as_ref().is_some_and(|b| !b.is_empty())
+            && !headers.iter().any(|h| matches!(h, Header::ContentType(_)))
+        {
+            headers.push(Header::ContentType("application/sdp".into()));
+        }
+        self.provisional(StatusCode::SessionProgress, Some(headers), body)
+    }
+
+    /// Sends a reliable provisional response (RFC 3262) with `Require: 100rel`
+    /// and a new RSeq, retransmitting it until the PRACK arrives. Resolves with
+    /// the PRACK, fails when none is received within 64*T1 or when the INVITE
+    /// neither supports nor requires 100rel.
+    pub async fn provisional_reliable(
+        &self,
+        status: StatusCode,
+        headers: Option<Vec<Header>>,
+        body: Option<Vec<u8>>,
+    ) -> Result<Request> {
+        let invite_headers = &self.inner.initial_request.headers;
+        if !has_supported(invite_headers, "100rel") && !has_required(invite_headers, "100rel") {
+            return Err(crate::Error::DialogError(
+                "the caller doesn't support 100rel".to_string(),
+                self.id(),
+            ));
+        }
+        let sender = match self.inner.tu_sender.lock().unwrap().as_ref() {
+            Some(sender) => sender.clone(),
+            None => {
+                return Err(crate::Error::DialogError(
+                    "transaction is already terminated".to_string(),
+                    self.id(),
+                ))
+            }
+        };
+        let rseq = self.inner.local_rseq.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut headers = headers.unwrap_or_default();
+        headers.push(Header::Other("Require".into(), "100rel".into()));
+        headers.push(Header::Other("RSeq".into(), rseq.to_string()));
+        let resp =
+            self.inner
+                .make_response(&self.inner.initial_request, status, Some(headers), body);
+
+        let (prack_sender, mut prack_receiver) = oneshot::channel();
+        self.inner
+            .pending_prack
+            .lock()
+            .unwrap()
+            .replace((rseq, prack_sender));
+        self.inner
+            .transition(DialogState::Early(self.id(), Arc::new(resp.clone())))?;
+
+        let t1 = self.inner.endpoint_inner.t1;
+        let t1x64 = self.inner.endpoint_inner.t1x64;
+        let mut interval = t1;
+        let mut elapsed = Duration::ZERO;
+        while elapsed < t1x64 {
+            sender.send(TransactionEvent::Respond(resp.clone()))?;
+            select! {
+                prack = &mut prack_receiver => {
+                    return prack.map_err(|_| crate::Error::DialogError(
+                        "prack waiter dropped".to_string(),
+                        self.id(),
+                    ));
+                }
+                _ = sleep(interval) => {}
+            }
+            elapsed += interval;
+            interval *= 2;
+        }
+        self.inner.pending_prack.lock().unwrap().take();
+        Err(crate::Error::DialogError(
+            format!("no prack received for rseq {}", rseq),
+            self.id(),
+        ))
+    }
+
+    /// Answers the INVITE with 302 Moved Temporarily, pointing the caller
+    /// to `targets`
+    pub fn redirect(&self, targets: Vec<RedirectTarget>) -> Result<()> {
+        if targets.is_empty() {
+            return Err(crate::Error::DialogError(
+                "no redirect target".to_string(),
+                self.id(),
+            ));
+        }
         if let Some(sender) = self.inner.tu_sender.lock().unwrap().as_ref() {
-            let resp = self.inner.make_response(
+            let mut resp = self.inner.make_response(
                 &self.inner.initial_request,
-                rsip::StatusCode::Decline,
+                rsip::StatusCode::MovedTemporarily,
                 None,
                 None,
             );
+            // the Contacts of a 3xx are the targets, not the local one, and
+            // make_response would keep only the last of them
+            resp.headers.retain(|h| !matches!(h, Header::Contact(_)));
+            for target in targets.iter() {
+                resp.headers.push(target.to_header());
+            }
+            sender
+                .send(TransactionEvent::Respond(resp))
+                .map_err(Into::into)
+        } else {
+            Err(crate::Error::DialogError(
+                "transaction is already terminated".to_string(),
+                self.id(),
+            ))
+        }
+    }
+
+    pub fn reject(&self) -> Result<()> {
+        self.reject_with(rsip::StatusCode::Decline, None)
+    }
+
+    /// Answers the INVITE with a final error `status`
+    pub fn reject_with(&self, status: StatusCode, headers: Option<Vec<Header>>) -> Result<()> {
+        if let Some(sender) = self.inner.tu_sender.lock().unwrap().as_ref() {
+            let resp = self
+                .inner
+                .make_response(&self.inner.initial_request, status, headers, None);
             sender
                 .send(TransactionEvent::Respond(resp))
                 .map_err(Into::into)
@@ -66,34 +271,204 @@ impl ServerInviteDialog {
         }
     }
 
-    pub async fn bye(&self) -> Result<()> {
+    /// Hangs up the confirmed dialog, `headers` are added to the BYE
+    pub async fn bye(&self, headers: Option<Vec<Header>>) -> Result<()> {
         if !self.inner.is_confirmed() {
             return Ok(());
         }
-        let request = self
-            .inner
-            .make_request(rsip::Method::Bye, None, None, None, None, None)?;
+        let request =
+            self.inner
+                .make_request(rsip::Method::Bye, None, None, None, headers, None)?;
+        let reason = Reason::from_headers(&request.headers);
         let resp = self.inner.do_request(request).await?;
         self.inner.transition(DialogState::Terminated(
             self.id(),
             resp.map(|r| r.status_code),
+            reason,
         ))?;
         Ok(())
     }
 
-    pub async fn reinvite(&self) -> Result<()> {
-        todo!()
+    pub async fn reinvite(
+        &self,
+        headers: Option<Vec<Header>>,
+        body: Option<Vec<u8>>,
+    ) -> Result<Option<Response>> {
+        self.inner.do_reinvite(headers, body).await
+    }
+
+    pub async fn update(
+        &self,
+        headers: Option<Vec<Header>>,
+        body: Option<Vec<u8>>,
+    ) -> Result<Option<Response>> {
+        self.inner.do_update(headers, body).await
+    }
+
+    /// Puts the call on hold with a `sendonly` re-INVITE of the local SDP
+    pub async fn hold(&self) -> Result<Option<Response>> {
+        self.inner.set_hold(true).await
+    }
+
+    /// Resumes a held call with a re-INVITE of the local SDP in its direction
+    /// before the hold, `sendrecv` by default
+    pub async fn unhold(&self) -> Result<Option<Response>> {
+        self.inner.set_hold(false).await
+    }
+
+    /// Terminates the dialog after a maximum lifetime or inactivity, so it
+    /// does not linger when the peer is gone
+    pub fn set_expiration(&self, expiration: DialogExpiration) {
+        self.inner.start_expiration(expiration);
+    }
+
+    /// Pings the peer periodically, terminating the dialog when it stops
+    /// answering
+    pub fn start_keepalive(&self, keepalive: DialogKeepalive) {
+        self.inner.start_keepalive(keepalive);
+    }
+
+    /// Typed events of the dialog from now on, see `DialogEvent`
+    pub fn events(&self) -> impl futures::Stream<Item = DialogEvent> {
+        self.inner.events()
+    }
+
+    /// Last session description sent to the peer, offer or answer
+    pub fn local_sdp(&self) -> Option<Vec<u8>> {
+        self.inner.local_sdp.lock().unwrap().clone()
+    }
+
+    /// Last session description received from the peer, offer or answer
+    pub fn remote_sdp(&self) -> Option<Vec<u8>> {
+        self.inner.remote_sdp.lock().unwrap().clone()
+    }
+
+    /// INVITE session and subscriptions currently sharing the dialog
+    pub fn usages(&self) -> Vec<DialogUsage> {
+        self.inner.usages()
+    }
+
+    /// Verifies the Identity of the INVITE (RFC 8224), the outcome being kept
+    /// for `identity()`. The application decides what to do of an
+    /// identity not valid, e.g. reject with `IdentityStatus::status_code`.
+    pub async fn verify_identity(&self, verifier: &IdentityVerifierRef) -> IdentityVerification {
+        let verification = verify_request(&self.inner.initial_request, verifier).await;
+        info!(
+            "identity of {}: {:?}, attestation {:?}",
+            self.id(),
+            verification.status,
+            verification.attestation()
+        );
+        self.inner
+            .identity
+            .lock()
+            .unwrap()
+            .replace(verification.clone());
+        verification
+    }
+
+    /// Outcome of `verify_identity`, `None` before it
+    pub fn identity(&self) -> Option<IdentityVerification> {
+        self.inner.identity.lock().unwrap().clone()
+    }
+
+    /// Sets the media layer hooks consulted when SDP is sent or received
+    pub fn set_offer_answer_handler(&self, handler: Option<OfferAnswerHandlerRef>) {
+        self.inner.set_offer_answer_handler(handler);
+    }
+
+    /// Accepts the INVITE with the answer of the offer/answer handler to its offer
+    pub async fn answer(&self, headers: Option<Vec<Header>>) -> Result<()> {
+        let handler = self.inner.offer_answer.lock().unwrap().clone();
+        let offer = self.inner.initial_request.body.clone();
+        let answer = match handler {
+            Some(handler) if !offer.is_empty() => handler.on_offer(offer).await?,
+            _ => {
+                return Err(crate::Error::DialogError(
+                    "no offer or offer/answer handler".to_string(),
+                    self.id(),
+                ))
+            }
+        };
+        let mut headers = headers.unwrap_or_default();
+        headers.push(Header::ContentType("application/sdp".into()));
+        self.accept(Some(headers), Some(answer))
+    }
+
+    pub fn set_refresh_method(&self, method: SessionRefreshMethod) {
+        *self.inner.refresh_method.lock().unwrap() = method;
+    }
+
+    /// Gives up on an in-dialog request (BYE, INFO, re-INVITE...) without a
+    /// final response after `timeout`, `None` waits for the transaction
+    pub fn set_request_timeout(&self, timeout: Option<Duration>) {
+        *self.inner.request_timeout.lock().unwrap() = timeout;
+    }
+
+    /// Refreshes the session with UPDATE or re-INVITE, see `SessionRefreshMethod`
+    pub async fn refresh_session(&self) -> Result<Option<Response>> {
+        self.inner.refresh_session().await
+    }
+
+    /// Asks the peer to call `target` (blind transfer), the transfer progress
+    /// is reported as `DialogState::ReferProgress`
+    pub async fn refer(
+        &self,
+        target: rsip::Uri,
+        headers: Option<Vec<Header>>,
+    ) -> Result<Option<Response>> {
+        self.inner.do_refer(target, headers).await
+    }
+
+    /// Accepts the REFER reported as `DialogState::Refer` with 202
+    pub fn accept_refer(&self) -> Result<()> {
+        self.inner.respond_refer(StatusCode::Accepted)
+    }
+
+    pub fn reject_refer(&self, status: StatusCode) -> Result<()> {
+        self.inner.respond_refer(status)
     }
 
-    pub async fn info(&self) -> Result<()> {
+    /// Reports the progress of an accepted REFER to the referrer, a final
+    /// status ends the implicit subscription
+    pub async fn notify_refer(&self, status: StatusCode) -> Result<()> {
+        self.inner.notify_refer_progress(status).await
+    }
+
+    /// Sends an in-dialog MESSAGE with `body` of `content_type`
+    pub async fn message(&self, content_type: &str, body: Vec<u8>) -> Result<Option<Response>> {
+        self.inner.do_message(content_type, body).await
+    }
+
+    /// Sends an in-dialog INFO, e.g. DTMF or a video fast-update request
+    pub async fn info(
+        &self,
+        headers: Option<Vec<Header>>,
+        content_type: Option<String>,
+        body: Option<Vec<u8>>,
+    ) -> Result<Option<Response>> {
         if !self.inner.is_confirmed() {
-            return Ok(());
+            return Ok(None);
         }
-        let request = self
-            .inner
-            .make_request(rsip::Method::Info, None, None, None, None, None)?;
-        self.inner.do_request(request).await?;
-        Ok(())
+        let mut headers = headers.unwrap_or_default();
+        if let Some(content_type) = content_type {
+            headers.push(Header::ContentType(content_type.into()));
+        }
+        let request =
+            self.inner
+                .make_request(rsip::Method::Info, None, None, None, Some(headers), body)?;
+        self.inner.do_request(request).await
+    }
+
+    /// Sends `digit` as an `application/dtmf-relay` INFO
+    pub async fn send_dtmf(&self, digit: char, duration: Duration) -> Result<Option<Response>> {
+        let event = DtmfEvent::new(digit, duration)?;
+        self.info(
+            None,
+            Some(DTMF_RELAY_CONTENT_TYPE.to_string()),
+            Some(event.to_body()),
+        )
+        .await
     }
 
     pub async fn handle(&mut self, mut tx: Transaction) -> Result<()> {
@@ -103,23 +478,21 @@ impl ServerInviteDialog {
             self.inner.state.lock().unwrap()
         );
 
-        let cseq = tx.original.cseq_header()?.seq()?;
-        if cseq < self.inner.remote_seq.load(Ordering::Relaxed) {
-            info!(
-                "received old request {} remote_seq: {} > {}",
-                tx.original.method(),
-                self.inner.remote_seq.load(Ordering::Relaxed),
-                cseq
-            );
-            // discard old request
+        if !self.inner.screen_request(&mut tx).await? {
             return Ok(());
         }
 
-        self.inner.remote_seq.store(cseq, Ordering::Relaxed);
-
         if self.inner.is_confirmed() {
+            if self.inner.invite_usage_ended(&tx.original.method) {
+                tx.reply(rsip::StatusCode::CallTransactionDoesNotExist)
+                    .await?;
+                return Ok(());
+            }
             match tx.original.method {
-                rsip::Method::Invite | rsip::Method::Ack => {
+                rsip::Method::Invite | rsip::Method::Update => {
+                    return self.inner.handle_session_update(tx).await
+                }
+                rsip::Method::Ack => {
                     info!(
                         "invalid request received {} {}",
                         tx.original.method, tx.original.uri
@@ -128,6 +501,10 @@ impl ServerInviteDialog {
                 rsip::Method::Bye => return self.handle_bye(tx).await,
                 rsip::Method::Info => return self.handle_info(tx).await,
                 rsip::Method::Options => return self.handle_options(tx).await,
+                rsip::Method::Message => return self.inner.handle_message(tx).await,
+                rsip::Method::Notify => return self.inner.handle_refer_notify(tx).await,
+                rsip::Method::Refer => return self.inner.handle_refer(tx).await,
+                rsip::Method::PRack => return self.handle_prack(tx).await,
                 _ => {
                     info!("invalid request method: {:?}", tx.original.method);
                     tx.reply(rsip::StatusCode::MethodNotAllowed).await?;
@@ -150,6 +527,7 @@ impl ServerInviteDialog {
                     }
                     return Ok(());
                 }
+                rsip::Method::PRack => return self.handle_prack(tx).await,
                 _ => {}
             }
         }
@@ -158,24 +536,59 @@ impl ServerInviteDialog {
 
     async fn handle_bye(&mut self, mut tx: Transaction) -> Result<()> {
         info!("received bye {}", tx.original.uri);
-        self.inner
-            .transition(DialogState::Terminated(self.id(), None))?;
+        if self.inner.end_usage(&DialogUsage::Invite)? {
+            self.inner.transition(DialogState::Terminated(
+                self.id(),
+                None,
+                Reason::from_headers(&tx.original.headers),
+            ))?;
+        }
         tx.reply(rsip::StatusCode::OK).await?;
         Ok(())
     }
 
     async fn handle_info(&mut self, mut tx: Transaction) -> Result<()> {
         info!("received info {}", tx.original.uri);
-        self.inner
-            .transition(DialogState::Info(self.id(), tx.original.clone()))?;
+        let state = match DtmfEvent::from_info(&tx.original) {
+            Some(event) => DialogState::Dtmf(self.id(), event),
+            None => DialogState::Info(self.id(), Arc::new(tx.original.clone())),
+        };
+        self.inner.transition(state)?;
         tx.reply(rsip::StatusCode::OK).await?;
         Ok(())
     }
 
+    async fn handle_prack(&mut self, mut tx: Transaction) -> Result<()> {
+        info!("received prack {}", tx.original.uri);
+        let rseq = header_value(&tx.original.headers, "RAck")
+            .and_then(|rack| rack.split_whitespace().next()?.parse::<u32>().ok());
+        let pending = {
+            let mut pending = self.inner.pending_prack.lock().unwrap();
+            match (pending.as_ref(), rseq) {
+                (Some((expected, _)), Some(rseq)) if *expected == rseq => pending.take(),
+                _ => None,
+            }
+        };
+        match pending {
+            Some((_, waiter)) => {
+                tx.reply(rsip::StatusCode::OK).await?;
+                waiter.send(tx.original.clone()).ok();
+            }
+            None => {
+                info!("prack without pending reliable provisional: {:?}", rseq);
+                tx.reply(rsip::StatusCode::CallTransactionDoesNotExist)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
     async fn handle_options(&mut self, mut tx: Transaction) -> Result<()> {
         info!("received options {}", tx.original.uri);
-        self.inner
-            .transition(DialogState::Options(self.id(), tx.original.clone()))?;
+        self.inner.transition(DialogState::Options(
+            self.id(),
+            Arc::new(tx.original.clone()),
+        ))?;
         tx.reply(rsip::StatusCode::OK).await?;
         Ok(())
     }
@@ -187,6 +600,16 @@ impl ServerInviteDialog {
             .unwrap()
             .replace(tx.tu_sender.clone());
 
+        let overload_status = self.inner.overload_status.lock().unwrap().clone();
+        if let Some(status) = overload_status {
+            info!("rejecting invite over the dialog limit: {}", status);
+            self.inner.tu_sender.lock().unwrap().take();
+            tx.reply(status.clone()).await?;
+            self.inner
+                .transition(DialogState::Terminated(self.id(), Some(status), None))?;
+            return Ok(());
+        }
+
         let handle_loop = async {
             if !self.inner.is_confirmed() {
                 self.inner.transition(DialogState::Calling(self.id()))?;
@@ -198,6 +621,27 @@ impl ServerInviteDialog {
                     SipMessage::Request(req) => match req.method {
                         rsip::Method::Ack => {
                             info!("received ack {}", req.uri);
+                            self.inner.update_remote_sdp(&req.body);
+                            // late offer, the answer comes with the ACK
+                            let handler = self.inner.offer_answer.lock().unwrap().clone();
+                            match handler {
+                                Some(handler)
+                                    if self.inner.initial_request.body.is_empty()
+                                        && !req.body.is_empty() =>
+                                {
+                                    if let Err(e) = handler.on_answer(req.body.clone()).await {
+                                        warn!("failed to accept answer in ack: {:?}", e);
+                                        self.inner.transition(DialogState::Confirmed(self.id()))?;
+                                        self.inner
+                                            .bye_on_error(
+                                                Reason::sip(488).with_text("Not Acceptable Here"),
+                                            )
+                                            .await?;
+                                        continue;
+                                    }
+                                }
+                                _ => {}
+                            }
                             self.inner.transition(DialogState::Confirmed(self.id()))?;
                         }
                         rsip::Method::Cancel => {
@@ -206,6 +650,7 @@ impl ServerInviteDialog {
                             self.inner.transition(DialogState::Terminated(
                                 self.id(),
                                 Some(StatusCode::RequestTerminated),
+                                Reason::from_headers(&req.headers),
                             ))?;
                         }
                         _ => {}
@@ -219,6 +664,17 @@ impl ServerInviteDialog {
             Ok(_) => {
                 trace!("process done");
                 self.inner.tu_sender.lock().unwrap().take();
+                // the 2xx was retransmitted for 64*T1 without ACK
+                let waiting_ack = matches!(
+                    *self.inner.state.lock().unwrap(),
+                    DialogState::WaitAck(_, _)
+                );
+                if waiting_ack {
+                    warn!("no ack received for 2xx: {}", self.id());
+                    self.inner
+                        .bye_on_error(Reason::sip(408).with_text("ACK Timeout"))
+                        .await?;
+                }
                 Ok(())
             }
             Err(e) => {
@@ -0,0 +1,889 @@
+use super::{
+    authenticate::CredentialStoreRef,
+    dialog::{Dialog, DialogInner, DialogInnerRef, DialogState, DialogStateSender},
+    dialog_layer::{DialogLayer, DialogLayerInner},
+    event_package::EventPackageRef,
+    DialogId,
+};
+use crate::{
+    rsip_ext::{header_value, set_route_set},
+    transaction::{key::TransactionRole, make_tag, transaction::Transaction},
+    Error, Result,
+};
+use rsip::{prelude::HeadersExt, Header, Request, Response, StatusCode};
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
+use tokio::{
+    select,
+    sync::Notify,
+    time::{sleep, sleep_until, Instant},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// Subscription-State header value (RFC 6665 8.2.3)
+#[derive(Debug, Clone, PartialEq)]
+pub enum SubscriptionState {
+    Active {
+        expires: Option<u32>,
+    },
+    Pending {
+        expires: Option<u32>,
+    },
+    Terminated {
+        reason: Option<String>,
+        retry_after: Option<u32>,
+    },
+}
+
+impl TryFrom<&str> for SubscriptionState {
+    type Error = crate::Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        let mut parts = value.split(';').map(|p| p.trim());
+        let state = parts.next().unwrap_or_default().to_lowercase();
+        let mut expires = None;
+        let mut reason = None;
+        let mut retry_after = None;
+        for param in parts {
+            let (name, v) = param.split_once('=').unwrap_or((param, ""));
+            match name.trim().to_lowercase().as_str() {
+                "expires" => expires = v.trim().parse().ok(),
+                "reason" => reason = Some(v.trim().to_lowercase()),
+                "retry-after" => retry_after = v.trim().parse().ok(),
+                _ => {}
+            }
+        }
+        match state.as_str() {
+            "active" => Ok(SubscriptionState::Active { expires }),
+            "pending" => Ok(SubscriptionState::Pending { expires }),
+            "terminated" => Ok(SubscriptionState::Terminated {
+                reason,
+                retry_after,
+            }),
+            _ => Err(Error::Error(format!(
+                "invalid subscription state: {}",
+                value
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for SubscriptionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SubscriptionState::Active { expires } => match expires {
+                Some(expires) => write!(f, "active;expires={}", expires),
+                None => write!(f, "active"),
+            },
+            SubscriptionState::Pending { expires } => match expires {
+                Some(expires) => write!(f, "pending;expires={}", expires),
+                None => write!(f, "pending"),
+            },
+            SubscriptionState::Terminated {
+                reason,
+                retry_after,
+            } => {
+                write!(f, "terminated")?;
+                if let Some(reason) = reason {
+                    write!(f, ";reason={}", reason)?;
+                }
+                if let Some(retry_after) = retry_after {
+                    write!(f, ";retry-after={}", retry_after)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl SubscriptionState {
+    pub fn is_terminated(&self) -> bool {
+        matches!(self, SubscriptionState::Terminated { .. })
+    }
+
+    /// How long to wait before subscribing again after the notifier terminated
+    /// the subscription, `None` if the subscriber must not retry (RFC 6665 4.1.3)
+    pub fn retry_delay(&self) -> Option<Duration> {
+        let (reason, retry_after) = match self {
+            SubscriptionState::Terminated {
+                reason,
+                retry_after,
+            } => (reason.as_deref(), *retry_after),
+            _ => return None,
+        };
+        let retry_after = retry_after.map(|s| Duration::from_secs(s as u64));
+        match reason {
+            Some("deactivated") | Some("timeout") => Some(retry_after.unwrap_or_default()),
+            Some("rejected") | Some("noresource") | Some("invariant") => None,
+            _ => retry_after,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SubscribeOption {
+    pub subscriber: rsip::Uri,
+    pub target: rsip::Uri,
+    pub event: String,
+    pub accept: Option<String>,
+    pub expires: u32,
+    pub contact: rsip::Uri,
+    pub credential: Option<CredentialStoreRef>,
+    pub headers: Option<Vec<rsip::Header>>,
+    /// Content-Type of `body`
+    pub content_type: Option<String>,
+    /// Body of the initial SUBSCRIBE, e.g. a KPML request
+    pub body: Option<Vec<u8>>,
+    /// Overrides the preloaded route set of the endpoint
+    pub route_set: Option<Vec<rsip::Uri>>,
+}
+
+pub struct ClientSubscriptionInner {
+    pub option: SubscribeOption,
+    pub expires: AtomicU32,
+    pub stop_token: CancellationToken,
+    refreshed: Notify,
+    layer: Weak<DialogLayerInner>,
+}
+
+/// The subscriber side of a SUBSCRIBE/NOTIFY dialog.
+///
+/// The subscription is refreshed before the granted expiry, re-created from
+/// scratch when a refresh gets 481 and when the notifier terminates it with a
+/// reason that allows retrying.
+#[derive(Clone)]
+pub struct ClientSubscriptionDialog {
+    pub(super) inner: DialogInnerRef,
+    pub(super) subscription: Arc<ClientSubscriptionInner>,
+}
+
+/// Refresh a subscription once 80% of the granted duration has elapsed
+pub fn refresh_interval(expires: u32) -> Duration {
+    Duration::from_secs((expires as u64 * 4 / 5).max(1))
+}
+
+impl ClientSubscriptionDialog {
+    pub fn id(&self) -> DialogId {
+        self.inner.id.lock().unwrap().clone()
+    }
+
+    pub fn cancel_token(&self) -> &CancellationToken {
+        &self.inner.cancel_token
+    }
+
+    pub fn event(&self) -> &str {
+        &self.subscription.option.event
+    }
+
+    pub fn expires(&self) -> u32 {
+        self.subscription.expires.load(Ordering::Relaxed)
+    }
+
+    fn set_expires(&self, expires: u32) {
+        self.subscription.expires.store(expires, Ordering::Relaxed);
+        self.subscription.refreshed.notify_one();
+    }
+
+    fn subscribe_headers(&self, expires: u32) -> Vec<Header> {
+        let option = &self.subscription.option;
+        let mut headers = vec![
+            Header::Other("Event".into(), option.event.clone()),
+            Header::Expires(expires.to_string().into()),
+        ];
+        if let Some(accept) = option.accept.as_ref() {
+            headers.push(Header::Accept(accept.clone().into()));
+        }
+        headers
+    }
+
+    /// Sends an in-dialog SUBSCRIBE refreshing the subscription
+    pub async fn refresh(&self) -> Result<Option<Response>> {
+        let headers = self.subscribe_headers(self.subscription.option.expires);
+        let request = self.inner.make_request(
+            rsip::Method::Subscribe,
+            None,
+            None,
+            None,
+            Some(headers),
+            None,
+        )?;
+        let resp = self.inner.do_request(request).await?;
+        if let Some(resp) = resp.as_ref() {
+            if resp.status_code.kind() == rsip::StatusCodeKind::Successful {
+                self.set_expires(granted_expires(resp, self.subscription.option.expires));
+            }
+        }
+        Ok(resp)
+    }
+
+    /// Terminates the subscription by sending SUBSCRIBE with Expires: 0
+    pub async fn unsubscribe(&self) -> Result<()> {
+        self.subscription.stop_token.cancel();
+        let request = self.inner.make_request(
+            rsip::Method::Subscribe,
+            None,
+            None,
+            None,
+            Some(self.subscribe_headers(0)),
+            None,
+        )?;
+        let resp = self.inner.do_request(request).await?;
+        self.inner.remove_from_layer().await;
+        self.inner.transition(DialogState::Terminated(
+            self.id(),
+            resp.map(|r| r.status_code),
+            None,
+        ))?;
+        Ok(())
+    }
+
+    pub async fn handle(&mut self, mut tx: Transaction) -> Result<()> {
+        // a NOTIFY ahead of the 2xx of the SUBSCRIBE gives the remote tag
+        // (RFC 6665 4.1.2.4)
+        if self.id().to_tag.is_empty() {
+            if let Some(tag) = tx.original.from_header()?.tag()? {
+                self.inner.update_remote_tag(tag.value()).await?;
+            }
+        }
+        if !self.inner.screen_request(&mut tx).await? {
+            return Ok(());
+        }
+
+        match tx.original.method {
+            rsip::Method::Notify => self.handle_notify(tx).await,
+            _ => {
+                info!("invalid request method: {:?}", tx.original.method);
+                tx.reply(StatusCode::MethodNotAllowed).await?;
+                Err(Error::DialogError("invalid request".to_string(), self.id()))
+            }
+        }
+    }
+
+    async fn handle_notify(&mut self, mut tx: Transaction) -> Result<()> {
+        let state = match header_value(&tx.original.headers, "Subscription-State")
+            .map(|v| SubscriptionState::try_from(v.as_str()))
+        {
+            Some(Ok(state)) => state,
+            _ => {
+                info!("invalid or missing Subscription-State in NOTIFY");
+                tx.reply(StatusCode::BadRequest).await?;
+                return Ok(());
+            }
+        };
+        info!("received notify {} state: {}", tx.original.uri, state);
+        self.inner.transition(DialogState::Notify(
+            self.id(),
+            Arc::new(tx.original.clone()),
+        ))?;
+        tx.reply(StatusCode::OK).await?;
+
+        match state {
+            SubscriptionState::Active { expires } | SubscriptionState::Pending { expires } => {
+                // the notifier may shorten the subscription, never extend it
+                if let Some(expires) = expires.filter(|e| *e < self.expires()) {
+                    self.set_expires(expires);
+                }
+            }
+            SubscriptionState::Terminated { .. } => {
+                self.inner.cancel_token.cancel();
+                self.inner.remove_from_layer().await;
+                self.inner
+                    .transition(DialogState::Terminated(self.id(), None, None))?;
+                if let Some(delay) = state.retry_delay() {
+                    info!("subscription terminated, retry after {:?}", delay);
+                    self.spawn_resubscribe(delay);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn spawn_resubscribe(&self, delay: Duration) {
+        let layer = match self.subscription.layer.upgrade() {
+            Some(inner) => DialogLayer {
+                endpoint: self.inner.endpoint_inner.clone(),
+                inner,
+            },
+            None => return,
+        };
+        let option = self.subscription.option.clone();
+        let state_sender = self.inner.state_sender.clone();
+        let stop_token = self.subscription.stop_token.clone();
+        tokio::spawn(async move {
+            select! {
+                _ = stop_token.cancelled() => {}
+                _ = sleep(delay) => {
+                    if let Err(e) = layer.do_subscribe(option, state_sender).await {
+                        warn!("resubscribe failed: {:?}", e);
+                    }
+                }
+            }
+        });
+    }
+
+    async fn refresh_loop(self) {
+        loop {
+            let interval = refresh_interval(self.expires());
+            select! {
+                _ = self.inner.cancel_token.cancelled() => return,
+                _ = self.subscription.stop_token.cancelled() => return,
+                _ = self.subscription.refreshed.notified() => continue,
+                _ = sleep(interval) => {}
+            }
+
+            let resp = match self.refresh().await {
+                Ok(resp) => resp,
+                Err(e) => {
+                    warn!("subscription refresh failed: {} {:?}", self.id(), e);
+                    None
+                }
+            };
+            match resp {
+                Some(resp) if resp.status_code.kind() == rsip::StatusCodeKind::Successful => {}
+                Some(resp) if resp.status_code == StatusCode::CallTransactionDoesNotExist => {
+                    info!("subscription {} is gone, subscribing again", self.id());
+                    self.inner.cancel_token.cancel();
+                    self.inner.remove_from_layer().await;
+                    self.inner
+                        .transition(DialogState::Terminated(
+                            self.id(),
+                            Some(resp.status_code),
+                            None,
+                        ))
+                        .ok();
+                    self.spawn_resubscribe(Duration::ZERO);
+                    return;
+                }
+                resp => {
+                    self.inner.cancel_token.cancel();
+                    self.inner.remove_from_layer().await;
+                    self.inner
+                        .transition(DialogState::Terminated(
+                            self.id(),
+                            resp.map(|r| r.status_code),
+                            None,
+                        ))
+                        .ok();
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn granted_expires(resp: &Response, requested: u32) -> u32 {
+    header_value(&resp.headers, "Expires")
+        .and_then(|e| e.parse::<u32>().ok())
+        .unwrap_or(requested)
+}
+
+impl DialogLayer {
+    pub fn make_subscribe_request(&self, opt: &SubscribeOption) -> Result<Request> {
+        let last_seq = self.increment_last_seq();
+        let to = rsip::typed::To {
+            display_name: None,
+            uri: opt.target.clone(),
+            params: vec![],
+        };
+        let from = rsip::typed::From {
+            display_name: None,
+            uri: opt.subscriber.clone(),
+            params: vec![],
+        }
+        .with_tag(make_tag());
+
+        let via = self.endpoint.get_via(None, None)?;
+        let mut request = self.endpoint.make_request(
+            rsip::Method::Subscribe,
+            opt.target.clone(),
+            via,
+            from,
+            to,
+            last_seq,
+        );
+
+        let contact = rsip::typed::Contact {
+            display_name: None,
+            uri: opt.contact.clone(),
+            params: vec![],
+        };
+        request
+            .headers
+            .unique_push(rsip::Header::Contact(contact.into()));
+        request
+            .headers
+            .push(Header::Other("Event".into(), opt.event.clone()));
+        request
+            .headers
+            .unique_push(Header::Expires(opt.expires.to_string().into()));
+        let accept = opt.accept.clone().or_else(|| {
+            self.get_event_package(&opt.event)
+                .and_then(|p| p.content_type().map(|c| c.to_string()))
+        });
+        if let Some(accept) = accept {
+            request.headers.unique_push(Header::Accept(accept.into()));
+        }
+        if let Some(route_set) = opt.route_set.as_ref() {
+            set_route_set(&mut request, route_set);
+        }
+        if let Some(headers) = opt.headers.as_ref() {
+            for header in headers {
+                request.headers.unique_push(header.clone());
+            }
+        }
+        if let Some(body) = opt.body.as_ref() {
+            if let Some(content_type) = opt.content_type.as_ref() {
+                request
+                    .headers
+                    .unique_push(Header::ContentType(content_type.clone().into()));
+            }
+            request.body = body.clone();
+            request
+                .headers
+                .unique_push(Header::ContentLength((body.len() as u32).into()));
+        }
+        Ok(request)
+    }
+
+    /// Sends an initial SUBSCRIBE and creates the subscription dialog on 2xx,
+    /// the subscription is then refreshed automatically until it's terminated
+    pub async fn do_subscribe(
+        &self,
+        opt: SubscribeOption,
+        state_sender: DialogStateSender,
+    ) -> Result<(ClientSubscriptionDialog, Option<Response>)> {
+        let request = self.make_subscribe_request(&opt)?;
+        let id = DialogId::try_from(&request)?;
+        let dlg_inner = DialogInner::new(
+            TransactionRole::Client,
+            id.clone(),
+            request.clone(),
+            self.endpoint.clone(),
+            state_sender,
+            opt.credential.clone(),
+            Some(opt.contact.clone()),
+        )?;
+        let expires = opt.expires;
+        let dialog = ClientSubscriptionDialog {
+            inner: Arc::new(dlg_inner),
+            subscription: Arc::new(ClientSubscriptionInner {
+                option: opt,
+                expires: AtomicU32::new(expires),
+                stop_token: CancellationToken::new(),
+                refreshed: Notify::new(),
+                layer: Arc::downgrade(&self.inner),
+            }),
+        };
+        self.inner
+            .insert_dialog(id.clone(), Dialog::ClientSubscription(dialog.clone()))
+            .await;
+        info!("client subscription dialog created: {}", id);
+
+        dialog.inner.transition(DialogState::Calling(dialog.id()))?;
+        let resp = match dialog.inner.do_request(request).await {
+            Ok(resp) => resp,
+            Err(e) => {
+                dialog.inner.remove_from_layer().await;
+                return Err(e);
+            }
+        };
+
+        match resp {
+            Some(resp)
+                if resp.status_code.kind() == rsip::StatusCodeKind::Successful
+                    && dialog.inner.cancel_token.is_cancelled() =>
+            {
+                info!("subscription {} terminated before its 2xx", dialog.id());
+                Ok((dialog, Some(resp)))
+            }
+            Some(resp) if resp.status_code.kind() == rsip::StatusCodeKind::Successful => {
+                if let Some(tag) = resp.to_header()?.tag()? {
+                    dialog.inner.update_remote_tag(tag.value()).await?;
+                }
+                let new_id = dialog.id();
+                self.inner.remove_dialog(&id).await;
+                self.inner
+                    .insert_dialog(new_id.clone(), Dialog::ClientSubscription(dialog.clone()))
+                    .await;
+                dialog.set_expires(granted_expires(&resp, expires));
+                dialog.inner.transition(DialogState::Confirmed(new_id))?;
+                tokio::spawn(dialog.clone().refresh_loop());
+                Ok((dialog, Some(resp)))
+            }
+            Some(resp) => {
+                dialog.inner.remove_from_layer().await;
+                dialog.inner.transition(DialogState::Terminated(
+                    id,
+                    Some(resp.status_code.clone()),
+                    None,
+                ))?;
+                Err(Error::DialogError(
+                    format!("subscription failed: {}", resp.status_code),
+                    dialog.id(),
+                ))
+            }
+            None => {
+                dialog.inner.remove_from_layer().await;
+                Err(Error::DialogError(
+                    "subscription transaction terminated".to_string(),
+                    id,
+                ))
+            }
+        }
+    }
+}
+
+struct PendingNotify {
+    state: SubscriptionState,
+    headers: Option<Vec<Header>>,
+    body: Option<Vec<u8>>,
+}
+
+pub struct ServerSubscriptionInner {
+    pub event: String,
+    pub package: Option<EventPackageRef>,
+    pub expires: AtomicU32,
+    min_notify_interval: Mutex<Option<Duration>>,
+    last_notify: Mutex<Option<Instant>>,
+    pending: Mutex<Option<PendingNotify>>,
+    /// End of the subscription unless refreshed before, `None` once terminated
+    expires_at: Mutex<Option<Instant>>,
+}
+
+/// The notifier side of a SUBSCRIBE/NOTIFY dialog.
+///
+/// With a minimum NOTIFY interval set, state changes arriving faster than the
+/// interval are coalesced and only the latest one is sent when it elapses.
+/// A subscription not refreshed within its Expires is terminated with a
+/// final NOTIFY (RFC 6665 4.2.2).
+#[derive(Clone)]
+pub struct ServerSubscriptionDialog {
+    pub(super) inner: DialogInnerRef,
+    pub(super) subscription: Arc<ServerSubscriptionInner>,
+}
+
+impl ServerSubscriptionDialog {
+    pub fn id(&self) -> DialogId {
+        self.inner.id.lock().unwrap().clone()
+    }
+
+    pub fn cancel_token(&self) -> &CancellationToken {
+        &self.inner.cancel_token
+    }
+
+    pub fn initial_request(&self) -> &Request {
+        &self.inner.initial_request
+    }
+
+    pub fn event(&self) -> &str {
+        &self.subscription.event
+    }
+
+    pub fn expires(&self) -> u32 {
+        self.subscription.expires.load(Ordering::Relaxed)
+    }
+
+    /// Sets the minimum interval between two NOTIFYs, `None` disables throttling
+    pub fn set_min_notify_interval(&self, interval: Option<Duration>) {
+        *self.subscription.min_notify_interval.lock().unwrap() = interval;
+    }
+
+    fn notify_delay(&self) -> Option<Duration> {
+        let interval = (*self.subscription.min_notify_interval.lock().unwrap())?;
+        let last = (*self.subscription.last_notify.lock().unwrap())?;
+        interval
+            .checked_sub(last.elapsed())
+            .filter(|d| !d.is_zero())
+    }
+
+    /// Sends a NOTIFY with the given state, throttled by the minimum interval.
+    ///
+    /// A throttled NOTIFY replaces any one already waiting, terminating the
+    /// subscription is never delayed.
+    pub async fn notify(
+        &self,
+        state: SubscriptionState,
+        headers: Option<Vec<Header>>,
+        body: Option<Vec<u8>>,
+    ) -> Result<()> {
+        if state.is_terminated() {
+            self.subscription.pending.lock().unwrap().take();
+            self.subscription.expires_at.lock().unwrap().take();
+            self.send_notify(state, headers, body).await?;
+            self.inner.remove_from_layer().await;
+            self.inner
+                .transition(DialogState::Terminated(self.id(), None, None))?;
+            return Ok(());
+        }
+
+        let delay = match self.notify_delay() {
+            Some(delay) => delay,
+            None => return self.send_notify(state, headers, body).await.map(|_| ()),
+        };
+        let scheduled = {
+            let mut pending = self.subscription.pending.lock().unwrap();
+            let body = match (pending.as_ref(), self.subscription.package.as_ref()) {
+                (
+                    Some(PendingNotify {
+                        body: Some(prev), ..
+                    }),
+                    Some(package),
+                ) => body.map(|b| package.merge(prev, b)),
+                _ => body,
+            };
+            pending
+                .replace(PendingNotify {
+                    state,
+                    headers,
+                    body,
+                })
+                .is_some()
+        };
+        if !scheduled {
+            let dialog = self.clone();
+            tokio::spawn(async move {
+                select! {
+                    _ = dialog.inner.cancel_token.cancelled() => {}
+                    _ = sleep(delay) => {
+                        dialog.flush_notify().await;
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+
+    async fn flush_notify(&self) {
+        let pending = self.subscription.pending.lock().unwrap().take();
+        if let Some(pending) = pending {
+            if let Err(e) = self
+                .send_notify(pending.state, pending.headers, pending.body)
+                .await
+            {
+                warn!("failed to send notify: {} {:?}", self.id(), e);
+            }
+        }
+    }
+
+    async fn send_notify(
+        &self,
+        state: SubscriptionState,
+        headers: Option<Vec<Header>>,
+        body: Option<Vec<u8>>,
+    ) -> Result<Option<Response>> {
+        let mut notify_headers = vec![
+            Header::Other("Event".into(), self.subscription.event.clone()),
+            Header::Other("Subscription-State".into(), state.to_string()),
+        ];
+        if let Some(content_type) = self
+            .subscription
+            .package
+            .as_ref()
+            .and_then(|p| p.content_type())
+            .filter(|_| body.is_some())
+        {
+            notify_headers.push(Header::ContentType(content_type.to_string().into()));
+        }
+        notify_headers.extend(headers.unwrap_or_default());
+        let request = self.inner.make_request(
+            rsip::Method::Notify,
+            None,
+            None,
+            None,
+            Some(notify_headers),
+            body,
+        )?;
+        self.subscription
+            .last_notify
+            .lock()
+            .unwrap()
+            .replace(Instant::now());
+        self.inner.do_request(request).await
+    }
+
+    /// Terminates the subscription once its deadline passes, each refresh
+    /// pushing the deadline further
+    fn expire_at_deadline(&self) {
+        let dialog = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let deadline = match *dialog.subscription.expires_at.lock().unwrap() {
+                    Some(deadline) => deadline,
+                    None => return,
+                };
+                if deadline <= Instant::now() {
+                    break;
+                }
+                select! {
+                    _ = dialog.inner.cancel_token.cancelled() => return,
+                    _ = sleep_until(deadline) => {}
+                }
+            }
+            info!("subscription expired: {}", dialog.id());
+            if let Err(e) = dialog.terminate(Some("timeout".to_string())).await {
+                warn!("failed to terminate expired subscription: {:?}", e);
+            }
+        });
+    }
+
+    /// Terminates the subscription with a final NOTIFY
+    pub async fn terminate(&self, reason: Option<String>) -> Result<()> {
+        self.notify(
+            SubscriptionState::Terminated {
+                reason,
+                retry_after: None,
+            },
+            None,
+            None,
+        )
+        .await
+    }
+
+    pub async fn handle(&mut self, mut tx: Transaction) -> Result<()> {
+        if !self.inner.screen_request(&mut tx).await? {
+            return Ok(());
+        }
+
+        match tx.original.method {
+            rsip::Method::Subscribe => self.handle_subscribe(tx).await,
+            _ => {
+                info!("invalid request method: {:?}", tx.original.method);
+                tx.reply(StatusCode::MethodNotAllowed).await?;
+                Err(Error::DialogError("invalid request".to_string(), self.id()))
+            }
+        }
+    }
+
+    async fn handle_subscribe(&mut self, mut tx: Transaction) -> Result<()> {
+        let default_expires = match self.subscription.package.as_ref() {
+            Some(package) if !self.inner.is_confirmed() => package.default_expires(),
+            _ => self.expires(),
+        };
+        let expires = header_value(&tx.original.headers, "Expires")
+            .and_then(|e| e.parse::<u32>().ok())
+            .unwrap_or(default_expires);
+        info!(
+            "received subscribe {} expires: {}",
+            tx.original.uri, expires
+        );
+        self.subscription.expires.store(expires, Ordering::Relaxed);
+
+        let resp = self.inner.make_response(
+            &tx.original,
+            StatusCode::OK,
+            Some(vec![Header::Expires(expires.to_string().into())]),
+            None,
+        );
+        tx.respond(resp).await?;
+
+        if expires == 0 {
+            return self.terminate(Some("timeout".to_string())).await;
+        }
+        let deadline = Instant::now() + Duration::from_secs(expires as u64);
+        let expiring = self
+            .subscription
+            .expires_at
+            .lock()
+            .unwrap()
+            .replace(deadline)
+            .is_some();
+        if !expiring {
+            self.expire_at_deadline();
+        }
+        if self.inner.is_confirmed() {
+            self.inner.transition(DialogState::Updated(
+                self.id(),
+                Arc::new(tx.original.clone()),
+            ))
+        } else {
+            self.inner.transition(DialogState::Confirmed(self.id()))
+        }
+    }
+}
+
+impl DialogLayer {
+    /// Returns the notifier dialog for an incoming SUBSCRIBE, creating it for
+    /// an initial request. Passing the transaction to `handle` accepts it.
+    pub async fn get_or_create_server_subscription(
+        &self,
+        tx: &Transaction,
+        state_sender: DialogStateSender,
+        credential: Option<CredentialStoreRef>,
+        contact: Option<rsip::Uri>,
+    ) -> Result<ServerSubscriptionDialog> {
+        let mut id = DialogId::try_from(&tx.original)?;
+        if !id.to_tag.is_empty() {
+            let dlg = self.inner.dialogs.read().unwrap().get(&id).cloned();
+            match dlg {
+                Some(Dialog::ServerSubscription(dlg)) => return Ok(dlg),
+                _ => {
+                    return Err(Error::DialogError("the dialog not found".to_string(), id));
+                }
+            }
+        }
+        id.to_tag = make_tag().to_string();
+
+        let event = header_value(&tx.original.headers, "Event").unwrap_or_default();
+        let package = self.get_event_package(&event);
+        let dlg_inner = DialogInner::new(
+            TransactionRole::Server,
+            id.clone(),
+            tx.original.clone(),
+            self.endpoint.clone(),
+            state_sender,
+            credential,
+            contact,
+        )?;
+        let dialog = ServerSubscriptionDialog {
+            inner: Arc::new(dlg_inner),
+            subscription: Arc::new(ServerSubscriptionInner {
+                event,
+                package,
+                expires: AtomicU32::new(0),
+                min_notify_interval: Mutex::new(None),
+                last_notify: Mutex::new(None),
+                pending: Mutex::new(None),
+                expires_at: Mutex::new(None),
+            }),
+        };
+        self.inner
+            .insert_dialog(id.clone(), Dialog::ServerSubscription(dialog.clone()))
+            .await;
+        info!("server subscription dialog created: {id}");
+        Ok(dialog)
+    }
+}
+
+impl TryFrom<&Dialog> for ServerSubscriptionDialog {
+    type Error = crate::Error;
+
+    fn try_from(dlg: &Dialog) -> Result<Self> {
+        match dlg {
+            Dialog::ServerSubscription(dlg) => Ok(dlg.clone()),
+            _ => Err(crate::Error::DialogError(
+                "Dialog is not a ServerSubscriptionDialog".to_string(),
+                dlg.id(),
+            )),
+        }
+    }
+}
+
+impl TryFrom<&Dialog> for ClientSubscriptionDialog {
+    type Error = crate::Error;
+
+    fn try_from(dlg: &Dialog) -> Result<Self> {
+        match dlg {
+            Dialog::ClientSubscription(dlg) => Ok(dlg.clone()),
+            _ => Err(crate::Error::DialogError(
+                "Dialog is not a ClientSubscriptionDialog".to_string(),
+                dlg.id(),
+            )),
+        }
+    }
+}
@@ -0,0 +1,312 @@
+mod test_ack;
+mod test_admission;
+mod test_b2bua;
+mod test_cdr;
+mod test_cseq;
+mod test_dialog_event;
+mod test_dialog_info;
+mod test_dialog_store;
+mod test_digest;
+mod test_dtmf;
+mod test_expiration;
+mod test_forwarding;
+mod test_glare;
+mod test_hold;
+mod test_identity;
+mod test_invite_outcome;
+mod test_keepalive;
+mod test_kpml;
+mod test_lookup;
+mod test_message;
+mod test_monitor;
+mod test_mwi;
+mod test_offer_answer;
+mod test_options;
+mod test_prack;
+mod test_presence;
+mod test_publication;
+mod test_reason;
+mod test_refer;
+mod test_reginfo;
+mod test_registrar;
+mod test_registration;
+mod test_route;
+mod test_shutdown;
+mod test_stream;
+mod test_subscription;
+mod test_usage;
+
+use crate::{
+    dialog::{
+        client_dialog::ClientInviteDialog,
+        dialog::{DialogState, DialogStateReceiver},
+        dialog_layer::DialogLayer,
+        invitation::InviteOption,
+        server_dialog::ServerInviteDialog,
+        subscription::ServerSubscriptionDialog,
+    },
+    transaction::{endpoint::EndpointOption, Endpoint, EndpointBuilder, TransactionReceiver},
+    transport::{udp::UdpConnection, TransportLayer},
+    Result,
+};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio_util::sync::CancellationToken;
+
+/// A server dialog of an incoming INVITE, not answered yet, and its states
+pub(super) type Incoming = (ServerInviteDialog, DialogStateReceiver);
+
+/// A notifier dialog of an incoming SUBSCRIBE, accepted, and its states
+pub(super) type Subscribed = (ServerSubscriptionDialog, DialogStateReceiver);
+
+/// A user agent on the loopback: an endpoint serving a dialog layer, whose
+/// incoming INVITEs come out of `incoming` and SUBSCRIBEs out of `subscribed`
+pub(super) struct TestUa {
+    pub endpoint: Endpoint,
+    pub layer: Arc<DialogLayer>,
+    pub contact: rsip::Uri,
+    incoming: UnboundedReceiver<Incoming>,
+    subscribed: UnboundedReceiver<Subscribed>,
+}
+
+/// A served endpoint on the loopback, whose incoming transactions are
+/// answered by hand, and the contact of `user` on it
+pub(super) async fn test_endpoint(
+    user: &str,
+) -> Result<(Endpoint, TransactionReceiver, rsip::Uri)> {
+    test_endpoint_with_option(user, EndpointOption::default()).await
+}
+
+/// Like `test_endpoint`, with the timers of `option`
+pub(super) async fn test_endpoint_with_option(
+    user: &str,
+    option: EndpointOption,
+) -> Result<(Endpoint, TransactionReceiver, rsip::Uri)> {
+    let token = CancellationToken::new();
+    let tl = TransportLayer::new(token.child_token());
+    let udp = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let contact = rsip::Uri::try_from(format!("sip:{}@{}", user, udp.get_addr().addr))?;
+    tl.add_transport(udp.into());
+    let endpoint = EndpointBuilder::new()
+        .user_agent("rsipstack-test")
+        .transport_layer(tl)
+        .cancel_token(token)
+        .option(option)
+        .build();
+    let transactions = endpoint.incoming_transactions();
+    let inner = endpoint.inner.clone();
+    tokio::spawn(async move { inner.serve().await });
+    Ok((endpoint, transactions, contact))
+}
+
+impl TestUa {
+    pub async fn new(user: &str) -> Result<Self> {
+        Self::with_option(user, EndpointOption::default()).await
+    }
+
+    pub async fn with_option(user: &str, option: EndpointOption) -> Result<Self> {
+        let (endpoint, transactions, contact) = test_endpoint_with_option(user, option).await?;
+        let layer = Arc::new(DialogLayer::new(endpoint.inner.clone()));
+
+        let (sender, incoming) = unbounded_channel();
+        let (subscriptions, subscribed) = unbounded_channel();
+        tokio::spawn(serve_dialogs(
+            layer.clone(),
+            transactions,
+            contact.clone(),
+            sender,
+            subscriptions,
+        ));
+        Ok(Self {
+            endpoint,
+            layer,
+            contact,
+            incoming,
+            subscribed,
+        })
+    }
+
+    /// Options of an INVITE from this user agent to `callee`
+    pub fn invite_option(&self, callee: &TestUa, offer: Option<Vec<u8>>) -> InviteOption {
+        InviteOption {
+            caller: self.contact.clone(),
+            callee: callee.contact.clone(),
+            content_type: None,
+            offer,
+            contact: self.contact.clone(),
+            credential: None,
+            headers: None,
+            route_set: None,
+            max_redirects: None,
+            identity: None,
+        }
+    }
+
+    /// Next INVITE received, once its server dialog may be answered
+    pub async fn incoming(&mut self) -> Incoming {
+        let (dialog, mut states) =
+            tokio::time::timeout(Duration::from_secs(5), self.incoming.recv())
+                .await
+                .expect("no INVITE received")
+                .expect("user agent stopped");
+        wait_state(&mut states, |s| matches!(s, DialogState::Calling(_))).await;
+        (dialog, states)
+    }
+
+    /// Next SUBSCRIBE received, once accepted
+    pub async fn subscribed(&mut self) -> Subscribed {
+        let (dialog, mut states) =
+            tokio::time::timeout(Duration::from_secs(5), self.subscribed.recv())
+                .await
+                .expect("no SUBSCRIBE received")
+                .expect("user agent stopped");
+        wait_state(&mut states, |s| matches!(s, DialogState::Confirmed(_))).await;
+        (dialog, states)
+    }
+
+    /// Calls `callee`, which answers `answer` to `offer`. Returns both
+    /// sides once confirmed.
+    pub async fn call(
+        &self,
+        callee: &mut TestUa,
+        offer: Vec<u8>,
+        answer: Vec<u8>,
+    ) -> Result<(ClientInviteDialog, DialogStateReceiver, Incoming)> {
+        let (sender, mut states) = unbounded_channel();
+        let layer = self.layer.clone();
+        let opt = self.invite_option(callee, Some(offer));
+        let invite = tokio::spawn(async move { layer.do_invite(opt, sender).await });
+        let (server, mut server_states) = callee.incoming().await;
+        server.accept(None, Some(answer))?;
+        let (client, _) = invite.await.expect("invite task")?;
+        wait_state(&mut states, |s| matches!(s, DialogState::Confirmed(_))).await;
+        wait_state(&mut server_states, |s| {
+            matches!(s, DialogState::Confirmed(_))
+        })
+        .await;
+        Ok((client, states, (server, server_states)))
+    }
+}
+
+impl Drop for TestUa {
+    fn drop(&mut self) {
+        self.endpoint.shutdown();
+    }
+}
+
+/// Hands the transactions of a `TestUa` to its dialogs, or to new server
+/// dialogs for INVITEs and SUBSCRIBEs
+async fn serve_dialogs(
+    layer: Arc<DialogLayer>,
+    mut transactions: TransactionReceiver,
+    contact: rsip::Uri,
+    incoming: UnboundedSender<Incoming>,
+    subscriptions: UnboundedSender<Subscribed>,
+) {
+    while let Some(mut tx) = transactions.recv().await {
+        if let Some(mut dialog) = layer.match_dialog(&tx.original) {
+            tokio::spawn(async move { dialog.handle(tx).await });
+            continue;
+        }
+        if tx.original.method == rsip::Method::Subscribe {
+            let (sender, states) = unbounded_channel();
+            let mut dialog = match layer
+                .get_or_create_server_subscription(&tx, sender, None, Some(contact.clone()))
+                .await
+            {
+                Ok(dialog) => dialog,
+                Err(_) => continue,
+            };
+            subscriptions.send((dialog.clone(), states)).ok();
+            tokio::spawn(async move { dialog.handle(tx).await });
+            continue;
+        }
+        if tx.original.method != rsip::Method::Invite {
+            tx.reply(rsip::StatusCode::CallTransactionDoesNotExist)
+                .await
+                .ok();
+            continue;
+        }
+        let (sender, states) = unbounded_channel();
+        let mut dialog = match layer
+            .get_or_create_server_invite(&tx, sender, None, Some(contact.clone()))
+            .await
+        {
+            Ok(dialog) => dialog,
+            Err(_) => continue,
+        };
+        incoming.send((dialog.clone(), states)).ok();
+        tokio::spawn(async move { dialog.handle(tx).await });
+    }
+}
+
+/// A response carrying only `headers`, for the functions inspecting them
+pub(super) fn make_response(
+    status_code: rsip::StatusCode,
+    headers: Vec<rsip::Header>,
+) -> rsip::Response {
+    rsip::Response {
+        status_code,
+        headers: headers.into(),
+        version: rsip::Version::V2,
+        body: vec![],
+    }
+}
+
+/// An out-of-dialog INVITE from `from` to `to`, followed by `headers`
+pub(super) fn make_invite(from: &str, to: &str, headers: Vec<rsip::Header>) -> rsip::Request {
+    let mut invite_headers: rsip::Headers = vec![
+        rsip::Header::Via("SIP/2.0/UDP 127.0.0.1:5060;branch=z9hG4bKtest".into()),
+        rsip::Header::From(format!("<{}>;tag=a1", from).into()),
+        rsip::Header::To(format!("<{}>", to).into()),
+        rsip::Header::CallId("test-call-id".into()),
+        rsip::Header::CSeq("1 INVITE".into()),
+    ]
+    .into();
+    for header in headers {
+        invite_headers.push(header);
+    }
+    rsip::Request {
+        method: rsip::Method::Invite,
+        uri: rsip::Uri::try_from(to).unwrap(),
+        headers: invite_headers,
+        version: rsip::Version::V2,
+        body: vec![],
+    }
+}
+
+/// Skips the states of a dialog up to the first matching `predicate`
+pub(super) async fn wait_state(
+    states: &mut DialogStateReceiver,
+    predicate: impl Fn(&DialogState) -> bool,
+) -> DialogState {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            match states.recv().await {
+                Some(state) if predicate(&state) => return state,
+                Some(_) => {}
+                None => panic!("dialog states closed"),
+            }
+        }
+    })
+    .await
+    .expect("timeout waiting for a dialog state")
+}
+
+/// Whether no state matching `predicate` comes within `duration`
+pub(super) async fn no_state(
+    states: &mut DialogStateReceiver,
+    duration: Duration,
+    predicate: impl Fn(&DialogState) -> bool,
+) -> bool {
+    tokio::time::timeout(duration, async {
+        while let Some(state) = states.recv().await {
+            if predicate(&state) {
+                return;
+            }
+        }
+        std::future::pending::<()>().await
+    })
+    .await
+    .is_err()
+}
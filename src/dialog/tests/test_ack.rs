@@ -0,0 +1,76 @@
+use super::{wait_state, TestUa};
+use crate::{dialog::dialog::DialogState, transaction::endpoint::EndpointOption};
+use rsip::prelude::HeadersExt;
+use std::time::Duration;
+use tokio::{net::UdpSocket, time::timeout};
+
+#[tokio::test]
+async fn test_2xx_without_ack() -> crate::Result<()> {
+    let option = EndpointOption {
+        t1: Duration::from_millis(20),
+        t2: Duration::from_millis(80),
+        ..Default::default()
+    };
+    let mut bob = TestUa::with_option("bob", option).await?;
+    let bob_addr = bob.contact.host_with_port.to_string();
+
+    // a caller which never ACKs
+    let alice = UdpSocket::bind("127.0.0.1:0").await?;
+    let alice_addr = alice.local_addr()?;
+    let invite = format!(
+        "INVITE sip:bob@{bob_addr} SIP/2.0\r\n\
+         Via: SIP/2.0/UDP {alice_addr};branch=z9hG4bKnoack\r\n\
+         From: <sip:alice@{alice_addr}>;tag=a1\r\n\
+         To: <sip:bob@{bob_addr}>\r\n\
+         Call-ID: no-ack\r\n\
+         CSeq: 1 INVITE\r\n\
+         Contact: <sip:alice@{alice_addr}>\r\n\
+         Max-Forwards: 70\r\n\
+         Content-Length: 0\r\n\r\n"
+    );
+    alice.send_to(invite.as_bytes(), &bob_addr).await?;
+
+    let (server, mut states) = bob.incoming().await;
+    server.accept(None, Some(b"v=0 bob\r\n".to_vec()))?;
+
+    // the 2xx is retransmitted until the transaction gives up, then the
+    // callee hangs up
+    let mut oks = 0;
+    let bye = loop {
+        let mut buf = [0u8; 2048];
+        let (n, _) = timeout(Duration::from_secs(5), alice.recv_from(&mut buf))
+            .await
+            .expect("timeout waiting for the callee")?;
+        match rsip::SipMessage::try_from(&buf[..n]).expect("SIP message") {
+            rsip::SipMessage::Response(resp) if resp.status_code == rsip::StatusCode::OK => {
+                oks += 1
+            }
+            rsip::SipMessage::Request(req) if req.method == rsip::Method::Bye => break req,
+            _ => {}
+        }
+    };
+    assert!(oks > 1, "2xx sent {} times", oks);
+    assert!(bye.to_string().contains("cause=408"));
+
+    let ok = rsip::Response {
+        status_code: rsip::StatusCode::OK,
+        version: rsip::Version::V2,
+        headers: vec![
+            bye.via_header()?.clone().into(),
+            bye.from_header()?.clone().into(),
+            bye.to_header()?.clone().into(),
+            bye.call_id_header()?.clone().into(),
+            bye.cseq_header()?.clone().into(),
+            rsip::Header::ContentLength(0.into()),
+        ]
+        .into(),
+        body: vec![],
+    };
+    alice.send_to(ok.to_string().as_bytes(), &bob_addr).await?;
+    let state = wait_state(&mut states, |s| matches!(s, DialogState::Terminated(..))).await;
+    assert!(matches!(
+        state,
+        DialogState::Terminated(_, Some(rsip::StatusCode::OK), Some(_))
+    ));
+    Ok(())
+}
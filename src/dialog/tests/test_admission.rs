@@ -0,0 +1,81 @@
+use super::TestUa;
+use crate::dialog::{
+    admission::{AdmissionStats, CallRateLimiter},
+    client_dialog::InviteOutcome,
+    dialog_layer::DialogLimits,
+};
+use std::sync::atomic::Ordering;
+use tokio::{
+    sync::mpsc::unbounded_channel,
+    task::JoinHandle,
+    time::{Duration, Instant},
+};
+
+#[test]
+fn test_call_rate_limiter() {
+    let limiter = CallRateLimiter::default();
+    let start = Instant::now();
+    assert!(limiter.try_acquire(2, start));
+    assert!(limiter.try_acquire(2, start + Duration::from_millis(100)));
+    assert!(!limiter.try_acquire(2, start + Duration::from_millis(200)));
+    // a new window
+    assert!(limiter.try_acquire(2, start + Duration::from_millis(1100)));
+    assert!(limiter.try_acquire(2, start + Duration::from_millis(1200)));
+    assert!(!limiter.try_acquire(2, start + Duration::from_millis(1300)));
+}
+
+#[test]
+fn test_admission_stats() {
+    let stats = AdmissionStats::default();
+    stats.admitted.fetch_add(3, Ordering::Relaxed);
+    stats.rejected_rate.fetch_add(2, Ordering::Relaxed);
+    stats.rejected_confirmed.fetch_add(1, Ordering::Relaxed);
+    assert_eq!(stats.rejected(), 3);
+}
+
+/// Calls `callee` from `caller` in the background, resolving with the outcome
+fn spawn_call(caller: &TestUa, callee: &TestUa) -> JoinHandle<crate::Result<InviteOutcome>> {
+    let (sender, _) = unbounded_channel();
+    let layer = caller.layer.clone();
+    let opt = caller.invite_option(callee, Some(b"v=0 alice\r\n".to_vec()));
+    tokio::spawn(async move {
+        let (_, outcome) = layer.do_invite_outcome(opt, sender, None).await?;
+        Ok(outcome)
+    })
+}
+
+#[tokio::test]
+async fn test_dialog_limits() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let mut bob = TestUa::new("bob").await?;
+    bob.layer.set_limits(DialogLimits {
+        max_dialogs: Some(1),
+        reject_status: rsip::StatusCode::BusyHere,
+        ..Default::default()
+    });
+    let ringing = spawn_call(&alice, &bob);
+    bob.incoming().await;
+
+    // over the limit, answered right away and never registered
+    let busy = spawn_call(&alice, &bob).await.expect("invite task")?;
+    assert!(matches!(busy, InviteOutcome::Busy(_)));
+    assert_eq!(bob.layer.len(), 1);
+    bob.incoming.recv().await.expect("rejected dialog");
+
+    // with eviction the unconfirmed call makes room for the new one
+    bob.layer.set_limits(DialogLimits {
+        max_dialogs: Some(1),
+        evict_unconfirmed: true,
+        ..Default::default()
+    });
+    let _admitted = spawn_call(&alice, &bob);
+    bob.incoming().await;
+    assert_eq!(bob.layer.len(), 1);
+    match ringing.await.expect("invite task")? {
+        InviteOutcome::Rejected(resp) => {
+            assert_eq!(resp.status_code, rsip::StatusCode::ServiceUnavailable)
+        }
+        _ => panic!("expected the evicted call to be rejected"),
+    }
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use super::{no_state, wait_state, TestUa};
+use crate::dialog::{
+    b2bua::{relayed_headers, B2bua, BridgeHooks, BridgeHooksRef, BridgeLeg},
+    dialog::DialogState,
+};
+use rsip::{prelude::UntypedHeader, Header};
+use std::{sync::Arc, time::Duration};
+
+struct HideCaller;
+
+impl BridgeHooks for HideCaller {
+    fn rewrite_headers(&self, to: BridgeLeg, _method: &rsip::Method, headers: &mut Vec<Header>) {
+        if to == BridgeLeg::Callee {
+            headers
+                .retain(|h| !matches!(h, Header::Other(name, _) if name == "P-Asserted-Identity"));
+            headers.push(Header::Other("Privacy".into(), "id".into()));
+        }
+    }
+}
+
+#[test]
+fn test_relayed_headers() {
+    let hooks: BridgeHooksRef = Arc::new(HideCaller);
+    let headers: rsip::Headers = vec![
+        rsip::headers::CallId::new("b2bua").into(),
+        Header::Other("X-Account".into(), "42".into()),
+        Header::Other(
+            "P-Asserted-Identity".into(),
+            "<sip:alice@example.com>".into(),
+        ),
+    ]
+    .into();
+
+    // only the extension headers cross the bridge
+    let relayed = relayed_headers(&hooks, BridgeLeg::Caller, &rsip::Method::Invite, &headers);
+    assert_eq!(relayed.len(), 2);
+
+    let relayed = relayed_headers(&hooks, BridgeLeg::Callee, &rsip::Method::Invite, &headers);
+    let names = relayed
+        .iter()
+        .filter_map(|h| match h {
+            Header::Other(name, _) => Some(name.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["X-Account", "Privacy"]);
+    assert_eq!(BridgeLeg::Callee.other(), BridgeLeg::Caller);
+}
+
+#[tokio::test]
+async fn test_b2bua_relay() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let mut bridge = TestUa::new("bridge").await?;
+    let mut bob = TestUa::new("bob").await?;
+    let (alice_dialog, mut alice_states, caller) = alice
+        .call(
+            &mut bridge,
+            b"v=0 alice\r\n".to_vec(),
+            b"v=0 bridge\r\n".to_vec(),
+        )
+        .await?;
+    let (callee, callee_states, (_, mut bob_states)) = bridge
+        .call(
+            &mut bob,
+            b"v=0 bridge\r\n".to_vec(),
+            b"v=0 bob\r\n".to_vec(),
+        )
+        .await?;
+    let b2bua = B2bua::new(caller, (callee, callee_states), None);
+    let run = tokio::spawn(b2bua.run());
+
+    // the INFO reaches bob, and isn't echoed back to alice
+    alice_dialog
+        .info(
+            None,
+            Some("application/x-test".into()),
+            Some(b"hello".to_vec()),
+        )
+        .await?;
+    match wait_state(&mut bob_states, |s| matches!(s, DialogState::Info(..))).await {
+        DialogState::Info(_, request) => assert_eq!(request.body, b"hello"),
+        _ => unreachable!(),
+    }
+    assert!(
+        no_state(&mut alice_states, Duration::from_millis(300), |s| {
+            matches!(s, DialogState::Info(_, request)
+                if request.from_header().unwrap().value().contains("bridge"))
+        })
+        .await
+    );
+
+    // an UPDATE is relayed as an UPDATE
+    let resp = alice_dialog
+        .update(None, Some(b"v=0 alice2\r\n".to_vec()))
+        .await?
+        .expect("response to the UPDATE");
+    assert_eq!(resp.status_code, rsip::StatusCode::OK);
+    match wait_state(&mut bob_states, |s| matches!(s, DialogState::Updated(..))).await {
+        DialogState::Updated(_, request) => {
+            assert_eq!(request.method, rsip::Method::Update);
+            assert_eq!(request.body, b"v=0 alice2\r\n");
+        }
+        _ => unreachable!(),
+    }
+
+    alice_dialog.bye(None).await?;
+    wait_state(&mut bob_states, |s| {
+        matches!(s, DialogState::Terminated(..))
+    })
+    .await;
+    run.await.expect("b2bua task")?;
+    Ok(())
+}
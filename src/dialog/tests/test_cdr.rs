@@ -0,0 +1,60 @@
+use crate::{
+    dialog::{
+        cdr::{CallDetailRecord, CdrSink},
+        reason::Reason,
+        DialogId,
+    },
+    transaction::key::TransactionRole,
+};
+use rsip::headers::*;
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc::unbounded_channel;
+
+#[test]
+fn test_call_detail_record() {
+    let request = rsip::Request {
+        method: rsip::Method::Invite,
+        uri: rsip::Uri::try_from("sip:bob@restsend.com").expect("uri"),
+        headers: vec![
+            Via::new("SIP/2.0/TCP alice.restsend.com:5060;branch=z9hG4bKcdr").into(),
+            CSeq::new("1 INVITE").into(),
+            From::new("Alice <sip:alice@restsend.com>;tag=alice").into(),
+            To::new("Bob <sip:bob@restsend.com>").into(),
+            CallId::new("cdr-1@restsend.com").into(),
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body: Default::default(),
+    };
+    let id = DialogId {
+        call_id: "cdr-1@restsend.com".to_string(),
+        from_tag: "alice".to_string(),
+        to_tag: "bob".to_string(),
+    };
+    let setup_time = SystemTime::now();
+    let mut cdr = CallDetailRecord::new(id, TransactionRole::Server, &request, setup_time);
+    assert_eq!(cdr.call_id, "cdr-1@restsend.com");
+    assert_eq!(
+        cdr.caller.as_ref().map(|uri| uri.to_string()),
+        Some("sip:alice@restsend.com".to_string())
+    );
+    assert_eq!(
+        cdr.callee.as_ref().map(|uri| uri.to_string()),
+        Some("sip:bob@restsend.com".to_string())
+    );
+    assert_eq!(cdr.transport, Some(rsip::transport::Transport::Tcp));
+    assert!(!cdr.is_answered());
+    assert_eq!(cdr.duration(), None);
+
+    cdr.answer_time = Some(setup_time + Duration::from_secs(5));
+    cdr.end_time = setup_time + Duration::from_secs(65);
+    cdr.release_code = Some(rsip::StatusCode::OK);
+    cdr.release_reason = Some(Reason::sip(200));
+    assert_eq!(cdr.setup_duration(), Duration::from_secs(5));
+    assert_eq!(cdr.duration(), Some(Duration::from_secs(60)));
+
+    let (sender, mut receiver) = unbounded_channel();
+    sender.on_cdr(cdr);
+    let received = receiver.try_recv().expect("cdr");
+    assert_eq!(received.release_code, Some(rsip::StatusCode::OK));
+}
@@ -0,0 +1,124 @@
+use super::{no_state, wait_state, TestUa};
+use crate::dialog::dialog::{
+    cseq_before, next_cseq, response_matches, Dialog, DialogState, MAX_CSEQ,
+};
+use std::time::Duration;
+
+#[test]
+fn test_next_cseq() {
+    assert_eq!(next_cseq(0), 1);
+    assert_eq!(next_cseq(100), 101);
+    assert_eq!(next_cseq(MAX_CSEQ - 2), MAX_CSEQ - 1);
+    assert_eq!(next_cseq(MAX_CSEQ - 1), 1);
+}
+
+#[test]
+fn test_cseq_before() {
+    assert!(cseq_before(1, 2));
+    assert!(!cseq_before(2, 1));
+    assert!(!cseq_before(2, 2));
+    // the peer wrapped around 2**31 and restarted from a small value
+    assert!(!cseq_before(3, MAX_CSEQ - 10));
+    assert!(cseq_before(MAX_CSEQ - 10, 3));
+}
+
+fn with_cseq(cseq: &str) -> rsip::Headers {
+    vec![rsip::Header::CSeq(cseq.into())].into()
+}
+
+#[test]
+fn test_response_matches() {
+    let request = rsip::Request {
+        method: rsip::Method::Info,
+        uri: rsip::Uri::try_from("sip:bob@example.com").unwrap(),
+        headers: with_cseq("5 INFO"),
+        body: vec![],
+        version: rsip::Version::V2,
+    };
+    let response = |cseq: &str| rsip::Response {
+        status_code: rsip::StatusCode::OK,
+        headers: with_cseq(cseq),
+        version: rsip::Version::V2,
+        body: vec![],
+    };
+    assert!(response_matches(&request, &response("5 INFO")));
+    assert!(!response_matches(&request, &response("4 INFO")));
+    assert!(!response_matches(&request, &response("5 OPTIONS")));
+    // no CSeq at all
+    assert!(!response_matches(
+        &request,
+        &rsip::Response {
+            headers: vec![].into(),
+            ..response("5 INFO")
+        }
+    ));
+}
+
+#[tokio::test]
+async fn test_retransmission_answered() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let mut bob = TestUa::new("bob").await?;
+    let (dialog, _states, (_, mut bob_states)) = alice
+        .call(&mut bob, b"v=0 alice\r\n".to_vec(), b"v=0 bob\r\n".to_vec())
+        .await?;
+
+    let request = dialog
+        .inner
+        .make_request(rsip::Method::Info, None, None, None, None, None)?;
+    let resp = dialog.inner.do_request(request.clone()).await?;
+    assert_eq!(resp.map(|r| r.status_code), Some(rsip::StatusCode::OK));
+    wait_state(&mut bob_states, |s| matches!(s, DialogState::Info(..))).await;
+
+    // the same INFO on another transaction gets the 200 again, and isn't
+    // processed twice
+    let mut retransmission = request;
+    let via = alice.endpoint.inner.get_via(None, None)?;
+    retransmission
+        .headers
+        .unique_push(rsip::Header::Via(via.into()));
+    let resp = dialog.inner.do_request(retransmission).await?;
+    assert_eq!(resp.map(|r| r.status_code), Some(rsip::StatusCode::OK));
+    assert!(
+        no_state(&mut bob_states, Duration::from_millis(200), |s| {
+            matches!(s, DialogState::Info(..))
+        })
+        .await
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_send_request() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let mut bob = TestUa::new("bob").await?;
+    let (client, _states, (_server, mut server_states)) = alice
+        .call(&mut bob, b"v=0 alice\r\n".to_vec(), b"v=0 bob\r\n".to_vec())
+        .await?;
+    let dialog = Dialog::ClientInvite(client);
+
+    // the methods with a transaction of their own are refused
+    for method in [
+        rsip::Method::Invite,
+        rsip::Method::Ack,
+        rsip::Method::Cancel,
+    ] {
+        assert!(dialog.send_request(method, None, None).await.is_err());
+    }
+
+    // any other goes in the dialog
+    let resp = dialog
+        .send_request(
+            rsip::Method::Info,
+            Some(vec![rsip::Header::ContentType("text/plain".into())]),
+            Some(b"hello".to_vec()),
+        )
+        .await?
+        .expect("response");
+    assert_eq!(resp.status_code, rsip::StatusCode::OK);
+    let state = wait_state(&mut server_states, |s| matches!(s, DialogState::Info(_, _))).await;
+    match state {
+        DialogState::Info(_, req) => assert_eq!(req.body, b"hello".to_vec()),
+        _ => unreachable!(),
+    }
+    Ok(())
+}
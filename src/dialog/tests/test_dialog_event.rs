@@ -0,0 +1,70 @@
+use crate::dialog::{
+    dialog::DialogState,
+    dialog_event::{event_stream, DialogEvent},
+    hold::HoldState,
+    reason::Reason,
+    DialogId,
+};
+use futures::StreamExt;
+use tokio::sync::broadcast;
+
+fn dialog_id() -> DialogId {
+    DialogId {
+        call_id: "call-1".to_string(),
+        from_tag: "alice".to_string(),
+        to_tag: "bob".to_string(),
+    }
+}
+
+#[test]
+fn test_dialog_event_from_state() {
+    let id = dialog_id();
+    assert!(matches!(
+        DialogEvent::from_state(&DialogState::Confirmed(id.clone())),
+        Some(DialogEvent::Confirmed { .. })
+    ));
+    assert!(matches!(
+        DialogEvent::from_state(&DialogState::HoldStateChanged(id.clone(), HoldState::Held)),
+        Some(DialogEvent::Hold {
+            state: HoldState::Held,
+            ..
+        })
+    ));
+    assert!(DialogEvent::from_state(&DialogState::Trying(id.clone())).is_none());
+
+    let event = DialogEvent::from_state(&DialogState::Terminated(
+        id.clone(),
+        Some(rsip::StatusCode::OK),
+        Some(Reason::sip(200)),
+    ))
+    .expect("terminated event");
+    assert_eq!(event.id(), &id);
+    match event {
+        DialogEvent::Terminated { code, reason, .. } => {
+            assert_eq!(code, Some(rsip::StatusCode::OK));
+            assert_eq!(reason, Some(Reason::sip(200)));
+        }
+        event => panic!("unexpected event: {:?}", event),
+    }
+}
+
+#[tokio::test]
+async fn test_dialog_event_stream() {
+    let (sender, _) = broadcast::channel(4);
+    let mut first = Box::pin(event_stream(sender.subscribe()));
+    let mut second = Box::pin(event_stream(sender.subscribe()));
+    sender
+        .send(DialogEvent::Confirmed { id: dialog_id() })
+        .expect("send");
+    drop(sender);
+
+    assert!(matches!(
+        first.next().await,
+        Some(DialogEvent::Confirmed { .. })
+    ));
+    assert!(first.next().await.is_none());
+    assert!(matches!(
+        second.next().await,
+        Some(DialogEvent::Confirmed { .. })
+    ));
+}
@@ -0,0 +1,116 @@
+use super::{make_response, TestUa};
+use crate::dialog::{
+    dialog::DialogState,
+    dialog_info::{DialogInfo, DialogInfoEntry, DialogInfoState},
+    presence::presentity,
+    DialogId,
+};
+use std::{sync::Arc, time::Duration};
+
+#[test]
+fn test_dialog_info_roundtrip() {
+    let info = DialogInfo {
+        version: 3,
+        state: "full".to_string(),
+        entity: "sip:alice@example.com".to_string(),
+        dialogs: vec![DialogInfoEntry {
+            id: "d1".to_string(),
+            call_id: Some("abc@host".to_string()),
+            local_tag: Some("l1".to_string()),
+            remote_tag: None,
+            direction: Some("initiator".to_string()),
+            state: DialogInfoState::Early,
+        }],
+    };
+    let parsed = DialogInfo::try_from(info.to_xml().as_str()).unwrap();
+    assert_eq!(parsed, info);
+}
+
+#[test]
+fn test_parse_dialog_info() {
+    let xml = r#"<?xml version="1.0"?>
+<dialog-info xmlns="urn:ietf:params:xml:ns:dialog-info" version="1" state="partial" entity="sip:bob@example.com">
+  <dialog id="x" call-id="c1" direction="recipient">
+    <state event="rejected">terminated</state>
+  </dialog>
+  <dialog id='y'><state>confirmed</state></dialog>
+</dialog-info>"#;
+    let info = DialogInfo::try_from(xml).unwrap();
+    assert_eq!(info.version, 1);
+    assert_eq!(info.state, "partial");
+    assert_eq!(info.entity, "sip:bob@example.com");
+    assert_eq!(info.dialogs.len(), 2);
+    assert_eq!(info.dialogs[0].state, DialogInfoState::Terminated);
+    assert_eq!(info.dialogs[0].direction.as_deref(), Some("recipient"));
+    assert_eq!(info.dialogs[1].id, "y");
+    assert_eq!(info.dialogs[1].state, DialogInfoState::Confirmed);
+}
+
+#[test]
+fn test_dialog_info_state() {
+    let id = DialogId {
+        call_id: "c1".to_string(),
+        from_tag: "f1".to_string(),
+        to_tag: "".to_string(),
+    };
+    let ringing = |to: &str| {
+        let resp = make_response(rsip::StatusCode::Ringing, vec![rsip::Header::To(to.into())]);
+        DialogState::Early(id.clone(), Arc::new(resp))
+    };
+    let cases = [
+        (DialogState::Calling(id.clone()), DialogInfoState::Trying),
+        (DialogState::Trying(id.clone()), DialogInfoState::Proceeding),
+        (
+            ringing("<sip:bob@example.com>"),
+            DialogInfoState::Proceeding,
+        ),
+        (
+            ringing("<sip:bob@example.com>;tag=t1"),
+            DialogInfoState::Early,
+        ),
+        (
+            DialogState::Confirmed(id.clone()),
+            DialogInfoState::Confirmed,
+        ),
+        (
+            DialogState::Terminated(id.clone(), None, None),
+            DialogInfoState::Terminated,
+        ),
+    ];
+    for (state, expected) in cases {
+        assert_eq!(DialogInfoState::from(&state), expected, "{}", state);
+    }
+}
+
+#[tokio::test]
+async fn test_dialog_state_changes() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let mut bob = TestUa::new("bob").await?;
+    let mut changes = bob.layer.state_changes();
+    let (_, _, (server, _)) = alice
+        .call(&mut bob, b"v=0 alice\r\n".to_vec(), b"v=0 bob\r\n".to_vec())
+        .await?;
+
+    // the answered INVITE dialog of bob reports its state changes
+    let id = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let id = changes.recv().await.expect("state changes closed");
+            if bob
+                .layer
+                .get_dialog(&id)
+                .map(|d| d.inner().state.lock().unwrap().is_confirmed())
+                .unwrap_or(false)
+            {
+                return id;
+            }
+        }
+    })
+    .await
+    .expect("no state change");
+    assert_eq!(id, server.id());
+    let info = bob.layer.dialog_info(&presentity(&bob.contact), 0);
+    assert_eq!(info.dialogs.len(), 1);
+    assert_eq!(info.dialogs[0].state, DialogInfoState::Confirmed);
+    assert_eq!(info.dialogs[0].direction.as_deref(), Some("recipient"));
+    Ok(())
+}
@@ -0,0 +1,26 @@
+use crate::dialog::{
+    dialog_store::{DialogKind, DialogRecord, DialogStore, MemoryDialogStore},
+    DialogId,
+};
+
+#[tokio::test]
+async fn test_memory_dialog_store() {
+    let store = MemoryDialogStore::new();
+    let id = DialogId {
+        call_id: "call-1".to_string(),
+        from_tag: "alice".to_string(),
+        to_tag: "bob".to_string(),
+    };
+    let record = DialogRecord {
+        id: id.clone(),
+        kind: DialogKind::ServerInvite,
+        owner: "node-a".to_string(),
+    };
+    store.register(record.clone()).await.expect("register");
+    assert_eq!(store.lookup(&id).await.expect("lookup"), Some(record));
+    assert_eq!(store.list().await.expect("list").len(), 1);
+
+    store.unregister(&id).await.expect("unregister");
+    assert_eq!(store.lookup(&id).await.expect("lookup"), None);
+    assert!(store.list().await.expect("list").is_empty());
+}
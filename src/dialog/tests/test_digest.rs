@@ -0,0 +1,457 @@
+use crate::dialog::authenticate::{
+    is_stale_challenge, AuthAttempts, AuthCache, Credential, CredentialStore, CredentialStoreRef,
+    RealmCredentials,
+};
+use crate::dialog::digest::{
+    select_challenge, DigestAlgorithm, DigestAuthenticator, DigestChallenge, DigestCredentials,
+    DigestSecret, DigestSecretStore, DigestVerdict,
+};
+use rsip::Header;
+use std::sync::Arc;
+
+#[test]
+fn test_digest_algorithms() {
+    assert_eq!(
+        DigestAlgorithm::Md5.hash(b"abc"),
+        "900150983cd24fb0d6963f7d28e17f72"
+    );
+    assert_eq!(
+        DigestAlgorithm::Sha256.hash(b"abc"),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+    assert_eq!(
+        DigestAlgorithm::Sha512_256.hash(b"abc"),
+        "53048e2681941ef99b2e29b76b4c7dabe4c2d0c634fc6d46e0e2f13107e7af23"
+    );
+    assert_eq!(
+        DigestAlgorithm::parse("sha-512-256-sess"),
+        Some(DigestAlgorithm::Sha512_256Sess)
+    );
+    assert_eq!(DigestAlgorithm::parse("AKAv1-MD5"), None);
+}
+
+#[test]
+fn test_digest_challenge_selection() {
+    let challenge = select_challenge([
+        "Digest realm=\"example.com\", nonce=\"abc\", algorithm=AKAv1-MD5",
+        "Digest realm=\"example.com\", nonce=\"abc\", algorithm=SHA-256, qop=\"auth,auth-int\", opaque=\"o\"",
+        "Digest realm=\"example.com\", nonce=\"abc\"",
+    ])
+    .expect("challenge");
+    assert_eq!(challenge.algorithm, DigestAlgorithm::Sha256);
+    assert_eq!(challenge.qop, vec!["auth", "auth-int"]);
+    assert_eq!(challenge.opaque.as_deref(), Some("o"));
+    assert_eq!(
+        DigestChallenge::parse(&challenge.to_string()),
+        Some(challenge)
+    );
+
+    let md5 = DigestChallenge::parse("Digest realm=\"example.com\", nonce=\"abc\"").unwrap();
+    assert_eq!(md5.algorithm, DigestAlgorithm::Md5);
+    assert!(DigestChallenge::parse("Basic realm=\"example.com\"").is_none());
+}
+
+#[test]
+fn test_digest_credentials() {
+    for algorithm in [
+        DigestAlgorithm::Md5,
+        DigestAlgorithm::Sha256Sess,
+        DigestAlgorithm::Sha512_256,
+    ] {
+        let challenge = DigestChallenge {
+            realm: "example.com".to_string(),
+            nonce: "nonce".to_string(),
+            algorithm,
+            qop: vec!["auth".to_string()],
+            ..Default::default()
+        };
+        let credentials = DigestCredentials::answer(
+            &challenge,
+            "alice",
+            "secret",
+            &rsip::Method::Invite,
+            "sip:bob@example.com",
+            &[],
+            "cnonce",
+        );
+        let parsed = DigestCredentials::parse(&credentials.to_string()).expect("credentials");
+        assert_eq!(parsed, credentials);
+        assert_eq!(parsed.nc, Some(1));
+        assert!(parsed.verify(
+            &algorithm.ha1("alice", "example.com", "secret"),
+            &rsip::Method::Invite,
+            &[]
+        ));
+        assert!(!parsed.verify(
+            &algorithm.ha1("alice", "example.com", "wrong"),
+            &rsip::Method::Invite,
+            &[]
+        ));
+        assert!(!parsed.verify(
+            &algorithm.ha1("alice", "example.com", "secret"),
+            &rsip::Method::Bye,
+            &[]
+        ));
+    }
+}
+
+#[test]
+fn test_digest_auth_int() {
+    let challenge = DigestChallenge {
+        realm: "example.com".to_string(),
+        nonce: "nonce".to_string(),
+        algorithm: DigestAlgorithm::Sha256,
+        qop: vec!["auth".to_string(), "auth-int".to_string()],
+        ..Default::default()
+    };
+    let ha1 = DigestAlgorithm::Sha256.ha1("alice", "example.com", "secret");
+    let body = b"v=0\r\n";
+    let answer = |body: &[u8]| {
+        DigestCredentials::answer(
+            &challenge,
+            "alice",
+            "secret",
+            &rsip::Method::Message,
+            "sip:bob@example.com",
+            body,
+            "cnonce",
+        )
+    };
+
+    let credentials = answer(body);
+    assert_eq!(credentials.qop.as_deref(), Some("auth-int"));
+    assert!(credentials.verify(&ha1, &rsip::Method::Message, body));
+    // the body is covered
+    assert!(!credentials.verify(&ha1, &rsip::Method::Message, b"v=1\r\n"));
+
+    // without body, auth is enough
+    let credentials = answer(&[]);
+    assert_eq!(credentials.qop.as_deref(), Some("auth"));
+    assert!(credentials.verify(&ha1, &rsip::Method::Message, b"ignored"));
+}
+
+#[test]
+fn test_auth_cache() {
+    let cache = AuthCache::new();
+    let cred: CredentialStoreRef = Credential {
+        username: "alice".to_string(),
+        password: "secret".to_string(),
+    }
+    .into();
+    let mut request = rsip::Request {
+        method: rsip::Method::Register,
+        uri: rsip::Uri::try_from("sip:example.com").unwrap(),
+        headers: vec![].into(),
+        version: rsip::Version::V2,
+        body: vec![],
+    };
+    assert!(cache.authorize(&request, &cred).is_empty());
+
+    let challenge = DigestChallenge::parse(
+        "Digest realm=\"example.com\", nonce=\"abc\", algorithm=SHA-256, qop=\"auth\"",
+    )
+    .unwrap();
+    cache.remember(&request, "alice", challenge, false);
+    // answered as alice, never replayed for another user
+    let other: CredentialStoreRef = Credential {
+        username: "bob".to_string(),
+        password: "secret".to_string(),
+    }
+    .into();
+    assert!(cache.authorize(&request, &other).is_empty());
+    cache.authorize_request(&mut request, &cred);
+    let value = crate::rsip_ext::header_value(&request.headers, "Authorization").unwrap();
+    let auth = DigestCredentials::parse(&value).unwrap();
+    assert_eq!(auth.nc, Some(2));
+    let ha1 = DigestAlgorithm::Sha256.ha1("alice", "example.com", "secret");
+    assert!(auth.verify(&ha1, &rsip::Method::Register, &[]));
+
+    // already authorized
+    cache.authorize_request(&mut request, &cred);
+    assert_eq!(
+        crate::rsip_ext::header_values(&request.headers, "Authorization").len(),
+        1
+    );
+
+    request.method = rsip::Method::Ack;
+    assert!(cache.authorize(&request, &cred).is_empty());
+    request.method = rsip::Method::Options;
+    assert_eq!(cache.authorize(&request, &cred).len(), 1);
+
+    // the authorization of the request got rejected
+    cache.forget(&request);
+    assert!(cache.authorize(&request, &cred).is_empty());
+}
+
+#[test]
+fn test_realm_credentials() {
+    let credential = |username: &str| Credential {
+        username: username.to_string(),
+        password: "secret".to_string(),
+    };
+    let credentials = RealmCredentials::new()
+        .with_realm("carrier.net", credential("trunk"))
+        .with_destination("carrier.net", "sbc2.carrier.net", credential("trunk2"))
+        .with_realm("pbx.local", credential("100"));
+    let username = |realm: &str, destination: &str| {
+        credentials
+            .credential(realm, destination)
+            .map(|cred| cred.username)
+    };
+    assert_eq!(
+        username("carrier.net", "sbc1.carrier.net").as_deref(),
+        Some("trunk")
+    );
+    assert_eq!(
+        username("carrier.net", "SBC2.carrier.net").as_deref(),
+        Some("trunk2")
+    );
+    assert_eq!(
+        username("pbx.local", "sbc2.carrier.net").as_deref(),
+        Some("100")
+    );
+    assert_eq!(username("other.org", "sbc1.carrier.net"), None);
+    assert_eq!(
+        credentials.username("sbc2.carrier.net").as_deref(),
+        Some("trunk2")
+    );
+    assert_eq!(credentials.username("10.0.0.1").as_deref(), Some("trunk"));
+
+    let credentials = credentials.with_fallback(credential("guest"));
+    assert_eq!(username("other.org", "10.0.0.1").as_deref(), Some("guest"));
+    assert_eq!(credentials.username("10.0.0.1").as_deref(), Some("guest"));
+}
+
+/// HA1s of alice, per algorithm
+struct Ha1s;
+
+#[async_trait::async_trait]
+impl DigestSecretStore for Ha1s {
+    async fn secret(
+        &self,
+        username: &str,
+        realm: &str,
+        algorithm: DigestAlgorithm,
+    ) -> crate::Result<Option<DigestSecret>> {
+        Ok((username == "alice")
+            .then(|| DigestSecret::Ha1(algorithm.ha1(username, realm, "secret"))))
+    }
+}
+
+#[tokio::test]
+async fn test_digest_authenticator() {
+    let authenticator = DigestAuthenticator::new("example.com", Arc::new(Ha1s))
+        .with_algorithms(vec![DigestAlgorithm::Sha256, DigestAlgorithm::Md5])
+        .with_opaque("o1");
+    let mut request = rsip::Request {
+        method: rsip::Method::Invite,
+        uri: rsip::Uri::try_from("sip:bob@example.com").unwrap(),
+        headers: vec![].into(),
+        version: rsip::Version::V2,
+        body: b"v=0\r\n".to_vec(),
+    };
+    assert_eq!(authenticator.verify(&request, true).await.unwrap(), None);
+
+    let headers = authenticator.challenge_headers(true, false);
+    assert_eq!(headers.len(), 2);
+    let challenge = select_challenge(headers.iter().filter_map(|h| match h {
+        Header::ProxyAuthenticate(h) => Some(rsip::prelude::UntypedHeader::value(h)),
+        _ => None,
+    }))
+    .expect("challenge");
+    assert_eq!(challenge.algorithm, DigestAlgorithm::Sha256);
+    assert_eq!(challenge.opaque.as_deref(), Some("o1"));
+
+    let answer = |username: &str, password: &str, challenge: &DigestChallenge| {
+        DigestCredentials::answer(
+            challenge,
+            username,
+            password,
+            &rsip::Method::Invite,
+            "sip:bob@example.com",
+            b"v=0\r\n",
+            "cnonce",
+        )
+    };
+    let authorize = |request: &mut rsip::Request, auth: DigestCredentials| {
+        request
+            .headers
+            .retain(|h| !matches!(h, Header::ProxyAuthorization(_)));
+        request
+            .headers
+            .push(Header::ProxyAuthorization(auth.to_string().into()));
+    };
+
+    authorize(&mut request, answer("alice", "wrong", &challenge));
+    assert_eq!(authenticator.verify(&request, true).await.unwrap(), None);
+    authorize(&mut request, answer("carol", "secret", &challenge));
+    assert_eq!(authenticator.verify(&request, true).await.unwrap(), None);
+    // a nonce we never issued
+    let forged = DigestChallenge {
+        nonce: "forged".to_string(),
+        ..challenge.clone()
+    };
+    authorize(&mut request, answer("alice", "secret", &forged));
+    assert_eq!(authenticator.verify(&request, true).await.unwrap(), None);
+    // one of our nonces with another issue time
+    let (issued, rest) = challenge.nonce.split_once('.').unwrap();
+    let tampered = DigestChallenge {
+        nonce: format!("{}0.{}", issued, rest),
+        ..challenge.clone()
+    };
+    authorize(&mut request, answer("alice", "secret", &tampered));
+    assert_eq!(
+        authenticator.check(&request, true).await.unwrap(),
+        DigestVerdict::Stale
+    );
+    // right credentials for another Request-URI
+    let elsewhere = DigestCredentials::answer(
+        &challenge,
+        "alice",
+        "secret",
+        &rsip::Method::Invite,
+        "sip:carol@example.com",
+        b"v=0\r\n",
+        "cnonce",
+    );
+    authorize(&mut request, elsewhere);
+    assert_eq!(
+        authenticator.check(&request, true).await.unwrap(),
+        DigestVerdict::UriMismatch
+    );
+
+    authorize(&mut request, answer("alice", "secret", &challenge));
+    assert_eq!(
+        authenticator
+            .verify(&request, true)
+            .await
+            .unwrap()
+            .as_deref(),
+        Some("alice")
+    );
+    // Proxy-Authorization doesn't authorize for a 401
+    assert_eq!(authenticator.verify(&request, false).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_digest_nonce_lifecycle() {
+    let authenticator = DigestAuthenticator::new("example.com", Arc::new(Ha1s));
+    let challenge = authenticator.challenges(false).remove(0);
+    let mut request = rsip::Request {
+        method: rsip::Method::Register,
+        uri: rsip::Uri::try_from("sip:example.com").unwrap(),
+        headers: vec![].into(),
+        version: rsip::Version::V2,
+        body: vec![],
+    };
+    let mut auth = DigestCredentials::answer(
+        &challenge,
+        "alice",
+        "secret",
+        &rsip::Method::Register,
+        "sip:example.com",
+        &[],
+        "cnonce",
+    );
+    let ha1 = DigestAlgorithm::Md5.ha1("alice", "example.com", "secret");
+    let mut authorize = |request: &mut rsip::Request, nc: u32| {
+        auth.nc = Some(nc);
+        auth.response = auth.expected_response(&ha1, &rsip::Method::Register, &[]);
+        request.headers = vec![Header::Authorization(auth.to_string().into())].into();
+    };
+
+    authorize(&mut request, 1);
+    assert_eq!(
+        authenticator.check(&request, false).await.unwrap(),
+        DigestVerdict::Authorized("alice".to_string())
+    );
+    // replayed
+    assert_eq!(
+        authenticator.check(&request, false).await.unwrap(),
+        DigestVerdict::Unauthorized
+    );
+    authorize(&mut request, 3);
+    assert!(authenticator
+        .verify(&request, false)
+        .await
+        .unwrap()
+        .is_some());
+    authorize(&mut request, 2);
+    assert!(authenticator
+        .verify(&request, false)
+        .await
+        .unwrap()
+        .is_none());
+
+    // the nonce expired: right credentials are re-challenged as stale
+    let authenticator = authenticator.with_nonce_lifetime(std::time::Duration::ZERO);
+    authorize(&mut request, 4);
+    assert_eq!(
+        authenticator.check(&request, false).await.unwrap(),
+        DigestVerdict::Stale
+    );
+    let mut resp = rsip::Response {
+        status_code: rsip::StatusCode::Unauthorized,
+        headers: authenticator.challenge_headers(false, true).into(),
+        version: rsip::Version::V2,
+        body: vec![],
+    };
+    assert!(is_stale_challenge(&resp));
+
+    let mut attempts = AuthAttempts::default();
+    assert!(attempts.may_answer(&resp));
+    assert!(attempts.may_answer(&resp));
+    assert!(attempts.may_answer(&resp));
+    // a server expiring every nonce
+    assert!(!attempts.may_answer(&resp));
+
+    let mut attempts = AuthAttempts::default();
+    assert!(attempts.may_answer(&resp));
+    resp.headers = authenticator.challenge_headers(false, false).into();
+    assert!(!is_stale_challenge(&resp));
+    assert!(!attempts.may_answer(&resp));
+}
+
+#[tokio::test]
+async fn test_digest_nonce_table_full() {
+    let authenticator = DigestAuthenticator::new("example.com", Arc::new(Ha1s));
+    let register = |challenge: &DigestChallenge| {
+        let auth = DigestCredentials::answer(
+            challenge,
+            "alice",
+            "secret",
+            &rsip::Method::Register,
+            "sip:example.com",
+            &[],
+            "cnonce",
+        );
+        rsip::Request {
+            method: rsip::Method::Register,
+            uri: rsip::Uri::try_from("sip:example.com").unwrap(),
+            headers: vec![Header::Authorization(auth.to_string().into())].into(),
+            version: rsip::Version::V2,
+            body: vec![],
+        }
+    };
+
+    let first = register(&authenticator.challenges(false).remove(0));
+    assert!(authenticator.verify(&first, false).await.unwrap().is_some());
+    for _ in 1..4096 {
+        let request = register(&authenticator.challenges(false).remove(0));
+        assert!(authenticator
+            .verify(&request, false)
+            .await
+            .unwrap()
+            .is_some());
+    }
+    // a live nonce isn't forgotten for a new one: its count still replays
+    let request = register(&authenticator.challenges(false).remove(0));
+    assert_eq!(
+        authenticator.check(&request, false).await.unwrap(),
+        DigestVerdict::Stale
+    );
+    assert_eq!(
+        authenticator.check(&first, false).await.unwrap(),
+        DigestVerdict::Unauthorized
+    );
+}
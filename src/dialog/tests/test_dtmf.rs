@@ -0,0 +1,74 @@
+use super::{wait_state, TestUa};
+use crate::{
+    dialog::{dialog::DialogState, dtmf::DtmfEvent},
+    rsip_ext::header_value,
+};
+use rsip::prelude::{HeadersExt, UntypedHeader};
+use std::time::Duration;
+
+#[test]
+fn test_dtmf_relay_body() {
+    let event = DtmfEvent::new('5', Duration::from_millis(160)).unwrap();
+    assert_eq!(event.to_body(), b"Signal=5\r\nDuration=160\r\n".to_vec());
+    assert_eq!(
+        DtmfEvent::try_from("Signal=5\r\nDuration=160\r\n").unwrap(),
+        event
+    );
+    assert!(DtmfEvent::new('x', Duration::from_millis(100)).is_err());
+}
+
+#[test]
+fn test_parse_dtmf_relay() {
+    let event = DtmfEvent::try_from("Signal= 11\nDuration= 250\n").unwrap();
+    assert_eq!(event.digit, '#');
+    assert_eq!(event.duration, Duration::from_millis(250));
+    assert_eq!(DtmfEvent::try_from("signal=a").unwrap().digit, 'A');
+    assert!(DtmfEvent::try_from("Duration=100").is_err());
+}
+
+#[tokio::test]
+async fn test_info_with_body() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let mut bob = TestUa::new("bob").await?;
+    let (client, _states, (_server, mut server_states)) = alice
+        .call(&mut bob, b"v=0 alice\r\n".to_vec(), b"v=0 bob\r\n".to_vec())
+        .await?;
+
+    // a video fast-update request (RFC 5168)
+    let fast_update = b"<media_control><vc_primitive><to_encoder><picture_fast_update/></to_encoder></vc_primitive></media_control>".to_vec();
+    let resp = client
+        .info(
+            Some(vec![rsip::Header::Other(
+                "Info-Package".into(),
+                "fast-update".into(),
+            )]),
+            Some("application/media_control+xml".to_string()),
+            Some(fast_update.clone()),
+        )
+        .await?
+        .expect("response");
+    assert_eq!(resp.status_code, rsip::StatusCode::OK);
+    let DialogState::Info(_, req) =
+        wait_state(&mut server_states, |s| matches!(s, DialogState::Info(..))).await
+    else {
+        unreachable!()
+    };
+    assert_eq!(req.body, fast_update);
+    assert_eq!(
+        req.content_type_header()?.value(),
+        "application/media_control+xml"
+    );
+    assert_eq!(
+        header_value(&req.headers, "Info-Package"),
+        Some("fast-update".to_string())
+    );
+
+    // a dtmf-relay INFO is reported as the digit
+    client.send_dtmf('7', Duration::from_millis(100)).await?;
+    let state = wait_state(&mut server_states, |s| matches!(s, DialogState::Dtmf(..))).await;
+    let DialogState::Dtmf(_, event) = state else {
+        unreachable!()
+    };
+    assert_eq!(event, DtmfEvent::new('7', Duration::from_millis(100))?);
+    Ok(())
+}
@@ -0,0 +1,72 @@
+use super::{no_state, wait_state, TestUa};
+use crate::dialog::{dialog::DialogState, expiration::DialogExpiration};
+use std::time::Duration;
+
+fn expired_by(state: &DialogState) -> Option<u16> {
+    match state {
+        DialogState::Terminated(_, _, Some(reason)) => Some(reason.cause),
+        _ => None,
+    }
+}
+
+#[tokio::test]
+async fn test_inactivity_expiration() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let mut bob = TestUa::new("bob").await?;
+    let (client, mut states, (server, mut server_states)) = alice
+        .call(&mut bob, b"v=0 alice\r\n".to_vec(), b"v=0 bob\r\n".to_vec())
+        .await?;
+    server.set_expiration(DialogExpiration {
+        max_lifetime: None,
+        inactivity: Some(Duration::from_millis(300)),
+    });
+
+    // requests of the peer keep the dialog alive past the inactivity timeout
+    for _ in 0..4 {
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        client.info(None, None, None).await?;
+    }
+    assert!(
+        no_state(&mut server_states, Duration::from_millis(50), |s| {
+            matches!(s, DialogState::Terminated(..))
+        })
+        .await
+    );
+
+    // then the callee hangs up once the peer goes quiet
+    let state = wait_state(&mut states, |s| matches!(s, DialogState::Terminated(..))).await;
+    assert_eq!(expired_by(&state), Some(408));
+    let state = wait_state(&mut server_states, |s| {
+        matches!(s, DialogState::Terminated(..))
+    })
+    .await;
+    assert_eq!(expired_by(&state), Some(408));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_lifetime_expiration() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let mut bob = TestUa::new("bob").await?;
+    let (client, mut states, (_server, mut server_states)) = alice
+        .call(&mut bob, b"v=0 alice\r\n".to_vec(), b"v=0 bob\r\n".to_vec())
+        .await?;
+    client.set_expiration(DialogExpiration {
+        max_lifetime: Some(Duration::from_millis(300)),
+        inactivity: None,
+    });
+
+    assert!(
+        no_state(&mut states, Duration::from_millis(150), |s| {
+            matches!(s, DialogState::Terminated(..))
+        })
+        .await
+    );
+    let state = wait_state(&mut server_states, |s| {
+        matches!(s, DialogState::Terminated(..))
+    })
+    .await;
+    assert_eq!(expired_by(&state), Some(408));
+    wait_state(&mut states, |s| matches!(s, DialogState::Terminated(..))).await;
+    Ok(())
+}
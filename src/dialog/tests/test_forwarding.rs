@@ -0,0 +1,54 @@
+use super::make_invite;
+use crate::dialog::forwarding::CallForwarder;
+use crate::rsip_ext::header_value;
+use std::sync::Arc;
+
+#[test]
+fn test_forward_headers() {
+    let target = rsip::Uri::try_from("sip:voicemail@example.com").unwrap();
+    let forwarder = CallForwarder::new(target)
+        .with_diversion("no-answer")
+        .with_history_info();
+    let headers: rsip::Headers = forwarder
+        .make_headers(&make_invite(
+            "sip:alice@example.com",
+            "sip:bob@example.com",
+            vec![],
+        ))
+        .into();
+    assert_eq!(
+        header_value(&headers, "Diversion").unwrap(),
+        "<sip:bob@example.com>;reason=no-answer;counter=1"
+    );
+    assert_eq!(
+        header_value(&headers, "History-Info").unwrap(),
+        "<sip:bob@example.com>;index=1, <sip:voicemail@example.com?Reason=SIP%3Bcause%3D302>;index=1.1"
+    );
+
+    let forwarded = make_invite(
+        "sip:alice@example.com",
+        "sip:bob@example.com",
+        vec![rsip::Header::Other(
+            "History-Info".into(),
+            "<sip:bob@example.com>;index=1".into(),
+        )],
+    );
+    let headers: rsip::Headers = forwarder.make_headers(&forwarded).into();
+    assert_eq!(
+        header_value(&headers, "History-Info").unwrap(),
+        "<sip:voicemail@example.com?Reason=SIP%3Bcause%3D302>;index=1.1"
+    );
+}
+
+#[test]
+fn test_forward_filter() {
+    let target = rsip::Uri::try_from("sip:voicemail@example.com").unwrap();
+    let forwarder = CallForwarder::new(target).with_filter(Arc::new(|req: &rsip::Request| {
+        req.uri.to_string().contains("carol")
+    }));
+    assert!(!forwarder.matches(&make_invite(
+        "sip:alice@example.com",
+        "sip:bob@example.com",
+        vec![]
+    )));
+}
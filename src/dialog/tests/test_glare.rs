@@ -0,0 +1,15 @@
+use crate::dialog::dialog::glare_timeout;
+use std::time::Duration;
+
+#[test]
+fn test_glare_timeout() {
+    for _ in 0..100 {
+        let owner = glare_timeout(true);
+        assert!(owner >= Duration::from_millis(2100) && owner <= Duration::from_secs(4));
+        assert_eq!(owner.as_millis() % 10, 0);
+
+        let other = glare_timeout(false);
+        assert!(other <= Duration::from_secs(2));
+        assert_eq!(other.as_millis() % 10, 0);
+    }
+}
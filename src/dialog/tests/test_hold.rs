@@ -0,0 +1,56 @@
+use super::TestUa;
+use crate::dialog::hold::{media_direction, set_media_direction, MediaDirection};
+
+const SDP: &str = "v=0\r\n\
+o=- 1000 1 IN IP4 127.0.0.1\r\n\
+s=-\r\n\
+c=IN IP4 127.0.0.1\r\n\
+t=0 0\r\n\
+a=sendrecv\r\n\
+m=audio 4000 RTP/AVP 0\r\n\
+a=rtpmap:0 PCMU/8000\r\n\
+a=sendrecv\r\n\
+m=video 4002 RTP/AVP 96\r\n\
+a=rtpmap:96 H264/90000\r\n";
+
+#[test]
+fn test_set_media_direction() {
+    let held = set_media_direction(SDP.as_bytes(), MediaDirection::SendOnly);
+    let held_text = String::from_utf8(held.clone()).unwrap();
+    assert!(held_text.contains("o=- 1000 2 IN IP4 127.0.0.1\r\n"));
+    assert_eq!(held_text.matches("a=sendonly").count(), 2);
+    assert!(!held_text.contains("a=sendrecv"));
+    assert!(held_text.contains("a=rtpmap:0 PCMU/8000\r\na=sendonly\r\nm=video"));
+    assert!(held_text.ends_with("a=sendonly\r\n"));
+    assert_eq!(media_direction(&held), MediaDirection::SendOnly);
+
+    let resumed = set_media_direction(&held, MediaDirection::SendRecv);
+    let resumed_text = String::from_utf8(resumed.clone()).unwrap();
+    assert!(resumed_text.contains("o=- 1000 3 IN IP4 127.0.0.1\r\n"));
+    assert_eq!(resumed_text.matches("a=sendrecv").count(), 2);
+    assert_eq!(media_direction(&resumed), MediaDirection::SendRecv);
+    assert_eq!(
+        media_direction(b"v=0\r\nm=audio 4000 RTP/AVP 0\r\n"),
+        MediaDirection::SendRecv
+    );
+}
+
+#[tokio::test]
+async fn test_unhold_restores_direction() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let mut bob = TestUa::new("bob").await?;
+    let offer = set_media_direction(SDP.as_bytes(), MediaDirection::RecvOnly);
+    let (client, _states, _server) = alice.call(&mut bob, offer, SDP.as_bytes().to_vec()).await?;
+    let direction = || media_direction(&client.local_sdp().unwrap());
+
+    client.hold().await?.expect("response");
+    assert_eq!(direction(), MediaDirection::SendOnly);
+    // held again, the direction before the first hold is kept
+    client.hold().await?.expect("response");
+    client.unhold().await?.expect("response");
+    assert_eq!(direction(), MediaDirection::RecvOnly);
+    // nothing recorded without a hold
+    client.unhold().await?.expect("response");
+    assert_eq!(direction(), MediaDirection::SendRecv);
+    Ok(())
+}
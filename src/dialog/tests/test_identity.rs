@@ -0,0 +1,146 @@
+use super::make_invite;
+use crate::dialog::identity::{
+    sign_request, verify_request, verify_request_at, Attestation, IdentitySigner, IdentitySigning,
+    IdentityStatus, IdentityVerifier, IdentityVerifierRef,
+};
+use crate::rsip_ext::header_value;
+use rsip::{Header, StatusCode};
+use sha2::{Digest, Sha256};
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+const X5U: &str = "https://cert.example.com/sp.pem";
+
+/// Stands in for ES256, a keyed hash
+struct KeyedHash(&'static str);
+
+impl IdentitySigner for KeyedHash {
+    fn x5u(&self) -> String {
+        X5U.to_string()
+    }
+
+    fn sign(&self, input: &[u8]) -> crate::Result<Vec<u8>> {
+        Ok(Sha256::digest([self.0.as_bytes(), input].concat()).to_vec())
+    }
+}
+
+#[async_trait::async_trait]
+impl IdentityVerifier for KeyedHash {
+    async fn verify(&self, x5u: &str, input: &[u8], signature: &[u8]) -> crate::Result<bool> {
+        if x5u != X5U {
+            return Err(crate::Error::Error(format!("untrusted {}", x5u)));
+        }
+        Ok(self.sign(input)? == signature)
+    }
+}
+
+#[tokio::test]
+async fn test_identity_sign_and_verify() {
+    let signing = IdentitySigning {
+        signer: Arc::new(KeyedHash("key")),
+        attest: Attestation::A,
+        origid: "123e4567-e89b-12d3-a456-426655440000".to_string(),
+    };
+    let verifier: IdentityVerifierRef = Arc::new(KeyedHash("key"));
+
+    let mut request = make_invite(
+        "sip:+1-212-555-0100@example.com",
+        "sip:12125550199@example.com",
+        vec![],
+    );
+    let verification = verify_request(&request, &verifier).await;
+    assert_eq!(verification.status, IdentityStatus::Missing);
+    assert_eq!(
+        verification.status.status_code(),
+        Some(StatusCode::from(428))
+    );
+
+    sign_request(&mut request, &signing).unwrap();
+    let identity = header_value(&request.headers, "Identity").unwrap();
+    assert!(identity.ends_with(";info=<https://cert.example.com/sp.pem>;alg=ES256;ppt=shaken"));
+
+    let verification = verify_request(&request, &verifier).await;
+    assert_eq!(verification.status, IdentityStatus::Valid);
+    assert_eq!(verification.attestation(), Some(Attestation::A));
+    let passport = verification.passport.unwrap();
+    assert_eq!(passport.orig, "12125550100");
+    assert_eq!(passport.dest, vec!["12125550199"]);
+
+    let later = SystemTime::now() + Duration::from_secs(120);
+    let verification = verify_request_at(&request, &verifier, later).await;
+    assert_eq!(verification.status, IdentityStatus::Stale);
+    assert_eq!(verification.attestation(), None);
+
+    let other_key: IdentityVerifierRef = Arc::new(KeyedHash("other"));
+    let verification = verify_request(&request, &other_key).await;
+    assert_eq!(verification.status, IdentityStatus::BadSignature);
+    assert_eq!(
+        verification.status.status_code(),
+        Some(StatusCode::from(438))
+    );
+
+    // the called number changed on the way
+    request.headers.retain(|h| !matches!(h, Header::To(_)));
+    request
+        .headers
+        .push(Header::To("<sip:12125550123@example.com>".into()));
+    let verification = verify_request(&request, &verifier).await;
+    assert_eq!(verification.status, IdentityStatus::NumberMismatch);
+
+    // not a telephone number
+    let mut request = make_invite(
+        "sip:alice@example.com",
+        "sip:12125550199@example.com",
+        vec![],
+    );
+    assert!(sign_request(&mut request, &signing).is_err());
+}
+
+#[tokio::test]
+async fn test_identity_parameters() {
+    let signing = IdentitySigning {
+        signer: Arc::new(KeyedHash("key")),
+        attest: Attestation::B,
+        origid: "123e4567-e89b-12d3-a456-426655440000".to_string(),
+    };
+    let verifier: IdentityVerifierRef = Arc::new(KeyedHash("key"));
+    let mut signed = make_invite(
+        "sip:12125550100@example.com",
+        "sip:12125550199@example.com",
+        vec![],
+    );
+    sign_request(&mut signed, &signing).unwrap();
+    let identity = header_value(&signed.headers, "Identity").unwrap();
+    let jws = identity.split(';').next().unwrap().to_string();
+
+    let verify = |params: &str| {
+        let mut request = signed.clone();
+        request
+            .headers
+            .retain(|h| !matches!(h, Header::Other(name, _) if name == "Identity"));
+        request.headers.push(Header::Other(
+            "Identity".into(),
+            format!("{}{}", jws, params),
+        ));
+        let verifier = verifier.clone();
+        async move { verify_request(&request, &verifier).await.status }
+    };
+    assert_eq!(
+        verify(";info=<https://cert.example.com/sp.pem>;alg=ES256;ppt=shaken").await,
+        IdentityStatus::Valid
+    );
+    // a URI with parameters of its own
+    assert_eq!(
+        verify(";info=<https://cert.example.com/sp.pem;v=2>;alg=ES256;ppt=shaken").await,
+        IdentityStatus::BadCertificate
+    );
+    assert_eq!(
+        verify(";alg=ES256;ppt=shaken").await,
+        IdentityStatus::Malformed
+    );
+    let status = verify(";info=<https://cert.example.com/sp.pem>;alg=ES256;ppt=div").await;
+    assert_eq!(status, IdentityStatus::UnsupportedType);
+    assert_eq!(status.status_code(), Some(StatusCode::from(438)));
+}
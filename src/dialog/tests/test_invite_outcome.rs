@@ -0,0 +1,234 @@
+use super::{make_response, wait_state, TestUa};
+use crate::dialog::{
+    authenticate::Credential,
+    client_dialog::{redirect_target, InviteOutcome},
+    dialog::DialogState,
+    digest::{DigestChallenge, DigestCredentials},
+    server_dialog::RedirectTarget,
+};
+use crate::rsip_ext::header_values;
+use tokio::sync::mpsc::unbounded_channel;
+
+#[test]
+fn test_invite_outcome() {
+    assert!(matches!(InviteOutcome::from(None), InviteOutcome::Timeout));
+    assert!(matches!(
+        InviteOutcome::from(Some(make_response(rsip::StatusCode::OK, vec![]))),
+        InviteOutcome::Answered { sdp: None, .. }
+    ));
+    assert!(matches!(
+        InviteOutcome::from(Some(make_response(rsip::StatusCode::BusyHere, vec![]))),
+        InviteOutcome::Busy(_)
+    ));
+    assert!(matches!(
+        InviteOutcome::from(Some(make_response(
+            rsip::StatusCode::RequestTerminated,
+            vec![]
+        ))),
+        InviteOutcome::Cancelled
+    ));
+    assert!(matches!(
+        InviteOutcome::from(Some(make_response(rsip::StatusCode::NotFound, vec![]))),
+        InviteOutcome::Rejected(_)
+    ));
+    match InviteOutcome::from(Some(make_response(
+        rsip::StatusCode::MovedTemporarily,
+        vec![rsip::Header::Contact("<sip:bob@example.com>".into())],
+    ))) {
+        InviteOutcome::Redirected(contacts) => {
+            assert_eq!(contacts.len(), 1);
+            assert_eq!(contacts[0].to_string(), "sip:bob@example.com");
+        }
+        outcome => panic!("unexpected outcome: {:?}", outcome),
+    }
+}
+
+#[test]
+fn test_redirect_target() {
+    let resp = make_response(
+        rsip::StatusCode::MovedTemporarily,
+        vec![
+            rsip::Header::Contact("<sip:bob@a.example.com>;q=0.5".into()),
+            rsip::Header::Contact("<sip:bob@b.example.com>;q=0.9".into()),
+            rsip::Header::Contact("<sip:bob@c.example.com>;q=0.9".into()),
+        ],
+    );
+    let target = redirect_target(&resp, &[]).expect("redirect target");
+    assert_eq!(target.to_string(), "sip:bob@b.example.com");
+
+    let tried = vec![target];
+    let target = redirect_target(&resp, &tried).expect("redirect target");
+    assert_eq!(target.to_string(), "sip:bob@c.example.com");
+
+    let tried = vec![
+        rsip::Uri::try_from("sip:bob@a.example.com").unwrap(),
+        rsip::Uri::try_from("sip:bob@b.example.com").unwrap(),
+        rsip::Uri::try_from("sip:bob@c.example.com").unwrap(),
+    ];
+    assert!(redirect_target(&resp, &tried).is_none());
+}
+
+#[test]
+fn test_redirect_target_header() {
+    let mut first = RedirectTarget::new(rsip::Uri::try_from("sip:bob@a.example.com").unwrap());
+    first.q = Some(0.5);
+    first.expires = Some(60);
+    let mut second = RedirectTarget::new(rsip::Uri::try_from("sip:bob@b.example.com").unwrap());
+    second.q = Some(0.8);
+
+    let header = first.to_header();
+    assert_eq!(
+        header.to_string(),
+        "Contact: <sip:bob@a.example.com>;q=0.500;expires=60"
+    );
+    let resp = make_response(
+        rsip::StatusCode::MovedTemporarily,
+        vec![header, second.to_header()],
+    );
+    let target = redirect_target(&resp, &[]).expect("redirect target");
+    assert_eq!(target.to_string(), "sip:bob@b.example.com");
+}
+
+#[tokio::test]
+async fn test_redirect_contacts() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let mut bob = TestUa::new("bob").await?;
+
+    let (sender, _states) = unbounded_channel();
+    let layer = alice.layer.clone();
+    let opt = alice.invite_option(&bob, None);
+    let invite = tokio::spawn(async move { layer.do_invite_outcome(opt, sender, None).await });
+
+    let (server, _) = bob.incoming().await;
+    let targets = ["sip:bob@a.example.com", "sip:bob@b.example.com"]
+        .iter()
+        .map(|uri| RedirectTarget::new(rsip::Uri::try_from(*uri).unwrap()))
+        .collect::<Vec<_>>();
+    server.redirect(targets)?;
+
+    let (_, outcome) = invite.await.expect("invite task")?;
+    match outcome {
+        InviteOutcome::Redirected(contacts) => assert_eq!(
+            contacts.iter().map(|c| c.to_string()).collect::<Vec<_>>(),
+            vec!["sip:bob@a.example.com", "sip:bob@b.example.com"]
+        ),
+        _ => panic!("expected a redirect"),
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_invite_bad_extension() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let bob = TestUa::new("bob").await?;
+
+    let (sender, _states) = unbounded_channel();
+    let mut opt = alice.invite_option(&bob, None);
+    opt.headers = Some(vec![rsip::Header::Other("Require".into(), "foo".into())]);
+    let (_, outcome) = alice.layer.do_invite_outcome(opt, sender, None).await?;
+    match outcome {
+        InviteOutcome::Rejected(resp) => {
+            assert_eq!(resp.status_code, rsip::StatusCode::from(420));
+            assert!(resp.to_string().contains("Unsupported: foo\r\n"));
+        }
+        _ => panic!("expected a rejection"),
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cancel_after_redirect() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let mut bob = TestUa::new("bob").await?;
+    let mut carol = TestUa::new("carol").await?;
+
+    let (sender, _states) = unbounded_channel();
+    let mut opt = alice.invite_option(&bob, None);
+    opt.max_redirects = Some(1);
+    let (dialog, tx) = alice.layer.create_client_invite(opt, sender).await?;
+    let answer = tokio::spawn({
+        let dialog = dialog.clone();
+        async move { dialog.wait_for_answer(tx, None).await }
+    });
+
+    let (server, _) = bob.incoming().await;
+    server.redirect(vec![RedirectTarget::new(carol.contact.clone())])?;
+
+    // the CANCEL matches the INVITE sent to carol, not the one to bob
+    let (_, mut carol_states) = carol.incoming().await;
+    dialog.cancel(None).await?;
+    let state = wait_state(&mut carol_states, |s| {
+        matches!(s, DialogState::Terminated(..))
+    })
+    .await;
+    assert!(matches!(
+        state,
+        DialogState::Terminated(_, Some(rsip::StatusCode::RequestTerminated), _)
+    ));
+
+    let (_, outcome) = answer.await.expect("answer task")?;
+    assert!(matches!(outcome, InviteOutcome::Cancelled));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_redirect_authorization() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let mut bob = TestUa::new("bob").await?;
+    let mut carol = TestUa::new("carol").await?;
+    // challenges of bob and carol answered before
+    for (ua, realm) in [(&bob, "bob"), (&carol, "carol")] {
+        let request = rsip::Request {
+            method: rsip::Method::Invite,
+            uri: ua.contact.clone(),
+            headers: vec![].into(),
+            version: rsip::Version::V2,
+            body: vec![],
+        };
+        let challenge =
+            DigestChallenge::parse(&format!("Digest realm=\"{}\", nonce=\"abc\"", realm)).unwrap();
+        alice
+            .endpoint
+            .inner
+            .auth_cache
+            .remember(&request, "alice", challenge, false);
+    }
+    let authorizations = |request: &rsip::Request| {
+        header_values(&request.headers, "Authorization")
+            .iter()
+            .filter_map(|value| DigestCredentials::parse(value))
+            .map(|auth| (auth.realm, auth.uri))
+            .collect::<Vec<_>>()
+    };
+
+    let (sender, _states) = unbounded_channel();
+    let mut opt = alice.invite_option(&bob, None);
+    opt.max_redirects = Some(1);
+    opt.credential = Some(
+        Credential {
+            username: "alice".to_string(),
+            password: "secret".to_string(),
+        }
+        .into(),
+    );
+    let (dialog, tx) = alice.layer.create_client_invite(opt, sender).await?;
+    let answer = tokio::spawn(async move { dialog.wait_for_answer(tx, None).await });
+
+    let (server, _) = bob.incoming().await;
+    assert_eq!(
+        authorizations(&server.inner.initial_request),
+        vec![("bob".to_string(), bob.contact.to_string())]
+    );
+    server.redirect(vec![RedirectTarget::new(carol.contact.clone())])?;
+
+    // authorized again for carol, not with the authorization of bob
+    let (server, _) = carol.incoming().await;
+    assert_eq!(
+        authorizations(&server.inner.initial_request),
+        vec![("carol".to_string(), carol.contact.to_string())]
+    );
+    server.reject()?;
+    let (_, outcome) = answer.await.expect("answer task")?;
+    assert!(matches!(outcome, InviteOutcome::Rejected(_)));
+    Ok(())
+}
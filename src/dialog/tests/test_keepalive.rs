@@ -0,0 +1,24 @@
+use super::make_response;
+use crate::dialog::keepalive::is_keepalive_failure;
+
+#[test]
+fn test_keepalive_failure() {
+    assert!(is_keepalive_failure(None));
+    assert!(is_keepalive_failure(Some(&make_response(
+        rsip::StatusCode::RequestTimeout,
+        vec![]
+    ))));
+    assert!(is_keepalive_failure(Some(&make_response(
+        rsip::StatusCode::CallTransactionDoesNotExist,
+        vec![]
+    ))));
+    assert!(!is_keepalive_failure(Some(&make_response(
+        rsip::StatusCode::OK,
+        vec![]
+    ))));
+    // the peer is alive even though it does not support OPTIONS
+    assert!(!is_keepalive_failure(Some(&make_response(
+        rsip::StatusCode::MethodNotAllowed,
+        vec![]
+    ))));
+}
@@ -0,0 +1,30 @@
+use crate::dialog::kpml::{KpmlPersist, KpmlRequest, KpmlResponse};
+
+#[test]
+fn test_kpml_request() {
+    let mut request = KpmlRequest::new("x{4}#");
+    request.persist = KpmlPersist::Persist;
+    request.interdigit_timer = Some(4000);
+    request.tag = Some("pin".to_string());
+    let xml = request.to_xml();
+    assert!(xml.contains("<pattern persist=\"persist\" interdigittimer=\"4000\">"));
+    assert!(xml.contains("<regex tag=\"pin\">x{4}#</regex>"));
+}
+
+#[test]
+fn test_parse_kpml_response() {
+    let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<kpml-response xmlns="urn:ietf:params:xml:ns:kpml-response" version="1.0"
+  code="200" text="Success" digits="1234#" tag="pin"/>"#;
+    let report = KpmlResponse::try_from(xml).unwrap();
+    assert!(report.is_success());
+    assert_eq!(report.digits.as_deref(), Some("1234#"));
+    assert_eq!(report.tag.as_deref(), Some("pin"));
+
+    let report =
+        KpmlResponse::try_from(r#"<kpml-response version="1.0" code="423" text="Timer Expired"/>"#)
+            .unwrap();
+    assert_eq!(report.code, 423);
+    assert_eq!(report.digits, None);
+    assert!(KpmlResponse::try_from("<foo/>").is_err());
+}
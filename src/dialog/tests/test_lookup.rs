@@ -0,0 +1,40 @@
+use super::TestUa;
+use crate::dialog::dialog::DialogState;
+
+#[tokio::test]
+async fn test_find_dialogs() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let mut bob = TestUa::new("bob").await?;
+    let (client, _states, (server, _)) = alice
+        .call(&mut bob, b"v=0 alice\r\n".to_vec(), b"v=0 bob\r\n".to_vec())
+        .await?;
+    let id = client.id();
+
+    let found = alice.layer.find_by_call_id(&id.call_id);
+    assert_eq!(
+        found.iter().map(|d| d.id()).collect::<Vec<_>>(),
+        vec![id.clone()]
+    );
+    assert!(alice.layer.find_by_call_id("unknown-call-id").is_empty());
+
+    // the parameters of the AOR don't matter, only its user and host
+    let bob_aor = rsip::Uri::try_from(format!(
+        "sip:bob@{};transport=udp",
+        bob.contact.host_with_port
+    ))?;
+    assert_eq!(alice.layer.find_by_remote_aor(&bob_aor).len(), 1);
+    let carol_aor = rsip::Uri::try_from(format!("sip:carol@{}", bob.contact.host_with_port))?;
+    assert!(alice.layer.find_by_remote_aor(&carol_aor).is_empty());
+    // the peer of the callee is the caller
+    let found = bob.layer.find_by_remote_aor(&alice.contact);
+    assert_eq!(
+        found.iter().map(|d| d.id()).collect::<Vec<_>>(),
+        vec![server.id()]
+    );
+
+    let states = alice.layer.states().collect::<Vec<_>>();
+    assert_eq!(states.len(), 1);
+    assert_eq!(states[0].0, id);
+    assert!(matches!(states[0].1, DialogState::Confirmed(_)));
+    Ok(())
+}
@@ -0,0 +1,90 @@
+use super::{test_endpoint, wait_state, TestUa};
+use crate::dialog::dialog::DialogState;
+use rsip::prelude::{HeadersExt, UntypedHeader};
+use tokio::sync::mpsc::unbounded_channel;
+
+#[tokio::test]
+async fn test_send_message() -> crate::Result<()> {
+    let (alice, _, _) = test_endpoint("alice").await?;
+    let (bob, mut transactions, target) = test_endpoint("bob").await?;
+
+    let (sender, mut requests) = unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(mut tx) = transactions.recv().await {
+            let resp = bob
+                .inner
+                .make_response(&tx.original, rsip::StatusCode::OK, None);
+            sender.send(tx.original.clone()).ok();
+            tx.respond(resp).await.ok();
+        }
+    });
+
+    for text in ["hello", "again"] {
+        let resp = alice
+            .send_message(target.clone(), "text/plain", text.as_bytes().to_vec(), None)
+            .await?;
+        assert_eq!(resp.status_code, rsip::StatusCode::OK);
+    }
+    alice.options(target.clone(), None).await?;
+
+    let mut sent = vec![];
+    while let Ok(request) = requests.try_recv() {
+        sent.push(request);
+    }
+    assert_eq!(
+        sent.iter().map(|r| r.method.clone()).collect::<Vec<_>>(),
+        vec![
+            rsip::Method::Message,
+            rsip::Method::Message,
+            rsip::Method::Options
+        ]
+    );
+    assert_eq!(sent[0].body, b"hello".to_vec());
+    assert_eq!(sent[0].content_type_header()?.value(), "text/plain");
+    // every standalone request gets the next CSeq
+    let seqs = sent
+        .iter()
+        .map(|r| r.cseq_header()?.seq())
+        .collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(seqs, vec![1, 2, 3]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dialog_message() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let mut bob = TestUa::new("bob").await?;
+    let (client, mut states, (server, mut server_states)) = alice
+        .call(&mut bob, b"v=0 alice\r\n".to_vec(), b"v=0 bob\r\n".to_vec())
+        .await?;
+
+    let resp = client
+        .message("text/plain", b"hello bob".to_vec())
+        .await?
+        .expect("response");
+    assert_eq!(resp.status_code, rsip::StatusCode::OK);
+    let DialogState::Message(id, req) = wait_state(&mut server_states, |s| {
+        matches!(s, DialogState::Message(_, _))
+    })
+    .await
+    else {
+        unreachable!()
+    };
+    assert_eq!(id, server.id());
+    assert_eq!(req.body, b"hello bob".to_vec());
+    assert_eq!(req.content_type_header()?.value(), "text/plain");
+
+    // the callee can message back within the same dialog
+    let resp = server
+        .message("text/plain", b"hello alice".to_vec())
+        .await?
+        .expect("response");
+    assert_eq!(resp.status_code, rsip::StatusCode::OK);
+    let DialogState::Message(_, req) =
+        wait_state(&mut states, |s| matches!(s, DialogState::Message(_, _))).await
+    else {
+        unreachable!()
+    };
+    assert_eq!(req.body, b"hello alice".to_vec());
+    Ok(())
+}
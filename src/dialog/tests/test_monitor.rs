@@ -0,0 +1,46 @@
+use crate::dialog::{
+    monitor::{PeerEvent, PeerStatus},
+    options::Capabilities,
+};
+use std::time::Duration;
+
+fn answer() -> Option<(Capabilities, Duration)> {
+    let resp = rsip::Response {
+        status_code: rsip::StatusCode::OK,
+        version: rsip::Version::V2,
+        headers: Default::default(),
+        body: vec![],
+    };
+    Some((resp.into(), Duration::from_millis(20)))
+}
+
+#[test]
+fn test_peer_status() {
+    let uri = rsip::Uri::try_from("sip:trunk.example.com").expect("uri");
+    let mut status = PeerStatus::new(uri.clone());
+    assert!(status.is_available());
+
+    assert!(matches!(
+        status.on_ping(answer(), 2),
+        Some(PeerEvent::Up { rtt, .. }) if rtt == Duration::from_millis(20)
+    ));
+    assert!(status.on_ping(answer(), 2).is_none());
+    assert_eq!(status.rtt, Some(Duration::from_millis(20)));
+
+    // down after 2 failed pings in a row
+    assert!(status.on_ping(None, 2).is_none());
+    assert!(status.is_available());
+    assert!(matches!(
+        status.on_ping(None, 2),
+        Some(PeerEvent::Down { failures: 2, .. })
+    ));
+    assert!(!status.is_available());
+    assert!(status.on_ping(None, 2).is_none());
+
+    assert!(matches!(
+        status.on_ping(answer(), 2),
+        Some(PeerEvent::Up { .. })
+    ));
+    assert_eq!(status.failures, 0);
+    assert!(status.is_available());
+}
@@ -0,0 +1,31 @@
+use crate::dialog::mwi::{MessageCounts, MessageSummary};
+
+#[test]
+fn test_parse_message_summary() {
+    let body = "Messages-Waiting: yes\r\n\
+                Message-Account: sip:alice@vmail.example.com\r\n\
+                Voice-Message: 4/8 (1/2)\r\n\
+                Fax-Message: 1/0\r\n";
+    let summary = MessageSummary::try_from(body).unwrap();
+    assert!(summary.messages_waiting);
+    assert_eq!(
+        summary.account.as_deref(),
+        Some("sip:alice@vmail.example.com")
+    );
+    assert_eq!(
+        summary.voice(),
+        Some(&MessageCounts {
+            new: 4,
+            old: 8,
+            urgent_new: 1,
+            urgent_old: 2,
+        })
+    );
+    assert_eq!(summary.messages.len(), 2);
+    assert_eq!(summary.messages[1].1.new, 1);
+
+    let parsed = MessageSummary::try_from(summary.to_body().as_str()).unwrap();
+    assert_eq!(parsed, summary);
+
+    assert!(MessageSummary::try_from("Voice-Message: 1/0").is_err());
+}
@@ -0,0 +1,265 @@
+use super::{wait_state, TestUa};
+use crate::dialog::dialog::{DialogState, OfferAnswerHandler, SessionRefreshMethod};
+use rsip::prelude::{HeadersExt, UntypedHeader};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc::unbounded_channel;
+
+/// Answers every offer with `v=0 answer`, recording them
+#[derive(Default)]
+struct Answerer {
+    offers: Mutex<Vec<Vec<u8>>>,
+}
+
+#[async_trait::async_trait]
+impl OfferAnswerHandler for Answerer {
+    async fn on_offer(&self, offer: Vec<u8>) -> crate::Result<Vec<u8>> {
+        self.offers.lock().unwrap().push(offer);
+        Ok(b"v=0 answer\r\n".to_vec())
+    }
+
+    async fn on_answer(&self, _answer: Vec<u8>) -> crate::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_update_offer() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let mut bob = TestUa::new("bob").await?;
+    let (dialog, _states, (server, _)) = alice
+        .call(&mut bob, b"v=0 alice\r\n".to_vec(), b"v=0 bob\r\n".to_vec())
+        .await?;
+    let answerer = Arc::new(Answerer::default());
+    server.set_offer_answer_handler(Some(answerer.clone()));
+
+    // an UPDATE without offer gets a 2xx without answer
+    let resp = dialog.update(None, None).await?.expect("response");
+    assert_eq!(resp.status_code, rsip::StatusCode::OK);
+    assert!(resp.body.is_empty());
+    assert!(!resp.to_string().contains("application/sdp"));
+    assert!(answerer.offers.lock().unwrap().is_empty());
+
+    // one with an offer gets the answer of the handler
+    let resp = dialog
+        .update(
+            Some(vec![rsip::Header::ContentType("application/sdp".into())]),
+            Some(b"v=0 update\r\n".to_vec()),
+        )
+        .await?
+        .expect("response");
+    assert_eq!(resp.status_code, rsip::StatusCode::OK);
+    assert_eq!(resp.body, b"v=0 answer\r\n".to_vec());
+    assert_eq!(
+        *answerer.offers.lock().unwrap(),
+        vec![b"v=0 update\r\n".to_vec()]
+    );
+    assert_eq!(server.local_sdp(), Some(b"v=0 answer\r\n".to_vec()));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_late_offer() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let mut bob = TestUa::new("bob").await?;
+    let (sender, mut states) = unbounded_channel();
+    let layer = alice.layer.clone();
+    let opt = alice.invite_option(&bob, None);
+    let invite = tokio::spawn(async move { layer.do_invite(opt, sender).await });
+    let (server, mut server_states) = bob.incoming().await;
+    server.accept(None, Some(b"v=0 bob\r\n".to_vec()))?;
+
+    // the INVITE completes with the offer of the 2xx, not answered yet
+    let (client, resp) = invite.await.expect("invite task")?;
+    assert_eq!(resp.expect("response").body, b"v=0 bob\r\n".to_vec());
+    wait_state(&mut states, |s| matches!(s, DialogState::LateOffer(_, _))).await;
+
+    // the answer goes in the ACK, which confirms both sides
+    client.send_ack(None, b"v=0 alice\r\n".to_vec()).await?;
+    wait_state(&mut states, |s| matches!(s, DialogState::Confirmed(_))).await;
+    wait_state(&mut server_states, |s| {
+        matches!(s, DialogState::Confirmed(_))
+    })
+    .await;
+    assert_eq!(server.remote_sdp(), Some(b"v=0 alice\r\n".to_vec()));
+
+    // nothing left to answer
+    assert!(client
+        .send_ack(None, b"v=0 again\r\n".to_vec())
+        .await
+        .is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_session_refresh_method() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let mut bob = TestUa::new("bob").await?;
+    let (dialog, _states, (_server, mut server_states)) = alice
+        .call(&mut bob, b"v=0 alice\r\n".to_vec(), b"v=0 bob\r\n".to_vec())
+        .await?;
+
+    // bob announces no Allow, so Auto falls back to a re-INVITE with our SDP
+    let resp = dialog.refresh_session().await?.expect("response");
+    assert_eq!(resp.status_code, rsip::StatusCode::OK);
+    let refresh = wait_state(&mut server_states, |s| {
+        matches!(s, DialogState::Updated(_, _))
+    })
+    .await;
+    let DialogState::Updated(_, req) = refresh else {
+        unreachable!()
+    };
+    assert_eq!(req.method, rsip::Method::Invite);
+    assert_eq!(req.body, b"v=0 alice\r\n".to_vec());
+
+    // an UPDATE refresh carries no offer
+    dialog.set_refresh_method(SessionRefreshMethod::Update);
+    dialog.refresh_session().await?.expect("response");
+    let refresh = wait_state(&mut server_states, |s| {
+        matches!(s, DialogState::Updated(_, _))
+    })
+    .await;
+    let DialogState::Updated(_, req) = refresh else {
+        unreachable!()
+    };
+    assert_eq!(req.method, rsip::Method::Update);
+    assert!(req.body.is_empty());
+
+    dialog.set_refresh_method(SessionRefreshMethod::Invite);
+    dialog.refresh_session().await?.expect("response");
+    let refresh = wait_state(&mut server_states, |s| {
+        matches!(s, DialogState::Updated(_, _))
+    })
+    .await;
+    let DialogState::Updated(_, req) = refresh else {
+        unreachable!()
+    };
+    assert_eq!(req.method, rsip::Method::Invite);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_session_descriptions() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let mut bob = TestUa::new("bob").await?;
+    let (client, _states, (server, _)) = alice
+        .call(&mut bob, b"v=0 alice\r\n".to_vec(), b"v=0 bob\r\n".to_vec())
+        .await?;
+    assert_eq!(client.local_sdp(), Some(b"v=0 alice\r\n".to_vec()));
+    assert_eq!(client.remote_sdp(), Some(b"v=0 bob\r\n".to_vec()));
+    assert_eq!(server.local_sdp(), Some(b"v=0 bob\r\n".to_vec()));
+    assert_eq!(server.remote_sdp(), Some(b"v=0 alice\r\n".to_vec()));
+
+    // a re-INVITE from either side replaces the offer of its sender, the
+    // peer without handler answers with its last SDP
+    let sdp = Some(vec![rsip::Header::ContentType("application/sdp".into())]);
+    client
+        .reinvite(sdp.clone(), Some(b"v=0 alice 2\r\n".to_vec()))
+        .await?
+        .expect("response");
+    assert_eq!(client.local_sdp(), Some(b"v=0 alice 2\r\n".to_vec()));
+    assert_eq!(server.remote_sdp(), Some(b"v=0 alice 2\r\n".to_vec()));
+    assert_eq!(client.remote_sdp(), Some(b"v=0 bob\r\n".to_vec()));
+
+    server
+        .reinvite(sdp, Some(b"v=0 bob 2\r\n".to_vec()))
+        .await?
+        .expect("response");
+    assert_eq!(server.local_sdp(), Some(b"v=0 bob 2\r\n".to_vec()));
+    assert_eq!(client.remote_sdp(), Some(b"v=0 bob 2\r\n".to_vec()));
+    assert_eq!(server.remote_sdp(), Some(b"v=0 alice 2\r\n".to_vec()));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_session_progress() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let mut bob = TestUa::new("bob").await?;
+    let (sender, mut states) = unbounded_channel();
+    let layer = alice.layer.clone();
+    let opt = alice.invite_option(&bob, Some(b"v=0 alice\r\n".to_vec()));
+    let invite = tokio::spawn(async move { layer.do_invite(opt, sender).await });
+    let (server, _) = bob.incoming().await;
+
+    // early media SDP goes in the 183, typed as SDP by default
+    server.session_progress(None, Some(b"v=0 early\r\n".to_vec()))?;
+    let DialogState::Early(_, resp) =
+        wait_state(&mut states, |s| matches!(s, DialogState::Early(..))).await
+    else {
+        unreachable!()
+    };
+    assert_eq!(resp.status_code, rsip::StatusCode::SessionProgress);
+    assert_eq!(resp.body, b"v=0 early\r\n".to_vec());
+    assert_eq!(resp.content_type_header()?.value(), "application/sdp");
+    assert_eq!(server.local_sdp(), Some(b"v=0 early\r\n".to_vec()));
+
+    // only provisional statuses past 100 are accepted
+    assert!(server
+        .provisional(rsip::StatusCode::OK, None, None)
+        .is_err());
+    assert!(server
+        .provisional(rsip::StatusCode::Trying, None, None)
+        .is_err());
+
+    server.accept(None, Some(b"v=0 bob\r\n".to_vec()))?;
+    let (_, resp) = invite.await.expect("invite task")?;
+    assert_eq!(resp.expect("response").status_code, rsip::StatusCode::OK);
+    Ok(())
+}
+
+/// Never answers, leaving the re-INVITEs of the peer without final response
+struct Stalled;
+
+#[async_trait::async_trait]
+impl OfferAnswerHandler for Stalled {
+    async fn on_offer(&self, _offer: Vec<u8>) -> crate::Result<Vec<u8>> {
+        std::future::pending().await
+    }
+
+    async fn on_answer(&self, _answer: Vec<u8>) -> crate::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_request_timeout() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let mut bob = TestUa::new("bob").await?;
+    let sdp = Some(vec![rsip::Header::ContentType("application/sdp".into())]);
+
+    let (client, _states, (server, _)) = alice
+        .call(&mut bob, b"v=0 alice\r\n".to_vec(), b"v=0 bob\r\n".to_vec())
+        .await?;
+    server.set_offer_answer_handler(Some(Arc::new(Stalled)));
+    client.set_request_timeout(Some(Duration::from_millis(200)));
+    let started = Instant::now();
+    let result = client
+        .reinvite(sdp.clone(), Some(b"v=0 alice 2\r\n".to_vec()))
+        .await;
+    assert!(result.is_err());
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    // without timeout, cancelling the dialog ends the request
+    let (client, _states, (server, _)) = alice
+        .call(&mut bob, b"v=0 alice\r\n".to_vec(), b"v=0 bob\r\n".to_vec())
+        .await?;
+    server.set_offer_answer_handler(Some(Arc::new(Stalled)));
+    let reinvite = {
+        let client = client.clone();
+        tokio::spawn(async move {
+            client
+                .reinvite(sdp, Some(b"v=0 alice 2\r\n".to_vec()))
+                .await
+        })
+    };
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    client.inner.cancel_token.cancel();
+    let result = tokio::time::timeout(Duration::from_secs(2), reinvite)
+        .await
+        .expect("timeout waiting for the cancelled re-INVITE")
+        .expect("re-INVITE task");
+    assert!(result.is_err());
+    Ok(())
+}
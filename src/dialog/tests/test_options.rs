@@ -0,0 +1,31 @@
+use super::make_response;
+use crate::dialog::options::Capabilities;
+
+#[test]
+fn test_parse_capabilities() {
+    let resp = make_response(
+        rsip::StatusCode::OK,
+        vec![
+            rsip::Header::Allow("INVITE, ack,CANCEL, BYE".into()),
+            rsip::Header::Accept("application/sdp, application/dtmf-relay".into()),
+            rsip::Header::Supported("timer, 100rel".into()),
+            rsip::Header::Server("Trunk/1.0".into()),
+        ],
+    );
+    let caps = Capabilities::from(resp);
+    assert!(caps.is_available());
+    assert_eq!(caps.allow, vec!["INVITE", "ACK", "CANCEL", "BYE"]);
+    assert!(caps.allows(&rsip::Method::Ack));
+    assert!(!caps.allows(&rsip::Method::Refer));
+    assert_eq!(
+        caps.accept,
+        vec!["application/sdp", "application/dtmf-relay"]
+    );
+    assert_eq!(caps.supported, vec!["timer", "100rel"]);
+    assert_eq!(caps.server.as_deref(), Some("Trunk/1.0"));
+
+    let unavailable =
+        Capabilities::from(make_response(rsip::StatusCode::ServiceUnavailable, vec![]));
+    assert!(!unavailable.is_available());
+    assert!(unavailable.allow.is_empty());
+}
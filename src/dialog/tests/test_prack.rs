@@ -0,0 +1,171 @@
+use super::{make_response, TestUa};
+use crate::{
+    dialog::dialog::reliable_rseq,
+    transport::{udp::UdpConnection, TransportEvent},
+};
+use rsip::prelude::{HeadersExt, ToTypedHeader, UntypedHeader};
+use std::time::Duration;
+use tokio::sync::mpsc::unbounded_channel;
+
+#[test]
+fn test_reliable_rseq() {
+    let reliable = make_response(
+        rsip::StatusCode::SessionProgress,
+        vec![
+            rsip::Header::Other("Require".into(), "timer, 100rel".into()),
+            rsip::Header::Other("RSeq".into(), "42".into()),
+        ],
+    );
+    assert_eq!(reliable_rseq(&reliable), Some(42));
+
+    let unreliable = make_response(
+        rsip::StatusCode::Ringing,
+        vec![rsip::Header::Other("RSeq".into(), "42".into())],
+    );
+    assert_eq!(reliable_rseq(&unreliable), None);
+
+    let final_response = make_response(
+        rsip::StatusCode::OK,
+        vec![
+            rsip::Header::Other("Require".into(), "100rel".into()),
+            rsip::Header::Other("RSeq".into(), "42".into()),
+        ],
+    );
+    assert_eq!(reliable_rseq(&final_response), None);
+}
+
+#[tokio::test]
+async fn test_prack_per_early_dialog() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let fork = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let (sender, mut received) = unbounded_channel();
+    tokio::spawn({
+        let fork = fork.clone();
+        async move { fork.serve_loop(sender).await }
+    });
+
+    let (state_sender, _states) = unbounded_channel();
+    let mut opt = alice.invite_option(&alice, None);
+    opt.callee = rsip::Uri::try_from(format!("sip:bob@{}", fork.get_addr().addr))?;
+    let (dialog, tx) = alice.layer.create_client_invite(opt, state_sender).await?;
+
+    // two UASs a proxy forked the INVITE to, each in its early dialog
+    let progress = |tag: &str, user: &str| -> crate::Result<rsip::Response> {
+        let mut resp = alice.endpoint.inner.make_response(
+            &tx.original,
+            rsip::StatusCode::SessionProgress,
+            None,
+        );
+        let to = tx
+            .original
+            .to_header()?
+            .typed()?
+            .with_tag(tag.to_string().into());
+        resp.headers.unique_push(rsip::Header::To(to.into()));
+        resp.headers.push(rsip::Header::Contact(
+            format!("<sip:{}@{}>", user, fork.get_addr().addr).into(),
+        ));
+        resp.headers
+            .push(rsip::Header::Other("Require".into(), "100rel".into()));
+        resp.headers
+            .push(rsip::Header::Other("RSeq".into(), "1".into()));
+        Ok(resp)
+    };
+    for (tag, user) in [("bob1", "bob1"), ("bob2", "bob2")] {
+        let resp = progress(tag, user)?;
+        dialog.inner.update_early_dialog(&resp);
+        dialog.inner.send_prack(&resp)?;
+    }
+
+    let mut pracks = vec![];
+    while pracks.len() < 2 {
+        let event = tokio::time::timeout(Duration::from_secs(5), received.recv())
+            .await
+            .expect("no PRACK received");
+        if let Some(TransportEvent::Incoming(rsip::SipMessage::Request(req), _, _)) = event {
+            pracks.push(req);
+        }
+    }
+    for tag in ["bob1", "bob2"] {
+        let prack = pracks
+            .iter()
+            .find(|prack| prack.to_string().contains(&format!("tag={}", tag)))
+            .expect("PRACK of the early dialog");
+        assert_eq!(prack.method, rsip::Method::PRack);
+        assert_eq!(
+            prack.uri.to_string(),
+            format!("sip:{}@{}", tag, fork.get_addr().addr)
+        );
+        assert_eq!(
+            prack.to_header()?.tag()?.map(|t| t.value().to_string()),
+            Some(tag.to_string())
+        );
+        assert!(prack.to_string().contains("RAck: 1 "));
+    }
+    // the dialog itself is left to the final response
+    assert!(dialog.id().to_tag.is_empty());
+    assert_eq!(dialog.early_dialogs().len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_provisional_reliable() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let mut bob = TestUa::new("bob").await?;
+
+    // a caller not supporting 100rel gets no reliable provisional
+    let (sender, _states) = unbounded_channel();
+    let layer = alice.layer.clone();
+    let opt = alice.invite_option(&bob, Some(b"v=0 alice\r\n".to_vec()));
+    let invite = tokio::spawn(async move { layer.do_invite(opt, sender).await });
+    let (server, _) = bob.incoming().await;
+    assert!(server
+        .provisional_reliable(rsip::StatusCode::SessionProgress, None, None)
+        .await
+        .is_err());
+    server.reject()?;
+    assert!(invite.await.expect("invite task").is_err());
+
+    // one supporting it PRACKs it
+    let (sender, _states) = unbounded_channel();
+    let layer = alice.layer.clone();
+    let mut opt = alice.invite_option(&bob, Some(b"v=0 alice\r\n".to_vec()));
+    opt.headers = Some(vec![rsip::Header::Supported("100rel".into())]);
+    let invite = tokio::spawn(async move { layer.do_invite(opt, sender).await });
+    let (server, _) = bob.incoming().await;
+    let prack = server
+        .provisional_reliable(rsip::StatusCode::SessionProgress, None, None)
+        .await?;
+    assert_eq!(prack.method, rsip::Method::PRack);
+    assert!(prack.to_string().contains("RAck: 1 "));
+    server.accept(None, Some(b"v=0 bob\r\n".to_vec()))?;
+    let (_, resp) = invite.await.expect("invite task")?;
+    assert_eq!(resp.map(|r| r.status_code), Some(rsip::StatusCode::OK));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_remote_tag_rekeys_dialog() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let bob = TestUa::new("bob").await?;
+    let (sender, _states) = unbounded_channel();
+    let opt = alice.invite_option(&bob, None);
+    let (dialog, _tx) = alice.layer.create_client_invite(opt, sender).await?;
+    let id = dialog.id();
+    assert!(alice.layer.get_dialog(&id).is_some());
+
+    assert!(alice.layer.lookup_dialog_owner(&id).await?.is_some());
+
+    dialog.inner.update_remote_tag("bob").await?;
+    assert_eq!(dialog.id().to_tag, "bob");
+    assert!(alice.layer.get_dialog(&id).is_none());
+    assert!(alice.layer.get_dialog(&dialog.id()).is_some());
+    // the store follows, without waiting for any background task
+    assert!(alice.layer.lookup_dialog_owner(&id).await?.is_none());
+    assert!(alice
+        .layer
+        .lookup_dialog_owner(&dialog.id())
+        .await?
+        .is_some());
+    Ok(())
+}
@@ -0,0 +1,21 @@
+use crate::dialog::presence::{presentity, PresenceState};
+
+#[test]
+fn test_pidf() {
+    let pidf = PresenceState::open()
+        .with_note("in a <meeting>")
+        .to_pidf("sip:alice@example.com");
+    assert!(pidf.contains("entity=\"sip:alice@example.com\""));
+    assert!(pidf.contains("<basic>open</basic>"));
+    assert!(pidf.contains("<note>in a &lt;meeting&gt;</note>"));
+
+    let pidf = PresenceState::closed().to_pidf("sip:bob@example.com");
+    assert!(pidf.contains("<basic>closed</basic>"));
+    assert!(!pidf.contains("<note>"));
+}
+
+#[test]
+fn test_presentity() {
+    let uri = rsip::Uri::try_from("sip:alice@example.com:5060;transport=tcp").unwrap();
+    assert_eq!(presentity(&uri), "alice@example.com");
+}
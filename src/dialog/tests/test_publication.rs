@@ -0,0 +1,70 @@
+use super::test_endpoint;
+use crate::{dialog::publication::Publication, rsip_ext::header_value};
+use rsip::prelude::{HeadersExt, UntypedHeader};
+use tokio::sync::mpsc::unbounded_channel;
+
+#[tokio::test]
+async fn test_publication() -> crate::Result<()> {
+    let (alice, _, _) = test_endpoint("alice").await?;
+    let (server, mut transactions, target) = test_endpoint("pa").await?;
+
+    // answers the PUBLISHes with these statuses, reporting the requests
+    let (sender, mut requests) = unbounded_channel();
+    let statuses = [200, 200, 500, 200];
+    tokio::spawn(async move {
+        for status in statuses {
+            let mut tx = match transactions.recv().await {
+                Some(tx) => tx,
+                None => return,
+            };
+            let mut resp =
+                server
+                    .inner
+                    .make_response(&tx.original, rsip::StatusCode::from(status), None);
+            resp.headers
+                .push(rsip::Header::Other("SIP-ETag".into(), "e1".into()));
+            sender.send(tx.original.clone()).ok();
+            tx.respond(resp).await.ok();
+        }
+    });
+
+    let mut publication = Publication::new(alice.inner.clone(), None, target, "presence");
+    let resp = publication
+        .publish("application/pidf+xml", b"<presence/>".to_vec())
+        .await?;
+    assert_eq!(resp.status_code, rsip::StatusCode::OK);
+    assert_eq!(publication.etag.as_deref(), Some("e1"));
+    publication.refresh().await?;
+
+    // a failed removal keeps the ETag to retry with
+    let resp = publication.remove().await?;
+    assert_eq!(resp.status_code, rsip::StatusCode::ServerInternalError);
+    assert_eq!(publication.etag.as_deref(), Some("e1"));
+    let resp = publication.remove().await?;
+    assert_eq!(resp.status_code, rsip::StatusCode::OK);
+    assert!(publication.etag.is_none());
+
+    let mut sent = vec![];
+    while let Ok(request) = requests.try_recv() {
+        sent.push(request);
+    }
+    assert_eq!(sent.len(), 4);
+    assert_eq!(sent[0].body, b"<presence/>".to_vec());
+    assert!(header_value(&sent[0].headers, "SIP-If-Match").is_none());
+    // one Call-ID and From tag, a new CSeq for each PUBLISH
+    let call_id = sent[0].call_id_header()?.value().to_string();
+    let from_tag = sent[0].from_header()?.tag()?;
+    for (i, request) in sent.iter().enumerate().skip(1) {
+        assert_eq!(request.call_id_header()?.value(), call_id);
+        assert_eq!(request.from_header()?.tag()?, from_tag);
+        assert_eq!(
+            request.cseq_header()?.seq()?,
+            sent[0].cseq_header()?.seq()? + i as u32
+        );
+        assert_eq!(
+            header_value(&request.headers, "SIP-If-Match").as_deref(),
+            Some("e1")
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,32 @@
+use crate::dialog::reason::Reason;
+
+#[test]
+fn test_reason_header() {
+    let reason = Reason::sip(487);
+    assert_eq!(reason.to_string(), "SIP;cause=487");
+    let reason = Reason::q850(16).with_text("Normal call clearing");
+    assert_eq!(
+        reason.to_string(),
+        "Q.850;cause=16;text=\"Normal call clearing\""
+    );
+    assert_eq!(
+        Reason::try_from(reason.to_string().as_str()).unwrap(),
+        reason
+    );
+}
+
+#[test]
+fn test_parse_reason() {
+    let mut headers = rsip::Headers::default();
+    headers.push(rsip::Header::Other(
+        "Reason".into(),
+        "SIP ; cause=200 ; text=\"Call completed elsewhere\"".into(),
+    ));
+    let reason = Reason::from_headers(&headers).unwrap();
+    assert_eq!(reason.protocol, "SIP");
+    assert_eq!(reason.cause, 200);
+    assert_eq!(reason.text.as_deref(), Some("Call completed elsewhere"));
+
+    assert!(Reason::try_from("SIP;text=\"no cause\"").is_err());
+    assert!(Reason::from_headers(&rsip::Headers::default()).is_none());
+}
@@ -0,0 +1,77 @@
+use super::{wait_state, TestUa};
+use crate::dialog::dialog::DialogState;
+use crate::dialog::refer::{sipfrag_status, ReferTo};
+use crate::rsip_ext::percent_decode;
+
+#[test]
+fn test_parse_refer_to() {
+    let refer_to = ReferTo::try_from(
+        "<sip:bob@example.com?Replaces=abc%40host%3Bto-tag%3D1%3Bfrom-tag%3D2>;method=INVITE",
+    )
+    .unwrap();
+    assert_eq!(refer_to.uri.to_string(), "sip:bob@example.com");
+    assert_eq!(
+        refer_to.headers,
+        vec![(
+            "Replaces".to_string(),
+            "abc@host;to-tag=1;from-tag=2".to_string()
+        )]
+    );
+
+    let refer_to = ReferTo::try_from("sip:carol@example.com").unwrap();
+    assert_eq!(refer_to.uri.to_string(), "sip:carol@example.com");
+    assert!(refer_to.headers.is_empty());
+}
+
+#[test]
+fn test_percent_decode() {
+    assert_eq!(percent_decode("a%3Bb%3d"), "a;b=");
+    assert_eq!(percent_decode("100%"), "100%");
+}
+
+#[test]
+fn test_sipfrag_status() {
+    assert_eq!(
+        sipfrag_status(b"SIP/2.0 180 Ringing\r\n"),
+        Some(rsip::StatusCode::Ringing)
+    );
+    assert_eq!(
+        sipfrag_status(b"SIP/2.0 200 OK"),
+        Some(rsip::StatusCode::OK)
+    );
+    assert_eq!(sipfrag_status(b"INVITE sip:bob@example.com SIP/2.0"), None);
+    assert_eq!(sipfrag_status(b""), None);
+}
+
+#[tokio::test]
+async fn test_refer_accepted() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let mut bob = TestUa::new("bob").await?;
+    let (client, mut states, (server, mut server_states)) = alice
+        .call(&mut bob, b"v=0 alice\r\n".to_vec(), b"v=0 bob\r\n".to_vec())
+        .await?;
+
+    let target = rsip::Uri::try_from("sip:carol@example.com")?;
+    let refer = tokio::spawn(async move { client.refer(target, None).await });
+    let state = wait_state(&mut server_states, |s| matches!(s, DialogState::Refer(..))).await;
+    match state {
+        DialogState::Refer(_, refer_to, _) => {
+            assert_eq!(refer_to.uri.to_string(), "sip:carol@example.com")
+        }
+        _ => unreachable!(),
+    }
+    server.accept_refer()?;
+
+    let resp = refer.await.expect("refer task")?;
+    assert_eq!(
+        resp.map(|r| r.status_code),
+        Some(rsip::StatusCode::Accepted)
+    );
+    // the decision is followed by the first NOTIFY
+    let state = wait_state(&mut states, |s| matches!(s, DialogState::ReferProgress(..))).await;
+    assert!(matches!(
+        state,
+        DialogState::ReferProgress(_, rsip::StatusCode::Trying)
+    ));
+    Ok(())
+}
@@ -0,0 +1,50 @@
+use crate::dialog::{
+    reginfo::{ContactEvent, RegInfo, RegistrationInfo},
+    registrar::Binding,
+};
+use rsip::prelude::ToTypedHeader;
+use std::time::{Duration, SystemTime};
+
+#[test]
+fn test_reginfo() {
+    let binding = Binding {
+        contact: rsip::headers::Contact::new("<sip:alice@10.0.0.1>")
+            .typed()
+            .unwrap(),
+        call_id: "reg".to_string(),
+        cseq: 1,
+        expires_at: SystemTime::now() + Duration::from_secs(600),
+        instance: None,
+        reg_id: None,
+        flow: None,
+        path: vec![],
+    };
+    let removed = rsip::Uri::try_from("sip:alice@10.0.0.2").unwrap();
+    let registration = RegistrationInfo::new(
+        "sip:alice@example.com",
+        &[binding.clone()],
+        &[
+            (binding.contact.uri.clone(), ContactEvent::Shortened),
+            (removed, ContactEvent::Deactivated),
+        ],
+    );
+    assert_eq!(registration.state, "active");
+    assert_eq!(registration.contacts.len(), 2);
+    assert_eq!(registration.contacts[0].event, ContactEvent::Shortened);
+    assert_eq!(registration.contacts[1].state, "terminated");
+
+    let info = RegInfo {
+        version: 3,
+        state: "full".to_string(),
+        registrations: vec![registration],
+    };
+    let parsed = RegInfo::try_from(info.to_xml().as_str()).unwrap();
+    assert_eq!(parsed, info);
+
+    let terminated = RegistrationInfo::new(
+        "sip:alice@example.com",
+        &[],
+        &[(binding.contact.uri.clone(), ContactEvent::Unregistered)],
+    );
+    assert_eq!(terminated.state, "terminated");
+}
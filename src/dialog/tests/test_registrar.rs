@@ -0,0 +1,343 @@
+use crate::dialog::{
+    digest::{select_challenge, DigestAlgorithm, DigestCredentials},
+    registrar::{LocationService, MemoryLocationService, PasswordStore, Registrar},
+};
+use rsip::{
+    headers::auth::AuthQop,
+    prelude::{HeadersExt, ToTypedHeader, UntypedHeader},
+    services::DigestGenerator,
+    Header, StatusCode,
+};
+use std::sync::Arc;
+
+struct Users;
+
+#[async_trait::async_trait]
+impl PasswordStore for Users {
+    async fn password(&self, username: &str, _realm: &str) -> crate::Result<Option<String>> {
+        Ok((username == "alice").then(|| "secret".to_string()))
+    }
+}
+
+fn make_register(seq: u32, contacts: &[&str], expires: Option<u32>) -> rsip::Request {
+    let mut headers: Vec<Header> = vec![
+        rsip::headers::Via::new("SIP/2.0/UDP 10.0.0.1:5060;branch=z9hG4bKreg").into(),
+        rsip::headers::CSeq::new(format!("{} REGISTER", seq)).into(),
+        rsip::headers::From::new("<sip:alice@example.com>;tag=reg").into(),
+        rsip::headers::To::new("<sip:alice@example.com>").into(),
+        rsip::headers::CallId::new("registrar-test").into(),
+    ];
+    headers.extend(contacts.iter().map(|c| Header::Contact((*c).into())));
+    if let Some(expires) = expires {
+        headers.push(Header::Expires(expires.to_string().into()));
+    }
+    rsip::Request {
+        method: rsip::Method::Register,
+        uri: rsip::Uri::try_from("sip:example.com").unwrap(),
+        headers: headers.into(),
+        version: rsip::Version::V2,
+        body: vec![],
+    }
+}
+
+fn contact_count(headers: &[Header]) -> usize {
+    headers
+        .iter()
+        .filter(|h| matches!(h, Header::Contact(_)))
+        .count()
+}
+
+#[tokio::test]
+async fn test_registrar_bindings() {
+    let registrar = Registrar::new("example.com", Arc::new(MemoryLocationService::new()));
+
+    let (status, headers) = registrar
+        .process(&make_register(
+            1,
+            &["<sip:alice@10.0.0.1>", "<sip:alice@10.0.0.2>;expires=300"],
+            Some(600),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(contact_count(&headers), 2);
+
+    // too brief
+    let (status, headers) = registrar
+        .process(&make_register(
+            2,
+            &["<sip:alice@10.0.0.3>;expires=10"],
+            None,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(status, StatusCode::from(423));
+    assert!(headers
+        .iter()
+        .any(|h| h.to_string().contains("Min-Expires")));
+
+    // an older CSeq is out of order
+    let (status, _) = registrar
+        .process(&make_register(1, &["<sip:alice@10.0.0.1>"], Some(600)))
+        .await
+        .unwrap();
+    assert_eq!(status, StatusCode::ServerInternalError);
+
+    // removal of one binding, the other one is still listed
+    let (status, headers) = registrar
+        .process(&make_register(3, &["<sip:alice@10.0.0.1>;expires=0"], None))
+        .await
+        .unwrap();
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(contact_count(&headers), 1);
+
+    // a wildcard needs Expires: 0
+    let (status, _) = registrar
+        .process(&make_register(4, &["*"], Some(60)))
+        .await
+        .unwrap();
+    assert_eq!(status, StatusCode::BadRequest);
+    let (status, headers) = registrar
+        .process(&make_register(5, &["*"], Some(0)))
+        .await
+        .unwrap();
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(contact_count(&headers), 0);
+    assert!(registrar
+        .location()
+        .lookup("sip:alice@example.com")
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn test_registrar_challenge() {
+    let registrar = Registrar::new("example.com", Arc::new(MemoryLocationService::new()))
+        .with_credentials(Arc::new(Users));
+    let mut request = make_register(1, &["<sip:alice@10.0.0.1>"], Some(600));
+    let (status, headers) = registrar.process(&request).await.unwrap();
+    assert_eq!(status, StatusCode::Unauthorized);
+
+    let challenge = match &headers[0] {
+        Header::WwwAuthenticate(h) => h.typed().unwrap(),
+        h => panic!("unexpected header {}", h),
+    };
+    let qop = AuthQop::Auth {
+        cnonce: "cnonce".to_string(),
+        nc: 1,
+    };
+    let authorize = |request: &mut rsip::Request, password: &str| {
+        let response = DigestGenerator {
+            username: "alice",
+            password,
+            algorithm: challenge.algorithm.clone().unwrap_or_default(),
+            nonce: challenge.nonce.as_str(),
+            method: &rsip::Method::Register,
+            qop: Some(&qop),
+            uri: &request.uri,
+            realm: challenge.realm.as_str(),
+        }
+        .compute();
+        request.headers.unique_push(
+            rsip::typed::Authorization {
+                scheme: challenge.scheme.clone(),
+                username: "alice".to_string(),
+                realm: challenge.realm.clone(),
+                nonce: challenge.nonce.clone(),
+                uri: request.uri.clone(),
+                response,
+                algorithm: challenge.algorithm.clone(),
+                opaque: None,
+                qop: Some(qop.clone()),
+            }
+            .into(),
+        );
+    };
+
+    authorize(&mut request, "wrong");
+    let (status, _) = registrar.process(&request).await.unwrap();
+    assert_eq!(status, StatusCode::Unauthorized);
+
+    authorize(&mut request, "secret");
+    request.cseq_header_mut().unwrap().mut_seq(2).unwrap();
+    let (status, headers) = registrar.process(&request).await.unwrap();
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(contact_count(&headers), 1);
+}
+
+#[tokio::test]
+async fn test_registrar_sha256_challenge() {
+    let registrar = Registrar::new("example.com", Arc::new(MemoryLocationService::new()))
+        .with_credentials(Arc::new(Users))
+        .with_algorithms(vec![DigestAlgorithm::Sha256, DigestAlgorithm::Md5]);
+    let mut request = make_register(1, &["<sip:alice@10.0.0.1>"], Some(600));
+    let (status, headers) = registrar.process(&request).await.unwrap();
+    assert_eq!(status, StatusCode::Unauthorized);
+    let challenges = headers
+        .iter()
+        .filter_map(|h| match h {
+            Header::WwwAuthenticate(h) => Some(h.value()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(challenges.len(), 2);
+    let challenge = select_challenge(challenges).expect("challenge");
+    assert_eq!(challenge.algorithm, DigestAlgorithm::Sha256);
+
+    let credentials = DigestCredentials::answer(
+        &challenge,
+        "alice",
+        "secret",
+        &rsip::Method::Register,
+        &request.uri.to_string(),
+        &request.body,
+        "cnonce",
+    );
+    request
+        .headers
+        .push(Header::Authorization(credentials.to_string().into()));
+    let (status, _) = registrar.process(&request).await.unwrap();
+    assert_eq!(status, StatusCode::OK);
+}
+
+/// Authorizes `request` as alice with a fresh challenge of `registrar`
+async fn answer_challenge(registrar: &Registrar, request: &mut rsip::Request) {
+    let (_, headers) = registrar.process(request).await.unwrap();
+    let challenge = select_challenge(headers.iter().filter_map(|h| match h {
+        Header::WwwAuthenticate(h) => Some(h.value()),
+        _ => None,
+    }))
+    .expect("challenge");
+    let credentials = DigestCredentials::answer(
+        &challenge,
+        "alice",
+        "secret",
+        &rsip::Method::Register,
+        &request.uri.to_string(),
+        &request.body,
+        "cnonce",
+    );
+    request
+        .headers
+        .unique_push(Header::Authorization(credentials.to_string().into()));
+}
+
+#[tokio::test]
+async fn test_registrar_aor_authorization() {
+    // alice may not register the address of bob
+    let registrar = Registrar::new("example.com", Arc::new(MemoryLocationService::new()))
+        .with_credentials(Arc::new(Users));
+    let mut request = make_register(1, &["<sip:alice@10.0.0.1>"], Some(600));
+    request
+        .headers
+        .unique_push(rsip::headers::To::new("<sip:bob@example.com>").into());
+    answer_challenge(&registrar, &mut request).await;
+    let (status, _) = registrar.process(&request).await.unwrap();
+    assert_eq!(status, StatusCode::Forbidden);
+
+    // unless the authorizer allows it
+    let registrar = Registrar::new("example.com", Arc::new(MemoryLocationService::new()))
+        .with_credentials(Arc::new(Users))
+        .with_aor_authorizer(Arc::new(|username, aor| {
+            username == "alice" && aor.host_with_port.to_string() == "example.com"
+        }));
+    answer_challenge(&registrar, &mut request).await;
+    let (status, _) = registrar.process(&request).await.unwrap();
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_registrar_path() {
+    let location = Arc::new(MemoryLocationService::new());
+    let registrar = Registrar::new("example.com", location.clone());
+    let mut request = make_register(1, &["<sip:alice@10.0.0.1>"], Some(600));
+    request.headers.push(Header::Other(
+        "Path".into(),
+        "<sip:edge1.example.com;lr>, <sip:edge2.example.com;lr>".into(),
+    ));
+    let (status, headers) = registrar.process(&request).await.unwrap();
+    assert_eq!(status, StatusCode::OK);
+    // the UA did not ask for the Path
+    assert!(!headers
+        .iter()
+        .any(|h| matches!(h, Header::Other(name, _) if name == "Path")));
+    let bindings = location.lookup("sip:alice@example.com").await.unwrap();
+    let route_set = bindings[0].route_set();
+    assert_eq!(route_set.len(), 2);
+    assert_eq!(route_set[0].host_with_port.to_string(), "edge1.example.com");
+
+    request.headers.push(Header::Supported("path".into()));
+    request.cseq_header_mut().unwrap().mut_seq(2).unwrap();
+    let (_, headers) = registrar.process(&request).await.unwrap();
+    assert_eq!(
+        headers
+            .iter()
+            .filter(|h| matches!(h, Header::Other(name, _) if name == "Path"))
+            .count(),
+        2
+    );
+}
+
+#[tokio::test]
+async fn test_registrar_outbound() {
+    let location = Arc::new(MemoryLocationService::new());
+    let registrar = Registrar::new("example.com", location.clone());
+    let instance = "+sip.instance=\"<urn:uuid:00000000-0000-1000-8000-000a95a0e128>\"";
+    let flow = |seq: u32, reg_id: u32, expires: u32| {
+        let mut request = make_register(
+            seq,
+            &[&format!(
+                "<sip:alice@10.0.0.1>;{};reg-id={}",
+                instance, reg_id
+            )],
+            Some(expires),
+        );
+        request
+            .headers
+            .push(Header::Supported("outbound, path".into()));
+        request
+    };
+
+    let (status, headers) = registrar
+        .process_with_flow(&flow(1, 1, 600), Some("flow1"))
+        .await
+        .unwrap();
+    assert_eq!(status, StatusCode::OK);
+    assert!(headers
+        .iter()
+        .any(|h| matches!(h, Header::Require(r) if r.value() == "outbound")));
+    let (status, headers) = registrar
+        .process_with_flow(&flow(2, 2, 600), Some("flow2"))
+        .await
+        .unwrap();
+    assert_eq!(status, StatusCode::OK);
+    // both flows of the instance are bound, over the same contact URI
+    assert_eq!(contact_count(&headers), 2);
+    let bindings = location.lookup("sip:alice@example.com").await.unwrap();
+    assert_eq!(bindings[0].reg_id, Some(1));
+    assert_eq!(bindings[0].flow.as_deref(), Some("flow1"));
+    assert_eq!(bindings[1].flow.as_deref(), Some("flow2"));
+
+    // a flow registered again over a new connection replaces its binding
+    let (_, headers) = registrar
+        .process_with_flow(&flow(3, 1, 600), Some("flow3"))
+        .await
+        .unwrap();
+    assert_eq!(contact_count(&headers), 2);
+    let (_, headers) = registrar
+        .process_with_flow(&flow(4, 2, 0), None)
+        .await
+        .unwrap();
+    assert_eq!(contact_count(&headers), 1);
+    let bindings = location.lookup("sip:alice@example.com").await.unwrap();
+    assert_eq!(bindings[0].flow.as_deref(), Some("flow3"));
+
+    // a single flow per REGISTER
+    let mut request = flow(5, 1, 600);
+    request.headers.push(Header::Contact(
+        format!("<sip:alice@10.0.0.2>;{};reg-id=3", instance).into(),
+    ));
+    let (status, _) = registrar.process(&request).await.unwrap();
+    assert_eq!(status, StatusCode::BadRequest);
+}
@@ -0,0 +1,31 @@
+use crate::dialog::registration::{set_contact_instance, set_contact_q, ContactBinding};
+use rsip::prelude::ToTypedHeader;
+
+#[test]
+fn test_contact_binding() {
+    let mut contact = rsip::headers::Contact::new("<sip:alice@10.0.0.1:5060;transport=tcp>")
+        .typed()
+        .unwrap();
+    set_contact_q(&mut contact, 0.5);
+    set_contact_instance(
+        &mut contact,
+        "urn:uuid:00000000-0000-1000-8000-000A95A0E128",
+    );
+    set_contact_q(&mut contact, 0.7);
+    let value = rsip::headers::Contact::from(contact).value().to_string();
+    assert!(value.contains("q=0.7"));
+    assert!(!value.contains("q=0.5"));
+
+    let binding = ContactBinding::parse(&format!("{};expires=120", value), 3600).unwrap();
+    assert_eq!(binding.expires, 120);
+    assert_eq!(binding.q, Some(0.7));
+    assert_eq!(
+        binding.instance.as_deref(),
+        Some("urn:uuid:00000000-0000-1000-8000-000A95A0E128")
+    );
+
+    let binding = ContactBinding::parse("<sip:alice@10.0.0.2>", 3600).unwrap();
+    assert_eq!(binding.expires, 3600);
+    assert_eq!(binding.q, None);
+    assert!(binding.instance.is_none());
+}
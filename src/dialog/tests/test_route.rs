@@ -0,0 +1,124 @@
+use crate::dialog::dialog::{record_route_set, request_target};
+use crate::rsip_ext::{next_hop, restore_strict_route, set_route_set};
+use crate::transaction::key::TransactionRole;
+use rsip::{headers::Route, prelude::UntypedHeader};
+
+#[test]
+fn test_record_route_set() {
+    let mut headers = rsip::Headers::default();
+    headers.push(rsip::Header::RecordRoute(
+        "<sip:p2.example.com;lr>, <sip:p1.example.com;lr>".into(),
+    ));
+    headers.push(rsip::Header::RecordRoute("<sip:p0.example.com;lr>".into()));
+
+    let uas = record_route_set(&headers, TransactionRole::Server);
+    assert_eq!(
+        uas.iter()
+            .map(|r| r.value().to_string())
+            .collect::<Vec<_>>(),
+        vec![
+            "<sip:p2.example.com;lr>",
+            "<sip:p1.example.com;lr>",
+            "<sip:p0.example.com;lr>"
+        ]
+    );
+    let uac = record_route_set(&headers, TransactionRole::Client);
+    assert_eq!(uac.first().unwrap().value(), "<sip:p0.example.com;lr>");
+    assert_eq!(uac.last().unwrap().value(), "<sip:p2.example.com;lr>");
+}
+
+#[test]
+fn test_request_target() {
+    let target = rsip::Uri::try_from("sip:bob@192.168.1.2:5060").unwrap();
+    let (uri, routes) = request_target(&[], &target).unwrap();
+    assert_eq!(uri, target);
+    assert!(routes.is_empty());
+
+    let loose = vec![
+        Route::from("<sip:p1.example.com;lr>"),
+        Route::from("<sip:p2.example.com;lr>"),
+    ];
+    let (uri, routes) = request_target(&loose, &target).unwrap();
+    assert_eq!(uri, target);
+    assert_eq!(routes, loose);
+
+    let strict = vec![
+        Route::from("<sip:p1.example.com>"),
+        Route::from("<sip:p2.example.com;lr>"),
+    ];
+    let (uri, routes) = request_target(&strict, &target).unwrap();
+    assert_eq!(uri.to_string(), "sip:p1.example.com");
+    assert_eq!(routes.len(), 2);
+    assert_eq!(routes[0].value(), "<sip:p2.example.com;lr>");
+    assert_eq!(routes[1].value(), "<sip:bob@192.168.1.2:5060>");
+}
+
+#[test]
+fn test_restore_strict_route() {
+    let local = rsip::Uri::try_from("sip:10.0.0.1:5060")
+        .unwrap()
+        .host_with_port;
+    let mut request = rsip::Request {
+        method: rsip::Method::Bye,
+        uri: rsip::Uri::try_from("sip:10.0.0.1;lr;ftag=alice").unwrap(),
+        headers: vec![
+            rsip::Header::Route("<sip:p2.example.com>, <sip:bob@192.168.1.2>".into()),
+            rsip::Header::MaxForwards(70.into()),
+        ]
+        .into(),
+        body: vec![],
+        version: rsip::Version::V2,
+    };
+    assert!(restore_strict_route(&mut request, &[local.clone()]));
+    assert_eq!(request.uri.to_string(), "sip:bob@192.168.1.2");
+    assert_eq!(
+        request.headers.iter().next().unwrap().to_string(),
+        "Route: <sip:p2.example.com>"
+    );
+
+    // the Request-URI is not ours, nothing to restore
+    assert!(!restore_strict_route(&mut request, &[local.clone()]));
+
+    // one of our addresses without our Record-Route marker is a target
+    request.uri = rsip::Uri::try_from("sip:10.0.0.1").unwrap();
+    request
+        .headers
+        .push(rsip::Header::Route("<sip:alice@192.168.1.3>".into()));
+    assert!(!restore_strict_route(&mut request, &[local]));
+
+    // sips: defaults to port 5061
+    let tls = rsip::Uri::try_from("sips:10.0.0.1:5061")
+        .unwrap()
+        .host_with_port;
+    request.uri = rsip::Uri::try_from("sips:10.0.0.1;lr").unwrap();
+    assert!(restore_strict_route(&mut request, &[tls.clone()]));
+    assert_eq!(request.uri.to_string(), "sip:alice@192.168.1.3");
+    request.uri = rsip::Uri::try_from("sip:10.0.0.1;lr").unwrap();
+    assert!(!restore_strict_route(&mut request, &[tls]));
+}
+
+#[test]
+fn test_preloaded_route_set() {
+    let mut request = rsip::Request {
+        method: rsip::Method::Invite,
+        uri: rsip::Uri::try_from("sip:bob@example.com").unwrap(),
+        headers: vec![rsip::Header::Route("<sip:old.example.com;lr>".into())].into(),
+        body: vec![],
+        version: rsip::Version::V2,
+    };
+    let proxy = rsip::Uri::try_from("sip:proxy.example.com:5080").unwrap();
+    set_route_set(&mut request, &[proxy]);
+    let routes = request
+        .headers
+        .iter()
+        .map(|h| h.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(routes, vec!["Route: <sip:proxy.example.com:5080;lr>"]);
+    assert_eq!(
+        next_hop(&request).unwrap().host_with_port.to_string(),
+        "proxy.example.com:5080"
+    );
+
+    set_route_set(&mut request, &[]);
+    assert!(next_hop(&request).is_none());
+}
@@ -0,0 +1,30 @@
+use super::{wait_state, TestUa};
+use crate::dialog::{client_dialog::InviteOutcome, dialog::DialogState};
+use std::time::Duration;
+use tokio::sync::mpsc::unbounded_channel;
+
+#[tokio::test]
+async fn test_layer_shutdown() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let mut bob = TestUa::new("bob").await?;
+    let (_, mut states, _) = alice
+        .call(&mut bob, b"v=0 alice\r\n".to_vec(), b"v=0 bob\r\n".to_vec())
+        .await?;
+
+    // a second call still ringing at bob
+    let (sender, _ringing_states) = unbounded_channel();
+    let layer = alice.layer.clone();
+    let opt = alice.invite_option(&bob, Some(b"v=0 alice\r\n".to_vec()));
+    let ringing = tokio::spawn(async move { layer.do_invite_outcome(opt, sender, None).await });
+    let _ringing = bob.incoming().await;
+    assert_eq!(bob.layer.len(), 2);
+
+    bob.layer.shutdown(Duration::from_secs(2)).await;
+    assert_eq!(bob.layer.len(), 0);
+
+    // the answered call is hung up, the ringing one terminated with 487
+    wait_state(&mut states, |s| matches!(s, DialogState::Terminated(..))).await;
+    let (_, outcome) = ringing.await.expect("invite task")?;
+    assert!(matches!(outcome, InviteOutcome::Cancelled));
+    Ok(())
+}
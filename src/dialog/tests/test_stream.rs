@@ -0,0 +1,23 @@
+use crate::dialog::{
+    dialog::{dialog_state_stream, DialogState},
+    DialogId,
+};
+use futures::StreamExt;
+use tokio::sync::mpsc::unbounded_channel;
+
+#[tokio::test]
+async fn test_dialog_state_stream() {
+    let (sender, receiver) = unbounded_channel();
+    let id = DialogId {
+        call_id: "call-id".to_string(),
+        from_tag: "from-tag".to_string(),
+        to_tag: "to-tag".to_string(),
+    };
+    sender.send(DialogState::Calling(id.clone())).unwrap();
+    sender.send(DialogState::Confirmed(id.clone())).unwrap();
+    drop(sender);
+
+    let states = dialog_state_stream(receiver).collect::<Vec<_>>().await;
+    assert_eq!(states.len(), 2);
+    assert!(states[1].is_confirmed());
+}
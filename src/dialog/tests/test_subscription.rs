@@ -0,0 +1,289 @@
+use super::{no_state, wait_state, TestUa};
+use crate::{
+    dialog::{
+        dialog::DialogState,
+        subscription::{refresh_interval, SubscribeOption, SubscriptionState},
+    },
+    rsip_ext::header_value,
+    transport::{connection::TransportReceiver, udp::UdpConnection, SipAddr, TransportEvent},
+};
+use rsip::prelude::{HeadersExt, ToTypedHeader, UntypedHeader};
+use std::time::Duration;
+use tokio::sync::mpsc::unbounded_channel;
+
+/// Next message a raw peer receives matching `predicate`
+async fn receive(
+    received: &mut TransportReceiver,
+    predicate: impl Fn(&rsip::SipMessage) -> bool,
+) -> rsip::SipMessage {
+    loop {
+        let event = tokio::time::timeout(Duration::from_secs(5), received.recv())
+            .await
+            .expect("no message received");
+        if let Some(TransportEvent::Incoming(msg, _, _)) = event {
+            if predicate(&msg) {
+                return msg;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_parse_subscription_state() {
+    assert_eq!(
+        SubscriptionState::try_from("active;expires=3600").unwrap(),
+        SubscriptionState::Active {
+            expires: Some(3600)
+        }
+    );
+    assert_eq!(
+        SubscriptionState::try_from("Pending").unwrap(),
+        SubscriptionState::Pending { expires: None }
+    );
+    assert_eq!(
+        SubscriptionState::try_from("terminated;reason=timeout;retry-after=30").unwrap(),
+        SubscriptionState::Terminated {
+            reason: Some("timeout".to_string()),
+            retry_after: Some(30)
+        }
+    );
+    assert!(SubscriptionState::try_from("unknown").is_err());
+    assert_eq!(
+        SubscriptionState::try_from("terminated;reason=noresource")
+            .unwrap()
+            .to_string(),
+        "terminated;reason=noresource"
+    );
+}
+
+#[test]
+fn test_retry_delay() {
+    let state = |v: &str| SubscriptionState::try_from(v).unwrap();
+    assert_eq!(
+        state("terminated;reason=deactivated").retry_delay(),
+        Some(Duration::ZERO)
+    );
+    assert_eq!(
+        state("terminated;reason=probation;retry-after=10").retry_delay(),
+        Some(Duration::from_secs(10))
+    );
+    assert_eq!(state("terminated;reason=probation").retry_delay(), None);
+    assert_eq!(
+        state("terminated;reason=rejected;retry-after=10").retry_delay(),
+        None
+    );
+    assert_eq!(state("active;expires=60").retry_delay(), None);
+}
+
+#[test]
+fn test_refresh_interval() {
+    assert_eq!(refresh_interval(3600), Duration::from_secs(2880));
+    assert_eq!(refresh_interval(1), Duration::from_secs(1));
+}
+
+#[test]
+fn test_event_package() {
+    use crate::dialog::event_package::{event_name, EventPackage, SimpleEventPackage};
+    assert_eq!(event_name("Presence;id=1"), "presence");
+    let package = SimpleEventPackage::new("dialog")
+        .with_expires(600)
+        .with_content_type("application/dialog-info+xml");
+    assert_eq!(package.default_expires(), 600);
+    assert_eq!(package.content_type(), Some("application/dialog-info+xml"));
+    assert_eq!(package.merge(b"old", b"new".to_vec()), b"new".to_vec());
+}
+
+#[tokio::test]
+async fn test_notify_before_2xx() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let alice_addr = SipAddr::try_from(&alice.contact)?;
+    let bob = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let (sender, mut received) = unbounded_channel();
+    tokio::spawn({
+        let bob = bob.clone();
+        async move { bob.serve_loop(sender).await }
+    });
+    let bob_uri = format!("sip:bob@{}", bob.get_addr().addr);
+
+    let (state_sender, mut states) = unbounded_channel();
+    let opt = SubscribeOption {
+        subscriber: alice.contact.clone(),
+        target: rsip::Uri::try_from(bob_uri.as_str())?,
+        event: "presence".to_string(),
+        accept: None,
+        expires: 60,
+        contact: alice.contact.clone(),
+        credential: None,
+        headers: None,
+        content_type: None,
+        body: None,
+        route_set: None,
+    };
+    let layer = alice.layer.clone();
+    let subscribe = tokio::spawn(async move { layer.do_subscribe(opt, state_sender).await });
+    let request = match receive(&mut received, |m| matches!(m, rsip::SipMessage::Request(_))).await
+    {
+        rsip::SipMessage::Request(request) => request,
+        _ => unreachable!(),
+    };
+    assert_eq!(request.method, rsip::Method::Subscribe);
+
+    let notify = |cseq: u32, state: &str| -> crate::Result<String> {
+        Ok(format!(
+            "NOTIFY {} SIP/2.0\r\n\
+             Via: SIP/2.0/UDP {};branch=z9hG4bKnotify{}\r\n\
+             Max-Forwards: 70\r\n\
+             From: <{}>;tag=bob\r\n\
+             To: {}\r\n\
+             Call-ID: {}\r\n\
+             CSeq: {} NOTIFY\r\n\
+             Contact: <{}>\r\n\
+             Event: presence\r\n\
+             Subscription-State: {}\r\n\
+             Content-Length: 0\r\n\r\n",
+            alice.contact,
+            bob.get_addr().addr,
+            cseq,
+            bob_uri,
+            request.from_header()?.value(),
+            request.call_id_header()?.value(),
+            cseq,
+            bob_uri,
+            state
+        ))
+    };
+    let is_notify_response = |m: &rsip::SipMessage| {
+        matches!(m, rsip::SipMessage::Response(resp)
+            if resp.cseq_header().is_ok_and(|c| c.value().ends_with("NOTIFY")))
+    };
+
+    // the NOTIFY overtakes the 2xx of the SUBSCRIBE
+    bob.send_raw(notify(1, "active;expires=60")?.as_bytes(), &alice_addr)
+        .await?;
+    match receive(&mut received, is_notify_response).await {
+        rsip::SipMessage::Response(resp) => assert_eq!(resp.status_code, rsip::StatusCode::OK),
+        _ => unreachable!(),
+    }
+    let mut ok = alice
+        .endpoint
+        .inner
+        .make_response(&request, rsip::StatusCode::OK, None);
+    let to = request
+        .to_header()?
+        .typed()?
+        .with_tag("bob".to_string().into());
+    ok.headers.unique_push(rsip::Header::To(to.into()));
+    ok.headers
+        .push(rsip::Header::Contact(format!("<{}>", bob_uri).into()));
+    ok.headers.push(rsip::Header::Expires("60".into()));
+    bob.send(ok.into(), Some(&alice_addr)).await?;
+
+    let (dialog, _) = subscribe.await.expect("subscribe task")?;
+    assert_eq!(dialog.id().to_tag, "bob");
+    assert!(alice.layer.get_dialog(&dialog.id()).is_some());
+    assert_eq!(alice.layer.len(), 1);
+
+    // terminated, the subscription leaves the layer
+    bob.send_raw(
+        notify(2, "terminated;reason=noresource")?.as_bytes(),
+        &alice_addr,
+    )
+    .await?;
+    wait_state(&mut states, |s| matches!(s, DialogState::Terminated(..))).await;
+    assert_eq!(alice.layer.len(), 0);
+    Ok(())
+}
+
+/// Subscription of `subscriber` to the presence of `notifier`
+fn presence_option(subscriber: &TestUa, notifier: &TestUa, expires: u32) -> SubscribeOption {
+    SubscribeOption {
+        subscriber: subscriber.contact.clone(),
+        target: notifier.contact.clone(),
+        event: "presence".to_string(),
+        accept: None,
+        expires,
+        contact: subscriber.contact.clone(),
+        credential: None,
+        headers: None,
+        content_type: None,
+        body: None,
+        route_set: None,
+    }
+}
+
+fn notify_body(state: &DialogState) -> Option<Vec<u8>> {
+    match state {
+        DialogState::Notify(_, notify) => Some(notify.body.clone()),
+        _ => None,
+    }
+}
+
+#[tokio::test]
+async fn test_notify_throttling() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let mut bob = TestUa::new("bob").await?;
+    let (state_sender, mut states) = unbounded_channel();
+    let opt = presence_option(&alice, &bob, 60);
+    let layer = alice.layer.clone();
+    let subscribe = tokio::spawn(async move { layer.do_subscribe(opt, state_sender).await });
+    let (notifier, _) = bob.subscribed().await;
+    subscribe.await.expect("subscribe task")?;
+
+    notifier.set_min_notify_interval(Some(Duration::from_millis(300)));
+    let active = || SubscriptionState::Active { expires: Some(60) };
+    for body in ["open", "busy", "closed"] {
+        notifier
+            .notify(active(), None, Some(body.as_bytes().to_vec()))
+            .await?;
+    }
+
+    // the first goes right away, the others are coalesced into the latest
+    let state = wait_state(&mut states, |s| notify_body(s).is_some()).await;
+    assert_eq!(notify_body(&state), Some(b"open".to_vec()));
+    let state = wait_state(&mut states, |s| notify_body(s).is_some()).await;
+    assert_eq!(notify_body(&state), Some(b"closed".to_vec()));
+    assert!(
+        no_state(&mut states, Duration::from_millis(500), |s| {
+            notify_body(s).is_some()
+        })
+        .await
+    );
+
+    // terminating is never delayed
+    notifier
+        .notify(active(), None, Some(b"open".to_vec()))
+        .await?;
+    notifier.terminate(None).await?;
+    wait_state(&mut states, |s| matches!(s, DialogState::Terminated(..))).await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_subscription_expires() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let mut bob = TestUa::new("bob").await?;
+    let (state_sender, mut states) = unbounded_channel();
+    let opt = presence_option(&alice, &bob, 1);
+    let layer = alice.layer.clone();
+    let subscribe = tokio::spawn(async move { layer.do_subscribe(opt, state_sender).await });
+    let (notifier, mut notifier_states) = bob.subscribed().await;
+    let (subscriber, _) = subscribe.await.expect("subscribe task")?;
+    assert_eq!(notifier.expires(), 1);
+
+    // never refreshed, the notifier ends it once Expires elapsed
+    subscriber.subscription.stop_token.cancel();
+    let state = wait_state(&mut states, |s| notify_body(s).is_some()).await;
+    match state {
+        DialogState::Notify(_, notify) => assert_eq!(
+            header_value(&notify.headers, "Subscription-State").as_deref(),
+            Some("terminated;reason=timeout")
+        ),
+        _ => unreachable!(),
+    }
+    wait_state(&mut notifier_states, |s| {
+        matches!(s, DialogState::Terminated(..))
+    })
+    .await;
+    assert!(bob.layer.get_dialog(&notifier.id()).is_none());
+    Ok(())
+}
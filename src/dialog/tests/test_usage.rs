@@ -0,0 +1,66 @@
+use super::{wait_state, TestUa};
+use crate::dialog::{dialog::DialogState, usage::DialogUsage};
+
+#[test]
+fn test_subscription_usage_from_event() {
+    let usage = DialogUsage::from_event("Refer;id=93809824");
+    assert_eq!(
+        usage,
+        DialogUsage::Subscription {
+            event: "refer".to_string(),
+            id: Some("93809824".to_string()),
+        }
+    );
+    assert_eq!(usage.to_string(), "refer;id=93809824");
+    assert_eq!(DialogUsage::from_event("presence").to_string(), "presence");
+}
+
+#[test]
+fn test_usage_matches() {
+    let first = DialogUsage::from_event("refer;id=1");
+    let second = DialogUsage::from_event("refer;id=2");
+    // a NOTIFY without id belongs to any refer subscription
+    assert!(first.matches(&DialogUsage::from_event("refer")));
+    assert!(first.matches(&DialogUsage::from_event("refer;id=1")));
+    assert!(!first.matches(&second));
+    assert!(!first.matches(&DialogUsage::from_event("presence")));
+    assert!(!first.matches(&DialogUsage::Invite));
+    assert!(DialogUsage::Invite.matches(&DialogUsage::Invite));
+}
+
+#[tokio::test]
+async fn test_bye_ends_invite_usage() -> crate::Result<()> {
+    let alice = TestUa::new("alice").await?;
+    let mut bob = TestUa::new("bob").await?;
+    let (client, mut states, (server, mut server_states)) = alice
+        .call(&mut bob, b"v=0 alice\r\n".to_vec(), b"v=0 bob\r\n".to_vec())
+        .await?;
+
+    // bob accepts a transfer, its subscription outlives the session
+    let referrer = client.clone();
+    let target = rsip::Uri::try_from("sip:carol@example.com")?;
+    let refer = tokio::spawn(async move { referrer.refer(target, None).await });
+    wait_state(&mut server_states, |s| matches!(s, DialogState::Refer(..))).await;
+    server.accept_refer()?;
+    refer.await.expect("refer task")?;
+    wait_state(&mut states, |s| matches!(s, DialogState::ReferProgress(..))).await;
+    assert_eq!(bob.layer.confirmed_len(), 1);
+
+    client.bye(None).await?;
+    wait_state(&mut server_states, |s| {
+        matches!(s, DialogState::UsageTerminated(_, DialogUsage::Invite))
+    })
+    .await;
+    let state = server.inner.state.lock().unwrap().clone();
+    assert!(matches!(
+        state,
+        DialogState::UsageTerminated(_, DialogUsage::Invite)
+    ));
+    // still confirmed for the subscription, but no call in progress
+    assert!(state.is_confirmed());
+    let usages = server.usages();
+    assert_eq!(usages.len(), 1);
+    assert!(usages[0].matches(&DialogUsage::from_event("refer")));
+    assert_eq!(bob.layer.confirmed_len(), 0);
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use super::dialog::{DialogInner, DialogState};
+use crate::Result;
+
+/// A usage sharing the dialog (RFC 5057 3), the INVITE session or one of the
+/// subscriptions created in it, e.g. the implicit subscription of a REFER
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DialogUsage {
+    Invite,
+    /// A subscription, identified by its event package and `id` parameter
+    Subscription {
+        event: String,
+        id: Option<String>,
+    },
+}
+
+impl DialogUsage {
+    /// Subscription usage of an Event header value, e.g. `refer;id=93809824`
+    pub fn from_event(value: &str) -> Self {
+        let mut parts = value.split(';').map(|p| p.trim());
+        let event = parts.next().unwrap_or_default().to_lowercase();
+        let id = parts
+            .filter_map(|p| p.split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("id"))
+            .map(|(_, id)| id.trim().to_string());
+        DialogUsage::Subscription { event, id }
+    }
+
+    /// Returns true if a request for `other` belongs to this usage, a
+    /// NOTIFY without id matches any subscription of its event package
+    /// (RFC 3515 2.4.6)
+    pub fn matches(&self, other: &DialogUsage) -> bool {
+        match (self, other) {
+            (DialogUsage::Invite, DialogUsage::Invite) => true,
+            (
+                DialogUsage::Subscription { event, id },
+                DialogUsage::Subscription {
+                    event: other_event,
+                    id: other_id,
+                },
+            ) => event == other_event && (other_id.is_none() || id == other_id),
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for DialogUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DialogUsage::Invite => write!(f, "invite"),
+            DialogUsage::Subscription { event, id } => match id {
+                Some(id) => write!(f, "{};id={}", event, id),
+                None => write!(f, "{}", event),
+            },
+        }
+    }
+}
+
+impl DialogInner {
+    /// Usages currently sharing the dialog
+    pub fn usages(&self) -> Vec<DialogUsage> {
+        self.usages.lock().unwrap().clone()
+    }
+
+    pub(super) fn has_usage(&self, usage: &DialogUsage) -> bool {
+        self.usages.lock().unwrap().iter().any(|u| u.matches(usage))
+    }
+
+    pub(super) fn add_usage(&self, usage: DialogUsage) {
+        let mut usages = self.usages.lock().unwrap();
+        if !usages.contains(&usage) {
+            usages.push(usage);
+        }
+    }
+
+    /// Ends one usage of the dialog, reported as `DialogState::UsageTerminated`
+    /// while others remain. Returns true when it was the last one, the
+    /// caller then terminates the dialog itself.
+    pub(super) fn end_usage(&self, usage: &DialogUsage) -> Result<bool> {
+        let (ended, remaining) = {
+            let mut usages = self.usages.lock().unwrap();
+            match usages.iter().position(|u| u.matches(usage)) {
+                Some(pos) => (usages.remove(pos), usages.len()),
+                None => return Ok(false),
+            }
+        };
+        if remaining == 0 {
+            return Ok(true);
+        }
+        self.transition(DialogState::UsageTerminated(
+            self.id.lock().unwrap().clone(),
+            ended,
+        ))?;
+        Ok(false)
+    }
+
+    /// Requests of the INVITE usage are answered with 481 once a BYE ended
+    /// it, while a subscription keeps the dialog alive (RFC 5057 5.3)
+    pub(super) fn invite_usage_ended(&self, method: &rsip::Method) -> bool {
+        matches!(
+            method,
+            rsip::Method::Invite | rsip::Method::Update | rsip::Method::Bye | rsip::Method::Info
+        ) && !self.has_usage(&DialogUsage::Invite)
+    }
+}
@@ -1,4 +1,5 @@
 use rsip::message::HasHeaders;
+use rsip::prelude::{ToTypedHeader, UntypedHeader};
 pub trait RsipResponseExt {
     fn reason_phrase(&self) -> Option<&str>;
 }
@@ -29,6 +30,47 @@ impl RsipHeadersExt for rsip::Headers {
     }
 }
 
+/// Returns the values of all headers named `name` (case-insensitive), whether
+/// rsip parsed them into a typed variant or kept them as `Header::Other`
+pub fn header_values(headers: &rsip::Headers, name: &str) -> Vec<String> {
+    headers
+        .iter()
+        .filter_map(|h| {
+            let line = h.to_string();
+            let (n, v) = line.split_once(':')?;
+            if n.trim().eq_ignore_ascii_case(name) {
+                Some(v.trim().to_string())
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+pub fn header_value(headers: &rsip::Headers, name: &str) -> Option<String> {
+    header_values(headers, name).into_iter().next()
+}
+
+/// Decodes `%XX` escapes, used by headers embedded in a SIP URI (RFC 3261 19.1.1)
+pub fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+            if let Ok(b) = u8::from_str_radix(hex, 16) {
+                decoded.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
 #[macro_export]
 macro_rules! header_pop {
     ($iter:expr, $header:path) => {
@@ -65,6 +107,466 @@ pub fn extract_uri_from_contact(line: &str) -> crate::Result<rsip::Uri> {
     }
 }
 
+/// Values of the Contact headers one per contact, a header may list several
+/// separated by commas (RFC 3261 20.10)
+pub fn contact_values(headers: &rsip::Headers) -> Vec<String> {
+    let mut values = vec![];
+    for value in headers.iter().filter_map(|h| match h {
+        rsip::Header::Contact(contact) => Some(contact.value().to_string()),
+        _ => None,
+    }) {
+        split_addresses(&value, &mut values);
+    }
+    values
+}
+
+/// Values of the `name` headers listing name-addrs, e.g. Service-Route,
+/// one per address, topmost first
+pub fn address_values(headers: &rsip::Headers, name: &str) -> Vec<String> {
+    let mut values = vec![];
+    for value in header_values(headers, name) {
+        split_addresses(&value, &mut values);
+    }
+    values
+}
+
+/// Values of the Path headers (RFC 3327) one per URI, topmost first
+pub fn path_values(headers: &rsip::Headers) -> Vec<String> {
+    address_values(headers, "Path")
+}
+
+/// Splits a list of name-addrs on the commas outside quotes and brackets
+fn split_addresses(value: &str, values: &mut Vec<String>) {
+    let (mut quoted, mut bracketed, mut start) = (false, false, 0);
+    for (i, c) in value.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '<' if !quoted => bracketed = true,
+            '>' if !quoted => bracketed = false,
+            ',' if !quoted && !bracketed => {
+                values.push(value[start..i].trim().to_string());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    values.push(value[start..].trim().to_string());
+    values.retain(|v| !v.is_empty());
+}
+
+/// Adds the option tag `tag` to the Supported header, keeping the tags
+/// already there
+pub fn add_supported(headers: &mut rsip::Headers, tag: &str) {
+    let mut tags = header_values(headers, "Supported")
+        .iter()
+        .flat_map(|v| v.split(','))
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+        .collect::<Vec<_>>();
+    if tags.iter().any(|t| t.eq_ignore_ascii_case(tag)) {
+        return;
+    }
+    tags.push(tag.to_string());
+    headers.retain(|h| !matches!(h, rsip::Header::Supported(_)));
+    headers.push(rsip::Header::Supported(tags.join(", ").into()));
+}
+
+/// Whether the Supported header lists the option tag `tag`
+pub fn has_supported(headers: &rsip::Headers, tag: &str) -> bool {
+    header_values(headers, "Supported")
+        .iter()
+        .flat_map(|v| v.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case(tag))
+}
+
+/// Whether the Require header lists the option tag `tag`
+pub fn has_required(headers: &rsip::Headers, tag: &str) -> bool {
+    header_values(headers, "Require")
+        .iter()
+        .flat_map(|v| v.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case(tag))
+}
+
+/// URI of the first entry of a Route header
+pub fn route_uri(route: &rsip::headers::Route) -> Option<rsip::Uri> {
+    route
+        .typed()
+        .ok()
+        .and_then(|r| r.uris().first().map(|u| u.uri.clone()))
+}
+
+/// Whether the first entry of a Route header has the `lr` parameter
+pub fn is_loose_route(route: &rsip::headers::Route) -> bool {
+    route
+        .value()
+        .to_ascii_lowercase()
+        .split(['>', ','])
+        .next()
+        .map(|uri| {
+            uri.split(';')
+                .any(|p| p.trim() == "lr" || p.trim().starts_with("lr="))
+        })
+        .unwrap_or(false)
+}
+
+/// Route header of a preloaded route, made a loose route when it isn't one
+pub fn make_route(uri: &rsip::Uri) -> rsip::Header {
+    let mut uri = uri.clone();
+    let loose = uri.params.iter().any(
+        |p| matches!(p, rsip::Param::Other(name, _) if name.to_string().eq_ignore_ascii_case("lr")),
+    );
+    if !loose {
+        uri.params.push(rsip::Param::Other("lr".into(), None));
+    }
+    rsip::Header::Route(format!("<{}>", uri).into())
+}
+
+/// Replaces the Route headers of an out-of-dialog request with `routes`
+pub fn set_route_set(request: &mut rsip::Request, routes: &[rsip::Uri]) {
+    request
+        .headers
+        .retain(|h| !matches!(h, rsip::Header::Route(_)));
+    for uri in routes {
+        request.headers.push(make_route(uri));
+    }
+}
+
+/// Next hop of a request: its first Route when that is a loose route
+pub fn next_hop(request: &rsip::Request) -> Option<rsip::Uri> {
+    request
+        .headers
+        .iter()
+        .find_map(|h| match h {
+            rsip::Header::Route(route) => Some(route),
+            _ => None,
+        })
+        .filter(|route| is_loose_route(route))
+        .and_then(route_uri)
+}
+
+/// Whether `uri` is at the `local` address, a missing port being the
+/// default one of the scheme of `uri`
+fn same_host(local: &rsip::HostWithPort, uri: &rsip::Uri) -> bool {
+    let default_port = match uri.scheme {
+        Some(rsip::Scheme::Sips) => "5061",
+        _ => "5060",
+    };
+    let port = |h: &rsip::HostWithPort| {
+        h.port
+            .as_ref()
+            .map(|p| p.to_string())
+            .unwrap_or(default_port.to_string())
+    };
+    local
+        .host
+        .to_string()
+        .eq_ignore_ascii_case(&uri.host_with_port.host.to_string())
+        && port(local) == port(&uri.host_with_port)
+}
+
+/// Undoes the rewrite of an RFC 2543 strict router (RFC 3261 16.4).
+///
+/// When the Request-URI is a Record-Route we inserted, a loose route (`lr`)
+/// to one of the `local` addresses, the previous hop put it there and moved
+/// the target into the last Route, which becomes the Request-URI again.
+/// Returns whether the request was changed.
+pub fn restore_strict_route(request: &mut rsip::Request, local: &[rsip::HostWithPort]) -> bool {
+    let uri = request.uri.to_string().to_ascii_lowercase();
+    let loose = uri
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .split(';')
+        .skip(1)
+        .any(|p| p.trim() == "lr" || p.trim().starts_with("lr="));
+    if !loose || !local.iter().any(|addr| same_host(addr, &request.uri)) {
+        return false;
+    }
+    let mut headers = request.headers.iter().cloned().collect::<Vec<_>>();
+    let index = match headers
+        .iter()
+        .rposition(|h| matches!(h, rsip::Header::Route(_)))
+    {
+        Some(index) => index,
+        None => return false,
+    };
+    let mut routes = match &headers[index] {
+        rsip::Header::Route(route) => route
+            .value()
+            .split(',')
+            .map(|r| r.trim().to_string())
+            .collect::<Vec<_>>(),
+        _ => return false,
+    };
+    let target = match routes.pop().map(|r| extract_uri_from_contact(&r)) {
+        Some(Ok(target)) => target,
+        _ => return false,
+    };
+    if routes.is_empty() {
+        headers.remove(index);
+    } else {
+        headers[index] = rsip::Header::Route(routes.join(", ").into());
+    }
+    request.headers = headers.into();
+    request.uri = target;
+    true
+}
+
+/// Removes the topmost Route entry when it's one of the `local` addresses,
+/// i.e. a Record-Route we inserted earlier in the dialog or a preloaded
+/// route to us (RFC 3261 16.4), and returns its URI
+pub fn pop_local_route(
+    request: &mut rsip::Request,
+    local: &[rsip::HostWithPort],
+) -> Option<rsip::Uri> {
+    let mut headers = request.headers.iter().cloned().collect::<Vec<_>>();
+    let index = headers
+        .iter()
+        .position(|h| matches!(h, rsip::Header::Route(_)))?;
+    let mut routes = match &headers[index] {
+        rsip::Header::Route(route) => route
+            .value()
+            .split(',')
+            .map(|r| r.trim().to_string())
+            .collect::<Vec<_>>(),
+        _ => return None,
+    };
+    let uri = extract_uri_from_contact(routes.first()?).ok()?;
+    if !local.iter().any(|addr| same_host(addr, &uri)) {
+        return None;
+    }
+    routes.remove(0);
+    if routes.is_empty() {
+        headers.remove(index);
+    } else {
+        headers[index] = rsip::Header::Route(routes.join(", ").into());
+    }
+    request.headers = headers.into();
+    Some(uri)
+}
+
+/// Delay of the Retry-After header (RFC 3261 20.33), its comment and
+/// parameters ignored
+pub fn retry_after(headers: &rsip::Headers) -> Option<std::time::Duration> {
+    let value = header_value(headers, "Retry-After")?;
+    let seconds = value
+        .split(|c: char| !c.is_ascii_digit())
+        .next()?
+        .parse()
+        .ok()?;
+    Some(std::time::Duration::from_secs(seconds))
+}
+
+/// Value of the Max-Forwards header, `None` when it's missing or invalid
+pub fn max_forwards(request: &rsip::Request) -> Option<u32> {
+    request.headers.iter().find_map(|h| match h {
+        rsip::Header::MaxForwards(max_forwards) => max_forwards.value().trim().parse().ok(),
+        _ => None,
+    })
+}
+
+/// Decrements Max-Forwards of a request about to be forwarded (RFC 3261 16.6
+/// step 3), a missing header starts from the default of 70. Returns false
+/// when it already reached zero, the request is then rejected with 483.
+pub fn decrement_max_forwards(request: &mut rsip::Request) -> bool {
+    let value = max_forwards(request).unwrap_or(crate::transaction::MAX_FORWARDS);
+    if value == 0 {
+        return false;
+    }
+    request
+        .headers
+        .retain(|h| !matches!(h, rsip::Header::MaxForwards(_)));
+    request
+        .headers
+        .push(rsip::Header::MaxForwards((value - 1).into()));
+    true
+}
+
+/// Makes the topmost Via name `transport` once the transport of the
+/// request is chosen, with `sent_by` when it changes (RFC 3261 18.1.1)
+pub fn set_via_transport(
+    request: &mut rsip::Request,
+    transport: rsip::transport::Transport,
+    sent_by: Option<rsip::HostWithPort>,
+) {
+    let mut list = request.headers.iter().cloned().collect::<Vec<_>>();
+    let position = match list.iter().position(|h| matches!(h, rsip::Header::Via(_))) {
+        Some(position) => position,
+        None => return,
+    };
+    let mut via = match &list[position] {
+        rsip::Header::Via(via) => match via.typed() {
+            Ok(via) => via,
+            Err(_) => return,
+        },
+        _ => return,
+    };
+    if via.transport == transport {
+        return;
+    }
+    via.transport = transport;
+    if let Some(sent_by) = sent_by {
+        via.uri = sent_by.into();
+    }
+    list[position] = rsip::Header::Via(via.into());
+    request.headers = list.into();
+}
+
+/// Removes the topmost Via, e.g. the one of a proxy from a response it
+/// forwards back (RFC 3261 16.7 step 3), and returns it
+pub fn pop_via(headers: &mut rsip::Headers) -> Option<rsip::headers::Via> {
+    let mut list = headers.iter().cloned().collect::<Vec<_>>();
+    let position = list
+        .iter()
+        .position(|h| matches!(h, rsip::Header::Via(_)))?;
+    let via = match list.remove(position) {
+        rsip::Header::Via(via) => via,
+        _ => return None,
+    };
+    *headers = list.into();
+    Some(via)
+}
+
+#[test]
+fn test_set_via_transport() {
+    let mut request = rsip::Request {
+        method: rsip::Method::Options,
+        uri: rsip::Uri::try_from("sip:bob@example.com").unwrap(),
+        headers: vec![
+            rsip::Header::Via("SIP/2.0/UDP 10.0.0.1:5060;branch=z9hG4bKpolicy;rport".into()),
+            rsip::Header::Via("SIP/2.0/UDP 10.0.0.2:5060;branch=z9hG4bKupstream".into()),
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body: vec![],
+    };
+    let sent_by =
+        rsip::HostWithPort::from("10.0.0.1:5070".parse::<std::net::SocketAddr>().unwrap());
+    set_via_transport(
+        &mut request,
+        rsip::transport::Transport::Tcp,
+        Some(sent_by.clone()),
+    );
+    let vias = request
+        .headers
+        .iter()
+        .filter_map(|h| match h {
+            rsip::Header::Via(via) => via.typed().ok(),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(vias[0].transport, rsip::transport::Transport::Tcp);
+    assert_eq!(vias[0].uri.host_with_port, sent_by);
+    assert!(vias[0]
+        .params
+        .iter()
+        .any(|p| matches!(p, rsip::Param::Branch(b) if b.to_string() == "z9hG4bKpolicy")));
+    // the Vias of upstream elements are left alone
+    assert_eq!(vias[1].transport, rsip::transport::Transport::Udp);
+}
+
+#[test]
+fn test_decrement_max_forwards() {
+    let mut request = rsip::Request {
+        method: rsip::Method::Options,
+        uri: rsip::Uri::try_from("sip:bob@example.com").unwrap(),
+        headers: vec![rsip::Header::MaxForwards(1.into())].into(),
+        version: rsip::Version::V2,
+        body: vec![],
+    };
+    assert!(decrement_max_forwards(&mut request));
+    assert_eq!(max_forwards(&request), Some(0));
+    assert!(!decrement_max_forwards(&mut request));
+
+    request.headers = vec![].into();
+    assert!(decrement_max_forwards(&mut request));
+    assert_eq!(max_forwards(&request), Some(69));
+}
+
+#[test]
+fn test_pop_local_route() {
+    let local = vec![rsip::HostWithPort::try_from("10.0.0.1:5060").unwrap()];
+    let mut request = rsip::Request {
+        method: rsip::Method::Bye,
+        uri: rsip::Uri::try_from("sip:bob@192.168.1.2").unwrap(),
+        headers: vec![rsip::Header::Route(
+            "<sip:10.0.0.1;lr;ftag=alice>, <sip:edge.example.com;lr>".into(),
+        )]
+        .into(),
+        version: rsip::Version::V2,
+        body: vec![],
+    };
+    let uri = pop_local_route(&mut request, &local).expect("local route");
+    assert_eq!(uri.host_with_port.to_string(), "10.0.0.1");
+    assert_eq!(
+        next_hop(&request).map(|uri| uri.host_with_port.to_string()),
+        Some("edge.example.com".to_string())
+    );
+    // the next route is not ours
+    assert!(pop_local_route(&mut request, &local).is_none());
+    assert!(next_hop(&request).is_some());
+}
+
+#[test]
+fn test_retry_after() {
+    let mut headers: rsip::Headers = vec![].into();
+    assert_eq!(retry_after(&headers), None);
+    headers.push(rsip::Header::Other(
+        "Retry-After".into(),
+        "120 (in a meeting);duration=60".into(),
+    ));
+    assert_eq!(
+        retry_after(&headers),
+        Some(std::time::Duration::from_secs(120))
+    );
+}
+
+#[test]
+fn test_contact_values() {
+    let headers: rsip::Headers = vec![
+        rsip::Header::Contact(
+            "\"Alice, home\" <sip:alice@10.0.0.1;transport=tcp>;q=0.7, <sip:alice@10.0.0.2>".into(),
+        ),
+        rsip::Header::Contact("sip:alice@10.0.0.3;expires=60".into()),
+    ]
+    .into();
+    assert_eq!(
+        contact_values(&headers),
+        vec![
+            "\"Alice, home\" <sip:alice@10.0.0.1;transport=tcp>;q=0.7",
+            "<sip:alice@10.0.0.2>",
+            "sip:alice@10.0.0.3;expires=60",
+        ]
+    );
+}
+
+#[test]
+fn test_path_and_supported() {
+    let mut headers: rsip::Headers = vec![
+        rsip::Header::Other(
+            "Path".into(),
+            "<sip:edge1.example.com;lr>, <sip:edge2.example.com;lr>".into(),
+        ),
+        rsip::Header::Supported("outbound".into()),
+    ]
+    .into();
+    assert_eq!(
+        path_values(&headers),
+        vec!["<sip:edge1.example.com;lr>", "<sip:edge2.example.com;lr>"]
+    );
+    add_supported(&mut headers, "path");
+    add_supported(&mut headers, "Outbound");
+    assert_eq!(header_values(&headers, "Supported"), vec!["outbound, path"]);
+    assert!(has_supported(&headers, "PATH"));
+    assert!(!has_supported(&headers, "timer"));
+    headers.push(rsip::Header::Other(
+        "Require".into(),
+        "timer, 100rel".into(),
+    ));
+    assert!(has_required(&headers, "100REL"));
+    assert!(!has_required(&headers, "path"));
+}
+
 #[test]
 fn test_rsip_headers_ext() {
     use rsip::{Header, Headers};
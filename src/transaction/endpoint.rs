@@ -1,44 +1,288 @@
 use super::{
-    key::TransactionKey,
-    make_via_branch,
+    key::{TransactionKey, TransactionRole},
+    loop_hash, make_via_branch,
+    table::TransactionTable,
     timer::Timer,
     transaction::{Transaction, TransactionEvent, TransactionEventSender},
-    SipConnection, TransactionReceiver, TransactionSender, TransactionTimer,
+    IncomingRequest, MessageInterceptorRef, RequestHandlerRef, RequestRouter, SipConnection,
+    TransactionMetricsRef, TransactionReceiver, TransactionSender, TransactionTimer, MAX_FORWARDS,
 };
 use crate::{
-    transport::{SipAddr, TransportEvent, TransportLayer},
+    dialog::authenticate::AuthCache,
+    rsip_ext::{has_supported, header_values, next_hop, pop_local_route, restore_strict_route},
+    transport::{
+        flow::flow_remote,
+        normalize::{make_bad_request, BadMessagePolicy},
+        SipAddr, TransportEvent, TransportLayer,
+    },
     Error, Result, USER_AGENT,
 };
-use rsip::SipMessage;
+use rsip::{
+    prelude::{HeadersExt, ToTypedHeader, UntypedHeader},
+    SipMessage,
+};
 use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
-    time::{Duration, Instant},
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use tokio::{
     select,
     sync::mpsc::{error, unbounded_channel},
-    time::sleep,
+    time::{sleep, Instant},
 };
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, info, trace, warn};
 
+/// New INVITEs queued while the endpoint is busy, older ones are rejected
+/// with 503 beyond this
+const MAX_DEFERRED_INVITES: usize = 1024;
+
+/// Longest Retry-After a target is skipped for, whatever it asked
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(3600);
+
+/// INVITE outside of a dialog, the lowest priority work under load
+fn is_initial_invite(req: &rsip::Request) -> bool {
+    req.method == rsip::Method::Invite
+        && req
+            .to_header()
+            .and_then(|to| to.tag())
+            .map(|tag| tag.is_none())
+            .unwrap_or(true)
+}
+
+/// Out-of-dialog request an overloaded endpoint rejects, ACK and CANCEL
+/// have no transaction of their own to reject
+fn is_new_request(req: &rsip::Request) -> bool {
+    !matches!(req.method, rsip::Method::Ack | rsip::Method::Cancel)
+        && req
+            .to_header()
+            .and_then(|to| to.tag())
+            .map(|tag| tag.is_none())
+            .unwrap_or(true)
+}
+
+/// Request creating a dialog, i.e. an INVITE, SUBSCRIBE or REFER outside of one
+fn is_dialog_forming(req: &rsip::Request) -> bool {
+    matches!(
+        req.method,
+        rsip::Method::Invite | rsip::Method::Subscribe | rsip::Method::Refer
+    ) && req
+        .to_header()
+        .and_then(|to| to.tag())
+        .map(|tag| tag.is_none())
+        .unwrap_or(false)
+}
+
+/// Out-of-dialog request that doesn't create a dialog, left to the
+/// `RequestHandler` when one is set
+fn is_standalone_request(req: &rsip::Request) -> bool {
+    !matches!(
+        req.method,
+        rsip::Method::Invite
+            | rsip::Method::Ack
+            | rsip::Method::Cancel
+            | rsip::Method::Subscribe
+            | rsip::Method::Refer
+    ) && req
+        .to_header()
+        .and_then(|to| to.tag())
+        .map(|tag| tag.is_none())
+        .unwrap_or(false)
+}
+
+/// A 2xx to an INVITE already acknowledged for another to-tag, i.e. the
+/// answer of a losing fork
+fn is_forked_answer(ack: &rsip::Request, resp: &rsip::Response) -> bool {
+    let to_tag = |to: Option<&rsip::headers::To>| {
+        to.and_then(|to| to.tag().ok().flatten())
+            .map(|tag| tag.value().to_string())
+    };
+    ack.method == rsip::Method::Ack
+        && resp.status_code.kind() == rsip::StatusCodeKind::Successful
+        && resp
+            .cseq_header()
+            .map(|cseq| cseq.method().ok() == Some(rsip::Method::Invite))
+            .unwrap_or(false)
+        && to_tag(resp.to_header().ok()) != to_tag(ack.to_header().ok())
+}
+
+/// A stored final response answers retransmissions of its own request only,
+/// the ACK or CANCEL sharing the key of an INVITE still reach the dialog
+fn is_retransmission_of(msg: &SipMessage, last_message: &SipMessage) -> bool {
+    match (msg, last_message) {
+        (SipMessage::Request(req), SipMessage::Response(resp)) => resp
+            .cseq_header()
+            .map(|cseq| cseq.method().ok() == Some(req.method.clone()))
+            .unwrap_or(false),
+        _ => true,
+    }
+}
+
+/// Where to resend a stored ACK: its next hop when it has a route set,
+/// otherwise the transport derives it from the message
+fn stored_destination(msg: &SipMessage) -> Option<SipAddr> {
+    match msg {
+        SipMessage::Request(req) => next_hop(req).and_then(|uri| SipAddr::try_from(&uri).ok()),
+        SipMessage::Response(_) => None,
+    }
+}
+
+/// Transaction timer values (RFC 3261 17), the defaults are the ones of the
+/// RFC for a UDP network
+#[derive(Clone, Debug)]
+pub struct EndpointOption {
+    /// RTT estimate, first retransmission interval of Timers A, E and G
+    pub t1: Duration,
+    /// Cap of the retransmission interval of Timers E and G
+    pub t2: Duration,
+    /// Time a message may stay in the network, Timers I and K
+    pub t4: Duration,
+    /// Transaction timeout of Timers B, F, H and J, 64*T1 when `None`
+    pub transaction_timeout: Option<Duration>,
+    /// Timer D, response retransmissions absorbed by a client INVITE
+    /// transaction after its ACK, 64*T1 when `None`
+    pub timer_d: Option<Duration>,
+    /// Interval at which a ringing INVITE server transaction resends its last
+    /// provisional response (RFC 3261 13.3.1.1), `None` disables it
+    pub provisional_interval: Option<Duration>,
+    /// Timer C, how long a proxied INVITE branch may stay without a final
+    /// response before it's cancelled
+    pub timer_c: Duration,
+    pub overload: OverloadThresholds,
+}
+
+/// Load from which new out-of-dialog requests are rejected with 503 and a
+/// Retry-After growing with the load, `None` leaves a measure unchecked
+#[derive(Clone, Debug)]
+pub struct OverloadThresholds {
+    /// Transactions in progress
+    pub max_transactions: Option<usize>,
+    /// Dialogs of the dialog layers of the endpoint
+    pub max_dialogs: Option<usize>,
+    /// Received messages waiting to be processed, deferred INVITEs included
+    pub max_queue_depth: Option<usize>,
+    /// Retry-After at the threshold, scaled by how far it's exceeded
+    pub retry_after: Duration,
+    pub max_retry_after: Duration,
+}
+
+impl Default for OverloadThresholds {
+    fn default() -> Self {
+        OverloadThresholds {
+            max_transactions: None,
+            max_dialogs: None,
+            max_queue_depth: None,
+            retry_after: Duration::from_secs(5),
+            max_retry_after: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Snapshot of the load of an endpoint, see `EndpointInner::load`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EndpointLoad {
+    pub transactions: usize,
+    pub dialogs: usize,
+    pub queue_depth: usize,
+}
+
+impl Default for EndpointOption {
+    fn default() -> Self {
+        EndpointOption {
+            t1: Duration::from_millis(500),
+            t2: Duration::from_secs(4),
+            t4: Duration::from_secs(4),
+            transaction_timeout: None,
+            timer_d: None,
+            provisional_interval: Some(Duration::from_secs(60)),
+            timer_c: Duration::from_secs(180),
+            overload: OverloadThresholds::default(),
+        }
+    }
+}
+
+/// What the endpoint advertises of itself in the requests and responses it
+/// builds, empty lists leave the header out
+#[derive(Clone, Debug)]
+pub struct EndpointIdentity {
+    /// Server header of responses, which carry the User-Agent when `None`
+    pub server: Option<String>,
+    pub allow: Vec<rsip::Method>,
+    /// Option tags of the Supported header, e.g. `timer`, `100rel`. A
+    /// request requiring any other is answered 420 Bad Extension.
+    pub supported: Vec<String>,
+    /// Media types of the Accept header, e.g. `application/sdp`
+    pub accept: Vec<String>,
+    /// Parameters added to every Contact, e.g. `+sip.instance` or `expires`
+    pub contact_params: Vec<rsip::Param>,
+    pub max_forwards: u32,
+}
+
+impl Default for EndpointIdentity {
+    fn default() -> Self {
+        EndpointIdentity {
+            server: None,
+            allow: vec![],
+            supported: vec![],
+            accept: vec![],
+            contact_params: vec![],
+            max_forwards: MAX_FORWARDS,
+        }
+    }
+}
+
+/// Automatic 200 OK to out-of-dialog OPTIONS, e.g. the keepalive probes of
+/// a provider. Allow, Accept and Supported come from `EndpointIdentity`.
+#[derive(Clone, Debug, Default)]
+pub struct OptionsResponder {
+    /// Media capabilities sent as an `application/sdp` body
+    pub sdp: Option<String>,
+}
+
 pub struct EndpointInner {
     pub user_agent: String,
+    pub identity: EndpointIdentity,
     pub timers: Timer<TransactionTimer>,
     pub transport_layer: TransportLayer,
-    pub finished_transactions: Mutex<HashMap<TransactionKey, Option<SipMessage>>>,
-    pub transactions: Mutex<HashMap<TransactionKey, TransactionEventSender>>,
+    pub finished_transactions: TransactionTable<Option<SipMessage>>,
+    pub transactions: TransactionTable<TransactionEventSender>,
     incoming_sender: Mutex<Option<TransactionSender>>,
     cancel_token: CancellationToken,
     timer_interval: Duration,
     pub transport_tx: UnboundedSender<TransportEvent>,
-    transport_rx: Mutex<UnboundedReceiver<TransportEvent>>,
+    /// Locked for as long as `serve` runs, across its awaits
+    transport_rx: tokio::sync::Mutex<UnboundedReceiver<TransportEvent>>,
 
     pub t1: Duration,
+    pub t2: Duration,
     pub t4: Duration,
     pub t1x64: Duration,
+    pub timer_d: Duration,
+    pub provisional_interval: Option<Duration>,
+    pub timer_c: Duration,
+    pub overload: OverloadThresholds,
+    dialog_count: AtomicUsize,
+    queue_depth: AtomicUsize,
+    /// CSeq of the last standalone MESSAGE or OPTIONS sent by the endpoint
+    pub(crate) last_seq: AtomicU32,
+    /// Targets that answered 503 with Retry-After, and until when
+    unavailable: Mutex<HashMap<SipAddr, Instant>>,
+    /// Digest challenges answered, reused to authorize the next requests
+    pub auth_cache: AuthCache,
+    /// Preloaded Route set of out-of-dialog requests, e.g. an outbound proxy
+    pub route_set: Vec<rsip::Uri>,
+    /// Service-Route (RFC 3608) learned from the last successful REGISTER,
+    /// preloaded after `route_set`
+    service_route: Mutex<Vec<rsip::Uri>>,
+    request_handler: Option<RequestHandlerRef>,
+    pub metrics: Option<TransactionMetricsRef>,
+    interceptors: Vec<MessageInterceptorRef>,
+    options_responder: Option<OptionsResponder>,
 }
 pub type EndpointInnerRef = Arc<EndpointInner>;
 
@@ -47,6 +291,13 @@ pub struct EndpointBuilder {
     transport_layer: Option<TransportLayer>,
     cancel_token: Option<CancellationToken>,
     timer_interval: Option<Duration>,
+    route_set: Vec<rsip::Uri>,
+    option: EndpointOption,
+    request_handler: Option<RequestHandlerRef>,
+    metrics: Option<TransactionMetricsRef>,
+    interceptors: Vec<MessageInterceptorRef>,
+    identity: EndpointIdentity,
+    options_responder: Option<OptionsResponder>,
 }
 
 pub struct Endpoint {
@@ -54,30 +305,173 @@ pub struct Endpoint {
 }
 
 impl EndpointInner {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         user_agent: String,
         transport_layer: TransportLayer,
         cancel_token: CancellationToken,
         timer_interval: Option<Duration>,
+        route_set: Vec<rsip::Uri>,
+        option: EndpointOption,
+        request_handler: Option<RequestHandlerRef>,
+        metrics: Option<TransactionMetricsRef>,
+        interceptors: Vec<MessageInterceptorRef>,
+        identity: EndpointIdentity,
+        options_responder: Option<OptionsResponder>,
     ) -> Arc<Self> {
         let (transport_tx, transport_rx) = unbounded_channel();
+        let t1x64 = option.transaction_timeout.unwrap_or(option.t1 * 64);
         Arc::new(EndpointInner {
             user_agent,
+            identity,
             timers: Timer::new(),
             transport_layer,
-            transactions: Mutex::new(HashMap::new()),
-            finished_transactions: Mutex::new(HashMap::new()),
+            transactions: TransactionTable::new(),
+            finished_transactions: TransactionTable::new(),
             timer_interval: timer_interval.unwrap_or(Duration::from_millis(20)),
             transport_tx,
-            transport_rx: Mutex::new(transport_rx),
+            transport_rx: tokio::sync::Mutex::new(transport_rx),
             cancel_token,
             incoming_sender: Mutex::new(None),
-            t1: Duration::from_millis(500),
-            t4: Duration::from_secs(4),
-            t1x64: Duration::from_millis(64 * 500),
+            t1: option.t1,
+            t2: option.t2,
+            t4: option.t4,
+            t1x64,
+            timer_d: option.timer_d.unwrap_or(option.t1 * 64),
+            provisional_interval: option.provisional_interval,
+            timer_c: option.timer_c,
+            overload: option.overload,
+            dialog_count: AtomicUsize::new(0),
+            queue_depth: AtomicUsize::new(0),
+            last_seq: AtomicU32::new(0),
+            unavailable: Mutex::new(HashMap::new()),
+            auth_cache: AuthCache::new(),
+            route_set,
+            service_route: Mutex::new(vec![]),
+            request_handler,
+            metrics,
+            interceptors,
+            options_responder,
         })
     }
 
+    /// Option tags of the `header` of `request`, Require or Proxy-Require,
+    /// the endpoint doesn't advertise in `EndpointIdentity::supported`, ACK
+    /// and CANCEL being exempt
+    pub fn unsupported_extensions(&self, request: &rsip::Request, header: &str) -> Vec<String> {
+        if matches!(request.method, rsip::Method::Ack | rsip::Method::Cancel) {
+            return vec![];
+        }
+        header_values(&request.headers, header)
+            .iter()
+            .flat_map(|v| v.split(','))
+            .map(|tag| tag.trim().to_string())
+            .filter(|tag| !tag.is_empty())
+            .filter(|tag| {
+                !self
+                    .identity
+                    .supported
+                    .iter()
+                    .any(|t| t.eq_ignore_ascii_case(tag))
+            })
+            .collect()
+    }
+
+    pub fn load(&self) -> EndpointLoad {
+        EndpointLoad {
+            transactions: self.transactions.len(),
+            dialogs: self.dialog_count.load(Ordering::Relaxed),
+            queue_depth: self.queue_depth.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Counts the dialogs of the dialog layers, `added` or removed
+    pub(crate) fn on_dialog_count(&self, added: bool) {
+        if added {
+            self.dialog_count.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.dialog_count
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                .ok();
+        }
+    }
+
+    /// Retry-After of the 503 answering new requests while the load exceeds
+    /// one of the `OverloadThresholds`, `None` when it doesn't
+    pub fn overload_retry_after(&self) -> Option<Duration> {
+        let load = self.load();
+        let ratio = [
+            (load.transactions, self.overload.max_transactions),
+            (load.dialogs, self.overload.max_dialogs),
+            (load.queue_depth, self.overload.max_queue_depth),
+        ]
+        .iter()
+        .filter_map(|(value, max)| max.map(|max| *value as f64 / max.max(1) as f64))
+        .fold(0.0, f64::max);
+        if ratio < 1.0 {
+            return None;
+        }
+        let retry_after = self.overload.retry_after.mul_f64(ratio);
+        Some(retry_after.min(self.overload.max_retry_after))
+    }
+
+    /// Rejects a new request with 503 and Retry-After, statelessly so the
+    /// overload isn't fed with transactions
+    async fn reject_overload(
+        &self,
+        request: &rsip::Request,
+        connection: &SipConnection,
+        retry_after: Duration,
+    ) -> Result<()> {
+        info!(
+            "overloaded {:?}, rejecting {} for {:?}",
+            self.load(),
+            request.method,
+            retry_after
+        );
+        let mut resp = self.make_response(request, rsip::StatusCode::ServiceUnavailable, None);
+        // rounded up, 0 would invite an immediate retry
+        let seconds = (retry_after.as_secs_f64().ceil() as u64).max(1);
+        resp.headers
+            .push(rsip::Header::RetryAfter(seconds.to_string().into()));
+        self.send_message(connection, resp.into(), None).await
+    }
+
+    /// Records `target` as unavailable for `retry_after` once it answered
+    /// 503 with Retry-After (RFC 3261 21.5.4), requests to it failing with a
+    /// local 503 meanwhile
+    pub fn set_unavailable(&self, target: SipAddr, retry_after: Duration) {
+        let retry_after = retry_after.min(MAX_RETRY_AFTER);
+        info!("{} unavailable for {:?}", target, retry_after);
+        self.unavailable
+            .lock()
+            .unwrap()
+            .insert(target, Instant::now() + retry_after);
+    }
+
+    /// Time left before `target` may be tried again, `None` when it's
+    /// available
+    pub fn unavailable_for(&self, target: &SipAddr) -> Option<Duration> {
+        let mut unavailable = self.unavailable.lock().unwrap();
+        let until = *unavailable.get(target)?;
+        let now = Instant::now();
+        if until <= now {
+            unavailable.remove(target);
+            return None;
+        }
+        Some(until - now)
+    }
+
+    pub fn service_route(&self) -> Vec<rsip::Uri> {
+        self.service_route.lock().unwrap().clone()
+    }
+
+    /// Replaces the Service-Route used by out-of-dialog requests other than
+    /// REGISTER, an empty one removes it
+    pub fn set_service_route(&self, service_route: Vec<rsip::Uri>) {
+        *self.service_route.lock().unwrap() = service_route;
+    }
+
     pub async fn serve(self: &Arc<Self>) -> Result<()> {
         select! {
             _ = self.cancel_token.cancelled() => {
@@ -97,42 +491,142 @@ impl EndpointInner {
         let transport_tx = self.transport_tx.clone();
         self.transport_layer.serve_listens(transport_tx).await.ok();
 
-        let mut transport_rx = self.transport_rx.lock().unwrap();
-        while let Some(event) = transport_rx.recv().await {
-            match event {
-                TransportEvent::Incoming(msg, connection, from) => {
-                    match self.on_received_message(msg, connection).await {
-                        Ok(()) => {}
-                        Err(e) => {
-                            warn!("on_received_message error:{} {:?}", from, e);
+        let mut transport_rx = self.transport_rx.lock().await;
+        // new INVITEs wait until the queue is drained, so CANCEL, BYE and
+        // responses of established sessions are never stuck behind them
+        let mut deferred = VecDeque::new();
+        loop {
+            self.queue_depth
+                .store(deferred.len() + transport_rx.len(), Ordering::Relaxed);
+            let event = if deferred.is_empty() {
+                match transport_rx.recv().await {
+                    Some(event) => event,
+                    None => break,
+                }
+            } else {
+                match transport_rx.try_recv() {
+                    Ok(event) => event,
+                    Err(error::TryRecvError::Empty) => {
+                        if let Some(event) = deferred.pop_front() {
+                            self.on_transport_event(event).await;
                         }
+                        continue;
                     }
+                    Err(error::TryRecvError::Disconnected) => break,
                 }
-                TransportEvent::New(t) => {
-                    trace!("new connection {} ", t);
+            };
+
+            match &event {
+                TransportEvent::Incoming(SipMessage::Request(req), _, _)
+                    if is_initial_invite(req) =>
+                {
+                    if deferred.len() >= MAX_DEFERRED_INVITES {
+                        if let Some(oldest) = deferred.pop_front() {
+                            self.shed_invite(oldest).await;
+                        }
+                    }
+                    deferred.push_back(event);
                 }
-                TransportEvent::Closed(t) => {
-                    trace!("connection closed {} ", t);
+                TransportEvent::Incoming(SipMessage::Request(req), _, _)
+                    if req.method == rsip::Method::Cancel =>
+                {
+                    // the INVITE being cancelled must have its transaction first
+                    let key = TransactionKey::from_request(req, TransactionRole::Server).ok();
+                    let position = deferred.iter().position(|e| match e {
+                        TransportEvent::Incoming(SipMessage::Request(invite), _, _) => {
+                            TransactionKey::from_request(invite, TransactionRole::Server).ok()
+                                == key
+                        }
+                        _ => false,
+                    });
+                    if let Some(invite) = position.and_then(|pos| deferred.remove(pos)) {
+                        self.on_transport_event(invite).await;
+                    }
+                    self.on_transport_event(event).await;
                 }
+                _ => self.on_transport_event(event).await,
             }
         }
         Ok(())
     }
 
+    async fn on_transport_event(self: &Arc<Self>, event: TransportEvent) {
+        match event {
+            TransportEvent::Incoming(msg, connection, from) => {
+                match self.on_received_message(msg, connection).await {
+                    Ok(()) => {}
+                    Err(e) => {
+                        warn!("on_received_message error:{} {:?}", from, e);
+                    }
+                }
+            }
+            TransportEvent::BadMessage(buf, connection, from, error) => {
+                self.on_bad_message(&buf, connection, from, error).await;
+            }
+            TransportEvent::New(t) => {
+                trace!("new connection {} ", t);
+            }
+            TransportEvent::Closed(t) => {
+                trace!("connection closed {} ", t);
+                self.transport_layer.remove_flow(&t);
+            }
+        }
+    }
+
+    /// Rejects a queued INVITE with 503 when too many new calls are waiting
+    async fn shed_invite(&self, event: TransportEvent) {
+        if let TransportEvent::Incoming(SipMessage::Request(req), connection, from) = event {
+            info!("overloaded, rejecting new INVITE from {}", from);
+            let resp = self.make_response(&req, rsip::StatusCode::ServiceUnavailable, None);
+            self.send_message(&connection, resp.into(), None)
+                .await
+                .map_err(|e| warn!("failed to reject INVITE: {:?}", e))
+                .ok();
+        }
+    }
+
+    async fn on_bad_message(
+        &self,
+        buf: &[u8],
+        connection: SipConnection,
+        from: SipAddr,
+        error: String,
+    ) {
+        let transport = from.r#type.unwrap_or(rsip::transport::Transport::Udp);
+        match self.transport_layer.bad_message_policy(transport) {
+            BadMessagePolicy::Drop => {
+                info!("dropping bad message from {}: {}", from, error);
+            }
+            BadMessagePolicy::Reject => match make_bad_request(buf, &error) {
+                Some(resp) => {
+                    info!("rejecting bad message from {}: {}", from, error);
+                    self.send_message(&connection, resp, Some(&from))
+                        .await
+                        .map_err(|e| warn!("failed to reject bad message: {:?}", e))
+                        .ok();
+                }
+                None => {
+                    info!("dropping bad message from {}: {}", from, error);
+                }
+            },
+            BadMessagePolicy::Hook(hook) => hook(buf, &from, &error),
+        }
+    }
+
     pub async fn process_timer(self: Arc<Self>) -> Result<()> {
         while !self.cancel_token.is_cancelled() {
             for t in self.timers.poll(Instant::now()) {
                 match t {
                     TransactionTimer::TimerCleanup(key) => {
                         debug!("TimerCleanup {}", key);
-                        self.transactions.lock().unwrap().remove(&key);
-                        self.finished_transactions.lock().unwrap().remove(&key);
+                        self.transactions.remove(&key);
+                        self.finished_transactions.remove(&key);
                         continue;
                     }
                     _ => {}
                 }
 
-                if let Some(tu) = { self.transactions.lock().unwrap().get(t.key()) } {
+                if let Some(tu) = self.transactions.get(t.key()) {
                     match tu.send(TransactionEvent::Timer(t)) {
                         Ok(_) => {}
                         Err(error::SendError(t)) => match t {
@@ -149,6 +643,19 @@ impl EndpointInner {
         Ok(())
     }
 
+    /// Sends `msg` on `connection` once the interceptors have seen it
+    pub async fn send_message(
+        &self,
+        connection: &SipConnection,
+        mut msg: SipMessage,
+        destination: Option<&SipAddr>,
+    ) -> Result<()> {
+        for interceptor in self.interceptors.iter() {
+            interceptor.on_outgoing(&mut msg, destination);
+        }
+        connection.send(msg, destination).await
+    }
+
     pub fn attach_incoming_sender(&self, sender: Option<TransactionSender>) {
         *self.incoming_sender.lock().unwrap() = sender;
     }
@@ -156,9 +663,12 @@ impl EndpointInner {
     // receive message from transport layer
     pub async fn on_received_message(
         self: &Arc<Self>,
-        msg: SipMessage,
+        mut msg: SipMessage,
         connection: SipConnection,
     ) -> Result<()> {
+        for interceptor in self.interceptors.iter() {
+            interceptor.on_incoming(&mut msg, &connection);
+        }
         let mut key = match &msg {
             SipMessage::Request(req) => {
                 TransactionKey::from_request(req, super::key::TransactionRole::Server)?
@@ -171,18 +681,36 @@ impl EndpointInner {
         // check is the termination of an existing transaction
         let last_message = self
             .finished_transactions
-            .lock()
-            .unwrap()
             .get(&key)
-            .map(|m| m.clone())
-            .flatten();
+            .flatten()
+            .filter(|m| is_retransmission_of(&msg, m));
 
         if let Some(last_message) = last_message {
-            connection.send(last_message, None).await?;
+            if let (SipMessage::Request(ack), SipMessage::Response(resp)) = (&last_message, &msg) {
+                if is_forked_answer(ack, resp) {
+                    self.reject_forked_answer(ack, resp, connection).await?;
+                    return Ok(());
+                }
+            }
+            let destination = stored_destination(&last_message);
+            self.send_message(&connection, last_message, destination.as_ref())
+                .await?;
+            if let Some(metrics) = self.metrics.as_ref() {
+                let method = match &msg {
+                    SipMessage::Request(req) => Some(req.method.clone()),
+                    SipMessage::Response(resp) => {
+                        resp.cseq_header().ok().and_then(|c| c.method().ok())
+                    }
+                };
+                if let Some(method) = method {
+                    metrics.on_retransmission_received(&method);
+                    metrics.on_retransmission_sent(&method);
+                }
+            }
             return Ok(());
         }
 
-        match self.transactions.lock().unwrap().get(&key) {
+        match self.transactions.get(&key) {
             Some(tu) => {
                 tu.send(TransactionEvent::Received(msg, Some(connection)))
                     .map_err(|e| Error::TransactionError(e.to_string(), key))?;
@@ -193,15 +721,78 @@ impl EndpointInner {
         // if the transaction is not exist, create a new transaction
         let request = match msg {
             SipMessage::Request(req) => req,
+            SipMessage::Response(resp) if self.is_stateless_response(&resp) => {
+                return self.forward_stateless_response(resp).await;
+            }
             SipMessage::Response(resp) => {
                 debug!("the transaction is not exist {} {}", key, resp);
                 return Ok(());
             }
         };
 
+        if is_new_request(&request) {
+            if let Some(retry_after) = self.overload_retry_after() {
+                return self
+                    .reject_overload(&request, &connection, retry_after)
+                    .await;
+            }
+        }
+
+        if let Some(responder) = self
+            .options_responder
+            .as_ref()
+            .filter(|_| request.method == rsip::Method::Options)
+            .filter(|_| is_standalone_request(&request))
+        {
+            let (headers, body) = match responder.sdp.as_ref() {
+                Some(sdp) => (
+                    vec![
+                        rsip::Header::ContentType("application/sdp".into()),
+                        rsip::Header::ContentLength((sdp.len() as u32).into()),
+                    ],
+                    Some(sdp.as_bytes().to_vec()),
+                ),
+                None => (vec![rsip::Header::ContentLength(0.into())], None),
+            };
+            let mut tx = Transaction::new_server(key, request, self.clone(), Some(connection));
+            tokio::spawn(async move {
+                let result = match tx.reply_unsupported("Require").await {
+                    Ok(false) => tx.reply_with(rsip::StatusCode::OK, headers, body).await,
+                    result => result.map(|_| ()),
+                };
+                if let Err(e) = result {
+                    warn!("failed to answer options: {:?}", e);
+                }
+            });
+            return Ok(());
+        }
+
+        if let Some(handler) = self
+            .request_handler
+            .clone()
+            .filter(|_| is_standalone_request(&request))
+        {
+            let mut tx = Transaction::new_server(key, request, self.clone(), Some(connection));
+            tokio::spawn(async move {
+                let method = tx.original.method.clone();
+                match tx.reply_unsupported("Require").await {
+                    Ok(false) => {}
+                    Ok(true) => return,
+                    Err(e) => {
+                        warn!("failed to answer bad extension: {:?}", e);
+                        return;
+                    }
+                }
+                if let Err(e) = handler.on_request(IncomingRequest::from(tx)).await {
+                    warn!("request handler failed on {}: {:?}", method, e);
+                }
+            });
+            return Ok(());
+        }
+
         if self.incoming_sender.lock().unwrap().is_none() {
             let resp = self.make_response(&request, rsip::StatusCode::ServiceUnavailable, None);
-            connection.send(resp.into(), None).await?;
+            self.send_message(&connection, resp.into(), None).await?;
             return Err(Error::TransactionError(
                 "incoming_sender not set".to_string(),
                 key,
@@ -224,34 +815,60 @@ impl EndpointInner {
         return Ok(());
     }
 
+    /// Acknowledges the 2xx of a losing fork of an INVITE whose first answer
+    /// was accepted, then hangs up the dialog it established
+    async fn reject_forked_answer(
+        self: &Arc<Self>,
+        ack: &rsip::Request,
+        resp: &rsip::Response,
+        connection: SipConnection,
+    ) -> Result<()> {
+        let fork_ack = self.make_fork_ack(ack, resp)?;
+        let bye = self.make_fork_bye(&fork_ack)?;
+        info!(
+            "hanging up forked answer: {}",
+            resp.to_header()
+                .map(|to| to.to_string())
+                .unwrap_or_default()
+        );
+        let fork_ack: SipMessage = fork_ack.into();
+        let destination = stored_destination(&fork_ack);
+        self.send_message(&connection, fork_ack, destination.as_ref())
+            .await?;
+
+        let key = TransactionKey::from_request(&bye, TransactionRole::Client)?;
+        let mut tx = Transaction::new_client(key, bye, self.clone(), None);
+        tokio::spawn(async move {
+            if let Err(e) = tx.send().await {
+                info!("failed to send bye to forked answer: {:?}", e);
+                return;
+            }
+            while let Some(_) = tx.receive().await {}
+        });
+        Ok(())
+    }
+
     pub fn attach_transaction(&self, key: &TransactionKey, tu_sender: TransactionEventSender) {
         trace!("attach_transaction {}", key);
-        self.transactions
-            .lock()
-            .unwrap()
-            .insert(key.clone(), tu_sender);
+        self.transactions.insert(key.clone(), tu_sender);
     }
 
     pub fn detach_transaction(&self, key: &TransactionKey, last_message: Option<SipMessage>) {
         trace!("detach_transaction {}", key);
-        self.transactions.lock().unwrap().remove(key);
+        self.transactions.remove(key);
 
         if let Some(msg) = last_message {
-            let timer_k_duration = if msg.is_request() {
-                self.t4
-            } else {
-                self.t1x64
-            };
+            // the ACK of a client INVITE answers the 2xx the peer retransmits
+            // for 64*T1 when the ACK is lost (RFC 3261 13.2.2.4), the final
+            // response of a server transaction its request retransmissions
+            let timer_k_duration = self.t1x64;
 
             self.timers.timeout(
                 timer_k_duration,
                 TransactionTimer::TimerCleanup(key.clone()), // maybe use TimerK ???
             );
 
-            self.finished_transactions
-                .lock()
-                .unwrap()
-                .insert(key.clone(), Some(msg));
+            self.finished_transactions.insert(key.clone(), Some(msg));
         }
     }
 
@@ -259,6 +876,18 @@ impl EndpointInner {
         self.transport_layer.get_addrs()
     }
 
+    /// Restores the Request-URI of a request received from a strict router
+    /// that targeted one of our Record-Route addresses, see
+    /// `rsip_ext::restore_strict_route`
+    pub fn restore_strict_route(&self, request: &mut rsip::Request) -> bool {
+        let local = self
+            .get_addrs()
+            .into_iter()
+            .map(|addr| addr.addr)
+            .collect::<Vec<_>>();
+        restore_strict_route(request, &local)
+    }
+
     pub fn get_record_route(&self) -> Result<rsip::typed::RecordRoute> {
         let first_addr = self
             .transport_layer
@@ -273,6 +902,170 @@ impl EndpointInner {
         Ok(rr.into())
     }
 
+    /// Record-Route of a request this element forwards: a loose route to our
+    /// first address, with its transport unless UDP and, when `with_ftag`,
+    /// the From tag of `request` telling which side of the dialog sends an
+    /// in-dialog request routed back to us
+    pub fn make_record_route(
+        &self,
+        request: &rsip::Request,
+        with_ftag: bool,
+    ) -> Result<rsip::Header> {
+        let first_addr = self
+            .transport_layer
+            .get_addrs()
+            .first()
+            .ok_or(Error::EndpointError("not sipaddrs".to_string()))
+            .cloned()?;
+        let transport = first_addr
+            .r#type
+            .clone()
+            .filter(|t| *t != rsip::transport::Transport::Udp);
+        let mut uri: rsip::Uri = first_addr.into();
+        if let Some(transport) = transport {
+            uri.params.push(rsip::Param::Transport(transport));
+        }
+        uri.params.push(rsip::Param::Other("lr".into(), None));
+        if with_ftag {
+            let tag = request
+                .from_header()?
+                .tag()?
+                .ok_or(Error::EndpointError("from without tag".to_string()))?;
+            uri.params
+                .push(rsip::Param::Other("ftag".into(), Some(tag.value().into())));
+        }
+        Ok(rsip::Header::RecordRoute(format!("<{}>", uri).into()))
+    }
+
+    /// Adds our Record-Route on top of a dialog-forming `request` about to be
+    /// forwarded, an INVITE, SUBSCRIBE or REFER outside of a dialog, so the
+    /// in-dialog requests go through this element. Returns whether it was
+    /// added.
+    pub fn insert_record_route(
+        &self,
+        request: &mut rsip::Request,
+        with_ftag: bool,
+    ) -> Result<bool> {
+        if !is_dialog_forming(request) {
+            return Ok(false);
+        }
+        let record_route = self.make_record_route(request, with_ftag)?;
+        request.headers.push_front(record_route);
+        Ok(true)
+    }
+
+    /// Consumes the topmost Route of a request to forward when it's ours,
+    /// the Record-Route of an in-dialog request or a preloaded route to this
+    /// element (RFC 3261 16.4), see `rsip_ext::pop_local_route`
+    pub fn consume_route(&self, request: &mut rsip::Request) -> Option<rsip::Uri> {
+        let local = self
+            .get_addrs()
+            .into_iter()
+            .map(|addr| addr.addr)
+            .collect::<Vec<_>>();
+        pop_local_route(request, &local)
+    }
+
+    /// Record-Route carrying the flow token of the connection `tx` was
+    /// received on, so in-dialog requests can be routed back over it
+    pub fn get_flow_record_route(&self, tx: &Transaction) -> Result<rsip::typed::RecordRoute> {
+        let connection = tx.connection.clone().ok_or(Error::EndpointError(
+            "transaction without connection".to_string(),
+        ))?;
+        let remote = flow_remote(&connection, &tx.original)?;
+        let token = self.transport_layer.register_flow(connection, remote);
+
+        let first_addr = self
+            .transport_layer
+            .get_addrs()
+            .first()
+            .ok_or(Error::EndpointError("not sipaddrs".to_string()))
+            .cloned()?;
+        let mut uri: rsip::Uri = first_addr.into();
+        uri.auth = Some(rsip::Auth {
+            user: token,
+            password: None,
+        });
+        let rr = rsip::UriWithParamsList(vec![rsip::UriWithParams {
+            uri,
+            params: vec![
+                rsip::Param::Other("lr".into(), None),
+                rsip::Param::Other("ob".into(), None),
+            ]
+            .into(),
+        }]);
+        Ok(rr.into())
+    }
+
+    /// Path header (RFC 3327) of an edge proxy forwarding a REGISTER, with
+    /// the flow token of `tx` when given so requests for the UA come back
+    /// over the same flow (RFC 5626 5.1)
+    pub fn get_path(&self, tx: Option<&Transaction>) -> Result<rsip::Header> {
+        let rr: rsip::headers::RecordRoute = match tx {
+            Some(tx) => self.get_flow_record_route(tx)?,
+            None => self.get_record_route()?,
+        }
+        .into();
+        Ok(rsip::Header::Other("Path".into(), rr.value().to_string()))
+    }
+
+    /// Adds our Path on top of the REGISTER `request` forwarded to the
+    /// registrar. Returns false, leaving it untouched, when the UA did not
+    /// advertise `Supported: path`; the proxy should then answer 421.
+    pub fn insert_path(
+        &self,
+        request: &mut rsip::Request,
+        tx: Option<&Transaction>,
+    ) -> Result<bool> {
+        if !has_supported(&request.headers, "path") {
+            return Ok(false);
+        }
+        let path = self.get_path(tx)?;
+        let mut headers = request.headers.iter().cloned().collect::<Vec<_>>();
+        let position = headers
+            .iter()
+            .position(
+                |h| matches!(h, rsip::Header::Other(name, _) if name.eq_ignore_ascii_case("Path")),
+            )
+            .unwrap_or(headers.len());
+        headers.insert(position, path);
+        request.headers = headers.into();
+        Ok(true)
+    }
+
+    /// Returns true if the request already went through this element with
+    /// the same routing fields, i.e. it looped rather than spiraled (RFC 3261
+    /// 16.3 step 4). A forwarding element answers it with 482 Loop Detected.
+    pub fn is_looped(&self, req: &rsip::Request) -> bool {
+        let addrs = self.get_addrs();
+        let vias = req
+            .headers
+            .iter()
+            .filter_map(|h| match h {
+                rsip::Header::Via(via) => Some(via),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        vias.iter().enumerate().any(|(i, via)| {
+            let via = match via.typed() {
+                Ok(via) => via,
+                Err(_) => return false,
+            };
+            if !addrs.iter().any(|a| a.addr == via.uri.host_with_port) {
+                return false;
+            }
+            let branch = via.params.iter().find_map(|p| match p {
+                rsip::Param::Branch(branch) => Some(branch.to_string()),
+                _ => None,
+            });
+            // the Via below ours was the topmost one when it was forwarded
+            match branch.as_ref().and_then(|b| b.rsplit_once('.')) {
+                Some((_, hash)) => hash == loop_hash(req, vias.get(i + 1).copied()),
+                None => false,
+            }
+        })
+    }
+
     pub fn get_via(
         &self,
         addr: Option<crate::transport::SipAddr>,
@@ -309,6 +1102,13 @@ impl EndpointBuilder {
             transport_layer: None,
             cancel_token: None,
             timer_interval: None,
+            route_set: vec![],
+            option: EndpointOption::default(),
+            request_handler: None,
+            metrics: None,
+            interceptors: vec![],
+            identity: EndpointIdentity::default(),
+            options_responder: None,
         }
     }
 
@@ -332,6 +1132,65 @@ impl EndpointBuilder {
         self
     }
 
+    /// Overrides the transaction timers, e.g. a larger T1 on lossy links or
+    /// short timeouts in tests
+    pub fn option(&mut self, option: EndpointOption) -> &mut Self {
+        self.option = option;
+        self
+    }
+
+    /// Server, Allow, Supported, Accept, Contact parameters and Max-Forwards
+    /// applied to the messages built by the endpoint and its dialogs
+    pub fn identity(&mut self, identity: EndpointIdentity) -> &mut Self {
+        self.identity = identity;
+        self
+    }
+
+    /// Answers out-of-dialog OPTIONS with a 200 OK by itself, before the
+    /// `RequestHandler` sees them
+    pub fn options_responder(&mut self, responder: OptionsResponder) -> &mut Self {
+        self.options_responder.replace(responder);
+        self
+    }
+
+    /// Hands out-of-dialog non-INVITE requests to `handler` instead of
+    /// `Endpoint::incoming_transactions`
+    pub fn request_handler(&mut self, handler: RequestHandlerRef) -> &mut Self {
+        self.request_handler.replace(handler);
+        self
+    }
+
+    /// Dispatches out-of-dialog non-INVITE requests by method with `router`,
+    /// see `request_handler`
+    pub fn router(&mut self, router: RequestRouter) -> &mut Self {
+        self.request_handler(Arc::new(router))
+    }
+
+    /// Reports the counters of the transaction layer to `metrics`
+    pub fn metrics(&mut self, metrics: TransactionMetricsRef) -> &mut Self {
+        self.metrics.replace(metrics);
+        self
+    }
+
+    /// Adds `interceptor` to the chain run on every message the transaction
+    /// layer sends or receives
+    pub fn interceptor(&mut self, interceptor: MessageInterceptorRef) -> &mut Self {
+        self.interceptors.push(interceptor);
+        self
+    }
+
+    /// Sends out-of-dialog requests through `proxy`
+    pub fn outbound_proxy(&mut self, proxy: rsip::Uri) -> &mut Self {
+        self.route_set = vec![proxy];
+        self
+    }
+
+    /// Preloaded Route set of out-of-dialog requests (RFC 3261 8.1.2)
+    pub fn route_set(&mut self, route_set: Vec<rsip::Uri>) -> &mut Self {
+        self.route_set = route_set;
+        self
+    }
+
     pub fn build(&mut self) -> Endpoint {
         let cancel_token = self.cancel_token.take().unwrap_or_default();
 
@@ -345,6 +1204,13 @@ impl EndpointBuilder {
             transport_layer,
             cancel_token,
             self.timer_interval,
+            self.route_set.clone(),
+            self.option.clone(),
+            self.request_handler.clone(),
+            self.metrics.clone(),
+            self.interceptors.clone(),
+            self.identity.clone(),
+            self.options_responder.clone(),
         );
 
         Endpoint { inner: core }
@@ -0,0 +1,17 @@
+use crate::transport::{SipAddr, SipConnection};
+use rsip::SipMessage;
+use std::sync::Arc;
+
+/// Observes and rewrites the messages crossing the transaction layer, e.g. to
+/// add P-headers, strip headers or collect traces.
+///
+/// Interceptors run in the order they were added to the endpoint. Every
+/// method defaults to a no-op.
+pub trait MessageInterceptor: Send + Sync {
+    /// A request or response about to be sent, retransmissions included
+    fn on_outgoing(&self, _msg: &mut SipMessage, _destination: Option<&SipAddr>) {}
+    /// A message received from the transport, before it's matched to a
+    /// transaction
+    fn on_incoming(&self, _msg: &mut SipMessage, _connection: &SipConnection) {}
+}
+pub type MessageInterceptorRef = Arc<dyn MessageInterceptor>;
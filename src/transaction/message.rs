@@ -1,5 +1,12 @@
 use super::{endpoint::EndpointInner, make_call_id};
-use rsip::{Header, Request, Response, StatusCode};
+use crate::{
+    rsip_ext::{extract_uri_from_contact, make_route},
+    Result,
+};
+use rsip::{
+    prelude::{HeadersExt, ToTypedHeader},
+    Header, Request, Response, StatusCode,
+};
 
 impl EndpointInner {
     pub fn make_request(
@@ -11,19 +18,24 @@ impl EndpointInner {
         to: rsip::typed::To,
         seq: u32,
     ) -> rsip::Request {
-        let headers = vec![
+        let is_register = method == rsip::Method::Register;
+        let mut headers = vec![
             Header::Via(via.into()),
             Header::CallId(make_call_id(None)),
             Header::From(from.into()),
             Header::To(to.into()),
             Header::CSeq(rsip::typed::CSeq { seq, method }.into()),
-            Header::MaxForwards(70.into()),
-            Header::UserAgent(self.user_agent.clone().into()),
         ];
+        headers.extend(self.route_set.iter().map(make_route));
+        if !is_register {
+            headers.extend(self.service_route().iter().map(make_route));
+        }
+        let mut headers: rsip::Headers = headers.into();
+        self.apply_identity(&mut headers, false);
         rsip::Request {
             method,
             uri: req_uri,
-            headers: headers.into(),
+            headers,
             body: vec![],
             version: rsip::Version::V2,
         }
@@ -47,7 +59,7 @@ impl EndpointInner {
                     | Header::CSeq(_)
             )
         });
-        headers.unique_push(Header::UserAgent(self.user_agent.clone().into()));
+        self.apply_identity(&mut headers, true);
         Response {
             status_code,
             version: req.version().clone(),
@@ -55,4 +67,187 @@ impl EndpointInner {
             body: body.unwrap_or_default(),
         }
     }
+
+    /// Adds the User-Agent or Server, Max-Forwards of a request, and the
+    /// Allow, Supported and Accept headers of `EndpointIdentity` missing from
+    /// `headers`, and its parameters to every Contact
+    pub fn apply_identity(&self, headers: &mut rsip::Headers, is_response: bool) {
+        let identity = &self.identity;
+        match (is_response, identity.server.as_ref()) {
+            (true, Some(server)) => headers.unique_push(Header::Server(server.clone().into())),
+            _ => headers.unique_push(Header::UserAgent(self.user_agent.clone().into())),
+        }
+        if !is_response && !headers.iter().any(|h| matches!(h, Header::MaxForwards(_))) {
+            headers.push(Header::MaxForwards(identity.max_forwards.into()));
+        }
+        if !identity.allow.is_empty() && !headers.iter().any(|h| matches!(h, Header::Allow(_))) {
+            let allow = identity
+                .allow
+                .iter()
+                .map(|m| m.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            headers.push(Header::Allow(allow.into()));
+        }
+        if !identity.supported.is_empty()
+            && !headers.iter().any(|h| matches!(h, Header::Supported(_)))
+        {
+            headers.push(Header::Supported(identity.supported.join(", ").into()));
+        }
+        if !identity.accept.is_empty() && !headers.iter().any(|h| matches!(h, Header::Accept(_))) {
+            headers.push(Header::Accept(identity.accept.join(", ").into()));
+        }
+        if !identity.contact_params.is_empty() {
+            *headers = headers
+                .iter()
+                .map(|h| match h {
+                    Header::Contact(contact) => match contact.typed() {
+                        Ok(mut contact) => {
+                            for param in identity.contact_params.iter() {
+                                if !contact.params.contains(param) {
+                                    contact.params.push(param.clone());
+                                }
+                            }
+                            Header::Contact(contact.into())
+                        }
+                        Err(_) => h.clone(), // e.g. the `*` of a REGISTER
+                    },
+                    _ => h.clone(),
+                })
+                .collect::<Vec<_>>()
+                .into();
+        }
+    }
+
+    /// ACK for a 2xx of a losing fork (RFC 3261 13.2.2.4), built from the ACK
+    /// sent for the accepted answer: it targets the Contact of the fork, and
+    /// follows its Record-Route
+    pub fn make_fork_ack(&self, ack: &Request, resp: &Response) -> Result<Request> {
+        let uri = match resp.contact_header() {
+            Ok(contact) => extract_uri_from_contact(contact.value())?,
+            Err(_) => ack.uri.clone(),
+        };
+        let mut routes = resp
+            .headers
+            .iter()
+            .filter_map(|h| match h {
+                Header::RecordRoute(rr) => Some(rr.value().to_string()),
+                _ => None,
+            })
+            .flat_map(|v| {
+                v.split(',')
+                    .map(|r| r.trim().to_string())
+                    .filter(|r| !r.is_empty())
+                    .collect::<Vec<_>>()
+            })
+            .map(|r| Header::Route(r.into()))
+            .collect::<Vec<_>>();
+        routes.reverse();
+
+        let mut headers = ack.headers.clone();
+        headers.retain(|h| {
+            matches!(
+                h,
+                Header::Via(_)
+                    | Header::CallId(_)
+                    | Header::From(_)
+                    | Header::CSeq(_)
+                    | Header::MaxForwards(_)
+                    | Header::UserAgent(_)
+            )
+        });
+        headers.push(Header::To(resp.to_header()?.clone()));
+        headers.extend(routes);
+        headers.push(Header::ContentLength(0.into()));
+        Ok(Request {
+            method: rsip::Method::Ack,
+            uri,
+            headers,
+            body: vec![],
+            version: rsip::Version::V2,
+        })
+    }
+
+    /// CANCEL of a pending INVITE (RFC 3261 9.1), sharing its Request-URI,
+    /// topmost Via, Call-ID, From, To, Route set and CSeq number
+    pub fn make_cancel(&self, invite: &Request) -> Result<Request> {
+        let mut headers = invite.headers.clone();
+        headers.retain(|h| {
+            matches!(
+                h,
+                Header::CallId(_)
+                    | Header::From(_)
+                    | Header::To(_)
+                    | Header::Route(_)
+                    | Header::MaxForwards(_)
+            )
+        });
+        headers.push_front(Header::Via(invite.via_header()?.clone()));
+        headers.push(Header::CSeq(
+            rsip::typed::CSeq {
+                seq: invite.cseq_header()?.seq()?,
+                method: rsip::Method::Cancel,
+            }
+            .into(),
+        ));
+        headers.push(Header::UserAgent(self.user_agent.clone().into()));
+        headers.push(Header::ContentLength(0.into()));
+        Ok(Request {
+            method: rsip::Method::Cancel,
+            uri: invite.uri.clone(),
+            headers,
+            body: vec![],
+            version: rsip::Version::V2,
+        })
+    }
+
+    /// ACK of a non-2xx final response to `invite`, part of its client
+    /// transaction (RFC 3261 17.1.1.3): same Request-URI, topmost Via and
+    /// Route set as the INVITE, the To of the response
+    pub fn make_non_2xx_ack(&self, invite: &Request, resp: &Response) -> Result<Request> {
+        let mut headers = invite.headers.clone();
+        headers.retain(|h| {
+            matches!(
+                h,
+                Header::CallId(_) | Header::From(_) | Header::Route(_) | Header::MaxForwards(_)
+            )
+        });
+        headers.push_front(Header::Via(invite.via_header()?.clone()));
+        headers.push(Header::To(resp.to_header()?.clone()));
+        headers.push(Header::CSeq(
+            rsip::typed::CSeq {
+                seq: invite.cseq_header()?.seq()?,
+                method: rsip::Method::Ack,
+            }
+            .into(),
+        ));
+        headers.push(Header::UserAgent(self.user_agent.clone().into()));
+        headers.push(Header::ContentLength(0.into()));
+        Ok(Request {
+            method: rsip::Method::Ack,
+            uri: invite.uri.clone(),
+            headers,
+            body: vec![],
+            version: rsip::Version::V2,
+        })
+    }
+
+    /// BYE tearing down the dialog of a losing fork once `fork_ack` is sent
+    pub fn make_fork_bye(&self, fork_ack: &Request) -> Result<Request> {
+        let seq = fork_ack.cseq_header()?.seq()?;
+        let mut bye = fork_ack.clone();
+        bye.method = rsip::Method::Bye;
+        bye.headers
+            .retain(|h| !matches!(h, Header::Via(_) | Header::CSeq(_)));
+        bye.headers
+            .push(Header::Via(self.get_via(None, None)?.into()));
+        bye.headers.push(Header::CSeq(
+            rsip::typed::CSeq {
+                seq: seq + 1,
+                method: rsip::Method::Bye,
+            }
+            .into(),
+        ));
+        Ok(bye)
+    }
 }
@@ -0,0 +1,110 @@
+use super::{transaction::TransactionCompletion, TransactionType};
+use rsip::{Method, StatusCode};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// Counters of the transaction layer, set on the endpoint to export them to
+/// Prometheus, statsd or the like. Every method defaults to a no-op.
+pub trait TransactionMetrics: Send + Sync {
+    fn on_transaction_created(&self, _transaction_type: &TransactionType, _method: &Method) {}
+    /// Every completion event of a transaction, see `TransactionCompletion`
+    fn on_transaction_completion(
+        &self,
+        _transaction_type: &TransactionType,
+        _method: &Method,
+        _completion: &TransactionCompletion,
+    ) {
+    }
+    /// A request or response sent again, by a timer or to answer a
+    /// retransmission
+    fn on_retransmission_sent(&self, _method: &Method) {}
+    fn on_retransmission_received(&self, _method: &Method) {}
+    fn on_response_sent(&self, _method: &Method, _status: &StatusCode) {}
+    fn on_response_received(&self, _method: &Method, _status: &StatusCode) {}
+}
+pub type TransactionMetricsRef = Arc<dyn TransactionMetrics>;
+
+/// In-memory `TransactionMetrics`, for tests or to be scraped periodically
+#[derive(Default)]
+pub struct TransactionStats {
+    pub created: AtomicU64,
+    /// Transactions that reached the Terminated state, whatever the outcome
+    pub completed: AtomicU64,
+    pub timed_out: AtomicU64,
+    pub transport_errors: AtomicU64,
+    pub retransmissions_sent: AtomicU64,
+    pub retransmissions_received: AtomicU64,
+    responses_sent: Mutex<HashMap<(String, u16), u64>>,
+    responses_received: Mutex<HashMap<(String, u16), u64>>,
+}
+
+impl TransactionStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Responses sent per method and status code
+    pub fn responses_sent(&self) -> HashMap<(String, u16), u64> {
+        self.responses_sent.lock().unwrap().clone()
+    }
+
+    /// Responses received per method and status code
+    pub fn responses_received(&self) -> HashMap<(String, u16), u64> {
+        self.responses_received.lock().unwrap().clone()
+    }
+}
+
+fn count_response(
+    responses: &Mutex<HashMap<(String, u16), u64>>,
+    method: &Method,
+    status: &StatusCode,
+) {
+    *responses
+        .lock()
+        .unwrap()
+        .entry((method.to_string(), status.code()))
+        .or_default() += 1;
+}
+
+impl TransactionMetrics for TransactionStats {
+    fn on_transaction_created(&self, _transaction_type: &TransactionType, _method: &Method) {
+        self.created.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_transaction_completion(
+        &self,
+        _transaction_type: &TransactionType,
+        _method: &Method,
+        completion: &TransactionCompletion,
+    ) {
+        let counter = match completion {
+            TransactionCompletion::Completed => return,
+            TransactionCompletion::Terminated => &self.completed,
+            TransactionCompletion::TimedOut => &self.timed_out,
+            TransactionCompletion::TransportError(_) => &self.transport_errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_retransmission_sent(&self, _method: &Method) {
+        self.retransmissions_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_retransmission_received(&self, _method: &Method) {
+        self.retransmissions_received
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_response_sent(&self, method: &Method, status: &StatusCode) {
+        count_response(&self.responses_sent, method, status);
+    }
+
+    fn on_response_received(&self, method: &Method, status: &StatusCode) {
+        count_response(&self.responses_received, method, status);
+    }
+}
@@ -1,30 +1,96 @@
-use crate::transport::{SipAddr, SipConnection};
+use crate::transport::SipConnection;
 use key::TransactionKey;
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use transaction::Transaction;
 use uuid::Uuid;
 
 pub mod endpoint;
+pub mod interceptor;
 pub mod key;
 pub mod message;
+pub mod metrics;
+pub mod proxy;
+pub mod router;
+pub mod stateless;
+mod table;
 mod timer;
 pub mod transaction;
 pub use endpoint::Endpoint;
 pub use endpoint::EndpointBuilder;
+pub use endpoint::EndpointIdentity;
+pub use endpoint::EndpointLoad;
+pub use endpoint::EndpointOption;
+pub use endpoint::OptionsResponder;
+pub use endpoint::OverloadThresholds;
+pub use interceptor::{MessageInterceptor, MessageInterceptorRef};
+pub use metrics::{TransactionMetrics, TransactionMetricsRef, TransactionStats};
+pub use proxy::ProxyCore;
+pub use router::RequestRouter;
 #[cfg(test)]
 mod tests;
 
 pub const TO_TAG_LEN: usize = 8;
 pub const BRANCH_LEN: usize = 12;
 pub const CNONCE_LEN: usize = 8;
+/// Initial Max-Forwards of the requests an element generates (RFC 3261 8.1.1.6)
+pub const MAX_FORWARDS: u32 = 70;
+/// Branch prefix of the requests forwarded statelessly, telling their
+/// responses apart from those of a transaction that ended
+const STATELESS_BRANCH_PREFIX: &str = "z9hG4bK-sl-";
 
+/// An out-of-dialog non-INVITE request, e.g. OPTIONS, MESSAGE, REGISTER or
+/// NOTIFY, handed to the `RequestHandler` of the endpoint
 pub struct IncomingRequest {
-    pub request: rsip::Request,
-    pub connection: SipConnection,
-    pub from: SipAddr,
+    tx: Transaction,
 }
 
+impl IncomingRequest {
+    pub fn request(&self) -> &rsip::Request {
+        &self.tx.original
+    }
+
+    pub fn method(&self) -> &rsip::Method {
+        &self.tx.original.method
+    }
+
+    /// Connection the request was received on
+    pub fn connection(&self) -> Option<&SipConnection> {
+        self.tx.connection.as_ref()
+    }
+
+    /// Answers the request, a final status completes the transaction
+    pub async fn reply(
+        &mut self,
+        status: rsip::StatusCode,
+        headers: Option<Vec<rsip::Header>>,
+        body: Option<Vec<u8>>,
+    ) -> crate::Result<()> {
+        self.tx
+            .reply_with(status, headers.unwrap_or_default(), body)
+            .await
+    }
+
+    /// The server transaction, for answers `reply` can't express
+    pub fn into_transaction(self) -> Transaction {
+        self.tx
+    }
+}
+
+impl From<Transaction> for IncomingRequest {
+    fn from(tx: Transaction) -> Self {
+        IncomingRequest { tx }
+    }
+}
+
+/// Application hook for out-of-dialog non-INVITE requests, requests creating
+/// a dialog keep going to `Endpoint::incoming_transactions`
+#[async_trait::async_trait]
+pub trait RequestHandler: Send + Sync {
+    async fn on_request(&self, request: IncomingRequest) -> crate::Result<()>;
+}
+pub type RequestHandlerRef = Arc<dyn RequestHandler>;
+
 pub type TransactionReceiver = UnboundedReceiver<Transaction>;
 pub type TransactionSender = UnboundedSender<Transaction>;
 
@@ -48,11 +114,14 @@ pub enum TransactionType {
 pub enum TransactionTimer {
     TimerA(TransactionKey, Duration),
     TimerB(TransactionKey),
+    TimerC(TransactionKey),
     TimerD(TransactionKey),
     TimerE(TransactionKey),
     TimerF(TransactionKey),
     TimerK(TransactionKey),
     TimerG(TransactionKey, Duration),
+    /// Refresh of the provisional response of a ringing INVITE server
+    TimerProvisional(TransactionKey),
     TimerCleanup(TransactionKey),
 }
 
@@ -61,11 +130,13 @@ impl TransactionTimer {
         match self {
             TransactionTimer::TimerA(key, _) => key,
             TransactionTimer::TimerB(key) => key,
+            TransactionTimer::TimerC(key) => key,
             TransactionTimer::TimerD(key) => key,
             TransactionTimer::TimerE(key) => key,
             TransactionTimer::TimerF(key) => key,
             TransactionTimer::TimerG(key, _) => key,
             TransactionTimer::TimerK(key) => key,
+            TransactionTimer::TimerProvisional(key) => key,
             TransactionTimer::TimerCleanup(key) => key,
         }
     }
@@ -78,6 +149,7 @@ impl std::fmt::Display for TransactionTimer {
                 write!(f, "TimerA: {} {}", key, duration.as_millis())
             }
             TransactionTimer::TimerB(key) => write!(f, "TimerB: {}", key),
+            TransactionTimer::TimerC(key) => write!(f, "TimerC: {}", key),
             TransactionTimer::TimerD(key) => write!(f, "TimerD: {}", key),
             TransactionTimer::TimerE(key) => write!(f, "TimerE: {}", key),
             TransactionTimer::TimerF(key) => write!(f, "TimerF: {}", key),
@@ -85,6 +157,7 @@ impl std::fmt::Display for TransactionTimer {
                 write!(f, "TimerG: {} {}", key, duration.as_millis())
             }
             TransactionTimer::TimerK(key) => write!(f, "TimerK: {}", key),
+            TransactionTimer::TimerProvisional(key) => write!(f, "TimerProvisional: {}", key),
             TransactionTimer::TimerCleanup(key) => write!(f, "TimerCleanup: {}", key),
         }
     }
@@ -94,6 +167,98 @@ pub fn make_via_branch() -> rsip::Param {
     rsip::Param::Branch(format!("z9hG4bK{}", random_text(BRANCH_LEN)).into())
 }
 
+/// Branch of a request forwarded by a proxy or B2BUA, suffixed with the loop
+/// detection hash of the request it received (RFC 3261 16.6 step 8)
+pub fn make_loop_branch(received: &rsip::Request) -> rsip::Param {
+    let via = received.headers.iter().find_map(|h| match h {
+        rsip::Header::Via(via) => Some(via),
+        _ => None,
+    });
+    rsip::Param::Branch(
+        format!(
+            "z9hG4bK{}.{}",
+            random_text(BRANCH_LEN),
+            loop_hash(received, via)
+        )
+        .into(),
+    )
+}
+
+/// Hash of the fields routing a request, `via` being its topmost Via when it
+/// was received. The request-URI is the one before any translation.
+pub fn loop_hash(req: &rsip::Request, via: Option<&rsip::headers::Via>) -> String {
+    use rsip::prelude::{HeadersExt, UntypedHeader};
+    let to_tag = req.to_header().ok().and_then(|h| h.tag().ok().flatten());
+    let from_tag = req.from_header().ok().and_then(|h| h.tag().ok().flatten());
+    let mut fields = vec![
+        to_tag.map(|t| t.to_string()).unwrap_or_default(),
+        from_tag.map(|t| t.to_string()).unwrap_or_default(),
+        req.call_id_header()
+            .map(|h| h.value().to_string())
+            .unwrap_or_default(),
+        req.uri.to_string(),
+        via.map(|v| v.value().to_string()).unwrap_or_default(),
+        req.cseq_header()
+            .ok()
+            .and_then(|h| h.seq().ok())
+            .map(|seq| seq.to_string())
+            .unwrap_or_default(),
+    ];
+    fields.extend(req.headers.iter().filter_map(|h| match h {
+        rsip::Header::ProxyRequire(_) | rsip::Header::ProxyAuthorization(_) => Some(h.to_string()),
+        _ => None,
+    }));
+    stable_hash(&fields.join("\n"))
+}
+
+/// FNV-1a, stable across processes unlike the std hasher
+fn stable_hash(text: &str) -> String {
+    let hash = text.bytes().fold(0xcbf29ce484222325u64, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+/// Branch of a request forwarded statelessly (RFC 3261 16.11), derived from
+/// the topmost Via of the request received: its retransmissions, the CANCEL
+/// and the ACK of a non-2xx all get the same branch downstream
+pub fn make_stateless_branch(received: &rsip::Request) -> rsip::Param {
+    use rsip::prelude::{ToTypedHeader, UntypedHeader};
+    let via = received.headers.iter().find_map(|h| match h {
+        rsip::Header::Via(via) => Some(via),
+        _ => None,
+    });
+    let branch = via
+        .and_then(|via| via.typed().ok())
+        .and_then(|via| {
+            via.params.into_iter().find_map(|p| match p {
+                rsip::Param::Branch(branch) => Some(branch.to_string()),
+                _ => None,
+            })
+        })
+        .filter(|branch| branch.starts_with("z9hG4bK"));
+    let hash = match (branch, via) {
+        (Some(branch), Some(via)) => {
+            // the sent-by tells apart the same branch from two clients
+            let sent_by = via
+                .value()
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .to_string();
+            stable_hash(&format!("{}\n{}", branch, sent_by))
+        }
+        // RFC 2543 peers, the ACK of a non-2xx gets a branch of its own
+        _ => loop_hash(received, via),
+    };
+    rsip::Param::Branch(format!("{}{}", STATELESS_BRANCH_PREFIX, hash).into())
+}
+
+/// Whether `branch` was made by `make_stateless_branch`
+pub fn is_stateless_branch(branch: &str) -> bool {
+    branch.starts_with(STATELESS_BRANCH_PREFIX)
+}
+
 pub fn make_call_id(domain: Option<&str>) -> rsip::headers::CallId {
     format!("{}@{}", Uuid::new_v4(), domain.unwrap_or("restsend.com")).into()
 }
@@ -112,6 +277,17 @@ pub fn random_text(count: usize) -> String {
         .collect::<String>()
 }
 
+#[cfg(not(target_family = "wasm"))]
+pub fn random_between(min: u64, max: u64) -> u64 {
+    use rand::Rng;
+    rand::rng().random_range(min..=max)
+}
+
+#[cfg(target_family = "wasm")]
+pub fn random_between(min: u64, max: u64) -> u64 {
+    min + (js_sys::Math::random() * (max - min + 1) as f64) as u64
+}
+
 #[cfg(target_family = "wasm")]
 pub fn random_text(count: usize) -> String {
     (0..count)
@@ -0,0 +1,385 @@
+use super::{
+    endpoint::EndpointInnerRef,
+    key::{TransactionKey, TransactionRole},
+    make_loop_branch,
+    transaction::Transaction,
+    TransactionType,
+};
+use crate::{
+    dialog::digest::{DigestAuthenticator, DigestCredentials, DigestVerdict},
+    rsip_ext::pop_via,
+    Error, Result,
+};
+use rsip::{
+    prelude::UntypedHeader, Header, Method, Request, Response, SipMessage, StatusCode,
+    StatusCodeKind,
+};
+use std::sync::Arc;
+use tokio::{
+    select,
+    sync::mpsc::{unbounded_channel, UnboundedSender},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+/// Stateful proxy core (RFC 3261 16): forwards the request of a server
+/// transaction to one or more targets in parallel, each over a client
+/// transaction of its own, and answers the server transaction with the
+/// responses of the branches.
+///
+/// Provisional responses and every 2xx are relayed as they come, the first
+/// 2xx or a 6xx cancelling the pending branches. Once all branches
+/// completed without a 2xx, the best final response is forwarded: a 6xx,
+/// else the lowest class, a 503 becoming 500 and the challenges of every
+/// 401 and 407 merged (16.7).
+pub struct ProxyCore {
+    endpoint: EndpointInnerRef,
+    /// Insert a Record-Route in the dialog-forming requests, keeping the
+    /// proxy on the path of the in-dialog requests
+    pub record_route: bool,
+    /// Add the `ftag` parameter to the Record-Route
+    pub record_route_ftag: bool,
+    /// Requires the requests to carry a Proxy-Authorization of its realm,
+    /// they are answered 407 otherwise
+    pub authenticator: Option<Arc<DigestAuthenticator>>,
+}
+
+/// Removes the Via of the proxy from a response of a branch
+fn strip_via(mut resp: Response) -> Response {
+    pop_via(&mut resp.headers);
+    resp
+}
+
+/// Best of the final responses of the branches (16.7 step 6), with the
+/// authentication challenges of all of them when it's a 401 or 407
+fn best_response(responses: Vec<Response>) -> Option<Response> {
+    fn class(resp: &Response) -> u16 {
+        resp.status_code.code() / 100
+    }
+    let mut best = responses
+        .iter()
+        .find(|resp| class(resp) == 6)
+        .or_else(|| responses.iter().min_by_key(|resp| class(resp)))?
+        .clone();
+    if best.status_code == StatusCode::ServiceUnavailable {
+        best.status_code = StatusCode::ServerInternalError;
+    }
+    if matches!(
+        best.status_code,
+        StatusCode::Unauthorized | StatusCode::ProxyAuthenticationRequired
+    ) {
+        best.headers
+            .retain(|h| !matches!(h, Header::WwwAuthenticate(_) | Header::ProxyAuthenticate(_)));
+        for resp in responses.iter().filter(|resp| {
+            matches!(
+                resp.status_code,
+                StatusCode::Unauthorized | StatusCode::ProxyAuthenticationRequired
+            )
+        }) {
+            best.headers.extend(
+                resp.headers
+                    .iter()
+                    .filter(|h| {
+                        matches!(h, Header::WwwAuthenticate(_) | Header::ProxyAuthenticate(_))
+                    })
+                    .cloned()
+                    .collect::<Vec<_>>(),
+            );
+        }
+    }
+    Some(best)
+}
+
+/// Sends the request of a branch and hands its responses to the proxy core.
+/// When `cancel_token` is cancelled a pending INVITE is cancelled, as soon
+/// as a provisional response came (RFC 3261 9.1).
+async fn run_branch(
+    mut tx: Transaction,
+    cancel_token: CancellationToken,
+    sender: UnboundedSender<Response>,
+) {
+    if let Err(e) = tx.send().await {
+        // a transport failure is answered with a local 503 already
+        info!("failed to send branch {}: {:?}", tx.key, e);
+        let resp =
+            tx.endpoint_inner
+                .make_response(&tx.original, StatusCode::ServiceUnavailable, None);
+        sender.send(resp).ok();
+        return;
+    }
+    let is_invite = tx.transaction_type == TransactionType::ClientInvite;
+    let mut cancelled = false;
+    let mut cancel_sent = false;
+    let mut provisional = false;
+    loop {
+        let resp = select! {
+            msg = tx.receive() => match msg {
+                Some(SipMessage::Response(resp)) => Some(resp),
+                Some(_) => continue,
+                None => break,
+            },
+            _ = cancel_token.cancelled(), if !cancelled => {
+                cancelled = true;
+                None
+            }
+        };
+        if let Some(resp) = resp.as_ref() {
+            provisional |= resp.status_code.kind() == StatusCodeKind::Provisional;
+        }
+        if is_invite && cancelled && provisional && !cancel_sent {
+            cancel_sent = true;
+            if let Err(e) = tx.spawn_cancel() {
+                info!("failed to cancel branch {}: {:?}", tx.key, e);
+            }
+        }
+        let resp = match resp {
+            Some(resp) => resp,
+            None => continue,
+        };
+        match resp.status_code.kind() {
+            StatusCodeKind::Provisional => {
+                sender.send(resp).ok();
+            }
+            StatusCodeKind::Successful => {
+                // the ACK of a 2xx is end-to-end, forwarded like any request
+                sender.send(resp).ok();
+                break;
+            }
+            _ => {
+                if is_invite {
+                    let acked = match tx.endpoint_inner.make_non_2xx_ack(&tx.original, &resp) {
+                        Ok(ack) => tx.send_ack(ack).await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = acked {
+                        info!("failed to acknowledge branch {}: {:?}", tx.key, e);
+                    }
+                }
+                sender.send(resp).ok();
+                break;
+            }
+        }
+    }
+}
+
+impl ProxyCore {
+    pub fn new(endpoint: EndpointInnerRef) -> Self {
+        Self {
+            endpoint,
+            record_route: false,
+            record_route_ftag: false,
+            authenticator: None,
+        }
+    }
+
+    pub fn with_record_route(mut self, record_route: bool) -> Self {
+        self.record_route = record_route;
+        self
+    }
+
+    pub fn with_record_route_ftag(mut self, record_route_ftag: bool) -> Self {
+        self.record_route_ftag = record_route_ftag;
+        self
+    }
+
+    pub fn with_authenticator(mut self, authenticator: Arc<DigestAuthenticator>) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Request forwarded to `target`, with the Via of a new branch on top
+    fn make_branch(
+        &self,
+        received: &Request,
+        request: &Request,
+        target: rsip::Uri,
+    ) -> Result<Transaction> {
+        let mut request = request.clone();
+        request.uri = target;
+        let via = self
+            .endpoint
+            .get_via(None, Some(make_loop_branch(received)))?;
+        request.headers.push_front(Header::Via(via.into()));
+        let key = TransactionKey::from_request(&request, TransactionRole::Client)?;
+        let mut tx = Transaction::new_client(key, request, self.endpoint.clone(), None);
+        tx.enable_timer_c();
+        Ok(tx)
+    }
+
+    /// Forwards the request of `server_tx` to `targets` and answers it with
+    /// the outcome, returning the status of the final response it got.
+    ///
+    /// A request routed to us, e.g. an in-dialog request through our
+    /// Record-Route, goes on to its Request-URI when `targets` is empty.
+    /// A request that looped is answered 482, one out of Max-Forwards 483,
+    /// one without target 480 and one with a Proxy-Require we don't support
+    /// 420, one without credentials for the realm of `authenticator` is
+    /// challenged with a 407. The ACK of a non-2xx and the
+    /// retransmissions of the request are absorbed by `server_tx`, which
+    /// keeps being received from afterwards; ACK and CANCEL are never
+    /// forwarded statefully.
+    pub async fn forward(
+        &self,
+        server_tx: &mut Transaction,
+        mut targets: Vec<rsip::Uri>,
+    ) -> Result<StatusCode> {
+        if matches!(server_tx.original.method, Method::Ack | Method::Cancel) {
+            return Err(Error::TransactionError(
+                format!("{} is not forwarded statefully", server_tx.original.method),
+                server_tx.key.clone(),
+            ));
+        }
+        if self.endpoint.is_looped(&server_tx.original) {
+            info!("loop detected: {}", server_tx.key);
+            server_tx.reply(StatusCode::LoopDetected).await?;
+            return Ok(StatusCode::LoopDetected);
+        }
+        // the Require of the request is for the user agents, not for us
+        if server_tx.reply_unsupported("Proxy-Require").await? {
+            return Ok(StatusCode::from(420));
+        }
+        if let Some(authenticator) = self.authenticator.as_ref() {
+            match authenticator.check(&server_tx.original, true).await? {
+                DigestVerdict::Authorized(username) => {
+                    info!("{} authenticated as {}", server_tx.key, username)
+                }
+                DigestVerdict::UriMismatch => {
+                    server_tx.reply(StatusCode::BadRequest).await?;
+                    return Ok(StatusCode::BadRequest);
+                }
+                verdict => {
+                    let stale = verdict == DigestVerdict::Stale;
+                    server_tx
+                        .reply_with(
+                            StatusCode::ProxyAuthenticationRequired,
+                            authenticator.challenge_headers(true, stale),
+                            None,
+                        )
+                        .await?;
+                    return Ok(StatusCode::ProxyAuthenticationRequired);
+                }
+            }
+        }
+        let mut request = match server_tx.make_forward_request().await? {
+            Some(request) => request,
+            None => return Ok(StatusCode::TooManyHops),
+        };
+        if let Some(authenticator) = self.authenticator.as_ref() {
+            // the credentials of our realm were consumed here
+            request.headers.retain(|h| match h {
+                Header::ProxyAuthorization(auth) => DigestCredentials::parse(auth.value())
+                    .map_or(true, |auth| auth.realm != authenticator.realm),
+                _ => true,
+            });
+        }
+        let restored = self.endpoint.restore_strict_route(&mut request);
+        let routed = self.endpoint.consume_route(&mut request).is_some();
+        if targets.is_empty() && (restored || routed) {
+            targets.push(request.uri.clone());
+        }
+        if targets.is_empty() {
+            server_tx.reply(StatusCode::TemporarilyUnavailable).await?;
+            return Ok(StatusCode::TemporarilyUnavailable);
+        }
+        if self.record_route {
+            self.endpoint
+                .insert_record_route(&mut request, self.record_route_ftag)?;
+        }
+        if request.method == Method::Invite {
+            server_tx.send_trying().await?;
+        }
+
+        let (sender, mut receiver) = unbounded_channel();
+        let cancel_token = CancellationToken::new();
+        let mut pending = 0;
+        for target in targets {
+            info!("forwarding {} to {}", server_tx.key, target);
+            let tx = self.make_branch(&server_tx.original, &request, target)?;
+            tokio::spawn(run_branch(tx, cancel_token.clone(), sender.clone()));
+            pending += 1;
+        }
+        drop(sender);
+
+        let mut answer: Option<StatusCode> = None;
+        let mut responses = vec![];
+        let mut upstream_gone = false;
+        while pending > 0 {
+            select! {
+                event = receiver.recv() => {
+                    let resp = match event {
+                        Some(resp) => resp,
+                        None => break,
+                    };
+                    let kind = resp.status_code.kind();
+                    if kind != StatusCodeKind::Provisional {
+                        pending -= 1;
+                    }
+                    match kind {
+                        StatusCodeKind::Provisional => {
+                            if resp.status_code == StatusCode::Trying || answer.is_some() {
+                                continue;
+                            }
+                            if let Err(e) = server_tx.respond(strip_via(resp)).await {
+                                info!("failed to relay provisional response: {:?}", e);
+                            }
+                        }
+                        StatusCodeKind::Successful => {
+                            cancel_token.cancel();
+                            let resp = strip_via(resp);
+                            match answer {
+                                None => {
+                                    answer = Some(resp.status_code.clone());
+                                    server_tx.respond(resp).await?;
+                                }
+                                // every 2xx of a forked INVITE reaches the caller
+                                Some(_) if server_tx.original.method == Method::Invite => {
+                                    if let Some(connection) = server_tx.connection.as_ref() {
+                                        self.endpoint
+                                            .send_message(
+                                                connection,
+                                                resp.into(),
+                                                server_tx.destination.as_ref(),
+                                            )
+                                            .await
+                                            .ok();
+                                    }
+                                }
+                                Some(_) => {}
+                            }
+                        }
+                        _ => {
+                            if kind == StatusCodeKind::GlobalFailure {
+                                cancel_token.cancel();
+                            }
+                            responses.push(resp);
+                        }
+                    }
+                }
+                msg = server_tx.receive(), if !upstream_gone => {
+                    match msg {
+                        Some(SipMessage::Request(req)) if req.method == Method::Cancel => {
+                            info!("cancelling branches of {}", server_tx.key);
+                            cancel_token.cancel();
+                        }
+                        Some(_) => {}
+                        None => upstream_gone = true,
+                    }
+                }
+            }
+        }
+
+        if let Some(status) = answer {
+            return Ok(status);
+        }
+        let resp = match best_response(responses) {
+            Some(resp) => strip_via(resp),
+            None => {
+                server_tx.reply(StatusCode::RequestTimeout).await?;
+                return Ok(StatusCode::RequestTimeout);
+            }
+        };
+        let status = resp.status_code.clone();
+        server_tx.respond(resp).await?;
+        Ok(status)
+    }
+}
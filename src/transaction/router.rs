@@ -0,0 +1,186 @@
+use super::{IncomingRequest, RequestHandler, RequestHandlerRef};
+use crate::{rsip_ext::header_value, Result};
+use rsip::{Header, Method, StatusCode};
+use std::{future::Future, pin::Pin, sync::Arc};
+
+type BoxFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+struct FnHandler<F>(F);
+
+#[async_trait::async_trait]
+impl<F> RequestHandler for FnHandler<F>
+where
+    F: Fn(IncomingRequest) -> BoxFuture + Send + Sync,
+{
+    async fn on_request(&self, request: IncomingRequest) -> Result<()> {
+        (self.0)(request).await
+    }
+}
+
+/// Why no route matched a request
+enum Rejection {
+    Method,
+    Event,
+    MediaType,
+}
+
+struct Route {
+    method: Method,
+    event: Option<String>,
+    content_type: Option<String>,
+    handler: RequestHandlerRef,
+}
+
+/// `RequestHandler` dispatching out-of-dialog requests to the handler
+/// registered for their method, and optionally their event package or
+/// content type. Routes are tried in the order they were added.
+///
+/// A request of a method without route is answered 405 Method Not Allowed,
+/// the Allow header listing the routed methods; one matching a method but
+/// none of its events or content types gets 489 Bad Event or 415
+/// Unsupported Media Type.
+#[derive(Default)]
+pub struct RequestRouter {
+    routes: Vec<Route>,
+}
+
+fn first_token(value: &str) -> String {
+    value
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase()
+}
+
+impl RequestRouter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hands the requests of `method` to `handler`
+    pub fn route(mut self, method: Method, handler: RequestHandlerRef) -> Self {
+        self.routes.push(Route {
+            method,
+            event: None,
+            content_type: None,
+            handler,
+        });
+        self
+    }
+
+    /// Hands the requests of `method` for the event package `event`, e.g.
+    /// NOTIFY of `message-summary`, to `handler`
+    pub fn route_event(mut self, method: Method, event: &str, handler: RequestHandlerRef) -> Self {
+        self.routes.push(Route {
+            method,
+            event: Some(event.to_lowercase()),
+            content_type: None,
+            handler,
+        });
+        self
+    }
+
+    /// Hands the requests of `method` with a body of `content_type`, e.g.
+    /// MESSAGE of `text/plain`, to `handler`
+    pub fn route_content_type(
+        mut self,
+        method: Method,
+        content_type: &str,
+        handler: RequestHandlerRef,
+    ) -> Self {
+        self.routes.push(Route {
+            method,
+            event: None,
+            content_type: Some(content_type.to_lowercase()),
+            handler,
+        });
+        self
+    }
+
+    /// Hands the requests of `method` to the async closure `f`
+    pub fn on<F, Fut>(self, method: Method, f: F) -> Self
+    where
+        F: Fn(IncomingRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let handler = FnHandler(move |request| Box::pin(f(request)) as BoxFuture);
+        self.route(method, Arc::new(handler))
+    }
+
+    /// Routed methods, in the order they were first added
+    pub fn allow(&self) -> Vec<Method> {
+        let mut methods: Vec<Method> = vec![];
+        for route in self.routes.iter() {
+            if !methods.contains(&route.method) {
+                methods.push(route.method.clone());
+            }
+        }
+        methods
+    }
+
+    fn find(&self, request: &rsip::Request) -> std::result::Result<&RequestHandlerRef, Rejection> {
+        let event = header_value(&request.headers, "Event").map(|e| first_token(&e));
+        let content_type = header_value(&request.headers, "Content-Type").map(|c| first_token(&c));
+        let mut rejection = Rejection::Method;
+        for route in self.routes.iter().filter(|r| r.method == request.method) {
+            if route.event.is_some() && route.event != event {
+                rejection = Rejection::Event;
+                continue;
+            }
+            if route.content_type.is_some() && route.content_type != content_type {
+                rejection = Rejection::MediaType;
+                continue;
+            }
+            return Ok(&route.handler);
+        }
+        Err(rejection)
+    }
+
+    /// Answer to a request of `method` no route matched
+    fn reject(&self, rejection: Rejection, method: &Method) -> (StatusCode, Vec<Header>) {
+        let routes = self.routes.iter().filter(|r| &r.method == method);
+        match rejection {
+            Rejection::Method => {
+                let allow = self
+                    .allow()
+                    .iter()
+                    .map(|m| m.to_string())
+                    .collect::<Vec<_>>();
+                (
+                    StatusCode::MethodNotAllowed,
+                    vec![Header::Allow(allow.join(", ").into())],
+                )
+            }
+            Rejection::Event => {
+                let events = routes.filter_map(|r| r.event.clone()).collect::<Vec<_>>();
+                (
+                    StatusCode::from(489),
+                    vec![Header::Other("Allow-Events".into(), events.join(", "))],
+                )
+            }
+            Rejection::MediaType => {
+                let types = routes
+                    .filter_map(|r| r.content_type.clone())
+                    .collect::<Vec<_>>();
+                (
+                    StatusCode::UnsupportedMediaType,
+                    vec![Header::Accept(types.join(", ").into())],
+                )
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RequestHandler for RequestRouter {
+    async fn on_request(&self, mut request: IncomingRequest) -> Result<()> {
+        match self.find(request.request()) {
+            Ok(handler) => handler.on_request(request).await,
+            Err(rejection) => {
+                let (status, headers) = self.reject(rejection, request.method());
+                request.reply(status, Some(headers), None).await
+            }
+        }
+    }
+}
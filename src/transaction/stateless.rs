@@ -0,0 +1,114 @@
+use super::{endpoint::EndpointInner, is_stateless_branch, make_stateless_branch};
+use crate::{
+    rsip_ext::{decrement_max_forwards, next_hop, pop_via, set_via_transport},
+    transport::{SipAddr, SipConnection},
+    Error, Result,
+};
+use rsip::{
+    prelude::{HeadersExt, ToTypedHeader},
+    Header, Method, Request, Response, StatusCode,
+};
+use tracing::info;
+
+/// Where a response is sent without server transaction: the received and
+/// rport of its topmost Via, over the transport of that Via (RFC 3261
+/// 18.2.2)
+fn via_target(response: &Response) -> Result<rsip::Uri> {
+    let via = response.via_header()?;
+    let transport = via.typed()?.transport;
+    let host_with_port = SipConnection::parse_target_from_via(via)?;
+    Ok(rsip::Uri {
+        scheme: Some(rsip::Scheme::Sip),
+        host_with_port,
+        params: vec![rsip::Param::Transport(transport)],
+        ..Default::default()
+    })
+}
+
+impl EndpointInner {
+    /// Forwards `request` without creating a transaction (RFC 3261 16.11),
+    /// to `target` when given, which replaces its Request-URI. Max-Forwards
+    /// is decremented, a Route to us consumed and our Via with a stateless
+    /// branch pushed on top, the responses come back through
+    /// `forward_stateless_response`. See `insert_record_route` to stay on
+    /// the path of the dialog.
+    ///
+    /// A request out of Max-Forwards is answered 483 statelessly.
+    pub async fn forward_stateless(
+        &self,
+        mut request: Request,
+        target: Option<rsip::Uri>,
+    ) -> Result<()> {
+        if !decrement_max_forwards(&mut request) {
+            info!("max-forwards exhausted: {} {}", request.method, request.uri);
+            if request.method == Method::Ack {
+                return Ok(());
+            }
+            let response = self.make_response(&request, StatusCode::TooManyHops, None);
+            return self.reply_stateless(response).await;
+        }
+        let branch = make_stateless_branch(&request);
+        self.restore_strict_route(&mut request);
+        self.consume_route(&mut request);
+        if let Some(target) = target {
+            request.uri = target;
+        }
+        let via = self.get_via(None, Some(branch))?;
+        request.headers.push_front(Header::Via(via.into()));
+
+        let next = self.transport_layer.select_transport(&request);
+        let destination = next_hop(&request).and_then(|_| SipAddr::try_from(&next).ok());
+        let connection = self
+            .transport_layer
+            .lookup(&next, self.transport_tx.clone())
+            .await?;
+        if let Some(transport) = connection.get_addr().r#type.clone() {
+            let sent_by = self
+                .transport_layer
+                .listener_addr(&transport)
+                .map(|addr| addr.addr);
+            set_via_transport(&mut request, transport, sent_by);
+        }
+        self.send_message(&connection, request.into(), destination.as_ref())
+            .await
+    }
+
+    /// Whether `response` answers a request we forwarded statelessly, its
+    /// topmost Via being ours with a stateless branch
+    pub fn is_stateless_response(&self, response: &Response) -> bool {
+        let via = match response.via_header().ok().and_then(|via| via.typed().ok()) {
+            Some(via) => via,
+            None => return false,
+        };
+        self.get_addrs()
+            .iter()
+            .any(|addr| addr.addr == via.uri.host_with_port)
+            && via.params.iter().any(|p| match p {
+                rsip::Param::Branch(branch) => is_stateless_branch(&branch.to_string()),
+                _ => false,
+            })
+    }
+
+    /// Sends a response to a request forwarded statelessly on to the
+    /// element of the next Via, once ours is removed
+    pub async fn forward_stateless_response(&self, mut response: Response) -> Result<()> {
+        pop_via(&mut response.headers);
+        if response.via_header().is_err() {
+            return Err(Error::EndpointError(
+                "no via left to forward the response".to_string(),
+            ));
+        }
+        self.reply_stateless(response).await
+    }
+
+    /// Sends `response` without a server transaction, to the element of its
+    /// topmost Via
+    pub async fn reply_stateless(&self, response: Response) -> Result<()> {
+        let target = via_target(&response)?;
+        let connection = self
+            .transport_layer
+            .lookup(&target, self.transport_tx.clone())
+            .await?;
+        self.send_message(&connection, response.into(), None).await
+    }
+}
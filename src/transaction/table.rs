@@ -0,0 +1,98 @@
+use super::key::TransactionKey;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::Mutex,
+};
+
+const DEFAULT_SHARDS: usize = 64;
+
+/// Map of transactions split in shards by key hash, so that lookups of
+/// concurrent transactions don't contend on a single lock
+pub struct TransactionTable<V> {
+    shards: Vec<Mutex<HashMap<TransactionKey, V>>>,
+}
+
+impl<V: Clone> TransactionTable<V> {
+    pub fn new() -> Self {
+        Self::with_shards(DEFAULT_SHARDS)
+    }
+
+    pub fn with_shards(count: usize) -> Self {
+        TransactionTable {
+            shards: (0..count.max(1))
+                .map(|_| Mutex::new(HashMap::new()))
+                .collect(),
+        }
+    }
+
+    fn shard(&self, key: &TransactionKey) -> &Mutex<HashMap<TransactionKey, V>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    pub fn get(&self, key: &TransactionKey) -> Option<V> {
+        self.shard(key).lock().unwrap().get(key).cloned()
+    }
+
+    pub fn contains_key(&self, key: &TransactionKey) -> bool {
+        self.shard(key).lock().unwrap().contains_key(key)
+    }
+
+    pub fn insert(&self, key: TransactionKey, value: V) -> Option<V> {
+        self.shard(&key).lock().unwrap().insert(key, value)
+    }
+
+    pub fn remove(&self, key: &TransactionKey) -> Option<V> {
+        self.shard(key).lock().unwrap().remove(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|s| s.lock().unwrap().is_empty())
+    }
+}
+
+impl<V: Clone> Default for TransactionTable<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn test_transaction_table() {
+    use super::key::TransactionRole;
+    let table = TransactionTable::with_shards(4);
+    let keys = (0..16)
+        .map(|i| {
+            let req = rsip::Request {
+                method: rsip::Method::Options,
+                uri: rsip::Uri::try_from("sip:bob@example.com").unwrap(),
+                headers: vec![
+                    rsip::Header::Via(
+                        format!("SIP/2.0/UDP 127.0.0.1:5060;branch=z9hG4bK{}", i).into(),
+                    ),
+                    rsip::Header::CSeq("1 OPTIONS".into()),
+                    rsip::Header::From("<sip:alice@example.com>;tag=alice".into()),
+                    rsip::Header::CallId("table-test".into()),
+                ]
+                .into(),
+                version: rsip::Version::V2,
+                body: vec![],
+            };
+            TransactionKey::from_request(&req, TransactionRole::Server).unwrap()
+        })
+        .collect::<Vec<_>>();
+    for (i, key) in keys.iter().enumerate() {
+        table.insert(key.clone(), i);
+    }
+    assert_eq!(table.len(), 16);
+    assert_eq!(table.get(&keys[3]), Some(3));
+    assert_eq!(table.remove(&keys[3]), Some(3));
+    assert!(!table.contains_key(&keys[3]));
+    assert_eq!(table.len(), 15);
+}
@@ -7,6 +7,7 @@ use tokio_util::sync::CancellationToken;
 
 mod test_client;
 mod test_endpoint;
+mod test_proxy;
 mod test_server;
 
 pub(super) async fn create_test_endpoint(addr: Option<&str>) -> Result<Endpoint> {
@@ -1,7 +1,8 @@
 use crate::transaction::key::{TransactionKey, TransactionRole};
-use crate::transaction::transaction::Transaction;
+use crate::transaction::transaction::{Transaction, TransactionCompletion};
 use crate::transport::udp::UdpConnection;
 use crate::{transport::TransportEvent, Result};
+use futures::StreamExt;
 use rsip::{headers::*, SipMessage};
 use std::time::Duration;
 use tokio::{select, sync::mpsc::unbounded_channel, time::sleep};
@@ -108,3 +109,331 @@ async fn test_client_transaction() -> Result<()> {
     }
     Ok(())
 }
+
+#[tokio::test(start_paused = true)]
+async fn test_client_transaction_timeout() -> Result<()> {
+    let endpoint = super::create_test_endpoint(Some("127.0.0.1:0")).await?;
+    // a peer that never answers
+    let peer_server = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let (sender, _receiver) = unbounded_channel();
+
+    let recv_loop = async {
+        let register_req = rsip::message::Request {
+            method: rsip::method::Method::Register,
+            uri: rsip::Uri {
+                scheme: Some(rsip::Scheme::Sip),
+                host_with_port: peer_server.get_addr().addr.clone(),
+                ..Default::default()
+            },
+            headers: vec![
+                Via::new("SIP/2.0/UDP restsend.com:5060;branch=z9hG4bKtimeout1").into(),
+                CSeq::new("1 REGISTER").into(),
+                From::new("Bob <sip:bob@restsend.com>;tag=ja743ks76zlflH").into(),
+                CallId::new("timeout-1@restsend.com").into(),
+            ]
+            .into(),
+            version: rsip::Version::V2,
+            body: Default::default(),
+        };
+        let key = TransactionKey::from_request(&register_req, TransactionRole::Client)
+            .expect("client_transaction");
+        let mut tx = Transaction::new_client(key, register_req, endpoint.inner.clone(), None);
+        let completion = tx.completed();
+        tx.send().await.expect("send request");
+        (tx.receive().await, completion.await)
+    };
+
+    // Timer F fires after 64*T1 of virtual time
+    select! {
+        (resp, completion) = recv_loop => {
+            match resp {
+                Some(SipMessage::Response(resp)) => {
+                    assert_eq!(resp.status_code, rsip::StatusCode::RequestTimeout)
+                }
+                _ => assert!(false, "expected a timeout response"),
+            }
+            assert_eq!(completion, TransactionCompletion::TimedOut);
+        },
+        _ = peer_server.serve_loop(sender) => {
+            assert!(false, "must not reach here");
+        }
+        _ = endpoint.serve() => {
+            assert!(false, "must not reach here");
+        }
+        _ = sleep(Duration::from_secs(40)) => {
+            assert!(false, "timeout waiting");
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_invite_timer_c() -> Result<()> {
+    let token = tokio_util::sync::CancellationToken::new();
+    let tl = crate::transport::TransportLayer::new(token.child_token());
+    let local = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    tl.add_transport(local.into());
+    let endpoint = crate::EndpointBuilder::new()
+        .transport_layer(tl)
+        .option(crate::transaction::EndpointOption {
+            timer_c: Duration::from_millis(200),
+            transaction_timeout: Some(Duration::from_millis(300)),
+            ..Default::default()
+        })
+        .build();
+
+    // a callee that rings forever and ignores the CANCEL
+    let peer_server = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let cancelled = std::sync::atomic::AtomicBool::new(false);
+    let peer_server_loop = async {
+        let (sender, mut receiver) = unbounded_channel();
+        select! {
+            _ = async {
+                while let Some(TransportEvent::Incoming(msg, connection, _)) = receiver.recv().await {
+                    if let SipMessage::Request(req) = msg {
+                        if req.method == rsip::Method::Invite {
+                            let ringing = SipMessage::Response(rsip::message::Response {
+                                version: rsip::Version::V2,
+                                status_code: rsip::StatusCode::Ringing,
+                                headers: req.headers.clone(),
+                                body: Default::default(),
+                            });
+                            connection.send(ringing, None).await.expect("send ringing");
+                        } else if req.method == rsip::Method::Cancel {
+                            cancelled.store(true, std::sync::atomic::Ordering::Relaxed);
+                        }
+                    }
+                }
+            } => {}
+            _ = peer_server.serve_loop(sender) => {}
+        }
+    };
+
+    let invite_req = rsip::message::Request {
+        method: rsip::method::Method::Invite,
+        uri: rsip::Uri {
+            scheme: Some(rsip::Scheme::Sip),
+            host_with_port: peer_server.get_addr().addr.clone(),
+            ..Default::default()
+        },
+        headers: vec![
+            Via::new("SIP/2.0/UDP restsend.com:5060;branch=z9hG4bKtimerc1").into(),
+            CSeq::new("1 INVITE").into(),
+            From::new("Bob <sip:bob@restsend.com>;tag=ja743ks76zlflH").into(),
+            To::new("Alice <sip:alice@restsend.com>").into(),
+            CallId::new("timer-c@restsend.com").into(),
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body: Default::default(),
+    };
+    let key = TransactionKey::from_request(&invite_req, TransactionRole::Client)?;
+    let mut tx = Transaction::new_client(key, invite_req, endpoint.inner.clone(), None);
+    tx.enable_timer_c();
+    let completion = tx.completed();
+    let recv_loop = async {
+        tx.send().await.expect("send request");
+        while let Some(msg) = tx.receive().await {
+            match msg {
+                SipMessage::Response(resp) if resp.status_code.code() >= 200 => {
+                    return Some(resp.status_code)
+                }
+                _ => {}
+            }
+        }
+        None
+    };
+
+    select! {
+        _ = peer_server_loop => {
+            assert!(false, "must not reach here");
+        }
+        status = recv_loop => {
+            // the branch ends with a 408 once the CANCEL went unanswered
+            assert_eq!(status, Some(rsip::StatusCode::RequestTimeout));
+        }
+        _ = endpoint.serve() => {
+            assert!(false, "must not reach here");
+        }
+        _ = sleep(Duration::from_secs(2)) => {
+            assert!(false, "timeout waiting");
+        }
+    }
+    assert!(cancelled.load(std::sync::atomic::Ordering::Relaxed));
+    assert_eq!(completion.await, TransactionCompletion::TimedOut);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_transport_failure() -> Result<()> {
+    let endpoint = super::create_test_endpoint(Some("127.0.0.1:0")).await?;
+    // a TCP port nobody listens on
+    let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let closed_addr = closed.local_addr()?;
+    drop(closed);
+
+    let register_req = rsip::message::Request {
+        method: rsip::method::Method::Register,
+        uri: rsip::Uri::try_from(format!("sip:{};transport=tcp", closed_addr).as_str())?,
+        headers: vec![
+            Via::new("SIP/2.0/TCP restsend.com:5060;branch=z9hG4bKfailure1").into(),
+            CSeq::new("1 REGISTER").into(),
+            From::new("Bob <sip:bob@restsend.com>;tag=ja743ks76zlflH").into(),
+            To::new("Bob <sip:bob@restsend.com>").into(),
+            CallId::new("failure@restsend.com").into(),
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body: Default::default(),
+    };
+    let key = TransactionKey::from_request(&register_req, TransactionRole::Client)?;
+    let mut tx = Transaction::new_client(key, register_req, endpoint.inner.clone(), None);
+    let mut completion = tx.completion_events();
+    tx.send().await.expect("send must not fail");
+
+    match tx.receive().await {
+        Some(SipMessage::Response(resp)) => {
+            assert_eq!(resp.status_code, rsip::StatusCode::ServiceUnavailable)
+        }
+        other => panic!("expected a local 503, got {:?}", other),
+    }
+    assert!(tx.receive().await.is_none());
+    assert!(tx.is_terminated());
+    assert!(tx.transport_failure().is_some());
+    assert!(matches!(
+        completion.recv().await,
+        Ok(TransactionCompletion::TransportError(_))
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_response_stream() -> Result<()> {
+    let endpoint = super::create_test_endpoint(Some("127.0.0.1:0")).await?;
+    let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let closed_addr = closed.local_addr()?;
+    drop(closed);
+
+    let register_req = rsip::message::Request {
+        method: rsip::method::Method::Register,
+        uri: rsip::Uri::try_from(format!("sip:{};transport=tcp", closed_addr).as_str())?,
+        headers: vec![
+            Via::new("SIP/2.0/TCP restsend.com:5060;branch=z9hG4bKstream1").into(),
+            CSeq::new("1 REGISTER").into(),
+            From::new("Bob <sip:bob@restsend.com>;tag=ja743ks76zlflH").into(),
+            To::new("Bob <sip:bob@restsend.com>").into(),
+            CallId::new("stream@restsend.com").into(),
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body: Default::default(),
+    };
+    let key = TransactionKey::from_request(&register_req, TransactionRole::Client)?;
+    let mut tx = Transaction::new_client(key, register_req, endpoint.inner.clone(), None);
+    tx.send().await.expect("send must not fail");
+
+    // the stream ends with the transaction, which stays usable afterwards
+    let responses = tx.responses().collect::<Vec<_>>().await;
+    assert_eq!(
+        responses
+            .iter()
+            .map(|r| r.status_code.clone())
+            .collect::<Vec<_>>(),
+        vec![rsip::StatusCode::ServiceUnavailable]
+    );
+    assert!(tx.is_terminated());
+    assert!(tx.transport_failure().is_some());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_retry_after_backoff() -> Result<()> {
+    let endpoint = super::create_test_endpoint(Some("127.0.0.1:0")).await?;
+    // an overloaded peer
+    let peer_server = UdpConnection::create_connection("127.0.0.1:0".parse()?, None).await?;
+    let peer_server_loop = async {
+        let (sender, mut receiver) = unbounded_channel();
+        select! {
+            _ = async {
+                while let Some(TransportEvent::Incoming(msg, connection, _)) = receiver.recv().await {
+                    if let SipMessage::Request(req) = msg {
+                        let mut headers = req.headers.clone();
+                        headers.push(rsip::Header::RetryAfter("30".into()));
+                        let response = SipMessage::Response(rsip::message::Response {
+                            version: rsip::Version::V2,
+                            status_code: rsip::StatusCode::ServiceUnavailable,
+                            headers,
+                            body: Default::default(),
+                        });
+                        connection.send(response, None).await.expect("send 503");
+                    }
+                }
+            } => {}
+            _ = peer_server.serve_loop(sender) => {}
+        }
+    };
+
+    let make_request = |branch: &str| rsip::message::Request {
+        method: rsip::method::Method::Options,
+        uri: rsip::Uri {
+            scheme: Some(rsip::Scheme::Sip),
+            host_with_port: peer_server.get_addr().addr.clone(),
+            ..Default::default()
+        },
+        headers: vec![
+            Via::new(format!("SIP/2.0/UDP restsend.com:5060;branch={}", branch)).into(),
+            CSeq::new("1 OPTIONS").into(),
+            From::new("Bob <sip:bob@restsend.com>;tag=ja743ks76zlflH").into(),
+            To::new("Alice <sip:alice@restsend.com>").into(),
+            CallId::new(format!("{}@restsend.com", branch)).into(),
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body: Default::default(),
+    };
+    let send_request = |branch: &str| {
+        let request = make_request(branch);
+        let key = TransactionKey::from_request(&request, TransactionRole::Client)
+            .expect("client_transaction");
+        let mut tx = Transaction::new_client(key, request, endpoint.inner.clone(), None);
+        async move {
+            tx.send().await.expect("send request");
+            let resp = match tx.receive().await {
+                Some(SipMessage::Response(resp)) => resp,
+                other => panic!("expected a response, got {:?}", other),
+            };
+            assert!(tx.transport_failure().is_none());
+            (resp, tx.target_unavailable().is_some())
+        }
+    };
+
+    let recv_loop = async {
+        let (resp, local) = send_request("z9hG4bKbackoff1").await;
+        assert_eq!(resp.status_code, rsip::StatusCode::ServiceUnavailable);
+        assert!(!local);
+
+        // skipped while it asked for a break
+        let (resp, local) = send_request("z9hG4bKbackoff2").await;
+        assert_eq!(resp.status_code, rsip::StatusCode::ServiceUnavailable);
+        assert!(local);
+        let seconds: u64 = crate::rsip_ext::header_value(&resp.headers, "Retry-After")
+            .expect("retry-after")
+            .parse()
+            .expect("seconds");
+        assert!(seconds > 0 && seconds <= 30);
+    };
+
+    select! {
+        _ = recv_loop => {}
+        _ = peer_server_loop => {
+            assert!(false, "must not reach here");
+        }
+        _ = endpoint.serve() => {
+            assert!(false, "must not reach here");
+        }
+        _ = sleep(Duration::from_secs(1)) => {
+            assert!(false, "timeout waiting");
+        }
+    }
+    Ok(())
+}
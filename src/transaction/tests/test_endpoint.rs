@@ -1,4 +1,5 @@
 use rsip::headers::*;
+use rsip::prelude::{HeadersExt, ToTypedHeader};
 use std::time::Duration;
 use tokio::{select, time::sleep};
 
@@ -86,3 +87,837 @@ async fn test_endpoint_recvrequests() {
         }
     }
 }
+
+#[tokio::test]
+async fn test_endpoint_prioritizes_bye_over_new_invite() {
+    let endpoint = super::create_test_endpoint(Some("127.0.0.1:0"))
+        .await
+        .expect("create_test_endpoint");
+    let peer = crate::transport::udp::UdpConnection::create_connection(
+        "127.0.0.1:0".parse().unwrap(),
+        None,
+    )
+    .await
+    .expect("create_connection");
+    let from = peer.get_addr().clone();
+
+    let make_request = |method: rsip::Method, to: &str, branch: &str| rsip::Request {
+        method,
+        uri: rsip::Uri::try_from("sip:bob@127.0.0.1:5060").expect("uri"),
+        headers: vec![
+            Via::new(format!("SIP/2.0/UDP 127.0.0.1:5060;branch={}", branch)).into(),
+            CSeq::new(format!("1 {}", method)).into(),
+            From::new("Alice <sip:alice@127.0.0.1>;tag=alice").into(),
+            To::new(to).into(),
+            CallId::new(format!("{}@127.0.0.1", branch)).into(),
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body: Default::default(),
+    };
+    let invite = make_request(
+        rsip::Method::Invite,
+        "Bob <sip:bob@127.0.0.1>",
+        "z9hG4bKinvite",
+    );
+    let bye = make_request(
+        rsip::Method::Bye,
+        "Bob <sip:bob@127.0.0.1>;tag=bob",
+        "z9hG4bKbye",
+    );
+
+    let mut incoming = endpoint.incoming_transactions();
+    // both are queued before the endpoint starts processing
+    for req in [invite, bye] {
+        endpoint
+            .inner
+            .transport_tx
+            .send(crate::transport::TransportEvent::Incoming(
+                req.into(),
+                peer.clone().into(),
+                from.clone(),
+            ))
+            .expect("send");
+    }
+
+    let incoming_loop = async {
+        let first = incoming.recv().await.expect("incoming").original.method;
+        let second = incoming.recv().await.expect("incoming").original.method;
+        (first, second)
+    };
+
+    select! {
+        _ = endpoint.serve() => {
+            assert!(false, "must not reach here");
+        }
+        (first, second) = incoming_loop => {
+            assert_eq!(first, rsip::Method::Bye);
+            assert_eq!(second, rsip::Method::Invite);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_forked_answer_ack_and_bye() {
+    let endpoint = super::create_test_endpoint(Some("127.0.0.1:0"))
+        .await
+        .expect("create_test_endpoint");
+
+    let ack = rsip::Request {
+        method: rsip::Method::Ack,
+        uri: rsip::Uri::try_from("sip:bob@192.168.1.2:5060").expect("uri"),
+        headers: vec![
+            Via::new("SIP/2.0/UDP 127.0.0.1:5060;branch=z9hG4bKfork").into(),
+            CSeq::new("1 ACK").into(),
+            From::new("Alice <sip:alice@127.0.0.1>;tag=alice").into(),
+            To::new("Bob <sip:bob@127.0.0.1>;tag=bob1").into(),
+            CallId::new("fork@127.0.0.1").into(),
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body: Default::default(),
+    };
+    let resp = rsip::Response {
+        status_code: rsip::StatusCode::OK,
+        headers: vec![
+            Via::new("SIP/2.0/UDP 127.0.0.1:5060;branch=z9hG4bKfork").into(),
+            CSeq::new("1 INVITE").into(),
+            From::new("Alice <sip:alice@127.0.0.1>;tag=alice").into(),
+            To::new("Bob <sip:bob@127.0.0.1>;tag=bob2").into(),
+            CallId::new("fork@127.0.0.1").into(),
+            Contact::new("<sip:bob@192.168.1.3:5060>").into(),
+            RecordRoute::new("<sip:p2.example.com;lr>, <sip:p1.example.com;lr>").into(),
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body: Default::default(),
+    };
+
+    let fork_ack = endpoint
+        .inner
+        .make_fork_ack(&ack, &resp)
+        .expect("make_fork_ack");
+    assert_eq!(fork_ack.uri.to_string(), "sip:bob@192.168.1.3:5060");
+    assert!(fork_ack.to_string().contains("tag=bob2"));
+    let routes = fork_ack
+        .headers
+        .iter()
+        .filter_map(|h| match h {
+            rsip::Header::Route(route) => Some(route.value().to_string()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        routes,
+        vec!["<sip:p1.example.com;lr>", "<sip:p2.example.com;lr>"]
+    );
+
+    let bye = endpoint
+        .inner
+        .make_fork_bye(&fork_ack)
+        .expect("make_fork_bye");
+    assert_eq!(bye.method, rsip::Method::Bye);
+    assert!(bye.to_string().contains("CSeq: 2 BYE"));
+    assert!(!bye.to_string().contains("branch=z9hG4bKfork"));
+    assert!(bye.to_string().contains("tag=bob2"));
+}
+
+#[test]
+fn test_endpoint_option_timers() {
+    let endpoint = crate::EndpointBuilder::new()
+        .option(crate::transaction::EndpointOption {
+            t1: Duration::from_millis(100),
+            timer_d: Some(Duration::ZERO),
+            ..Default::default()
+        })
+        .build();
+    assert_eq!(endpoint.inner.t1, Duration::from_millis(100));
+    assert_eq!(endpoint.inner.t2, Duration::from_secs(4));
+    // Timers B, F, H and J follow T1 unless set
+    assert_eq!(endpoint.inner.t1x64, Duration::from_millis(6400));
+    assert_eq!(endpoint.inner.timer_d, Duration::ZERO);
+}
+
+#[test]
+fn test_endpoint_identity() {
+    let endpoint = crate::EndpointBuilder::new()
+        .user_agent("rsipstack-test")
+        .identity(crate::transaction::EndpointIdentity {
+            server: Some("rsipstack-server".to_string()),
+            allow: vec![rsip::Method::Invite, rsip::Method::Ack, rsip::Method::Bye],
+            supported: vec!["timer".to_string()],
+            accept: vec!["application/sdp".to_string()],
+            contact_params: vec![rsip::Param::Other("+sip.instance".into(), None)],
+            max_forwards: 20,
+        })
+        .build();
+    let inner = endpoint.inner.clone();
+    let req = inner.make_request(
+        rsip::Method::Invite,
+        rsip::Uri::try_from("sip:bob@example.com").unwrap(),
+        Via::new("SIP/2.0/UDP 127.0.0.1:5060;branch=z9hG4bKidentity")
+            .typed()
+            .unwrap(),
+        From::new("<sip:alice@example.com>;tag=alice")
+            .typed()
+            .unwrap(),
+        To::new("<sip:bob@example.com>").typed().unwrap(),
+        1,
+    );
+    let text = req.to_string();
+    assert!(text.contains("User-Agent: rsipstack-test"));
+    assert!(text.contains("Max-Forwards: 20"));
+    assert!(text.contains("Allow: INVITE, ACK, BYE"));
+    assert!(text.contains("Supported: timer"));
+    assert!(text.contains("Accept: application/sdp"));
+
+    let resp = inner.make_response(&req, rsip::StatusCode::OK, None);
+    let text = resp.to_string();
+    assert!(text.contains("Server: rsipstack-server"));
+    assert!(!text.contains("User-Agent"));
+
+    let mut headers: rsip::Headers = vec![rsip::Header::Contact(Contact::new(
+        "<sip:alice@127.0.0.1:5060>",
+    ))]
+    .into();
+    inner.apply_identity(&mut headers, true);
+    assert!(headers
+        .iter()
+        .any(|h| matches!(h, rsip::Header::Contact(c) if c.value().contains("+sip.instance"))));
+}
+
+#[test]
+fn test_service_route() {
+    let endpoint = crate::EndpointBuilder::new()
+        .route_set(vec![
+            rsip::Uri::try_from("sip:proxy.example.com;lr").unwrap()
+        ])
+        .build();
+    let inner = endpoint.inner.clone();
+    inner.set_service_route(vec![
+        rsip::Uri::try_from("sip:orig@scscf.example.com;lr").unwrap()
+    ]);
+    let make = |method| {
+        inner.make_request(
+            method,
+            rsip::Uri::try_from("sip:bob@example.com").unwrap(),
+            Via::new("SIP/2.0/UDP 127.0.0.1:5060;branch=z9hG4bKservice")
+                .typed()
+                .unwrap(),
+            From::new("<sip:alice@example.com>;tag=alice")
+                .typed()
+                .unwrap(),
+            To::new("<sip:bob@example.com>").typed().unwrap(),
+            1,
+        )
+    };
+    let routes = |req: &rsip::Request| {
+        req.headers
+            .iter()
+            .filter_map(|h| match h {
+                rsip::Header::Route(route) => Some(route.value().to_string()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+    let invite = routes(&make(rsip::Method::Invite));
+    assert_eq!(invite.len(), 2);
+    assert!(invite[0].contains("proxy.example.com"));
+    assert!(invite[1].contains("scscf.example.com"));
+    // REGISTER is sent along the route set only
+    assert_eq!(routes(&make(rsip::Method::Register)).len(), 1);
+
+    inner.set_service_route(vec![]);
+    assert_eq!(routes(&make(rsip::Method::Invite)).len(), 1);
+}
+
+struct OptionsHandler {
+    sender: tokio::sync::mpsc::UnboundedSender<rsip::Method>,
+}
+
+#[async_trait::async_trait]
+impl crate::transaction::RequestHandler for OptionsHandler {
+    async fn on_request(
+        &self,
+        mut request: crate::transaction::IncomingRequest,
+    ) -> crate::Result<()> {
+        self.sender.send(request.method().clone()).ok();
+        request.reply(rsip::StatusCode::OK, None, None).await
+    }
+}
+
+#[tokio::test]
+async fn test_endpoint_request_handler() {
+    let token = tokio_util::sync::CancellationToken::new();
+    let tl = crate::transport::TransportLayer::new(token.child_token());
+    let conn = crate::transport::udp::UdpConnection::create_connection(
+        "127.0.0.1:0".parse().unwrap(),
+        None,
+    )
+    .await
+    .expect("create_connection");
+    tl.add_transport(conn.into());
+    let (sender, mut handled) = tokio::sync::mpsc::unbounded_channel();
+    let endpoint = crate::EndpointBuilder::new()
+        .transport_layer(tl)
+        .request_handler(std::sync::Arc::new(OptionsHandler { sender }))
+        .build();
+    let mut incoming = endpoint.incoming_transactions();
+
+    let peer = crate::transport::udp::UdpConnection::create_connection(
+        "127.0.0.1:0".parse().unwrap(),
+        None,
+    )
+    .await
+    .expect("create_connection");
+    let make_request = |method: rsip::Method, branch: &str| rsip::Request {
+        method,
+        uri: rsip::Uri::try_from("sip:bob@127.0.0.1:5060").expect("uri"),
+        headers: vec![
+            Via::new(format!("SIP/2.0/UDP 127.0.0.1:5060;branch={}", branch)).into(),
+            CSeq::new(format!("1 {}", method)).into(),
+            From::new("Alice <sip:alice@127.0.0.1>;tag=alice").into(),
+            To::new("Bob <sip:bob@127.0.0.1>").into(),
+            CallId::new(format!("{}@127.0.0.1", branch)).into(),
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body: Default::default(),
+    };
+    for req in [
+        make_request(rsip::Method::Options, "z9hG4bKoptions"),
+        make_request(rsip::Method::Invite, "z9hG4bKinvite"),
+    ] {
+        endpoint
+            .inner
+            .transport_tx
+            .send(crate::transport::TransportEvent::Incoming(
+                req.into(),
+                peer.clone().into(),
+                peer.get_addr().clone(),
+            ))
+            .expect("send");
+    }
+
+    select! {
+        _ = endpoint.serve() => {
+            assert!(false, "must not reach here");
+        }
+        (options, invite) = async {
+            (handled.recv().await, incoming.recv().await)
+        } => {
+            assert_eq!(options, Some(rsip::Method::Options));
+            // a dialog-creating request still goes to the dialog layer
+            assert_eq!(invite.expect("incoming").original.method, rsip::Method::Invite);
+        }
+        _ = sleep(Duration::from_secs(1)) => {
+            assert!(false, "timeout waiting");
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_loop_detection() {
+    let endpoint = super::create_test_endpoint(Some("127.0.0.1:0"))
+        .await
+        .expect("create_test_endpoint");
+    let received = rsip::Request {
+        method: rsip::Method::Invite,
+        uri: rsip::Uri::try_from("sip:bob@example.com").expect("uri"),
+        headers: vec![
+            Via::new("SIP/2.0/UDP 192.168.1.2:5060;branch=z9hG4bKupstream").into(),
+            CSeq::new("1 INVITE").into(),
+            From::new("Alice <sip:alice@example.com>;tag=alice").into(),
+            To::new("Bob <sip:bob@example.com>").into(),
+            CallId::new("loop@example.com").into(),
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body: Default::default(),
+    };
+    assert!(!endpoint.inner.is_looped(&received));
+
+    let via = endpoint
+        .inner
+        .get_via(None, Some(crate::transaction::make_loop_branch(&received)))
+        .expect("get_via");
+    let mut headers = vec![
+        Via::new("SIP/2.0/UDP 192.168.1.3:5060;branch=z9hG4bKnext").into(),
+        rsip::Header::Via(via.into()),
+    ];
+    headers.extend(received.headers.iter().cloned());
+    let mut returned = received.clone();
+    returned.headers = headers.into();
+    assert!(endpoint.inner.is_looped(&returned));
+
+    // retargeted by the next hop, the request is spiraling
+    returned.uri = rsip::Uri::try_from("sip:bob@192.168.1.4").expect("uri");
+    assert!(!endpoint.inner.is_looped(&returned));
+}
+
+#[tokio::test]
+async fn test_insert_path() {
+    let endpoint = super::create_test_endpoint(Some("127.0.0.1:0"))
+        .await
+        .expect("create_test_endpoint");
+    let mut request = rsip::Request {
+        method: rsip::Method::Register,
+        uri: rsip::Uri::try_from("sip:example.com").expect("uri"),
+        headers: vec![
+            Via::new("SIP/2.0/UDP 192.168.1.2:5060;branch=z9hG4bKregister").into(),
+            CSeq::new("1 REGISTER").into(),
+            From::new("<sip:alice@example.com>;tag=alice").into(),
+            To::new("<sip:alice@example.com>").into(),
+            CallId::new("path@example.com").into(),
+            rsip::Header::Other("Path".into(), "<sip:edge2.example.com;lr>".into()),
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body: Default::default(),
+    };
+    assert!(!endpoint
+        .inner
+        .insert_path(&mut request, None)
+        .expect("insert_path"));
+
+    request.headers.push(Supported::new("path").into());
+    assert!(endpoint
+        .inner
+        .insert_path(&mut request, None)
+        .expect("insert_path"));
+    let path = crate::rsip_ext::path_values(&request.headers);
+    assert_eq!(path.len(), 2);
+    let addr = endpoint.inner.get_addrs()[0].addr.to_string();
+    assert!(path[0].contains(&addr));
+    assert_eq!(path[1], "<sip:edge2.example.com;lr>");
+}
+
+#[tokio::test]
+async fn test_record_route() {
+    let endpoint = super::create_test_endpoint(Some("127.0.0.1:0"))
+        .await
+        .expect("create_test_endpoint");
+    let addr = endpoint.inner.get_addrs()[0].addr.to_string();
+    let mut invite = rsip::Request {
+        method: rsip::Method::Invite,
+        uri: rsip::Uri::try_from("sip:bob@example.com").expect("uri"),
+        headers: vec![
+            Via::new("SIP/2.0/UDP 192.168.1.2:5060;branch=z9hG4bKrecord").into(),
+            CSeq::new("1 INVITE").into(),
+            From::new("Alice <sip:alice@example.com>;tag=alice").into(),
+            To::new("Bob <sip:bob@example.com>").into(),
+            CallId::new("record@example.com").into(),
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body: Default::default(),
+    };
+    assert!(endpoint
+        .inner
+        .insert_record_route(&mut invite, true)
+        .expect("insert_record_route"));
+    let record_route = invite
+        .headers
+        .iter()
+        .find_map(|h| match h {
+            rsip::Header::RecordRoute(rr) => Some(rr.value().to_string()),
+            _ => None,
+        })
+        .expect("record-route");
+    assert!(record_route.contains(&addr));
+    assert!(record_route.contains(";lr"));
+    assert!(record_route.contains("ftag=alice"));
+    assert!(!record_route.contains("transport"));
+
+    // the BYE comes back through it
+    let mut bye = invite.clone();
+    bye.method = rsip::Method::Bye;
+    bye.headers
+        .retain(|h| !matches!(h, rsip::Header::RecordRoute(_)));
+    bye.headers
+        .unique_push(To::new("Bob <sip:bob@example.com>;tag=bob").into());
+    bye.headers.push(rsip::Header::Route(record_route.into()));
+    assert!(!endpoint
+        .inner
+        .insert_record_route(&mut bye, true)
+        .expect("insert_record_route"));
+    let route = endpoint.inner.consume_route(&mut bye).expect("our route");
+    assert_eq!(route.host_with_port.to_string(), addr);
+    assert!(!bye
+        .headers
+        .iter()
+        .any(|h| matches!(h, rsip::Header::Route(_))));
+    assert!(endpoint.inner.consume_route(&mut bye).is_none());
+}
+
+#[tokio::test]
+async fn test_endpoint_options_responder() {
+    let token = tokio_util::sync::CancellationToken::new();
+    let tl = crate::transport::TransportLayer::new(token.child_token());
+    let conn = crate::transport::udp::UdpConnection::create_connection(
+        "127.0.0.1:0".parse().unwrap(),
+        None,
+    )
+    .await
+    .expect("create_connection");
+    tl.add_transport(conn.into());
+    let endpoint = crate::EndpointBuilder::new()
+        .transport_layer(tl)
+        .identity(crate::transaction::EndpointIdentity {
+            allow: vec![rsip::Method::Invite, rsip::Method::Options],
+            ..Default::default()
+        })
+        .options_responder(crate::transaction::OptionsResponder {
+            sdp: Some("v=0\r\n".to_string()),
+        })
+        .build();
+
+    let peer = crate::transport::udp::UdpConnection::create_connection(
+        "127.0.0.1:0".parse().unwrap(),
+        None,
+    )
+    .await
+    .expect("create_connection");
+    let options = rsip::Request {
+        method: rsip::Method::Options,
+        uri: rsip::Uri::try_from("sip:bob@127.0.0.1:5060").expect("uri"),
+        headers: vec![
+            Via::new("SIP/2.0/UDP 127.0.0.1:5060;branch=z9hG4bKprobe").into(),
+            CSeq::new("1 OPTIONS").into(),
+            From::new("Provider <sip:provider@127.0.0.1>;tag=probe").into(),
+            To::new("Bob <sip:bob@127.0.0.1>").into(),
+            CallId::new("probe@127.0.0.1").into(),
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body: Default::default(),
+    };
+    endpoint
+        .inner
+        .transport_tx
+        .send(crate::transport::TransportEvent::Incoming(
+            options.into(),
+            peer.clone().into(),
+            peer.get_addr().clone(),
+        ))
+        .expect("send");
+
+    let (sender, mut received) = tokio::sync::mpsc::unbounded_channel();
+    select! {
+        _ = endpoint.serve() => {
+            assert!(false, "must not reach here");
+        }
+        _ = peer.serve_loop(sender) => {
+            assert!(false, "must not reach here");
+        }
+        event = received.recv() => {
+            let resp = match event {
+                Some(crate::transport::TransportEvent::Incoming(
+                    rsip::SipMessage::Response(resp),
+                    _,
+                    _,
+                )) => resp,
+                _ => panic!("expected a response"),
+            };
+            assert_eq!(resp.status_code, rsip::StatusCode::OK);
+            let text = resp.to_string();
+            assert!(text.contains("Allow: INVITE, OPTIONS"));
+            assert!(text.contains("Content-Type: application/sdp"));
+            assert_eq!(resp.body, b"v=0\r\n".to_vec());
+        }
+        _ = sleep(Duration::from_secs(1)) => {
+            assert!(false, "timeout waiting");
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_endpoint_router() {
+    let token = tokio_util::sync::CancellationToken::new();
+    let tl = crate::transport::TransportLayer::new(token.child_token());
+    let conn = crate::transport::udp::UdpConnection::create_connection(
+        "127.0.0.1:0".parse().unwrap(),
+        None,
+    )
+    .await
+    .expect("create_connection");
+    tl.add_transport(conn.into());
+    let (sender, _handled) = tokio::sync::mpsc::unbounded_channel();
+    let router = crate::transaction::RequestRouter::new()
+        .on(rsip::Method::Message, |mut request| async move {
+            request.reply(rsip::StatusCode::Accepted, None, None).await
+        })
+        .route_event(
+            rsip::Method::Notify,
+            "message-summary",
+            std::sync::Arc::new(OptionsHandler { sender }),
+        );
+    let endpoint = crate::EndpointBuilder::new()
+        .transport_layer(tl)
+        .router(router)
+        .build();
+
+    let peer = crate::transport::udp::UdpConnection::create_connection(
+        "127.0.0.1:0".parse().unwrap(),
+        None,
+    )
+    .await
+    .expect("create_connection");
+    let make_request = |method: rsip::Method, branch: &str| rsip::Request {
+        method,
+        uri: rsip::Uri::try_from("sip:bob@127.0.0.1:5060").expect("uri"),
+        headers: vec![
+            Via::new(format!("SIP/2.0/UDP 127.0.0.1:5060;branch={}", branch)).into(),
+            CSeq::new(format!("1 {}", method)).into(),
+            From::new("Alice <sip:alice@127.0.0.1>;tag=alice").into(),
+            To::new("Bob <sip:bob@127.0.0.1>").into(),
+            CallId::new(format!("{}@127.0.0.1", branch)).into(),
+            rsip::Header::Other("Event".into(), "presence".into()),
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body: Default::default(),
+    };
+    for req in [
+        make_request(rsip::Method::Message, "z9hG4bKmessage"),
+        make_request(rsip::Method::Notify, "z9hG4bKnotify"),
+        make_request(rsip::Method::Info, "z9hG4bKinfo"),
+    ] {
+        endpoint
+            .inner
+            .transport_tx
+            .send(crate::transport::TransportEvent::Incoming(
+                req.into(),
+                peer.clone().into(),
+                peer.get_addr().clone(),
+            ))
+            .expect("send");
+    }
+
+    let (sender, mut received) = tokio::sync::mpsc::unbounded_channel();
+    let mut responses = vec![];
+    select! {
+        _ = endpoint.serve() => {
+            assert!(false, "must not reach here");
+        }
+        _ = peer.serve_loop(sender) => {
+            assert!(false, "must not reach here");
+        }
+        _ = async {
+            while responses.len() < 3 {
+                if let Some(crate::transport::TransportEvent::Incoming(
+                    rsip::SipMessage::Response(resp),
+                    _,
+                    _,
+                )) = received.recv().await
+                {
+                    let method = resp.cseq_header().unwrap().method().unwrap();
+                    responses.push((method, resp));
+                }
+            }
+        } => {}
+        _ = sleep(Duration::from_secs(1)) => {
+            assert!(false, "timeout waiting");
+        }
+    }
+    let response = |method: rsip::Method| {
+        responses
+            .iter()
+            .find(|(m, _)| *m == method)
+            .map(|(_, resp)| resp.clone())
+            .expect("response")
+    };
+    assert_eq!(
+        response(rsip::Method::Message).status_code,
+        rsip::StatusCode::Accepted
+    );
+    let notify = response(rsip::Method::Notify).to_string();
+    assert!(notify.contains("489"));
+    assert!(notify.contains("Allow-Events: message-summary"));
+    let info = response(rsip::Method::Info);
+    assert_eq!(info.status_code, rsip::StatusCode::MethodNotAllowed);
+    assert!(info.to_string().contains("Allow: MESSAGE, NOTIFY"));
+}
+
+#[tokio::test]
+async fn test_endpoint_bad_extension() {
+    let token = tokio_util::sync::CancellationToken::new();
+    let tl = crate::transport::TransportLayer::new(token.child_token());
+    let conn = crate::transport::udp::UdpConnection::create_connection(
+        "127.0.0.1:0".parse().unwrap(),
+        None,
+    )
+    .await
+    .expect("create_connection");
+    tl.add_transport(conn.into());
+    let endpoint = crate::EndpointBuilder::new()
+        .transport_layer(tl)
+        .identity(crate::transaction::EndpointIdentity {
+            supported: vec!["timer".to_string(), "100rel".to_string()],
+            ..Default::default()
+        })
+        .options_responder(crate::transaction::OptionsResponder::default())
+        .build();
+    let _incoming = endpoint.incoming_transactions();
+
+    let peer = crate::transport::udp::UdpConnection::create_connection(
+        "127.0.0.1:0".parse().unwrap(),
+        None,
+    )
+    .await
+    .expect("create_connection");
+    // answered by the endpoint itself, which checks the Require
+    let options = rsip::Request {
+        method: rsip::Method::Options,
+        uri: rsip::Uri::try_from("sip:bob@127.0.0.1:5060").expect("uri"),
+        headers: vec![
+            Via::new("SIP/2.0/UDP 127.0.0.1:5060;branch=z9hG4bKrequire").into(),
+            CSeq::new("1 OPTIONS").into(),
+            From::new("Alice <sip:alice@127.0.0.1>;tag=alice").into(),
+            To::new("Bob <sip:bob@127.0.0.1>").into(),
+            CallId::new("require@127.0.0.1").into(),
+            rsip::Header::Other("Require".into(), "100rel, timer, foo".into()),
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body: Default::default(),
+    };
+    endpoint
+        .inner
+        .transport_tx
+        .send(crate::transport::TransportEvent::Incoming(
+            options.into(),
+            peer.clone().into(),
+            peer.get_addr().clone(),
+        ))
+        .expect("send");
+
+    let (sender, mut received) = tokio::sync::mpsc::unbounded_channel();
+    select! {
+        _ = endpoint.serve() => {
+            assert!(false, "must not reach here");
+        }
+        _ = peer.serve_loop(sender) => {
+            assert!(false, "must not reach here");
+        }
+        event = received.recv() => {
+            let resp = match event {
+                Some(crate::transport::TransportEvent::Incoming(
+                    rsip::SipMessage::Response(resp),
+                    _,
+                    _,
+                )) => resp,
+                _ => panic!("expected a response"),
+            };
+            assert_eq!(resp.status_code, rsip::StatusCode::from(420));
+            assert!(resp.to_string().contains("Unsupported: foo\r\n"));
+        }
+        _ = sleep(Duration::from_secs(1)) => {
+            assert!(false, "timeout waiting");
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_endpoint_overload() {
+    let token = tokio_util::sync::CancellationToken::new();
+    let tl = crate::transport::TransportLayer::new(token.child_token());
+    let conn = crate::transport::udp::UdpConnection::create_connection(
+        "127.0.0.1:0".parse().unwrap(),
+        None,
+    )
+    .await
+    .expect("create_connection");
+    tl.add_transport(conn.into());
+    let endpoint = crate::EndpointBuilder::new()
+        .transport_layer(tl)
+        .option(crate::transaction::EndpointOption {
+            overload: crate::transaction::OverloadThresholds {
+                max_transactions: Some(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .build();
+    assert_eq!(endpoint.inner.overload_retry_after(), None);
+
+    let make_request = |branch: &str| rsip::Request {
+        method: rsip::Method::Message,
+        uri: rsip::Uri::try_from("sip:bob@127.0.0.1:5060").expect("uri"),
+        headers: vec![
+            Via::new(format!("SIP/2.0/UDP 127.0.0.1:5060;branch={}", branch)).into(),
+            CSeq::new("1 MESSAGE").into(),
+            From::new("Alice <sip:alice@127.0.0.1>;tag=alice").into(),
+            To::new("Bob <sip:bob@127.0.0.1>").into(),
+            CallId::new(format!("{}@127.0.0.1", branch)).into(),
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body: Default::default(),
+    };
+    // a pending transaction reaches the threshold
+    let pending = make_request("z9hG4bKpending");
+    let key = crate::transaction::key::TransactionKey::from_request(
+        &pending,
+        crate::transaction::key::TransactionRole::Client,
+    )
+    .expect("key");
+    let _tx = crate::transaction::transaction::Transaction::new_client(
+        key,
+        pending,
+        endpoint.inner.clone(),
+        None,
+    );
+    assert_eq!(endpoint.inner.load().transactions, 1);
+    assert_eq!(
+        endpoint.inner.overload_retry_after(),
+        Some(Duration::from_secs(5))
+    );
+
+    let peer = crate::transport::udp::UdpConnection::create_connection(
+        "127.0.0.1:0".parse().unwrap(),
+        None,
+    )
+    .await
+    .expect("create_connection");
+    endpoint
+        .inner
+        .transport_tx
+        .send(crate::transport::TransportEvent::Incoming(
+            make_request("z9hG4bKoverload").into(),
+            peer.clone().into(),
+            peer.get_addr().clone(),
+        ))
+        .expect("send");
+
+    let (sender, mut received) = tokio::sync::mpsc::unbounded_channel();
+    select! {
+        _ = endpoint.serve() => {
+            assert!(false, "must not reach here");
+        }
+        _ = peer.serve_loop(sender) => {
+            assert!(false, "must not reach here");
+        }
+        resp = async {
+            loop {
+                if let Some(crate::transport::TransportEvent::Incoming(
+                    rsip::SipMessage::Response(resp),
+                    _,
+                    _,
+                )) = received.recv().await
+                {
+                    return resp;
+                }
+            }
+        } => {
+            assert_eq!(resp.status_code, rsip::StatusCode::ServiceUnavailable);
+            assert_eq!(
+                crate::rsip_ext::header_value(&resp.headers, "Retry-After"),
+                Some("5".to_string())
+            );
+        }
+        _ = sleep(Duration::from_secs(1)) => {
+            assert!(false, "timeout waiting");
+        }
+    }
+}
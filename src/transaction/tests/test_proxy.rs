@@ -0,0 +1,328 @@
+use crate::transaction::ProxyCore;
+use crate::transport::{udp::UdpConnection, TransportEvent};
+use rsip::headers::*;
+use rsip::prelude::HeadersExt;
+use rsip::{Request, SipMessage, StatusCode};
+use std::time::Duration;
+use tokio::{
+    select,
+    sync::mpsc::{unbounded_channel, UnboundedSender},
+    time::sleep,
+};
+
+/// A target answering every request it receives with `status`
+async fn answer_requests(
+    peer: UdpConnection,
+    status: StatusCode,
+    forwarded: UnboundedSender<Request>,
+) {
+    let (sender, mut received) = unbounded_channel();
+    select! {
+        _ = peer.serve_loop(sender) => {}
+        _ = async {
+            while let Some(event) = received.recv().await {
+                if let TransportEvent::Incoming(SipMessage::Request(req), connection, _) = event {
+                    let response = rsip::Response {
+                        version: rsip::Version::V2,
+                        status_code: status.clone(),
+                        headers: req.headers.clone(),
+                        body: Default::default(),
+                    };
+                    connection.send(response.into(), None).await.expect("send response");
+                    forwarded.send(req).ok();
+                }
+            }
+        } => {}
+    }
+}
+
+#[tokio::test]
+async fn test_proxy_best_response() {
+    let endpoint = super::create_test_endpoint(Some("127.0.0.1:0"))
+        .await
+        .expect("create_test_endpoint");
+    let proxy = ProxyCore::new(endpoint.inner.clone());
+    let mut incoming = endpoint.incoming_transactions();
+
+    let create_peer = || UdpConnection::create_connection("127.0.0.1:0".parse().unwrap(), None);
+    let uac = create_peer().await.expect("create_connection");
+    let busy = create_peer().await.expect("create_connection");
+    let unknown = create_peer().await.expect("create_connection");
+    let targets = [&busy, &unknown]
+        .iter()
+        .map(|peer| {
+            rsip::Uri::try_from(format!("sip:bob@{}", peer.get_addr().addr).as_str()).expect("uri")
+        })
+        .collect::<Vec<_>>();
+
+    let message = Request {
+        method: rsip::Method::Message,
+        uri: rsip::Uri::try_from("sip:bob@example.com").expect("uri"),
+        headers: vec![
+            Via::new(format!(
+                "SIP/2.0/UDP {};branch=z9hG4bKproxied",
+                uac.get_addr().addr
+            ))
+            .into(),
+            CSeq::new("1 MESSAGE").into(),
+            From::new("Alice <sip:alice@example.com>;tag=alice").into(),
+            To::new("Bob <sip:bob@example.com>").into(),
+            CallId::new("proxied@example.com").into(),
+            MaxForwards::new("10").into(),
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body: Default::default(),
+    };
+    endpoint
+        .inner
+        .transport_tx
+        .send(TransportEvent::Incoming(
+            message.into(),
+            uac.clone().into(),
+            uac.get_addr().clone(),
+        ))
+        .expect("send");
+
+    let (forwarded_sender, mut forwarded) = unbounded_channel();
+    let (sender, mut received) = unbounded_channel();
+    let response = select! {
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = uac.serve_loop(sender) => panic!("must not reach here"),
+        _ = answer_requests(busy, StatusCode::ServiceUnavailable, forwarded_sender.clone()) => {
+            panic!("must not reach here")
+        }
+        _ = answer_requests(unknown, StatusCode::NotFound, forwarded_sender) => {
+            panic!("must not reach here")
+        }
+        _ = async {
+            let mut tx = incoming.recv().await.expect("incoming");
+            let status = proxy.forward(&mut tx, targets).await.expect("forward");
+            assert_eq!(status, StatusCode::NotFound);
+            sleep(Duration::from_secs(1)).await;
+        } => panic!("no response relayed"),
+        response = async {
+            loop {
+                if let Some(TransportEvent::Incoming(SipMessage::Response(resp), _, _)) =
+                    received.recv().await
+                {
+                    return resp;
+                }
+            }
+        } => response,
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+
+    // the 503 loses to the lower class, without the Via of the proxy
+    assert_eq!(response.status_code, StatusCode::NotFound);
+    let vias = response
+        .headers
+        .iter()
+        .filter(|h| matches!(h, rsip::Header::Via(_)))
+        .count();
+    assert_eq!(vias, 1);
+    assert!(response
+        .via_header()
+        .expect("via")
+        .to_string()
+        .contains("z9hG4bKproxied"));
+
+    let proxy_addr = endpoint.get_addrs()[0].addr.to_string();
+    for _ in 0..2 {
+        let req = forwarded.try_recv().expect("forwarded request");
+        assert!(req.uri.to_string().starts_with("sip:bob@127.0.0.1"));
+        assert!(req
+            .via_header()
+            .expect("via")
+            .to_string()
+            .contains(&proxy_addr));
+        assert_eq!(crate::rsip_ext::max_forwards(&req), Some(9));
+    }
+}
+
+#[tokio::test]
+async fn test_stateless_forwarding() {
+    let endpoint = super::create_test_endpoint(Some("127.0.0.1:0"))
+        .await
+        .expect("create_test_endpoint");
+    let create_peer = || UdpConnection::create_connection("127.0.0.1:0".parse().unwrap(), None);
+    let uac = create_peer().await.expect("create_connection");
+    let uas = create_peer().await.expect("create_connection");
+    let target =
+        rsip::Uri::try_from(format!("sip:bob@{}", uas.get_addr().addr).as_str()).expect("uri");
+
+    let invite = Request {
+        method: rsip::Method::Invite,
+        uri: rsip::Uri::try_from("sip:bob@example.com").expect("uri"),
+        headers: vec![
+            Via::new(format!(
+                "SIP/2.0/UDP {};branch=z9hG4bKstateless",
+                uac.get_addr().addr
+            ))
+            .into(),
+            CSeq::new("1 INVITE").into(),
+            From::new("Alice <sip:alice@example.com>;tag=alice").into(),
+            To::new("Bob <sip:bob@example.com>").into(),
+            CallId::new("stateless@example.com").into(),
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body: Default::default(),
+    };
+    // retransmissions and the CANCEL share the branch downstream
+    let branch = crate::transaction::make_stateless_branch(&invite);
+    assert_eq!(branch, crate::transaction::make_stateless_branch(&invite));
+    let cancel = endpoint.inner.make_cancel(&invite).expect("make_cancel");
+    assert_eq!(branch, crate::transaction::make_stateless_branch(&cancel));
+
+    let (forwarded_sender, mut forwarded) = unbounded_channel();
+    let (sender, mut received) = unbounded_channel();
+    let response = select! {
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = uac.serve_loop(sender) => panic!("must not reach here"),
+        _ = answer_requests(uas, StatusCode::BusyHere, forwarded_sender) => {
+            panic!("must not reach here")
+        }
+        _ = async {
+            endpoint
+                .inner
+                .forward_stateless(invite.clone(), Some(target))
+                .await
+                .expect("forward_stateless");
+            sleep(Duration::from_secs(1)).await;
+        } => panic!("no response relayed"),
+        response = async {
+            loop {
+                if let Some(TransportEvent::Incoming(SipMessage::Response(resp), _, _)) =
+                    received.recv().await
+                {
+                    return resp;
+                }
+            }
+        } => response,
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+
+    let req = forwarded.try_recv().expect("forwarded request");
+    assert!(req
+        .via_header()
+        .expect("via")
+        .to_string()
+        .contains(&branch.to_string()));
+    assert_eq!(crate::rsip_ext::max_forwards(&req), Some(69));
+
+    // routed back through the Via of the proxy, which is removed
+    assert_eq!(response.status_code, StatusCode::BusyHere);
+    assert!(response
+        .via_header()
+        .expect("via")
+        .to_string()
+        .contains("z9hG4bKstateless"));
+    let vias = response
+        .headers
+        .iter()
+        .filter(|h| matches!(h, rsip::Header::Via(_)))
+        .count();
+    assert_eq!(vias, 1);
+}
+
+#[tokio::test]
+async fn test_proxy_require() {
+    let endpoint = super::create_test_endpoint(Some("127.0.0.1:0"))
+        .await
+        .expect("create_test_endpoint");
+    let proxy = ProxyCore::new(endpoint.inner.clone());
+    let mut incoming = endpoint.incoming_transactions();
+
+    let create_peer = || UdpConnection::create_connection("127.0.0.1:0".parse().unwrap(), None);
+    let uac = create_peer().await.expect("create_connection");
+    let uas = create_peer().await.expect("create_connection");
+    let target =
+        rsip::Uri::try_from(format!("sip:bob@{}", uas.get_addr().addr).as_str()).expect("uri");
+
+    let make_message = |branch: &str, require: rsip::Header| Request {
+        method: rsip::Method::Message,
+        uri: rsip::Uri::try_from("sip:bob@example.com").expect("uri"),
+        headers: vec![
+            Via::new(format!(
+                "SIP/2.0/UDP {};branch={}",
+                uac.get_addr().addr,
+                branch
+            ))
+            .into(),
+            CSeq::new("1 MESSAGE").into(),
+            From::new("Alice <sip:alice@example.com>;tag=alice").into(),
+            To::new("Bob <sip:bob@example.com>").into(),
+            CallId::new(format!("{}@example.com", branch)).into(),
+            require,
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body: Default::default(),
+    };
+    // the Require is for bob, only the Proxy-Require is for the proxy
+    let required = make_message(
+        "z9hG4bKrequire",
+        rsip::Header::Other("Require".into(), "foo".into()),
+    );
+    let proxy_required = make_message(
+        "z9hG4bKproxyrequire",
+        rsip::Header::Other("Proxy-Require".into(), "foo".into()),
+    );
+    for message in [required, proxy_required] {
+        endpoint
+            .inner
+            .transport_tx
+            .send(TransportEvent::Incoming(
+                message.into(),
+                uac.clone().into(),
+                uac.get_addr().clone(),
+            ))
+            .expect("send");
+    }
+
+    let (forwarded_sender, mut forwarded) = unbounded_channel();
+    let (sender, mut received) = unbounded_channel();
+    let responses = select! {
+        _ = endpoint.serve() => panic!("must not reach here"),
+        _ = uac.serve_loop(sender) => panic!("must not reach here"),
+        _ = answer_requests(uas, StatusCode::OK, forwarded_sender) => {
+            panic!("must not reach here")
+        }
+        _ = async {
+            let mut answered = vec![];
+            while let Some(mut tx) = incoming.recv().await {
+                proxy.forward(&mut tx, vec![target.clone()]).await.expect("forward");
+                answered.push(tx);
+            }
+        } => panic!("no response relayed"),
+        responses = async {
+            let mut responses = vec![];
+            while responses.len() < 2 {
+                if let Some(TransportEvent::Incoming(SipMessage::Response(resp), _, _)) =
+                    received.recv().await
+                {
+                    responses.push(resp);
+                }
+            }
+            responses
+        } => responses,
+        _ = sleep(Duration::from_secs(2)) => panic!("timeout waiting"),
+    };
+
+    let response = |branch: &str| {
+        responses
+            .iter()
+            .find(|resp| resp.to_string().contains(branch))
+            .expect("response")
+            .clone()
+    };
+    assert_eq!(response("z9hG4bKrequire").status_code, StatusCode::OK);
+    let rejected = response("z9hG4bKproxyrequire");
+    assert_eq!(rejected.status_code, StatusCode::from(420));
+    assert!(rejected.to_string().contains("Unsupported: foo\r\n"));
+
+    let req = forwarded.try_recv().expect("forwarded request");
+    assert!(req.to_string().contains("Require: foo"));
+    assert!(forwarded.try_recv().is_err());
+}
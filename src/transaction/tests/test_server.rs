@@ -126,8 +126,6 @@ async fn test_server_transaction() {
         assert!(tx
             .endpoint_inner
             .finished_transactions
-            .lock()
-            .unwrap()
             .contains_key(&tx.key));
         sleep(Duration::from_secs(2)).await;
     };
@@ -144,3 +142,299 @@ async fn test_server_transaction() {
         }
     }
 }
+
+#[tokio::test]
+async fn test_server_invite_retransmission() {
+    let token = CancellationToken::new();
+    let addr = SipAddr {
+        r#type: Some(rsip::transport::Transport::Udp),
+        addr: "127.0.0.1:2026".try_into().expect("parse addr"),
+    };
+    let (incoming_tx, incoming_rx) = unbounded_channel();
+    let (outgoing_tx, mut outgoing_rx) = unbounded_channel();
+
+    let mock_conn: SipConnection =
+        ChannelConnection::create_connection(incoming_rx, outgoing_tx, addr.clone())
+            .await
+            .expect("create_connection")
+            .into();
+
+    let tl = TransportLayer::new(token.child_token());
+    tl.add_transport(mock_conn.clone());
+
+    let stats = std::sync::Arc::new(crate::transaction::TransactionStats::new());
+    let endpoint = EndpointBuilder::new()
+        .user_agent("rsipstack-test")
+        .transport_layer(tl)
+        .metrics(stats.clone())
+        .build();
+
+    let invite_req: rsip::SipMessage = rsip::message::Request {
+        method: rsip::method::Method::Invite,
+        uri: rsip::Uri {
+            scheme: Some(rsip::Scheme::Sip),
+            host_with_port: rsip::HostWithPort::try_from("127.0.0.1:2026")
+                .expect("host_port parse")
+                .into(),
+            ..Default::default()
+        },
+        headers: vec![
+            Via::new("SIP/2.0/UDP restsend.com:5060;branch=z9hG4bKretrans1").into(),
+            CSeq::new("1 INVITE").into(),
+            From::new("Bob <sip:bob@restsend.com>;tag=ja743ks76zlflH").into(),
+            To::new("Alice <sip:alice@restsend.com>").into(),
+            CallId::new("retrans-invite@restsend.com").into(),
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body: Default::default(),
+    }
+    .into();
+
+    let send_loop = async {
+        incoming_tx
+            .send(TransportEvent::Incoming(
+                invite_req.clone(),
+                mock_conn.clone(),
+                addr.clone(),
+            ))
+            .expect("incoming_tx.send");
+        let must_busy = |event: TransportEvent| match event {
+            TransportEvent::Incoming(rsip::SipMessage::Response(resp), _, _) => {
+                assert_eq!(resp.status_code, rsip::StatusCode::BusyHere);
+            }
+            _ => assert!(false, "unexpected event"),
+        };
+        must_busy(outgoing_rx.recv().await.expect("outgoing_rx"));
+
+        // the transaction is gone, its final response answers the duplicate
+        sleep(Duration::from_millis(100)).await;
+        incoming_tx
+            .send(TransportEvent::Incoming(
+                invite_req.clone(),
+                mock_conn.clone(),
+                addr.clone(),
+            ))
+            .expect("incoming_tx.send");
+        must_busy(outgoing_rx.recv().await.expect("outgoing_rx"));
+        sleep(Duration::from_millis(200)).await;
+    };
+
+    let incoming_loop = async {
+        let mut incoming = endpoint.incoming_transactions();
+        let mut tx = incoming.recv().await.expect("incoming");
+        assert_eq!(tx.original.method, rsip::method::Method::Invite);
+        tx.reply(rsip::StatusCode::BusyHere).await.expect("reply");
+        drop(tx);
+        incoming.recv().await
+    };
+
+    select! {
+        _ = send_loop => {}
+        _ = endpoint.serve() => {}
+        tx = incoming_loop => {
+            assert!(tx.is_none(), "retransmission reached the dialog layer");
+        }
+        _ = sleep(Duration::from_secs(1)) => {
+            assert!(false, "timeout waiting");
+        }
+    }
+    let ordering = std::sync::atomic::Ordering::Relaxed;
+    assert_eq!(stats.created.load(ordering), 1);
+    assert_eq!(stats.retransmissions_received.load(ordering), 1);
+    assert_eq!(stats.retransmissions_sent.load(ordering), 1);
+    assert_eq!(
+        stats.responses_sent().get(&("INVITE".to_string(), 486)),
+        Some(&1)
+    );
+}
+
+#[tokio::test]
+async fn test_server_invite_provisional_refresh() {
+    let token = CancellationToken::new();
+    let addr = SipAddr {
+        r#type: Some(rsip::transport::Transport::Udp),
+        addr: "127.0.0.1:2027".try_into().expect("parse addr"),
+    };
+    let (incoming_tx, incoming_rx) = unbounded_channel();
+    let (outgoing_tx, mut outgoing_rx) = unbounded_channel();
+
+    let mock_conn: SipConnection =
+        ChannelConnection::create_connection(incoming_rx, outgoing_tx, addr.clone())
+            .await
+            .expect("create_connection")
+            .into();
+
+    let tl = TransportLayer::new(token.child_token());
+    tl.add_transport(mock_conn.clone());
+
+    let endpoint = EndpointBuilder::new()
+        .user_agent("rsipstack-test")
+        .transport_layer(tl)
+        .option(crate::transaction::EndpointOption {
+            provisional_interval: Some(Duration::from_millis(100)),
+            ..Default::default()
+        })
+        .build();
+
+    let invite_req = rsip::message::Request {
+        method: rsip::method::Method::Invite,
+        uri: rsip::Uri {
+            scheme: Some(rsip::Scheme::Sip),
+            host_with_port: rsip::HostWithPort::try_from("127.0.0.1:2027")
+                .expect("host_port parse")
+                .into(),
+            ..Default::default()
+        },
+        headers: vec![
+            Via::new("SIP/2.0/UDP restsend.com:5060;branch=z9hG4bKringing").into(),
+            CSeq::new("1 INVITE").into(),
+            From::new("Bob <sip:bob@restsend.com>;tag=ja743ks76zlflH").into(),
+            To::new("Alice <sip:alice@restsend.com>").into(),
+            CallId::new("ringing-invite@restsend.com").into(),
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body: Default::default(),
+    };
+
+    let send_loop = async {
+        incoming_tx
+            .send(TransportEvent::Incoming(
+                invite_req.into(),
+                mock_conn.clone(),
+                addr.clone(),
+            ))
+            .expect("incoming_tx.send");
+        // the 180 and two refreshes
+        for _ in 0..3 {
+            match outgoing_rx.recv().await.expect("outgoing_rx") {
+                TransportEvent::Incoming(rsip::SipMessage::Response(resp), _, _) => {
+                    assert_eq!(resp.status_code, rsip::StatusCode::Ringing);
+                }
+                _ => assert!(false, "unexpected event"),
+            }
+        }
+    };
+
+    let incoming_loop = async {
+        let mut incoming = endpoint.incoming_transactions();
+        let mut tx = incoming.recv().await.expect("incoming");
+        tx.reply(rsip::StatusCode::Ringing).await.expect("reply");
+        while tx.receive().await.is_some() {}
+    };
+
+    select! {
+        _ = send_loop => {}
+        _ = endpoint.serve() => {}
+        _ = incoming_loop => {
+            assert!(false, "must not reach here");
+        }
+        _ = sleep(Duration::from_secs(1)) => {
+            assert!(false, "timeout waiting");
+        }
+    }
+}
+
+struct TagInterceptor;
+
+impl crate::transaction::MessageInterceptor for TagInterceptor {
+    fn on_outgoing(&self, msg: &mut rsip::SipMessage, _destination: Option<&SipAddr>) {
+        if let rsip::SipMessage::Response(resp) = msg {
+            resp.headers
+                .push(rsip::Header::Other("P-Outgoing".into(), "1".into()));
+        }
+    }
+
+    fn on_incoming(&self, msg: &mut rsip::SipMessage, _connection: &SipConnection) {
+        if let rsip::SipMessage::Request(req) = msg {
+            req.headers
+                .push(rsip::Header::Other("P-Incoming".into(), "1".into()));
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_server_interceptor() {
+    let token = CancellationToken::new();
+    let addr = SipAddr {
+        r#type: Some(rsip::transport::Transport::Udp),
+        addr: "127.0.0.1:2028".try_into().expect("parse addr"),
+    };
+    let (incoming_tx, incoming_rx) = unbounded_channel();
+    let (outgoing_tx, mut outgoing_rx) = unbounded_channel();
+
+    let mock_conn: SipConnection =
+        ChannelConnection::create_connection(incoming_rx, outgoing_tx, addr.clone())
+            .await
+            .expect("create_connection")
+            .into();
+
+    let tl = TransportLayer::new(token.child_token());
+    tl.add_transport(mock_conn.clone());
+
+    let endpoint = EndpointBuilder::new()
+        .user_agent("rsipstack-test")
+        .transport_layer(tl)
+        .interceptor(std::sync::Arc::new(TagInterceptor))
+        .build();
+
+    let options_req = rsip::message::Request {
+        method: rsip::method::Method::Options,
+        uri: rsip::Uri {
+            scheme: Some(rsip::Scheme::Sip),
+            host_with_port: rsip::HostWithPort::try_from("127.0.0.1:2028")
+                .expect("host_port parse")
+                .into(),
+            ..Default::default()
+        },
+        headers: vec![
+            Via::new("SIP/2.0/UDP restsend.com:5060;branch=z9hG4bKintercept").into(),
+            CSeq::new("1 OPTIONS").into(),
+            From::new("Bob <sip:bob@restsend.com>;tag=ja743ks76zlflH").into(),
+            To::new("Alice <sip:alice@restsend.com>").into(),
+            CallId::new("intercept@restsend.com").into(),
+        ]
+        .into(),
+        version: rsip::Version::V2,
+        body: Default::default(),
+    };
+
+    let send_loop = async {
+        incoming_tx
+            .send(TransportEvent::Incoming(
+                options_req.into(),
+                mock_conn.clone(),
+                addr.clone(),
+            ))
+            .expect("incoming_tx.send");
+        match outgoing_rx.recv().await.expect("outgoing_rx") {
+            TransportEvent::Incoming(rsip::SipMessage::Response(resp), _, _) => {
+                assert_eq!(
+                    crate::rsip_ext::header_value(&resp.headers, "P-Outgoing"),
+                    Some("1".to_string())
+                );
+            }
+            _ => assert!(false, "unexpected event"),
+        }
+    };
+
+    let incoming_loop = async {
+        let mut incoming = endpoint.incoming_transactions();
+        let mut tx = incoming.recv().await.expect("incoming");
+        assert!(crate::rsip_ext::header_value(&tx.original.headers, "P-Incoming").is_some());
+        tx.reply(rsip::StatusCode::OK).await.expect("reply");
+        sleep(Duration::from_secs(1)).await;
+    };
+
+    select! {
+        _ = send_loop => {}
+        _ = endpoint.serve() => {}
+        _ = incoming_loop => {
+            assert!(false, "must not reach here");
+        }
+        _ = sleep(Duration::from_secs(1)) => {
+            assert!(false, "timeout waiting");
+        }
+    }
+}
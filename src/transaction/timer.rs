@@ -4,8 +4,10 @@ use std::{
         atomic::{AtomicU64, Ordering},
         RwLock,
     },
-    time::{Duration, Instant},
+    time::Duration,
 };
+// the clock of tokio, so timers follow `tokio::time::pause` in tests
+use tokio::time::Instant;
 
 #[derive(Debug, PartialOrd, PartialEq, Eq, Clone)]
 struct TimerKey {
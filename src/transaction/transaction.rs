@@ -1,13 +1,19 @@
 use super::endpoint::EndpointInnerRef;
-use super::key::TransactionKey;
+use super::key::{TransactionKey, TransactionRole};
 use super::{SipConnection, TransactionState, TransactionTimer, TransactionType};
+use crate::rsip_ext::{
+    decrement_max_forwards, max_forwards, next_hop, retry_after, set_via_transport,
+};
 use crate::transaction::make_tag;
 use crate::transport::SipAddr;
 use crate::{Error, Result};
+use futures::{Future, Stream};
 use rsip::headers::ContentLength;
 use rsip::message::HasHeaders;
 use rsip::prelude::HeadersExt;
 use rsip::{Header, Method, Request, Response, SipMessage, StatusCode};
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 use tracing::{debug, info, instrument};
 
@@ -20,6 +26,26 @@ pub enum TransactionEvent {
     Terminate,
 }
 
+/// Terminal events of a transaction, see `Transaction::completed`
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransactionCompletion {
+    /// The final response was sent or received
+    Completed,
+    Terminated,
+    /// Timer B, C, F or H fired before the transaction could complete
+    TimedOut,
+    /// The request couldn't be sent, the TU receives a local 503 instead
+    TransportError(String),
+}
+
+impl TransactionCompletion {
+    /// Returns true for the event ending a transaction, `Completed` may still
+    /// be followed by ACK or retransmissions
+    pub fn is_final(&self) -> bool {
+        !matches!(self, TransactionCompletion::Completed)
+    }
+}
+
 pub struct Transaction {
     pub transaction_type: TransactionType,
     pub key: TransactionKey,
@@ -34,9 +60,21 @@ pub struct Transaction {
     pub tu_sender: TransactionEventSender,
     pub timer_a: Option<u64>,
     pub timer_b: Option<u64>,
+    pub timer_c: Option<u64>, // proxied client invite only
     pub timer_d: Option<u64>,
-    pub timer_k: Option<u64>, // server invite only
-    pub timer_g: Option<u64>, // server invite only
+    pub timer_k: Option<u64>,           // server invite only
+    pub timer_g: Option<u64>,           // server invite only
+    pub timer_provisional: Option<u64>, // server invite only
+    use_timer_c: bool,
+    /// Timer C cancelled the branch, Timer B now waits for the 487
+    timer_c_fired: bool,
+    transport_failure: Option<String>,
+    /// Time left of the break the next hop asked for with 503 and
+    /// Retry-After, the request was answered locally without being sent
+    target_unavailable: Option<Duration>,
+    /// Resolved next hop of a client transaction, blacklisted when it fails
+    target: Option<SipAddr>,
+    completion_sender: broadcast::Sender<TransactionCompletion>,
     is_cleaned_up: bool,
 }
 
@@ -62,15 +100,26 @@ impl Transaction {
             last_ack: None,
             timer_a: None,
             timer_b: None,
+            timer_c: None,
             timer_d: None,
             timer_k: None,
             timer_g: None,
+            timer_provisional: None,
+            use_timer_c: false,
+            timer_c_fired: false,
+            transport_failure: None,
+            target_unavailable: None,
+            target: None,
             tu_receiver,
             tu_sender,
+            completion_sender: broadcast::channel(4).0,
             is_cleaned_up: false,
         };
         tx.endpoint_inner
             .attach_transaction(&tx.key, tx.tu_sender.clone());
+        if let Some(metrics) = tx.endpoint_inner.metrics.as_ref() {
+            metrics.on_transaction_created(&tx.transaction_type, &tx.original.method);
+        }
         tx
     }
 
@@ -99,6 +148,26 @@ impl Transaction {
         };
         Transaction::new(tx_type, key, original, connection, endpoint_inner)
     }
+    /// Runs Timer C on a client INVITE forwarded by a proxy (RFC 3261 16.6
+    /// step 11): the branch is cancelled when no final response arrives in
+    /// time, every provisional response restarts it
+    pub fn enable_timer_c(&mut self) {
+        if self.transaction_type == TransactionType::ClientInvite {
+            self.use_timer_c = true;
+        }
+    }
+
+    fn start_timer_c(&mut self) {
+        self.timer_c
+            .take()
+            .map(|id| self.endpoint_inner.timers.cancel(id));
+        let timer_c = self.endpoint_inner.timers.timeout(
+            self.endpoint_inner.timer_c,
+            TransactionTimer::TimerC(self.key.clone()),
+        );
+        self.timer_c.replace(timer_c);
+    }
+
     // send client request
     #[instrument(skip(self))]
     pub async fn send(&mut self) -> Result<()> {
@@ -112,13 +181,40 @@ impl Transaction {
             }
         }
 
+        if let Some(remaining) = self
+            .next_target()
+            .and_then(|target| self.endpoint_inner.unavailable_for(&target))
+        {
+            return self.on_target_unavailable(remaining);
+        }
+
         if let None = self.connection {
-            let connection = self
-                .endpoint_inner
-                .transport_layer
-                .lookup(&self.original.uri, self.endpoint_inner.transport_tx.clone())
-                .await?;
+            let transport_layer = &self.endpoint_inner.transport_layer;
+            // the policy applies to a preloaded or dialog route too, which
+            // takes the request to the proxy
+            let target = transport_layer.select_transport(&self.original);
+            if self.destination.is_none() && next_hop(&self.original).is_some() {
+                self.destination = SipAddr::try_from(&target).ok();
+            }
+            let resolved = match transport_layer.resolve(&target).await {
+                Ok(resolved) => resolved,
+                Err(e) => return self.on_transport_failure(e),
+            };
+            let connection = match transport_layer
+                .connect(&resolved, self.endpoint_inner.transport_tx.clone())
+                .await
+            {
+                Ok(connection) => connection,
+                Err(e) => return self.on_transport_failure(e),
+            };
+            if let Some(transport) = connection.get_addr().r#type.clone() {
+                let sent_by = transport_layer
+                    .listener_addr(&transport)
+                    .map(|addr| addr.addr);
+                set_via_transport(&mut self.original, transport, sent_by);
+            }
             self.connection.replace(connection.clone());
+            self.target.replace(resolved);
         }
 
         let connection = self.connection.as_ref().ok_or(Error::TransactionError(
@@ -130,12 +226,40 @@ impl Transaction {
         self.original
             .headers_mut()
             .unique_push(content_length_header);
-        connection
-            .send(self.original.to_owned().into(), self.destination.as_ref())
-            .await?;
+        if max_forwards(&self.original).is_none() {
+            self.original.headers.push(Header::MaxForwards(
+                self.endpoint_inner.identity.max_forwards.into(),
+            ));
+        }
+        let sent = self
+            .endpoint_inner
+            .send_message(
+                connection,
+                self.original.to_owned().into(),
+                self.destination.as_ref(),
+            )
+            .await;
+        if let Err(e) = sent {
+            return self.on_transport_failure(e);
+        }
+        if self.use_timer_c {
+            self.start_timer_c();
+        }
         self.transition(TransactionState::Trying).map(|_| ())
     }
 
+    /// Copy of the request to forward, with Max-Forwards decremented. `None`
+    /// once it was answered with 483 Too Many Hops (RFC 3261 16.3 step 3).
+    pub async fn make_forward_request(&mut self) -> Result<Option<Request>> {
+        let mut request = self.original.clone();
+        if !decrement_max_forwards(&mut request) {
+            info!("max-forwards exhausted: {}", self.key);
+            self.reply(StatusCode::TooManyHops).await?;
+            return Ok(None);
+        }
+        Ok(Some(request))
+    }
+
     pub async fn reply_with(
         &mut self,
         status_code: StatusCode,
@@ -159,6 +283,30 @@ impl Transaction {
         resp.headers.extend(headers);
         self.respond(resp).await
     }
+    /// Answers 420 Bad Extension when the request requires, in `header`,
+    /// option tags the endpoint doesn't support: Require for a user agent
+    /// (RFC 3261 8.2.2.3), Proxy-Require for a proxy (RFC 3261 16.3).
+    /// Returns whether it did.
+    pub async fn reply_unsupported(&mut self, header: &str) -> Result<bool> {
+        let unsupported = self
+            .endpoint_inner
+            .unsupported_extensions(&self.original, header);
+        if unsupported.is_empty() {
+            return Ok(false);
+        }
+        info!(
+            "{} requires unsupported {:?}",
+            self.original.method, unsupported
+        );
+        let headers = vec![rsip::Header::Other(
+            "Unsupported".into(),
+            unsupported.join(", "),
+        )];
+        self.reply_with(StatusCode::from(420), headers, None)
+            .await?;
+        Ok(true)
+    }
+
     /// Quick reply with status code
     #[instrument(skip(self))]
     pub async fn reply(&mut self, status_code: StatusCode) -> Result<()> {
@@ -195,13 +343,38 @@ impl Transaction {
             self.key.clone(),
         ))?;
         debug!("responding with {}", response);
-        connection
-            .send(response.to_owned().into(), self.destination.as_ref())
-            .await?;
+        self.endpoint_inner
+            .send_message(
+                connection,
+                response.to_owned().into(),
+                self.destination.as_ref(),
+            )
+            .await
+            .map_err(|e| self.transport_error(e))?;
+        if let Some(metrics) = self.endpoint_inner.metrics.as_ref() {
+            metrics.on_response_sent(&self.original.method, &response.status_code);
+        }
+        let is_ringing = self.transaction_type == TransactionType::ServerInvite
+            && new_state == TransactionState::Proceeding;
         self.last_response.replace(response);
+        if is_ringing && self.timer_provisional.is_none() {
+            self.start_provisional_timer();
+        }
         self.transition(new_state).map(|_| ())
     }
 
+    /// Keeps proxies from cancelling a long ringing INVITE on Timer C by
+    /// resending the last provisional response (RFC 3261 13.3.1.1)
+    fn start_provisional_timer(&mut self) {
+        if let Some(interval) = self.endpoint_inner.provisional_interval {
+            let timer = self.endpoint_inner.timers.timeout(
+                interval,
+                TransactionTimer::TimerProvisional(self.key.clone()),
+            );
+            self.timer_provisional.replace(timer);
+        }
+    }
+
     fn can_transition(&self, target: &TransactionState) -> Result<()> {
         match (&self.state, target) {
             (&TransactionState::Calling, &TransactionState::Trying)
@@ -213,6 +386,7 @@ impl Transaction {
             | (&TransactionState::Trying, &TransactionState::Completed)
             | (&TransactionState::Trying, &TransactionState::Confirmed)
             | (&TransactionState::Trying, &TransactionState::Terminated)
+            | (&TransactionState::Proceeding, &TransactionState::Proceeding) // 18x retransmission
             | (&TransactionState::Proceeding, &TransactionState::Completed)
             | (&TransactionState::Proceeding, &TransactionState::Confirmed)
             | (&TransactionState::Proceeding, &TransactionState::Terminated)
@@ -242,8 +416,12 @@ impl Transaction {
         match self.state {
             TransactionState::Calling | TransactionState::Trying | TransactionState::Proceeding => {
                 if let Some(connection) = &self.connection {
-                    connection
-                        .send(cancel.to_owned().into(), self.destination.as_ref())
+                    self.endpoint_inner
+                        .send_message(
+                            connection,
+                            cancel.to_owned().into(),
+                            self.destination.as_ref(),
+                        )
                         .await?;
                 }
                 self.transition(TransactionState::Terminated).map(|_| ())
@@ -258,6 +436,10 @@ impl Transaction {
     }
     #[instrument(skip(self, ack))]
     pub async fn send_ack(&mut self, ack: Request) -> Result<()> {
+        if self.is_local_response() {
+            // the final response was synthesized locally, nothing to ACK
+            return Ok(());
+        }
         if self.transaction_type != TransactionType::ClientInvite {
             return Err(Error::TransactionError(
                 "send_ack is only valid for client invite transactions".to_string(),
@@ -280,14 +462,37 @@ impl Transaction {
             }
         }
 
-        connection
-            .send(ack.to_owned().into(), self.destination.as_ref())
+        self.endpoint_inner
+            .send_message(connection, ack.to_owned().into(), self.destination.as_ref())
             .await?;
         self.last_ack.replace(ack);
         // client send ack and transition to Terminated
         self.transition(TransactionState::Terminated).map(|_| ())
     }
 
+    /// Cancels the INVITE of this client transaction over the same
+    /// connection, in a CANCEL transaction of its own. Unlike `send_cancel`
+    /// the INVITE transaction keeps running and receives the 487.
+    pub fn spawn_cancel(&self) -> Result<()> {
+        let cancel = self.endpoint_inner.make_cancel(&self.original)?;
+        let key = TransactionKey::from_ack_or_cancel(&cancel, TransactionRole::Client)?;
+        let mut cancel_tx = Transaction::new_client(
+            key,
+            cancel,
+            self.endpoint_inner.clone(),
+            self.connection.clone(),
+        );
+        cancel_tx.destination = self.destination.clone();
+        tokio::spawn(async move {
+            if let Err(e) = cancel_tx.send().await {
+                info!("failed to send cancel: {:?}", e);
+                return;
+            }
+            while cancel_tx.receive().await.is_some() {}
+        });
+        Ok(())
+    }
+
     pub async fn receive(&mut self) -> Option<SipMessage> {
         while let Some(event) = self.tu_receiver.recv().await {
             match event {
@@ -314,6 +519,33 @@ impl Transaction {
         None
     }
 
+    /// The responses `receive()` yields to a client transaction as a `Stream`,
+    /// so it can be combined with `select`, `timeout` or `merge` combinators.
+    /// The transaction is usable again once the stream is dropped.
+    pub fn responses(&mut self) -> impl Stream<Item = Response> + '_ {
+        futures::stream::unfold(self, |tx| async move {
+            loop {
+                match tx.receive().await? {
+                    SipMessage::Response(resp) => return Some((resp, tx)),
+                    SipMessage::Request(_) => continue,
+                }
+            }
+        })
+    }
+
+    /// The requests `receive()` yields to a server transaction, e.g. the ACK
+    /// of an INVITE, as a `Stream`
+    pub fn requests(&mut self) -> impl Stream<Item = Request> + '_ {
+        futures::stream::unfold(self, |tx| async move {
+            loop {
+                match tx.receive().await? {
+                    SipMessage::Request(req) => return Some((req, tx)),
+                    SipMessage::Response(_) => continue,
+                }
+            }
+        })
+    }
+
     pub async fn send_trying(&mut self) -> Result<()> {
         let response =
             self.endpoint_inner
@@ -324,9 +556,140 @@ impl Transaction {
     pub fn is_terminated(&self) -> bool {
         self.state == TransactionState::Terminated
     }
+
+    /// The transport error when the 503 received by the TU was synthesized
+    /// locally because the request couldn't be sent
+    pub fn transport_failure(&self) -> Option<&str> {
+        self.transport_failure.as_deref()
+    }
+
+    /// The time left of the break of the next hop when the 503 received by
+    /// the TU was synthesized locally because of an earlier Retry-After
+    pub fn target_unavailable(&self) -> Option<Duration> {
+        self.target_unavailable
+    }
+
+    /// The final response was synthesized locally, the request never sent
+    fn is_local_response(&self) -> bool {
+        self.transport_failure.is_some() || self.target_unavailable.is_some()
+    }
+
+    /// Terminal events of the transaction from now on
+    pub fn completion_events(&self) -> broadcast::Receiver<TransactionCompletion> {
+        self.completion_sender.subscribe()
+    }
+
+    /// Resolves with the event ending the transaction, `Terminated` when it's
+    /// dropped without one
+    pub fn completed(&self) -> impl Future<Output = TransactionCompletion> + Send + 'static {
+        let mut receiver = self.completion_sender.subscribe();
+        async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) if event.is_final() => return event,
+                    Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return TransactionCompletion::Terminated,
+                }
+            }
+        }
+    }
+
+    /// Calls `callback` with every terminal event of the transaction, e.g. to
+    /// clean up state or record metrics
+    pub fn on_completion<F>(&self, callback: F)
+    where
+        F: Fn(&TransactionCompletion) + Send + 'static,
+    {
+        let mut receiver = self.completion_sender.subscribe();
+        tokio::spawn(async move {
+            while let Ok(event) = receiver.recv().await {
+                callback(&event);
+                if event.is_final() {
+                    break;
+                }
+            }
+        });
+    }
 }
 
 impl Transaction {
+    fn emit_completion(&self, event: TransactionCompletion) {
+        if let Some(metrics) = self.endpoint_inner.metrics.as_ref() {
+            metrics.on_transaction_completion(
+                &self.transaction_type,
+                &self.original.method,
+                &event,
+            );
+        }
+        // no listener is not an error
+        self.completion_sender.send(event).ok();
+    }
+
+    /// Resends the last response, to a retransmitted request or on a timer
+    async fn resend_last_response(&self) -> Result<()> {
+        if let (Some(last_response), Some(connection)) = (&self.last_response, &self.connection) {
+            self.endpoint_inner
+                .send_message(
+                    connection,
+                    last_response.to_owned().into(),
+                    self.destination.as_ref(),
+                )
+                .await?;
+            if let Some(metrics) = self.endpoint_inner.metrics.as_ref() {
+                metrics.on_retransmission_sent(&self.original.method);
+            }
+        }
+        Ok(())
+    }
+
+    fn transport_error(&self, e: Error) -> Error {
+        self.emit_completion(TransactionCompletion::TransportError(e.to_string()));
+        e
+    }
+
+    /// The request couldn't be sent: answer the TU with a local 503 Service
+    /// Unavailable, which terminates the transaction once received.
+    fn on_transport_failure(&mut self, e: Error) -> Result<()> {
+        info!("transport failure: {} {}", self.key, e);
+        self.blacklist_target();
+        let e = self.transport_error(e);
+        self.transport_failure.replace(e.to_string());
+        let response =
+            self.endpoint_inner
+                .make_response(&self.original, StatusCode::ServiceUnavailable, None);
+        self.inform_tu_response(response)
+    }
+
+    /// Address of the next hop of the request, its first loose route or
+    /// its Request-URI, which may ask for a break with 503 and Retry-After
+    fn next_target(&self) -> Option<SipAddr> {
+        let uri = next_hop(&self.original).unwrap_or_else(|| self.original.uri.clone());
+        SipAddr::try_from(&uri).ok()
+    }
+
+    /// The next hop asked for a break: answer the TU with a local 503
+    /// carrying the time left, without sending the request
+    fn on_target_unavailable(&mut self, remaining: Duration) -> Result<()> {
+        info!("target unavailable for {:?}: {}", remaining, self.key);
+        self.target_unavailable.replace(remaining);
+        let mut response =
+            self.endpoint_inner
+                .make_response(&self.original, StatusCode::ServiceUnavailable, None);
+        let seconds = (remaining.as_secs_f64().ceil() as u64).max(1);
+        response
+            .headers
+            .push(Header::RetryAfter(seconds.to_string().into()));
+        self.inform_tu_response(response)
+    }
+
+    /// The next hop didn't answer or couldn't be reached: skip it in the
+    /// lookups for a while, so the next requests fail over at once
+    fn blacklist_target(&self) {
+        if let Some(target) = self.target.clone() {
+            self.endpoint_inner.transport_layer.blacklist(target);
+        }
+    }
+
     fn inform_tu_response(&mut self, response: Response) -> Result<()> {
         self.tu_sender
             .send(TransactionEvent::Received(
@@ -359,8 +722,8 @@ impl Transaction {
                         let resp = self
                             .endpoint_inner
                             .make_response(&req, StatusCode::OK, None);
-                        connection
-                            .send(resp.into(), self.destination.as_ref())
+                        self.endpoint_inner
+                            .send_message(connection, resp.into(), self.destination.as_ref())
                             .await
                             .ok();
                     }
@@ -373,8 +736,8 @@ impl Transaction {
                             StatusCode::CallTransactionDoesNotExist,
                             None,
                         );
-                        connection
-                            .send(resp.into(), self.destination.as_ref())
+                        self.endpoint_inner
+                            .send_message(connection, resp.into(), self.destination.as_ref())
                             .await
                             .ok();
                     }
@@ -383,18 +746,19 @@ impl Transaction {
             return None;
         }
 
+        if self.state == TransactionState::Completed && req.method == Method::Ack {
+            self.transition(TransactionState::Confirmed).ok();
+            return Some(req.into());
+        }
+        if let Some(metrics) = self.endpoint_inner.metrics.as_ref() {
+            metrics.on_retransmission_received(&req.method);
+        }
         match self.state {
-            TransactionState::Trying | TransactionState::Proceeding => {
-                // retransmission of last response
-                if let Some(last_response) = &self.last_response {
-                    self.respond(last_response.to_owned()).await.ok();
-                }
-            }
-            TransactionState::Completed => {
-                if req.method == Method::Ack {
-                    self.transition(TransactionState::Confirmed).ok();
-                    return Some(req.into());
-                }
+            // retransmission of last response, the final one when it was lost
+            TransactionState::Trying
+            | TransactionState::Proceeding
+            | TransactionState::Completed => {
+                self.resend_last_response().await.ok();
             }
             _ => {}
         }
@@ -408,6 +772,8 @@ impl Transaction {
         }
 
         let new_state = match resp.status_code.kind() {
+            // no ACK nor retransmission to absorb for a local 503
+            _ if self.is_local_response() => TransactionState::Terminated,
             rsip::StatusCodeKind::Provisional => {
                 if resp.status_code == rsip::StatusCode::Trying {
                     TransactionState::Trying
@@ -425,17 +791,43 @@ impl Transaction {
         };
 
         self.can_transition(&new_state).ok()?;
-        if self.state == new_state {
+        // provisional responses keep coming in Proceeding, from forks of the
+        // INVITE or as distinct reliable responses
+        if self.state == new_state && new_state != TransactionState::Proceeding {
             // ignore duplicate response
+            if let Some(metrics) = self.endpoint_inner.metrics.as_ref() {
+                metrics.on_retransmission_received(&self.original.method);
+            }
             return None;
         }
+        if let Some(metrics) = self
+            .endpoint_inner
+            .metrics
+            .as_ref()
+            .filter(|_| !self.is_local_response())
+        {
+            metrics.on_response_received(&self.original.method, &resp.status_code);
+        }
 
+        if resp.status_code == StatusCode::ServiceUnavailable && !self.is_local_response() {
+            if let (Some(target), Some(retry_after)) =
+                (self.next_target(), retry_after(&resp.headers))
+            {
+                self.endpoint_inner.set_unavailable(target, retry_after);
+            }
+        }
+        if self.use_timer_c && new_state == TransactionState::Proceeding {
+            self.start_timer_c();
+        }
         self.last_response.replace(resp.clone());
         self.transition(new_state).ok();
         return Some(SipMessage::Response(resp));
     }
 
     async fn on_timer(&mut self, timer: TransactionTimer) -> Result<()> {
+        if let TransactionTimer::TimerC(_) = timer {
+            return self.on_timer_c().await;
+        }
         match self.state {
             TransactionState::Trying => {
                 if matches!(
@@ -445,18 +837,32 @@ impl Transaction {
                     if let TransactionTimer::TimerA(key, duration) = timer {
                         // Resend the INVITE request
                         if let Some(connection) = &self.connection {
-                            connection
-                                .send(self.original.to_owned().into(), self.destination.as_ref())
+                            self.endpoint_inner
+                                .send_message(
+                                    connection,
+                                    self.original.to_owned().into(),
+                                    self.destination.as_ref(),
+                                )
                                 .await?;
+                            if let Some(metrics) = self.endpoint_inner.metrics.as_ref() {
+                                metrics.on_retransmission_sent(&self.original.method);
+                            }
                         }
-                        // Restart Timer A with an upper limit
-                        let duration = (duration * 2).min(self.endpoint_inner.t1x64);
+                        // Restart Timer A, or Timer E capped at T2 for a non-INVITE
+                        let limit = match self.transaction_type {
+                            TransactionType::ClientNonInvite => self.endpoint_inner.t2,
+                            _ => self.endpoint_inner.t1x64,
+                        };
+                        let duration = (duration * 2).min(limit);
                         let timer_a = self
                             .endpoint_inner
                             .timers
                             .timeout(duration, TransactionTimer::TimerA(key, duration));
                         self.timer_a.replace(timer_a);
                     } else if let TransactionTimer::TimerB(_) = timer {
+                        // not even a provisional response came
+                        self.blacklist_target();
+                        self.emit_completion(TransactionCompletion::TimedOut);
                         // Inform TU about timeout
                         let timeout_response = self.endpoint_inner.make_response(
                             &self.original,
@@ -468,7 +874,13 @@ impl Transaction {
                 }
             }
             TransactionState::Proceeding => {
-                if let TransactionTimer::TimerB(_) = timer {
+                if let TransactionTimer::TimerProvisional(_) = timer {
+                    self.resend_last_response().await?;
+                    self.start_provisional_timer();
+                } else if let TransactionTimer::TimerB(_) = timer {
+                    if !self.timer_c_fired {
+                        self.emit_completion(TransactionCompletion::TimedOut);
+                    }
                     // Inform TU about timeout
                     let timeout_response = self.endpoint_inner.make_response(
                         &self.original,
@@ -481,21 +893,19 @@ impl Transaction {
             TransactionState::Completed => {
                 if let TransactionTimer::TimerG(key, duration) = timer {
                     // resend the response
-                    if let Some(last_response) = &self.last_response {
-                        if let Some(connection) = &self.connection {
-                            connection
-                                .send(last_response.to_owned().into(), self.destination.as_ref())
-                                .await?;
-                        }
-                    }
-                    // restart Timer G with an upper limit
-                    let duration = (duration * 2).min(self.endpoint_inner.t1x64);
+                    self.resend_last_response().await?;
+                    // restart Timer G, doubling up to T2
+                    let duration = (duration * 2).min(self.endpoint_inner.t2);
                     let timer_g = self
                         .endpoint_inner
                         .timers
                         .timeout(duration, TransactionTimer::TimerG(key, duration));
                     self.timer_g.replace(timer_g);
                 } else if let TransactionTimer::TimerD(_) = timer {
+                    if self.transaction_type == TransactionType::ServerInvite {
+                        // Timer H, the ACK never came
+                        self.emit_completion(TransactionCompletion::TimedOut);
+                    }
                     self.transition(TransactionState::Terminated)?;
                 }
             }
@@ -509,6 +919,42 @@ impl Transaction {
         Ok(())
     }
 
+    /// Timer C fired: a ringing branch is cancelled, one that never answered
+    /// is treated as if it had answered 408 (RFC 3261 16.8). A cancelled
+    /// branch gets 64*T1 for its 487, then ends with a 408 too.
+    async fn on_timer_c(&mut self) -> Result<()> {
+        self.timer_c.take();
+        match self.state {
+            TransactionState::Proceeding => {
+                info!("timer C fired, cancelling {}", self.key);
+                self.emit_completion(TransactionCompletion::TimedOut);
+                self.spawn_cancel()?;
+                // later provisional responses must not restart Timer C
+                self.use_timer_c = false;
+                self.timer_c_fired = true;
+                self.timer_b
+                    .take()
+                    .map(|id| self.endpoint_inner.timers.cancel(id));
+                let timer_b = self.endpoint_inner.timers.timeout(
+                    self.endpoint_inner.t1x64,
+                    TransactionTimer::TimerB(self.key.clone()),
+                );
+                self.timer_b.replace(timer_b);
+            }
+            TransactionState::Trying => {
+                self.emit_completion(TransactionCompletion::TimedOut);
+                let timeout_response = self.endpoint_inner.make_response(
+                    &self.original,
+                    rsip::StatusCode::RequestTimeout,
+                    None,
+                );
+                self.inform_tu_response(timeout_response)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
     fn transition(&mut self, state: TransactionState) -> Result<TransactionState> {
         if self.state == state {
             return Ok(self.state.clone());
@@ -550,12 +996,23 @@ impl Transaction {
                 self.timer_a
                     .take()
                     .map(|id| self.endpoint_inner.timers.cancel(id));
-                // start Timer B
-                let timer_b = self.endpoint_inner.timers.timeout(
-                    self.endpoint_inner.t1x64,
-                    TransactionTimer::TimerB(self.key.clone()),
-                );
-                self.timer_b.replace(timer_b);
+                self.timer_b
+                    .take()
+                    .map(|id| self.endpoint_inner.timers.cancel(id));
+                // start Timer B, a server transaction waits for its TU as
+                // long as the callee rings and Timer C guards a proxied INVITE
+                if !self.use_timer_c
+                    && matches!(
+                        self.transaction_type,
+                        TransactionType::ClientInvite | TransactionType::ClientNonInvite
+                    )
+                {
+                    let timer_b = self.endpoint_inner.timers.timeout(
+                        self.endpoint_inner.t1x64,
+                        TransactionTimer::TimerB(self.key.clone()),
+                    );
+                    self.timer_b.replace(timer_b);
+                }
             }
             TransactionState::Completed => {
                 self.timer_a
@@ -564,6 +1021,12 @@ impl Transaction {
                 self.timer_b
                     .take()
                     .map(|id| self.endpoint_inner.timers.cancel(id));
+                self.timer_provisional
+                    .take()
+                    .map(|id| self.endpoint_inner.timers.cancel(id));
+                self.timer_c
+                    .take()
+                    .map(|id| self.endpoint_inner.timers.cancel(id));
 
                 if self.transaction_type == TransactionType::ServerInvite {
                     // start Timer G for server invite only
@@ -571,7 +1034,14 @@ impl Transaction {
                         "no connection found".to_string(),
                         self.key.clone(),
                     ))?;
-                    if !connection.is_reliable() {
+                    // a 2xx is retransmitted until the ACK over any transport
+                    // (RFC 3261 13.3.1.4)
+                    let is_2xx = self
+                        .last_response
+                        .as_ref()
+                        .map(|r| r.status_code.kind() == rsip::StatusCodeKind::Successful)
+                        .unwrap_or(false);
+                    if !connection.is_reliable() || is_2xx {
                         let timer_g = self.endpoint_inner.timers.timeout(
                             self.endpoint_inner.t1,
                             TransactionTimer::TimerG(self.key.clone(), self.endpoint_inner.t1),
@@ -580,11 +1050,15 @@ impl Transaction {
                     }
                 }
 
-                // start Timer D
-                let timer_d = self.endpoint_inner.timers.timeout(
-                    self.endpoint_inner.t1x64,
-                    TransactionTimer::TimerD(self.key.clone()),
-                );
+                // start Timer D, or Timers H and J of a server transaction
+                let duration = match self.transaction_type {
+                    TransactionType::ClientInvite => self.endpoint_inner.timer_d,
+                    _ => self.endpoint_inner.t1x64,
+                };
+                let timer_d = self
+                    .endpoint_inner
+                    .timers
+                    .timeout(duration, TransactionTimer::TimerD(self.key.clone()));
                 self.timer_d.replace(timer_d);
             }
             TransactionState::Confirmed => {
@@ -601,6 +1075,11 @@ impl Transaction {
                 self.tu_sender.send(TransactionEvent::Terminate).ok(); // tell TU to terminate
             }
         }
+        match state {
+            TransactionState::Completed => self.emit_completion(TransactionCompletion::Completed),
+            TransactionState::Terminated => self.emit_completion(TransactionCompletion::Terminated),
+            _ => {}
+        }
         debug!("transition: {:?} -> {:?}", self.state, state);
         self.state = state;
         Ok(self.state.clone())
@@ -622,6 +1101,12 @@ impl Transaction {
         self.timer_g
             .take()
             .map(|id| self.endpoint_inner.timers.cancel(id));
+        self.timer_provisional
+            .take()
+            .map(|id| self.endpoint_inner.timers.cancel(id));
+        self.timer_c
+            .take()
+            .map(|id| self.endpoint_inner.timers.cancel(id));
     }
 
     fn cleanup(&mut self) {
@@ -635,7 +1120,7 @@ impl Transaction {
                 TransactionType::ClientInvite => {
                     self.last_ack.take().map(|r| SipMessage::Request(r))
                 }
-                TransactionType::ServerNonInvite => {
+                TransactionType::ServerInvite | TransactionType::ServerNonInvite => {
                     self.last_response.take().map(|r| SipMessage::Response(r))
                 }
                 _ => None,
@@ -648,6 +1133,9 @@ impl Transaction {
 
 impl Drop for Transaction {
     fn drop(&mut self) {
+        if !self.is_terminated() {
+            self.emit_completion(TransactionCompletion::Terminated);
+        }
         self.cleanup();
         info!("transaction dropped: {}", self.key);
     }
@@ -2,6 +2,8 @@ use super::{
     channel::ChannelConnection, sip_addr::SipAddr, stream::StreamConnection, tcp::TcpConnection,
     udp::UdpConnection,
 };
+#[cfg(feature = "quic")]
+use crate::transport::quic::QuicConnection;
 use crate::transport::tls::TlsConnection;
 use crate::transport::websocket::WebSocketConnection;
 use crate::Result;
@@ -16,6 +18,8 @@ use tracing::debug;
 #[derive(Debug)]
 pub enum TransportEvent {
     Incoming(SipMessage, SipConnection, SipAddr),
+    /// Raw bytes that could not be parsed, with the parse error
+    BadMessage(Vec<u8>, SipConnection, SipAddr, String),
     New(SipConnection),
     Closed(SipConnection),
 }
@@ -35,6 +39,8 @@ pub enum SipConnection {
     Tls(TlsConnection),
     #[cfg(feature = "websocket")]
     WebSocket(WebSocketConnection),
+    #[cfg(feature = "quic")]
+    Quic(QuicConnection),
 }
 
 impl SipConnection {
@@ -53,6 +59,22 @@ impl SipConnection {
             SipConnection::Tls(transport) => transport.get_addr(),
             #[cfg(feature = "websocket")]
             SipConnection::WebSocket(transport) => transport.get_addr(),
+            #[cfg(feature = "quic")]
+            SipConnection::Quic(transport) => transport.get_addr(),
+        }
+    }
+    /// Remote end of a connection oriented transport, `None` for datagrams
+    pub fn remote_addr(&self) -> Option<&SipAddr> {
+        match self {
+            SipConnection::Udp(_) => None,
+            SipConnection::Channel(_) => None,
+            SipConnection::Tcp(transport) => transport.inner.remote_addr.as_ref(),
+            #[cfg(feature = "rustls")]
+            SipConnection::Tls(transport) => Some(transport.get_addr()),
+            #[cfg(feature = "websocket")]
+            SipConnection::WebSocket(transport) => transport.inner.remote_addr.as_ref(),
+            #[cfg(feature = "quic")]
+            SipConnection::Quic(transport) => Some(&transport.inner.remote_addr),
         }
     }
     pub async fn send(&self, msg: rsip::SipMessage, destination: Option<&SipAddr>) -> Result<()> {
@@ -81,8 +103,32 @@ impl SipConnection {
                 }
                 transport.send_message(msg).await
             }
+            #[cfg(feature = "quic")]
+            SipConnection::Quic(transport) => transport.send_message(msg).await,
+        }
+    }
+    /// Sends a CRLF keepalive (RFC 5626 4.4.1), `destination` is the peer of
+    /// a datagram transport
+    pub async fn send_keepalive(&self, destination: Option<&SipAddr>) -> Result<()> {
+        match self {
+            SipConnection::Udp(transport) => match destination {
+                Some(destination) => transport.send_raw(KEEPALIVE_REQUEST, destination).await,
+                None => Err(crate::Error::TransportLayerError(
+                    "no keepalive destination".to_string(),
+                    transport.get_addr().clone(),
+                )),
+            },
+            SipConnection::Channel(_) => Ok(()),
+            SipConnection::Tcp(transport) => transport.send_raw(KEEPALIVE_REQUEST).await,
+            #[cfg(feature = "rustls")]
+            SipConnection::Tls(transport) => transport.send_raw(KEEPALIVE_REQUEST).await,
+            #[cfg(feature = "websocket")]
+            SipConnection::WebSocket(transport) => transport.send_raw(KEEPALIVE_REQUEST).await,
+            #[cfg(feature = "quic")]
+            SipConnection::Quic(transport) => transport.send_raw(KEEPALIVE_REQUEST).await,
         }
     }
+
     pub async fn serve_loop(&self, sender: TransportSender) -> Result<()> {
         match self {
             SipConnection::Udp(transport) => transport.serve_loop(sender).await,
@@ -92,6 +138,8 @@ impl SipConnection {
             SipConnection::Tls(transport) => transport.serve_loop(sender).await,
             #[cfg(feature = "websocket")]
             SipConnection::WebSocket(transport) => transport.serve_loop(sender).await,
+            #[cfg(feature = "quic")]
+            SipConnection::Quic(transport) => transport.serve_loop(sender).await,
         }
     }
 
@@ -104,6 +152,8 @@ impl SipConnection {
             SipConnection::Tls(transport) => transport.close().await,
             #[cfg(feature = "websocket")]
             SipConnection::WebSocket(transport) => transport.close().await,
+            #[cfg(feature = "quic")]
+            SipConnection::Quic(transport) => transport.close().await,
         }
     }
 }
@@ -186,6 +236,8 @@ impl fmt::Display for SipConnection {
             SipConnection::Tls(t) => write!(f, "{}", t),
             #[cfg(feature = "websocket")]
             SipConnection::WebSocket(t) => write!(f, "{}", t),
+            #[cfg(feature = "quic")]
+            SipConnection::Quic(t) => write!(f, "{}", t),
         }
     }
 }
@@ -220,6 +272,13 @@ impl From<WebSocketConnection> for SipConnection {
     }
 }
 
+#[cfg(feature = "quic")]
+impl From<QuicConnection> for SipConnection {
+    fn from(connection: QuicConnection) -> Self {
+        SipConnection::Quic(connection)
+    }
+}
+
 impl Into<rsip::HostWithPort> for SipAddr {
     fn into(self) -> rsip::HostWithPort {
         self.addr
@@ -0,0 +1,105 @@
+//! Flow tokens (RFC 5626 5.3) let an edge proxy route in-dialog requests back
+//! over the exact connection a client behind NAT used, by encoding the flow
+//! into the user part of its Record-Route. The tokens are signed with a
+//! secret of the endpoint, so a forged Route can't steer requests over
+//! another flow.
+use super::{SipAddr, SipConnection};
+use crate::Result;
+use hmac::{Hmac, Mac};
+use rsip::prelude::HeadersExt;
+use sha2::Sha256;
+
+/// Bytes of the HMAC kept in a token
+const FLOW_MAC_LEN: usize = 10;
+
+/// Secret signing the flow tokens of an endpoint
+#[derive(Clone)]
+pub struct FlowKey([u8; 32]);
+
+impl FlowKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+
+    fn mac(&self, flow: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).expect("HMAC takes any key size");
+        mac.update(flow.as_bytes());
+        hex(&mac.finalize().into_bytes()[..FLOW_MAC_LEN])
+    }
+}
+
+impl Default for FlowKey {
+    fn default() -> Self {
+        Self(rand::random())
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// An inbound connection and the address of the peer on it
+#[derive(Clone, Debug)]
+pub struct Flow {
+    pub connection: SipConnection,
+    pub remote: SipAddr,
+}
+
+/// Token identifying the flow of `remote`, hex-encoded to stay a valid URI
+/// user part, followed by its HMAC with `key`
+pub fn flow_token(key: &FlowKey, remote: &SipAddr) -> String {
+    let transport = remote
+        .r#type
+        .as_ref()
+        .map(|t| t.to_string().to_lowercase())
+        .unwrap_or_else(|| "udp".to_string());
+    let flow = hex(format!("{}/{}", transport, remote.addr).as_bytes());
+    format!("{}.{}", flow, key.mac(&flow))
+}
+
+/// Whether `token` was issued with `key`
+pub fn verify_flow_token(key: &FlowKey, token: &str) -> bool {
+    let (flow, mac) = match token.rsplit_once('.') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    let expected = key.mac(flow);
+    expected.len() == mac.len()
+        && expected
+            .bytes()
+            .zip(mac.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Address of the peer a request was received from: the remote end of a
+/// stream connection, or the received/rport of the top Via for datagrams
+pub fn flow_remote(connection: &SipConnection, request: &rsip::Request) -> Result<SipAddr> {
+    if let Some(remote) = connection.remote_addr() {
+        return Ok(remote.clone());
+    }
+    let addr = SipConnection::parse_target_from_via(request.via_header()?)?;
+    Ok(SipAddr {
+        r#type: connection.get_addr().r#type.clone(),
+        addr,
+    })
+}
+
+/// Flow tokens found in the user part of the Route headers of `request`
+pub fn route_tokens(request: &rsip::Request) -> Vec<String> {
+    request
+        .headers
+        .iter()
+        .filter_map(|h| match h {
+            rsip::Header::Route(route) => route.typed().ok(),
+            _ => None,
+        })
+        .flat_map(|route| {
+            route
+                .uris()
+                .iter()
+                .filter_map(|u| u.uri.user().map(|u| u.to_string()))
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
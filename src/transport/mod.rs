@@ -1,7 +1,13 @@
 pub mod channel;
 pub mod connection;
+pub mod flow;
+pub mod normalize;
+pub mod policy;
+#[cfg(feature = "quic")]
+pub mod quic;
 pub mod sip_addr;
 pub mod stream;
+pub mod stun;
 pub mod tcp;
 pub mod tls;
 pub mod transport_layer;
@@ -10,6 +16,7 @@ pub mod websocket;
 
 pub use connection::SipConnection;
 pub use connection::TransportEvent;
+pub use policy::TransportPolicy;
 pub use sip_addr::SipAddr;
 pub use transport_layer::TransportLayer;
 
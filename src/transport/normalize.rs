@@ -0,0 +1,191 @@
+//! Fixes common interop breakage of inbound messages before they are parsed.
+//!
+//! - junk before the start line (stray CRLFs, partial keepalives) is skipped
+//! - the body is kept as binary, only the start line and headers must be UTF-8
+//! - Content-Length is checked against the bytes actually received
+//! - requests without Max-Forwards get the default of 70
+use super::SipAddr;
+use crate::{transaction::MAX_FORWARDS, Error, Result};
+use rsip::{Header, SipMessage};
+use std::sync::Arc;
+use tracing::info;
+
+/// Offset of the start line, skipping anything before a line that looks like
+/// a request or status line
+pub fn find_start_line(buf: &[u8]) -> Option<usize> {
+    let mut offset = 0;
+    while offset < buf.len() {
+        let line = &buf[offset..];
+        if is_start_line(line) {
+            return Some(offset);
+        }
+        match line.iter().position(|&b| b == b'\n') {
+            Some(pos) => offset += pos + 1,
+            None => return None,
+        }
+    }
+    None
+}
+
+fn is_start_line(line: &[u8]) -> bool {
+    if line.starts_with(b"SIP/2.0 ") {
+        return true;
+    }
+    // Method SP Request-URI SP SIP/2.0
+    let token_len = line
+        .iter()
+        .take_while(|b| b.is_ascii_uppercase() || **b == b'-')
+        .count();
+    let end = line.iter().position(|&b| b == b'\n').unwrap_or(line.len());
+    token_len > 0
+        && line.get(token_len) == Some(&b' ')
+        && line[..end].windows(7).any(|w| w == b"SIP/2.0")
+}
+
+/// Returns the header block length (including the empty line) and the
+/// offset of the body, accepting bare LF line endings
+fn split_head(buf: &[u8]) -> Option<(usize, usize)> {
+    if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        return Some((pos, pos + 4));
+    }
+    buf.windows(2)
+        .position(|w| w == b"\n\n")
+        .map(|pos| (pos, pos + 2))
+}
+
+fn content_length(head: &str) -> Option<usize> {
+    head.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        let name = name.trim();
+        if name.eq_ignore_ascii_case("content-length") || name.eq_ignore_ascii_case("l") {
+            value.trim().parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+/// Length of the first complete message in a stream buffer, including any
+/// junk skipped before it. `None` if more bytes are needed.
+///
+/// Over streams a missing Content-Length means an empty body (RFC 3261 18.3).
+pub fn frame_length(buf: &[u8]) -> Option<usize> {
+    let start = find_start_line(buf)?;
+    let (head_len, body_offset) = split_head(&buf[start..])?;
+    let head = String::from_utf8_lossy(&buf[start..start + head_len]);
+    let total = start + body_offset + content_length(&head).unwrap_or(0);
+    if total > buf.len() {
+        return None;
+    }
+    Some(total)
+}
+
+/// Parses one message from a datagram or a frame returned by `frame_length`
+pub fn normalize_message(buf: &[u8]) -> Result<SipMessage> {
+    let start = find_start_line(buf)
+        .ok_or_else(|| Error::SipMessageError("start line not found".to_string()))?;
+    let buf = &buf[start..];
+    let (head_len, body_offset) = split_head(buf).unwrap_or((buf.len(), buf.len()));
+    let head = std::str::from_utf8(&buf[..head_len])
+        .map_err(|e| Error::SipMessageError(format!("invalid header encoding: {}", e)))?;
+
+    let mut body = &buf[body_offset.min(buf.len())..];
+    // on datagrams the body ends with the packet, the bytes past a shorter
+    // Content-Length are discarded and a longer one means a truncated
+    // message (RFC 3261 18.3)
+    if let Some(len) = content_length(head) {
+        if len > body.len() {
+            return Err(Error::SipMessageError(format!(
+                "Content-Length {} exceeds the {} bytes of the body",
+                len,
+                body.len()
+            )));
+        }
+        if len < body.len() {
+            info!(
+                "discarding {} bytes past Content-Length {} of: {}",
+                body.len() - len,
+                len,
+                head.lines().next().unwrap_or_default()
+            );
+            body = &body[..len];
+        }
+    }
+
+    let head = head.replace("\r\n", "\n").replace('\n', "\r\n");
+    let mut msg = SipMessage::try_from(format!("{}\r\n\r\n", head.trim_end()).as_str())?;
+    match &mut msg {
+        SipMessage::Request(req) => {
+            if !req
+                .headers
+                .iter()
+                .any(|h| matches!(h, Header::MaxForwards(_)))
+            {
+                req.headers.push(Header::MaxForwards(MAX_FORWARDS.into()));
+            }
+            req.body = body.to_vec();
+            fix_content_length(&mut req.headers, body.len());
+        }
+        SipMessage::Response(resp) => {
+            resp.body = body.to_vec();
+            fix_content_length(&mut resp.headers, body.len());
+        }
+    }
+    Ok(msg)
+}
+
+fn fix_content_length(headers: &mut rsip::Headers, len: usize) {
+    headers.retain(|h| !matches!(h, Header::ContentLength(_)));
+    headers.push(Header::ContentLength((len as u32).into()));
+}
+
+pub type BadMessageHook = Arc<dyn Fn(&[u8], &SipAddr, &str) + Send + Sync>;
+
+/// What a transport does with a message it can't parse
+#[derive(Clone, Default)]
+pub enum BadMessagePolicy {
+    /// Log and drop the message
+    #[default]
+    Drop,
+    /// Reply 400 Bad Request statelessly when enough headers can be recovered
+    Reject,
+    /// Hand the raw bytes to the hook, for logging or quarantine
+    Hook(BadMessageHook),
+}
+
+/// Builds a stateless 400 for an unparsable request, from the header lines
+/// needed to route the response back. `None` for responses or when they are
+/// missing.
+pub fn make_bad_request(buf: &[u8], reason: &str) -> Option<SipMessage> {
+    let start = find_start_line(buf)?;
+    let buf = &buf[start..];
+    let (head_len, _) = split_head(buf).unwrap_or((buf.len(), buf.len()));
+    let head = String::from_utf8_lossy(&buf[..head_len]);
+    let mut lines = head.lines();
+    if lines.next()?.starts_with("SIP/2.0") {
+        return None;
+    }
+    let mut resp = String::from("SIP/2.0 400 Bad Request\r\n");
+    let mut found = 0;
+    for line in lines {
+        let name = match line.split_once(':') {
+            Some((name, _)) => name.trim().to_lowercase(),
+            None => continue,
+        };
+        match name.as_str() {
+            "via" | "v" | "from" | "f" | "to" | "t" | "call-id" | "i" | "cseq" => {
+                resp.push_str(line.trim_end());
+                resp.push_str("\r\n");
+                found += 1;
+            }
+            _ => {}
+        }
+    }
+    if found < 5 {
+        return None;
+    }
+    let reason = reason.replace(['\r', '\n', '"'], " ");
+    resp.push_str(&format!("Warning: 399 - \"{}\"\r\n", reason));
+    resp.push_str("Content-Length: 0\r\n\r\n");
+    SipMessage::try_from(resp.as_str()).ok()
+}
@@ -0,0 +1,57 @@
+use rsip::transport::Transport;
+use std::collections::HashMap;
+
+/// Decides which transport is used to reach a target.
+///
+/// Consulted by the transport layer before resolving the next hop of every
+/// outgoing request, its first loose route or its Request-URI, initial and
+/// in-dialog alike. Returning `None` keeps the transport given by the URI (or
+/// DNS), which is UDP in most cases.
+pub trait TransportPolicy: Send + Sync {
+    fn select(&self, target: &rsip::Uri, message_size: usize) -> Option<Transport>;
+}
+
+#[derive(Default, Clone)]
+pub struct DefaultTransportPolicy {
+    /// preferred transport per domain, e.g. TLS for `sip.example.com`
+    pub domains: HashMap<String, Transport>,
+    /// messages larger than this are sent over TCP instead of UDP (RFC 3261 18.1.1)
+    pub tcp_threshold: Option<usize>,
+}
+
+impl DefaultTransportPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn prefer(mut self, domain: &str, transport: Transport) -> Self {
+        self.domains.insert(domain.to_lowercase(), transport);
+        self
+    }
+
+    pub fn tcp_threshold(mut self, size: usize) -> Self {
+        self.tcp_threshold = Some(size);
+        self
+    }
+}
+
+impl TransportPolicy for DefaultTransportPolicy {
+    fn select(&self, target: &rsip::Uri, message_size: usize) -> Option<Transport> {
+        let current = target.transport().cloned();
+        let domain = target.host_with_port.host.to_string().to_lowercase();
+
+        let selected = match (current.as_ref(), self.domains.get(&domain)) {
+            (None, Some(transport)) => Some(transport.clone()),
+            _ => current,
+        };
+
+        match (&selected, self.tcp_threshold) {
+            (None, Some(threshold)) | (Some(Transport::Udp), Some(threshold))
+                if message_size > threshold =>
+            {
+                Some(Transport::Tcp)
+            }
+            _ => selected,
+        }
+    }
+}
@@ -0,0 +1,189 @@
+//! Experimental SIP over QUIC, for deployments controlling both ends.
+//!
+//! Every message is sent on its own unidirectional stream, so the stream end
+//! frames the message and no Content-Length based framing is needed. Clients
+//! reconnecting to a known server use 0-RTT when the server accepts it.
+//!
+//! There is no QUIC transport in `rsip`, so the addresses of QUIC connections
+//! have no transport type and are never selected by `TransportLayer::lookup`:
+//! client transactions must be given the connection explicitly.
+use crate::{
+    transport::{
+        connection::TransportSender, normalize::normalize_message, SipAddr, SipConnection,
+        TransportEvent,
+    },
+    Error, Result,
+};
+use rsip::SipMessage;
+use std::{fmt, net::SocketAddr, sync::Arc};
+use tracing::{debug, info, warn};
+
+const MAX_SIP_MESSAGE_SIZE: usize = 65535;
+
+pub struct QuicInner {
+    pub local_addr: SipAddr,
+    pub remote_addr: SipAddr,
+    pub connection: quinn::Connection,
+}
+
+#[derive(Clone)]
+pub struct QuicConnection {
+    pub inner: Arc<QuicInner>,
+}
+
+fn quic_addr(addr: SocketAddr) -> SipAddr {
+    SipAddr {
+        r#type: None,
+        addr: addr.into(),
+    }
+}
+
+impl QuicConnection {
+    fn new(endpoint: &quinn::Endpoint, connection: quinn::Connection) -> Result<Self> {
+        let local_addr = quic_addr(endpoint.local_addr()?);
+        let remote_addr = quic_addr(connection.remote_address());
+        Ok(QuicConnection {
+            inner: Arc::new(QuicInner {
+                local_addr,
+                remote_addr,
+                connection,
+            }),
+        })
+    }
+
+    /// Connects to `remote`, sending the first messages as 0-RTT data when
+    /// the endpoint holds a session ticket for the server
+    pub async fn connect(
+        endpoint: &quinn::Endpoint,
+        remote: SocketAddr,
+        server_name: &str,
+    ) -> Result<Self> {
+        let remote_addr = quic_addr(remote);
+        let connecting = endpoint
+            .connect(remote, server_name)
+            .map_err(|e| Error::TransportLayerError(e.to_string(), remote_addr.clone()))?;
+        let connection = match connecting.into_0rtt() {
+            Ok((connection, _)) => {
+                debug!("QUIC 0-RTT connection to {}", remote);
+                connection
+            }
+            Err(connecting) => connecting
+                .await
+                .map_err(|e| Error::TransportLayerError(e.to_string(), remote_addr.clone()))?,
+        };
+        let connection = Self::new(endpoint, connection)?;
+        info!("created QUIC connection: {}", connection);
+        Ok(connection)
+    }
+
+    pub fn create_listener(
+        local: SocketAddr,
+        server_config: quinn::ServerConfig,
+    ) -> Result<quinn::Endpoint> {
+        let endpoint = quinn::Endpoint::server(server_config, local)?;
+        info!("QUIC listening on {}", endpoint.local_addr()?);
+        Ok(endpoint)
+    }
+
+    pub async fn serve_listener(endpoint: quinn::Endpoint, sender: TransportSender) -> Result<()> {
+        while let Some(incoming) = endpoint.accept().await {
+            let connection = match incoming.await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!("error accepting QUIC connection: {}", e);
+                    continue;
+                }
+            };
+            let connection = Self::new(&endpoint, connection)?;
+            let sip_connection = SipConnection::Quic(connection.clone());
+            sender.send(TransportEvent::New(sip_connection.clone()))?;
+
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                if let Err(e) = connection.serve_loop(sender.clone()).await {
+                    warn!("QUIC connection error: {} {:?}", connection, e);
+                }
+                sender.send(TransportEvent::Closed(sip_connection)).ok();
+            });
+        }
+        Ok(())
+    }
+
+    pub fn get_addr(&self) -> &SipAddr {
+        &self.inner.local_addr
+    }
+
+    pub async fn send_message(&self, msg: SipMessage) -> Result<()> {
+        self.send_raw(msg.to_string().as_bytes()).await
+    }
+
+    pub async fn send_raw(&self, data: &[u8]) -> Result<()> {
+        let map_err = |e: String| Error::TransportLayerError(e, self.inner.remote_addr.clone());
+        let mut stream = self
+            .inner
+            .connection
+            .open_uni()
+            .await
+            .map_err(|e| map_err(e.to_string()))?;
+        stream
+            .write_all(data)
+            .await
+            .map_err(|e| map_err(e.to_string()))?;
+        stream.finish().map_err(|e| map_err(e.to_string()))?;
+        Ok(())
+    }
+
+    pub async fn serve_loop(&self, sender: TransportSender) -> Result<()> {
+        let sip_connection = SipConnection::Quic(self.clone());
+        loop {
+            let mut stream = match self.inner.connection.accept_uni().await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    info!("QUIC connection closed: {} {}", self, e);
+                    return Ok(());
+                }
+            };
+            let data = match stream.read_to_end(MAX_SIP_MESSAGE_SIZE).await {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!("error reading QUIC stream: {} {}", self, e);
+                    continue;
+                }
+            };
+            match normalize_message(&data) {
+                Ok(msg) => sender.send(TransportEvent::Incoming(
+                    msg,
+                    sip_connection.clone(),
+                    self.inner.remote_addr.clone(),
+                ))?,
+                Err(e) => sender.send(TransportEvent::BadMessage(
+                    data,
+                    sip_connection.clone(),
+                    self.inner.remote_addr.clone(),
+                    e.to_string(),
+                ))?,
+            }
+        }
+    }
+
+    pub async fn close(&self) -> Result<()> {
+        self.inner.connection.close(0u32.into(), b"closed");
+        Ok(())
+    }
+}
+
+impl fmt::Display for QuicConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "QUIC {} -> {}",
+            self.inner.local_addr, self.inner.remote_addr
+        )
+    }
+}
+
+impl fmt::Debug for QuicConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
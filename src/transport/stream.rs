@@ -1,6 +1,7 @@
 use crate::{
     transport::{
         connection::{TransportSender, KEEPALIVE_REQUEST, KEEPALIVE_RESPONSE},
+        normalize::{frame_length, normalize_message},
         SipAddr, SipConnection, TransportEvent,
     },
     Result,
@@ -50,9 +51,9 @@ impl Decoder for SipCodec {
             return Err(crate::Error::Keepalive);
         }
 
-        let data = match std::str::from_utf8(&src[..]) {
-            Ok(s) => s,
-            Err(_) => {
+        let frame_len = match frame_length(&src[..]) {
+            Some(len) => len,
+            None => {
                 if src.len() > self.max_size {
                     return Err(crate::Error::Error("SIP message too large".to_string()));
                 }
@@ -60,30 +61,13 @@ impl Decoder for SipCodec {
             }
         };
 
-        if !data.contains("\r\n\r\n") {
-            if src.len() > self.max_size {
-                return Err(crate::Error::Error("SIP message too large".to_string()));
-            }
-            return Ok(None);
-        }
-
-        match SipMessage::try_from(data) {
-            Ok(msg) => {
-                let msg_len = data.find("\r\n\r\n").unwrap() + 4;
-                src.advance(msg_len);
-                Ok(Some(msg))
-            }
-            Err(e) => {
-                if let Some(pos) = data[1..].find("\r\n\r\n") {
-                    src.advance(pos + 5);
-                } else {
-                    src.clear();
-                }
-                Err(crate::Error::Error(format!(
-                    "Failed to parse SIP message: {}",
-                    e
-                )))
-            }
+        let frame = src.split_to(frame_len);
+        match normalize_message(&frame) {
+            Ok(msg) => Ok(Some(msg)),
+            Err(e) => Err(crate::Error::Error(format!(
+                "Failed to parse SIP message: {}",
+                e
+            ))),
         }
     }
 }
@@ -0,0 +1,60 @@
+//! STUN (RFC 5389) packets sharing the SIP UDP port, used by outbound
+//! keepalives (RFC 5626 4.4.2) and ICE-lite deployments.
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+pub const MAGIC_COOKIE: u32 = 0x2112_A442;
+const HEADER_LEN: usize = 20;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+/// Handles a STUN packet received from `addr`, returns the datagram to send
+/// back if any
+pub type StunHandler = Arc<dyn Fn(&[u8], SocketAddr) -> Option<Vec<u8>> + Send + Sync>;
+
+/// SIP messages start with a letter, STUN messages with the two most
+/// significant bits set to zero and the magic cookie at offset 4
+pub fn is_stun(buf: &[u8]) -> bool {
+    buf.len() >= HEADER_LEN
+        && buf[0] & 0xc0 == 0
+        && buf[4..8] == MAGIC_COOKIE.to_be_bytes()
+        && u16::from_be_bytes([buf[2], buf[3]]) as usize + HEADER_LEN == buf.len()
+}
+
+/// Answers a Binding request with the reflexive address of the sender
+pub fn binding_response(request: &[u8], addr: SocketAddr) -> Option<Vec<u8>> {
+    if !is_stun(request) || u16::from_be_bytes([request[0], request[1]]) != BINDING_REQUEST {
+        return None;
+    }
+    let transaction_id = &request[8..20];
+    let port = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    let mut value = vec![0u8];
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            value.push(0x01);
+            value.extend_from_slice(&port.to_be_bytes());
+            let ip = u32::from(ip) ^ MAGIC_COOKIE;
+            value.extend_from_slice(&ip.to_be_bytes());
+        }
+        IpAddr::V6(ip) => {
+            value.push(0x02);
+            value.extend_from_slice(&port.to_be_bytes());
+            let mut mask = MAGIC_COOKIE.to_be_bytes().to_vec();
+            mask.extend_from_slice(transaction_id);
+            value.extend(ip.octets().iter().zip(mask).map(|(b, m)| b ^ m));
+        }
+    }
+
+    let mut response = Vec::with_capacity(HEADER_LEN + 4 + value.len());
+    response.extend_from_slice(&BINDING_SUCCESS.to_be_bytes());
+    response.extend_from_slice(&((4 + value.len()) as u16).to_be_bytes());
+    response.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    response.extend_from_slice(transaction_id);
+    response.extend_from_slice(&XOR_MAPPED_ADDRESS.to_be_bytes());
+    response.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    response.extend_from_slice(&value);
+    Some(response)
+}
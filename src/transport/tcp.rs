@@ -1,6 +1,7 @@
 use crate::{
     transport::{
         connection::{TransportSender, KEEPALIVE_REQUEST, KEEPALIVE_RESPONSE},
+        normalize::normalize_message,
         sip_addr::SipAddr,
         stream::{send_raw_to_stream, send_to_stream, StreamConnection},
         SipConnection, TransportEvent,
@@ -173,18 +174,15 @@ impl StreamConnection for TcpConnection {
                 }
             }
 
-            let undecoded = match std::str::from_utf8(&buf[..len]) {
-                Ok(s) => s,
-                Err(e) => {
-                    info!("decoding text ferror: {} buf: {:?}", e, &buf[..len]);
-                    continue;
-                }
-            };
-
-            let sip_msg = match rsip::SipMessage::try_from(undecoded) {
+            let sip_msg = match normalize_message(&buf[..len]) {
                 Ok(msg) => msg,
                 Err(e) => {
-                    info!("error parsing SIP message error: {} buf: {}", e, undecoded);
+                    sender.send(TransportEvent::BadMessage(
+                        buf[..len].to_vec(),
+                        sip_connection.clone(),
+                        remote_addr.clone(),
+                        e.to_string(),
+                    ))?;
                     continue;
                 }
             };
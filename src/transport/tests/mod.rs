@@ -1,3 +1,9 @@
+mod test_flow;
+mod test_normalize;
+mod test_policy;
+#[cfg(feature = "quic")]
+mod test_quic;
 mod test_sipaddr;
+mod test_stun;
 mod test_udp;
 mod transport_tests;
@@ -0,0 +1,40 @@
+use crate::transport::flow::{flow_token, route_tokens, verify_flow_token, FlowKey};
+use crate::transport::SipAddr;
+
+#[test]
+fn test_flow_token() {
+    let remote = SipAddr {
+        r#type: Some(rsip::transport::Transport::Tcp),
+        addr: rsip::HostWithPort::try_from("192.0.2.1:50123").unwrap(),
+    };
+    let key = FlowKey::new([7; 32]);
+    let token = flow_token(&key, &remote);
+    assert!(token.chars().all(|c| c.is_ascii_hexdigit() || c == '.'));
+    assert_eq!(token, flow_token(&key, &remote.clone()));
+    assert!(verify_flow_token(&key, &token));
+    assert!(!verify_flow_token(&FlowKey::new([8; 32]), &token));
+    assert!(!verify_flow_token(&key, token.split('.').next().unwrap()));
+
+    let other = SipAddr {
+        r#type: Some(rsip::transport::Transport::Tcp),
+        addr: rsip::HostWithPort::try_from("192.0.2.1:50124").unwrap(),
+    };
+    assert_ne!(token, flow_token(&key, &other));
+}
+
+#[test]
+fn test_route_tokens() {
+    let headers: rsip::Headers = vec![
+        rsip::Header::Route("<sip:abcd01@10.0.0.1:5060;lr;ob>".into()),
+        rsip::Header::Route("<sip:10.0.0.2:5060;lr>".into()),
+    ]
+    .into();
+    let request = rsip::Request {
+        method: rsip::Method::Bye,
+        uri: rsip::Uri::try_from("sip:alice@192.0.2.1").unwrap(),
+        headers,
+        version: rsip::Version::V2,
+        body: vec![],
+    };
+    assert_eq!(route_tokens(&request), vec!["abcd01".to_string()]);
+}
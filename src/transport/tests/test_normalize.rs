@@ -0,0 +1,76 @@
+use crate::transport::normalize::{frame_length, normalize_message};
+use rsip::{prelude::HeadersExt, Header, SipMessage};
+
+const OPTIONS: &str = "OPTIONS sip:bob@example.com SIP/2.0\r\n\
+Via: SIP/2.0/UDP 127.0.0.1:5060;branch=z9hG4bKnormalize\r\n\
+From: <sip:alice@example.com>;tag=abc\r\n\
+To: <sip:bob@example.com>\r\n\
+Call-ID: normalize-test\r\n\
+CSeq: 1 OPTIONS\r\n\
+Content-Length: 4\r\n\r\n";
+
+#[test]
+fn test_normalize_junk_and_binary_body() {
+    let mut buf = b"\r\n\x00garbage\r\n".to_vec();
+    buf.extend_from_slice(OPTIONS.as_bytes());
+    buf.extend_from_slice(&[0xff, 0xfe, 0x00, 0x01]);
+    let msg = normalize_message(&buf).expect("normalize message");
+    let req = match msg {
+        SipMessage::Request(req) => req,
+        _ => panic!("expected request"),
+    };
+    assert_eq!(req.body, vec![0xff, 0xfe, 0x00, 0x01]);
+    assert!(req
+        .headers
+        .iter()
+        .any(|h| matches!(h, Header::MaxForwards(_))));
+    assert_eq!(
+        req.call_id_header().unwrap().to_string(),
+        "Call-ID: normalize-test"
+    );
+}
+
+#[test]
+fn test_normalize_content_length() {
+    let mut buf = OPTIONS.as_bytes().to_vec();
+    buf.extend_from_slice(b"bodyEXTRA");
+    let req = match normalize_message(&buf).unwrap() {
+        SipMessage::Request(req) => req,
+        _ => panic!("expected request"),
+    };
+    assert_eq!(req.body, b"body".to_vec());
+
+    // truncated on the way
+    let mut buf = OPTIONS.as_bytes().to_vec();
+    buf.extend_from_slice(b"bo");
+    assert!(normalize_message(&buf).is_err());
+}
+
+#[test]
+fn test_frame_length() {
+    let mut buf = OPTIONS.as_bytes().to_vec();
+    assert_eq!(frame_length(&buf), None);
+    buf.extend_from_slice(b"body");
+    assert_eq!(frame_length(&buf), Some(buf.len()));
+    let len = buf.len();
+    buf.extend_from_slice(OPTIONS.as_bytes());
+    assert_eq!(frame_length(&buf), Some(len));
+}
+
+#[test]
+fn test_make_bad_request() {
+    use crate::transport::normalize::make_bad_request;
+    let broken = OPTIONS.replace("CSeq: 1 OPTIONS", "CSeq: 1 OPTIONS\r\nContact: <<broken");
+    let resp = match make_bad_request(broken.as_bytes(), "invalid contact") {
+        Some(SipMessage::Response(resp)) => resp,
+        _ => panic!("expected response"),
+    };
+    assert_eq!(resp.status_code, rsip::StatusCode::BadRequest);
+    assert_eq!(
+        resp.call_id_header().unwrap().to_string(),
+        "Call-ID: normalize-test"
+    );
+
+    let response = "SIP/2.0 200 OK\r\nCall-ID: x\r\n\r\n";
+    assert!(make_bad_request(response.as_bytes(), "").is_none());
+}
@@ -0,0 +1,46 @@
+use crate::transport::{
+    policy::{DefaultTransportPolicy, TransportPolicy},
+    TransportLayer,
+};
+use rsip::transport::Transport;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+
+#[test]
+fn test_default_transport_policy() {
+    let policy = DefaultTransportPolicy::new()
+        .prefer("secure.restsend.com", Transport::Tls)
+        .tcp_threshold(1300);
+
+    let uri = rsip::Uri::try_from("sip:bob@secure.restsend.com").expect("parse uri");
+    assert_eq!(policy.select(&uri, 100), Some(Transport::Tls));
+
+    let uri = rsip::Uri::try_from("sip:bob@restsend.com").expect("parse uri");
+    assert_eq!(policy.select(&uri, 100), None);
+    assert_eq!(policy.select(&uri, 1500), Some(Transport::Tcp));
+
+    let uri = rsip::Uri::try_from("sip:bob@restsend.com;transport=udp").expect("parse uri");
+    assert_eq!(policy.select(&uri, 1500), Some(Transport::Tcp));
+
+    let uri = rsip::Uri::try_from("sip:bob@secure.restsend.com;transport=ws").expect("parse uri");
+    assert_eq!(policy.select(&uri, 1500), Some(Transport::Ws));
+}
+
+#[test]
+fn test_policy_applies_to_route() {
+    let mut tl = TransportLayer::new(CancellationToken::new());
+    tl.policy = Some(Arc::new(
+        DefaultTransportPolicy::new().prefer("proxy.restsend.com", Transport::Tls),
+    ));
+    // an in-dialog request carrying the route set of the dialog
+    let request = rsip::Request {
+        method: rsip::Method::Bye,
+        uri: rsip::Uri::try_from("sip:bob@restsend.com").expect("parse uri"),
+        headers: vec![rsip::Header::Route("<sip:proxy.restsend.com;lr>".into())].into(),
+        version: rsip::Version::V2,
+        body: vec![],
+    };
+    let next = tl.select_transport(&request);
+    assert_eq!(next.host_with_port.host.to_string(), "proxy.restsend.com");
+    assert_eq!(next.transport(), Some(&Transport::Tls));
+}
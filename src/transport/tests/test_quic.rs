@@ -0,0 +1,81 @@
+use crate::{
+    transport::{quic::QuicConnection, SipConnection, TransportEvent},
+    Result,
+};
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::mpsc::unbounded_channel, time::timeout};
+
+/// A server endpoint with a self-signed certificate for `localhost`, and a
+/// client endpoint trusting it
+fn quic_endpoints() -> Result<(quinn::Endpoint, quinn::Endpoint)> {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+        .expect("self-signed certificate");
+    let cert = certified.cert.der().clone();
+    let key = rustls::pki_types::PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+    let server_config = quinn::ServerConfig::with_single_cert(vec![cert.clone()], key.into())
+        .expect("server config");
+    let server = QuicConnection::create_listener("127.0.0.1:0".parse()?, server_config)?;
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(cert).expect("root certificate");
+    let client_config =
+        quinn::ClientConfig::with_root_certificates(Arc::new(roots)).expect("client config");
+    let mut client = quinn::Endpoint::client("127.0.0.1:0".parse()?)?;
+    client.set_default_client_config(client_config);
+    Ok((server, client))
+}
+
+#[tokio::test]
+async fn test_quic_message_per_stream() -> Result<()> {
+    let (server, client) = quic_endpoints()?;
+    let server_addr = server.local_addr()?;
+    let (server_tx, mut server_rx) = unbounded_channel();
+    tokio::spawn(QuicConnection::serve_listener(server, server_tx));
+
+    let connection = QuicConnection::connect(&client, server_addr, "localhost").await?;
+    let (client_tx, mut client_rx) = unbounded_channel();
+    let client_connection = connection.clone();
+    tokio::spawn(async move { client_connection.serve_loop(client_tx).await });
+
+    // no Content-Length, the end of the stream frames each message
+    let register = "REGISTER sip:bob@restsend.com SIP/2.0\r\nVia: SIP/2.0/UDP 127.0.0.1:5061;branch=z9hG4bKnashd92\r\nCSeq: 1 REGISTER\r\n\r\n";
+    let options = "OPTIONS sip:bob@restsend.com SIP/2.0\r\nVia: SIP/2.0/UDP 127.0.0.1:5061;branch=z9hG4bKnashd93\r\nCSeq: 2 OPTIONS\r\n\r\n";
+    connection.send_raw(register.as_bytes()).await?;
+    connection.send_raw(options.as_bytes()).await?;
+
+    let mut accepted = None;
+    let mut methods = vec![];
+    while methods.len() < 2 {
+        let event = timeout(Duration::from_secs(5), server_rx.recv())
+            .await
+            .expect("timeout waiting for the server")
+            .expect("server events");
+        match event {
+            TransportEvent::New(connection) => accepted = Some(connection),
+            TransportEvent::Incoming(rsip::SipMessage::Request(req), _, _) => {
+                methods.push(req.method)
+            }
+            _ => panic!("unexpected QUIC event"),
+        }
+    }
+    // streams are independent, the messages may arrive in any order
+    methods.sort_by_key(|m| m.to_string());
+    assert_eq!(methods, vec![rsip::Method::Options, rsip::Method::Register]);
+
+    // the accepted connection reaches back the client
+    let Some(SipConnection::Quic(accepted)) = accepted else {
+        panic!("expected an accepted QUIC connection")
+    };
+    let response = "SIP/2.0 200 OK\r\nVia: SIP/2.0/UDP 127.0.0.1:5061;branch=z9hG4bKnashd92\r\nCSeq: 1 REGISTER\r\n\r\n";
+    accepted.send_raw(response.as_bytes()).await?;
+    match timeout(Duration::from_secs(5), client_rx.recv())
+        .await
+        .expect("timeout waiting for the client")
+    {
+        Some(TransportEvent::Incoming(rsip::SipMessage::Response(resp), _, _)) => {
+            assert_eq!(resp.status_code, rsip::StatusCode::OK)
+        }
+        _ => panic!("expected the response on the client"),
+    }
+    Ok(())
+}
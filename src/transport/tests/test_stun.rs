@@ -0,0 +1,34 @@
+use crate::transport::stun::{binding_response, is_stun, MAGIC_COOKIE};
+use std::net::SocketAddr;
+
+fn binding_request() -> Vec<u8> {
+    let mut req = vec![0x00, 0x01, 0x00, 0x00];
+    req.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    req.extend_from_slice(&[7u8; 12]);
+    req
+}
+
+#[test]
+fn test_is_stun() {
+    assert!(is_stun(&binding_request()));
+    assert!(!is_stun(b"OPTIONS sip:bob@example.com SIP/2.0\r\n\r\n"));
+    assert!(!is_stun(b"\r\n\r\n"));
+}
+
+#[test]
+fn test_binding_response() {
+    let addr: SocketAddr = "192.0.2.1:5060".parse().unwrap();
+    let resp = binding_response(&binding_request(), addr).expect("binding response");
+    assert!(is_stun(&resp));
+    assert_eq!(&resp[0..2], &[0x01, 0x01]);
+    assert_eq!(&resp[8..20], &[7u8; 12]);
+    // XOR-MAPPED-ADDRESS
+    assert_eq!(&resp[20..22], &[0x00, 0x20]);
+    let port = u16::from_be_bytes([resp[26], resp[27]]) ^ 0x2112;
+    assert_eq!(port, 5060);
+    let ip = u32::from_be_bytes([resp[28], resp[29], resp[30], resp[31]]) ^ MAGIC_COOKIE;
+    assert_eq!(
+        std::net::Ipv4Addr::from(ip),
+        std::net::Ipv4Addr::new(192, 0, 2, 1)
+    );
+}
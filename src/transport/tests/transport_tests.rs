@@ -65,6 +65,9 @@ async fn test_tcp_client_server() -> Result<()> {
         TransportEvent::New(_conn) => {
             info!("Connection created");
         }
+        TransportEvent::BadMessage(_, _, addr, e) => {
+            panic!("bad message from {}: {}", addr, e);
+        }
     }
 
     // Close connection
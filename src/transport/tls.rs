@@ -1,5 +1,6 @@
 use super::{
     connection::{TransportSender, KEEPALIVE_REQUEST, KEEPALIVE_RESPONSE},
+    normalize::normalize_message,
     sip_addr::SipAddr,
     stream::StreamConnection,
     SipConnection, TransportEvent,
@@ -402,18 +403,15 @@ impl StreamConnection for TlsConnection {
                 }
             }
 
-            let undecoded = match std::str::from_utf8(&buf[..len]) {
-                Ok(s) => s,
-                Err(e) => {
-                    info!("decoding text ferror: {} buf: {:?}", e, &buf[..len]);
-                    continue;
-                }
-            };
-
-            let sip_msg = match rsip::SipMessage::try_from(undecoded) {
+            let sip_msg = match normalize_message(&buf[..len]) {
                 Ok(msg) => msg,
                 Err(e) => {
-                    info!("error parsing SIP message error: {} buf: {}", e, undecoded);
+                    sender.send(TransportEvent::BadMessage(
+                        buf[..len].to_vec(),
+                        sip_connection.clone(),
+                        remote_addr.clone(),
+                        e.to_string(),
+                    ))?;
                     continue;
                 }
             };
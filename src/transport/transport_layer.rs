@@ -1,25 +1,52 @@
 use super::tls::{TlsConfig, TlsConnection};
 use super::websocket::WebSocketConnection;
-use super::{connection::TransportSender, sip_addr::SipAddr, tcp::TcpConnection, SipConnection};
-use crate::{transport::TransportEvent, Result};
+use super::{
+    connection::TransportSender,
+    flow::{flow_token, route_tokens, verify_flow_token, Flow, FlowKey},
+    normalize::BadMessagePolicy,
+    sip_addr::SipAddr,
+    tcp::TcpConnection,
+    SipConnection, TransportPolicy,
+};
+use crate::{rsip_ext::next_hop, transport::TransportEvent, Result};
 use rsip::HostWithPort;
 use rsip_dns::{trust_dns_resolver::TokioAsyncResolver, ResolvableExt};
 use std::net::SocketAddr;
 use std::{
     collections::HashMap,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 use tokio::select;
 use tokio_util::sync::CancellationToken;
 use tracing::{info, warn};
 
+const DEFAULT_BLACKLIST_TTL: Duration = Duration::from_secs(30);
+
 /// 传输层配置
-#[derive(Default, Clone)]
+#[derive(Clone)]
 pub struct TransportConfig {
     /// TLS 配置
     pub tls: Option<TlsConfig>,
     pub enable_ws: bool,
     pub enable_wss: bool,
+    /// Handling of unparsable messages per transport, dropped by default
+    pub bad_message: HashMap<rsip::transport::Transport, BadMessagePolicy>,
+    /// How long a target that failed, by a timeout or a refused connection,
+    /// is skipped by the lookups. Zero disables the blacklist.
+    pub blacklist_ttl: Duration,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            tls: None,
+            enable_ws: false,
+            enable_wss: false,
+            bad_message: HashMap::new(),
+            blacklist_ttl: DEFAULT_BLACKLIST_TTL,
+        }
+    }
 }
 
 #[derive(Default)]
@@ -27,23 +54,56 @@ pub struct TransportLayerInner {
     cancel_token: CancellationToken,
     listens: Arc<Mutex<HashMap<SipAddr, SipConnection>>>, // 监听的传输
     config: Arc<Mutex<TransportConfig>>,
+    flows: Mutex<HashMap<String, Flow>>,
+    flow_key: FlowKey,
+    /// Targets that failed recently, and until when they are skipped
+    blacklist: Mutex<HashMap<SipAddr, Instant>>,
 }
 
 #[derive(Default)]
 pub struct TransportLayer {
     pub outbound: Option<SipAddr>,
+    pub policy: Option<Arc<dyn TransportPolicy>>,
     inner: Arc<TransportLayerInner>,
 }
 
 impl TransportLayer {
+    pub fn set_bad_message_policy(
+        &self,
+        transport: rsip::transport::Transport,
+        policy: BadMessagePolicy,
+    ) {
+        self.inner
+            .config
+            .lock()
+            .unwrap()
+            .bad_message
+            .insert(transport, policy);
+    }
+
+    pub fn bad_message_policy(&self, transport: rsip::transport::Transport) -> BadMessagePolicy {
+        self.inner
+            .config
+            .lock()
+            .unwrap()
+            .bad_message
+            .get(&transport)
+            .cloned()
+            .unwrap_or_default()
+    }
+
     pub fn new(cancel_token: CancellationToken) -> Self {
         let inner = TransportLayerInner {
             cancel_token,
             listens: Arc::new(Mutex::new(HashMap::new())),
             config: Arc::new(Mutex::new(TransportConfig::default())),
+            flows: Mutex::new(HashMap::new()),
+            flow_key: FlowKey::default(),
+            blacklist: Mutex::new(HashMap::new()),
         };
         Self {
             outbound: None,
+            policy: None,
             inner: Arc::new(inner),
         }
     }
@@ -53,9 +113,13 @@ impl TransportLayer {
             cancel_token,
             listens: Arc::new(Mutex::new(HashMap::new())),
             config: Arc::new(Mutex::new(config)),
+            flows: Mutex::new(HashMap::new()),
+            flow_key: FlowKey::default(),
+            blacklist: Mutex::new(HashMap::new()),
         };
         Self {
             outbound: None,
+            policy: None,
             inner: Arc::new(inner),
         }
     }
@@ -68,12 +132,105 @@ impl TransportLayer {
         self.inner.del_connection(addr)
     }
 
+    /// Applies the transport policy to the next hop of `request`, its first
+    /// loose route or its Request-URI, returns the uri that should be
+    /// resolved to reach it
+    pub fn select_transport(&self, request: &rsip::Request) -> rsip::Uri {
+        let mut uri = next_hop(request).unwrap_or_else(|| request.uri.clone());
+        let policy = match self.policy.as_ref() {
+            Some(policy) => policy,
+            None => return uri,
+        };
+        let message_size = request.to_string().len();
+        match policy.select(&uri, message_size) {
+            Some(transport) => {
+                if uri.transport() != Some(&transport) {
+                    info!(
+                        "transport policy selected {} for {} ({} bytes)",
+                        transport, uri, message_size
+                    );
+                    uri.params
+                        .retain(|p| !matches!(p, rsip::Param::Transport(_)));
+                    uri.params.push(rsip::Param::Transport(transport));
+                }
+            }
+            None => {}
+        }
+        uri
+    }
+
+    /// Token of the flow of a peer, whether it's remembered or not
+    pub fn flow_token(&self, remote: &SipAddr) -> String {
+        flow_token(&self.inner.flow_key, remote)
+    }
+
+    /// Remembers the flow of a peer, returns its token for the Record-Route
+    pub fn register_flow(&self, connection: SipConnection, remote: SipAddr) -> String {
+        let token = self.flow_token(&remote);
+        self.inner
+            .flows
+            .lock()
+            .unwrap()
+            .insert(token.clone(), Flow { connection, remote });
+        token
+    }
+
+    /// Flow of `token`, `None` when it's unknown or wasn't issued by us
+    pub fn get_flow(&self, token: &str) -> Option<Flow> {
+        if !verify_flow_token(&self.inner.flow_key, token) {
+            return None;
+        }
+        self.inner.flows.lock().unwrap().get(token).cloned()
+    }
+
+    /// Flow referenced by the Route headers of an in-dialog request, the
+    /// tokens we didn't sign being ignored
+    pub fn route_flow(&self, request: &rsip::Request) -> Option<Flow> {
+        route_tokens(request)
+            .iter()
+            .find_map(|token| self.get_flow(token))
+    }
+
+    /// Forgets the flows over a closed connection
+    pub fn remove_flow(&self, connection: &SipConnection) {
+        self.inner.flows.lock().unwrap().retain(|_, flow| {
+            flow.connection.get_addr() != connection.get_addr()
+                || flow.connection.remote_addr() != connection.remote_addr()
+        });
+    }
+
     pub async fn lookup(
         &self,
         uri: &rsip::uri::Uri,
         sender: TransportSender,
     ) -> Result<SipConnection> {
-        self.inner.lookup(uri, self.outbound.as_ref(), sender).await
+        let target = self.resolve(uri).await?;
+        self.connect(&target, sender).await
+    }
+
+    /// Target of `uri`, the outbound proxy if any, skipping the blacklisted
+    /// ones among its DNS results
+    pub async fn resolve(&self, uri: &rsip::uri::Uri) -> Result<SipAddr> {
+        self.inner.resolve(uri, self.outbound.as_ref()).await
+    }
+
+    /// Connection reaching `target`, a listener for UDP. A refused
+    /// connection blacklists it.
+    pub async fn connect(
+        &self,
+        target: &SipAddr,
+        sender: TransportSender,
+    ) -> Result<SipConnection> {
+        self.inner.connect(target, sender).await
+    }
+
+    /// Skips `target` in the lookups for the blacklist TTL, after it failed
+    pub fn blacklist(&self, target: SipAddr) {
+        self.inner.blacklist(target)
+    }
+
+    pub fn is_blacklisted(&self, target: &SipAddr) -> bool {
+        self.inner.is_blacklisted(target)
     }
 
     pub async fn serve_listens(&self, sender: TransportSender) -> Result<()> {
@@ -101,6 +258,13 @@ impl TransportLayer {
         self.inner.listens.lock().unwrap().keys().cloned().collect()
     }
 
+    /// Address of a listener of `transport`, the sent-by of a Via naming it
+    pub fn listener_addr(&self, transport: &rsip::transport::Transport) -> Option<SipAddr> {
+        self.get_addrs()
+            .into_iter()
+            .find(|addr| addr.r#type.as_ref() == Some(transport))
+    }
+
     /// 创建并添加 UDP 监听器
     pub async fn add_udp_listener(&self, local: SocketAddr) -> Result<SipAddr> {
         use super::udp::UdpConnection;
@@ -243,58 +407,89 @@ impl TransportLayerInner {
         self.listens.lock().unwrap().remove(addr);
     }
 
-    async fn lookup<'a>(
-        &'a self,
-        uri: &rsip::uri::Uri,
-        outbound: Option<&'a SipAddr>,
-        sender: TransportSender,
-    ) -> Result<SipConnection> {
-        let target = if let Some(addr) = outbound {
-            addr
-        } else {
-            let context = rsip_dns::Context::initialize_from(
-                uri.clone(),
-                rsip_dns::AsyncTrustDnsClient::new(
-                    TokioAsyncResolver::tokio(Default::default(), Default::default()).unwrap(),
-                ),
-                rsip_dns::SupportedTransports::any(),
-            )?;
+    fn blacklist(&self, target: SipAddr) {
+        let ttl = self.config.lock().unwrap().blacklist_ttl;
+        if ttl.is_zero() {
+            return;
+        }
+        info!("blacklisting {} for {:?}", target, ttl);
+        self.blacklist
+            .lock()
+            .unwrap()
+            .insert(target, Instant::now() + ttl);
+    }
 
-            let mut lookup = rsip_dns::Lookup::from(context);
-            match lookup.resolve_next().await {
-                Some(mut target) => {
-                    match uri.host_with_port.host {
-                        rsip::Host::IpAddr(_) => {
-                            if let Some(port) = uri.host_with_port.port {
-                                target.port = port;
-                            }
-                        }
-                        _ => {}
-                    }
-                    &SipAddr {
-                        r#type: Some(target.transport),
-                        addr: HostWithPort::from(SocketAddr::new(
-                            target.ip_addr,
-                            u16::from(target.port),
-                        )),
+    fn is_blacklisted(&self, target: &SipAddr) -> bool {
+        let mut blacklist = self.blacklist.lock().unwrap();
+        match blacklist.get(target) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                blacklist.remove(target);
+                false
+            }
+            None => false,
+        }
+    }
+
+    async fn resolve(&self, uri: &rsip::uri::Uri, outbound: Option<&SipAddr>) -> Result<SipAddr> {
+        if let Some(addr) = outbound {
+            if self.is_blacklisted(addr) {
+                return Err(crate::Error::TransportLayerError(
+                    "outbound proxy blacklisted".to_string(),
+                    addr.to_owned(),
+                ));
+            }
+            return Ok(addr.to_owned());
+        }
+        let context = rsip_dns::Context::initialize_from(
+            uri.clone(),
+            rsip_dns::AsyncTrustDnsClient::new(
+                TokioAsyncResolver::tokio(Default::default(), Default::default()).unwrap(),
+            ),
+            rsip_dns::SupportedTransports::any(),
+        )?;
+
+        let mut lookup = rsip_dns::Lookup::from(context);
+        let mut skipped = 0;
+        while let Some(mut target) = lookup.resolve_next().await {
+            match uri.host_with_port.host {
+                rsip::Host::IpAddr(_) => {
+                    if let Some(port) = uri.host_with_port.port {
+                        target.port = port;
                     }
                 }
-                None => {
-                    return Err(crate::Error::DnsResolutionError(format!(
-                        "DNS resolution error: {}",
-                        uri
-                    )))
-                }
+                _ => {}
             }
-        };
-
-        info!("lookup target: {} -> {}", uri, target);
+            let target = SipAddr {
+                r#type: Some(target.transport),
+                addr: HostWithPort::from(SocketAddr::new(target.ip_addr, u16::from(target.port))),
+            };
+            if self.is_blacklisted(&target) {
+                info!("skipping blacklisted target: {} -> {}", uri, target);
+                skipped += 1;
+                continue;
+            }
+            info!("lookup target: {} -> {}", uri, target);
+            return Ok(target);
+        }
+        if skipped > 0 {
+            return Err(crate::Error::DnsResolutionError(format!(
+                "all {} targets of {} blacklisted",
+                skipped, uri
+            )));
+        }
+        Err(crate::Error::DnsResolutionError(format!(
+            "DNS resolution error: {}",
+            uri
+        )))
+    }
 
-        if let Some(transport) = self.listens.lock().unwrap().get(&target) {
+    async fn connect(&self, target: &SipAddr, sender: TransportSender) -> Result<SipConnection> {
+        if let Some(transport) = self.listens.lock().unwrap().get(target) {
             return Ok(transport.clone());
         }
 
-        match target.r#type {
+        let connected = match target.r#type {
             Some(rsip::transport::Transport::Udp) => {
                 let listens = self.listens.lock().unwrap();
                 for (_, transport) in listens.iter() {
@@ -302,32 +497,38 @@ impl TransportLayerInner {
                         return Ok(transport.clone());
                     }
                 }
+                None
             }
             Some(rsip::transport::Transport::Tcp) => {
-                let connection = TcpConnection::connect(target).await?;
-                let sip_connection = SipConnection::Tcp(connection);
-                self.start_serve(sip_connection.clone(), sender);
-                return Ok(sip_connection);
+                Some(TcpConnection::connect(target).await.map(SipConnection::Tcp))
             }
-            Some(rsip::transport::Transport::Tls) => {
-                let connection = TlsConnection::connect(target, None).await?;
-                let sip_connection = SipConnection::Tls(connection);
+            Some(rsip::transport::Transport::Tls) => Some(
+                TlsConnection::connect(target, None)
+                    .await
+                    .map(SipConnection::Tls),
+            ),
+            Some(rsip::transport::Transport::Ws) | Some(rsip::transport::Transport::Wss) => Some(
+                WebSocketConnection::connect(target)
+                    .await
+                    .map(SipConnection::WebSocket),
+            ),
+            _ => None,
+        };
+
+        match connected {
+            Some(Ok(sip_connection)) => {
                 self.start_serve(sip_connection.clone(), sender);
-                return Ok(sip_connection);
+                Ok(sip_connection)
             }
-            Some(rsip::transport::Transport::Ws) | Some(rsip::transport::Transport::Wss) => {
-                let connection = WebSocketConnection::connect(target).await?;
-                let sip_connection = SipConnection::WebSocket(connection);
-                self.start_serve(sip_connection.clone(), sender);
-                return Ok(sip_connection);
+            Some(Err(e)) => {
+                self.blacklist(target.to_owned());
+                Err(e)
             }
-            _ => {}
+            None => Err(crate::Error::TransportLayerError(
+                format!("unsupported transport type: {:?}", target.r#type),
+                target.to_owned(),
+            )),
         }
-
-        return Err(crate::Error::TransportLayerError(
-            format!("unsupported transport type: {:?}", target.r#type),
-            target.to_owned(),
-        ));
     }
 
     async fn serve_listens(&self, sender: TransportSender) -> Result<()> {
@@ -394,6 +595,73 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_blacklist() -> Result<()> {
+        let tl = super::TransportLayer::new(tokio_util::sync::CancellationToken::new());
+        let (sender, _receiver) = mpsc::unbounded_channel();
+
+        // nobody listens there anymore
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let uri = rsip::uri::Uri::try_from(format!("sip:bob@{};transport=tcp", closed).as_str())?;
+        let target = tl.resolve(&uri).await?;
+        assert!(tl.connect(&target, sender.clone()).await.is_err());
+        assert!(tl.is_blacklisted(&target));
+        assert!(tl.resolve(&uri).await.is_err());
+
+        let config = super::TransportConfig {
+            blacklist_ttl: std::time::Duration::ZERO,
+            ..Default::default()
+        };
+        let tl =
+            super::TransportLayer::with_config(tokio_util::sync::CancellationToken::new(), config);
+        tl.blacklist(target.clone());
+        assert!(!tl.is_blacklisted(&target));
+        assert!(tl.resolve(&uri).await.is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_flows() -> Result<()> {
+        let tl = super::TransportLayer::new(tokio_util::sync::CancellationToken::new());
+        let connection: super::SipConnection =
+            UdpConnection::create_connection("127.0.0.1:0".parse()?, None)
+                .await?
+                .into();
+        let remote = super::SipAddr {
+            r#type: Some(Transport::Udp),
+            addr: rsip::HostWithPort::try_from("192.0.2.1:50123").unwrap(),
+        };
+        // the token is known before the flow is remembered
+        let token = tl.flow_token(&remote);
+        assert!(tl.get_flow(&token).is_none());
+        assert_eq!(tl.register_flow(connection.clone(), remote.clone()), token);
+        let request_via = |token: &str| rsip::Request {
+            method: rsip::Method::Bye,
+            uri: rsip::Uri::try_from("sip:alice@192.0.2.1").unwrap(),
+            headers: vec![rsip::Header::Route(
+                format!("<sip:{}@10.0.0.1:5060;lr;ob>", token).into(),
+            )]
+            .into(),
+            version: rsip::Version::V2,
+            body: vec![],
+        };
+        assert_eq!(
+            tl.route_flow(&request_via(&token)).map(|f| f.remote),
+            Some(remote.clone())
+        );
+
+        // a token of another flow, not signed by us
+        let (flow, mac) = token.rsplit_once('.').unwrap();
+        let forged = format!("{}00.{}", flow, mac);
+        assert!(tl.route_flow(&request_via(&forged)).is_none());
+        let other = super::TransportLayer::new(tokio_util::sync::CancellationToken::new());
+        assert!(other.route_flow(&request_via(&token)).is_none());
+
+        tl.remove_flow(&connection);
+        assert!(tl.get_flow(&token).is_none());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_tcp_listener() -> Result<()> {
         let tl = super::TransportLayer::new(tokio_util::sync::CancellationToken::new());
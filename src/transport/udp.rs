@@ -2,6 +2,8 @@ use super::{connection::TransportSender, SipAddr, SipConnection};
 use crate::{
     transport::{
         connection::{KEEPALIVE_REQUEST, KEEPALIVE_RESPONSE},
+        normalize::normalize_message,
+        stun::{binding_response, is_stun, StunHandler},
         TransportEvent,
     },
     Result,
@@ -17,6 +19,7 @@ pub struct UdpInner {
 #[derive(Clone)]
 pub struct UdpConnection {
     pub external: Option<SipAddr>,
+    stun_handler: Option<StunHandler>,
     inner: Arc<UdpInner>,
 }
 
@@ -27,6 +30,7 @@ impl UdpConnection {
                 r#type: Some(rsip::transport::Transport::Udp),
                 addr: addr.into(),
             }),
+            stun_handler: None,
             inner: Arc::new(inner),
         }
     }
@@ -47,12 +51,20 @@ impl UdpConnection {
                 r#type: Some(rsip::transport::Transport::Udp),
                 addr: addr.into(),
             }),
+            stun_handler: None,
             inner: Arc::new(UdpInner { addr, conn }),
         };
         info!("created UDP connection: {} external: {:?}", t, external);
         Ok(t)
     }
 
+    /// Routes STUN packets received on the SIP port to `handler`, Binding
+    /// requests are answered by `stun::binding_response` without one
+    pub fn with_stun_handler(mut self, handler: StunHandler) -> Self {
+        self.stun_handler = Some(handler);
+        self
+    }
+
     pub async fn serve_loop(&self, sender: TransportSender) -> Result<()> {
         let mut buf = vec![0u8; 2048];
         loop {
@@ -70,6 +82,16 @@ impl UdpConnection {
                     continue;
                 }
                 KEEPALIVE_RESPONSE => continue,
+                data if is_stun(data) => {
+                    let response = match self.stun_handler.as_ref() {
+                        Some(handler) => handler(data, addr),
+                        None => binding_response(data, addr),
+                    };
+                    if let Some(response) = response {
+                        self.inner.conn.send_to(&response, addr).await.ok();
+                    }
+                    continue;
+                }
                 _ => {
                     if buf.iter().all(|&b| b.is_ascii_whitespace()) {
                         continue;
@@ -77,26 +99,18 @@ impl UdpConnection {
                 }
             }
 
-            let undecoded = match std::str::from_utf8(&buf[..len]) {
-                Ok(s) => s,
-                Err(e) => {
-                    info!(
-                        "decoding text from: {} error: {} buf: {:?}",
-                        addr,
-                        e,
-                        &buf[..len]
-                    );
-                    continue;
-                }
-            };
-
-            let msg = match rsip::SipMessage::try_from(undecoded) {
+            let msg = match normalize_message(&buf[..len]) {
                 Ok(msg) => msg,
                 Err(e) => {
-                    info!(
-                        "error parsing SIP message from: {} error: {} buf: {}",
-                        addr, e, undecoded
-                    );
+                    sender.send(TransportEvent::BadMessage(
+                        buf[..len].to_vec(),
+                        SipConnection::Udp(self.clone()),
+                        SipAddr {
+                            r#type: Some(rsip::transport::Transport::Udp),
+                            addr: addr.into(),
+                        },
+                        e.to_string(),
+                    ))?;
                     continue;
                 }
             };
@@ -104,21 +118,12 @@ impl UdpConnection {
             let msg = match SipConnection::update_msg_received(msg, addr) {
                 Ok(msg) => msg,
                 Err(e) => {
-                    info!(
-                        "error updating SIP via from: {} error: {:?} buf: {}",
-                        addr, e, undecoded
-                    );
+                    info!("error updating SIP via from: {} error: {:?}", addr, e);
                     continue;
                 }
             };
 
-            debug!(
-                "received {} {} -> {} {}",
-                len,
-                addr,
-                self.get_addr(),
-                undecoded
-            );
+            debug!("received {} {} -> {} {}", len, addr, self.get_addr(), msg);
 
             sender.send(TransportEvent::Incoming(
                 msg,
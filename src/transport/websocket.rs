@@ -1,6 +1,7 @@
 use crate::{
     transport::{
         connection::{TransportSender, KEEPALIVE_REQUEST, KEEPALIVE_RESPONSE},
+        normalize::normalize_message,
         sip_addr::SipAddr,
         stream::StreamConnection,
         SipConnection, TransportEvent,
@@ -184,7 +185,7 @@ impl StreamConnection for WebSocketConnection {
         let mut ws_read = self.inner.ws_read.lock().await;
         while let Some(msg) = ws_read.next().await {
             match msg {
-                Ok(Message::Text(text)) => match SipMessage::try_from(text.as_str()) {
+                Ok(Message::Text(text)) => match normalize_message(text.as_bytes()) {
                     Ok(sip_msg) => {
                         if let Err(e) = sender.send(TransportEvent::Incoming(
                             sip_msg,
@@ -197,6 +198,14 @@ impl StreamConnection for WebSocketConnection {
                     }
                     Err(e) => {
                         warn!("Error parsing SIP message: {}", e);
+                        sender
+                            .send(TransportEvent::BadMessage(
+                                text.as_bytes().to_vec(),
+                                sip_connection.clone(),
+                                remote_addr.clone(),
+                                e.to_string(),
+                            ))
+                            .ok();
                     }
                 },
                 Ok(Message::Binary(bin)) => {
@@ -207,24 +216,27 @@ impl StreamConnection for WebSocketConnection {
                         continue;
                     }
 
-                    match std::str::from_utf8(&bin) {
-                        Ok(text) => match SipMessage::try_from(text) {
-                            Ok(sip_msg) => {
-                                if let Err(e) = sender.send(TransportEvent::Incoming(
-                                    sip_msg,
-                                    sip_connection.clone(),
-                                    remote_addr.clone(),
-                                )) {
-                                    error!("Error sending incoming message: {:?}", e);
-                                    break;
-                                }
-                            }
-                            Err(e) => {
-                                warn!("Error parsing SIP message: {}", e);
+                    match normalize_message(&bin) {
+                        Ok(sip_msg) => {
+                            if let Err(e) = sender.send(TransportEvent::Incoming(
+                                sip_msg,
+                                sip_connection.clone(),
+                                remote_addr.clone(),
+                            )) {
+                                error!("Error sending incoming message: {:?}", e);
+                                break;
                             }
-                        },
+                        }
                         Err(e) => {
-                            warn!("Error decoding binary message: {}", e);
+                            warn!("Error parsing SIP message: {}", e);
+                            sender
+                                .send(TransportEvent::BadMessage(
+                                    bin.to_vec(),
+                                    sip_connection.clone(),
+                                    remote_addr.clone(),
+                                    e.to_string(),
+                                ))
+                                .ok();
                         }
                     }
                 }